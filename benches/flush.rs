@@ -0,0 +1,81 @@
+//! Compares peak heap usage of flushing the ordered account store against the
+//! previous approach of collecting and sorting a `HashMap` first.
+//!
+//! Run with `cargo bench --bench flush`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use payments_engine::domain::{Account, ClientId, OutputRepository};
+use payments_engine::output_repository::{MemoryOutput, format_amount};
+
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const CLIENTS: ClientId = u16::MAX as ClientId;
+
+/// Runs `f` and prints its peak heap growth and wall-clock time.
+fn measure<F: FnOnce()>(name: &str, f: F) {
+    let base = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    let peak = PEAK.load(Ordering::Relaxed) - base;
+    println!("{name:<24} peak +{peak:>10} bytes  {elapsed:?}");
+}
+
+fn main() {
+    let mut repo = MemoryOutput::new();
+    let mut unordered: HashMap<ClientId, Account> = HashMap::new();
+    for client_id in 0..CLIENTS {
+        repo.get_or_create_account(&client_id).unwrap().available = client_id.into();
+        unordered.entry(client_id).or_default().available = client_id.into();
+    }
+
+    measure("ordered store flush", || {
+        repo.flush().unwrap();
+    });
+
+    measure("sort-then-write flush", || {
+        let mut rows: Vec<_> = unordered.iter().collect();
+        rows.sort_by_key(|(client_id, _)| **client_id);
+        let mut out = io::sink();
+        for (client_id, account) in rows {
+            writeln!(
+                out,
+                "{},{},{},{},{}",
+                client_id,
+                format_amount(account.available),
+                format_amount(account.held),
+                format_amount(account.total),
+                account.locked
+            )
+            .unwrap();
+        }
+    });
+}
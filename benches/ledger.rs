@@ -0,0 +1,108 @@
+//! Compares candidate maps for the ledger, keyed by `(client, tx)` like
+//! `output_repository::LedgerMap`, under the access pattern of a transaction stream:
+//! every deposit or withdrawal inserts, and a few percent of rows dispute and later
+//! resolve an earlier transaction, which looks it up twice.
+//!
+//! Run with `cargo bench --bench ledger`. `LEDGER_TXS` sets the number of transactions
+//! per iteration (default one million).
+
+use std::collections::{BTreeMap, HashMap};
+use std::hint::black_box;
+
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use payments_engine::domain::ClientId;
+use rustc_hash::FxHashMap;
+
+const CLIENTS: u32 = 10_000;
+
+type Key = (ClientId, u32);
+
+/// The map operations the ledger needs.
+trait Ledger: Default {
+    fn insert(&mut self, key: Key, amount: u64);
+    fn get_mut(&mut self, key: &Key) -> Option<&mut u64>;
+}
+
+macro_rules! ledger {
+    ($map:ident) => {
+        impl Ledger for $map<Key, u64> {
+            fn insert(&mut self, key: Key, amount: u64) {
+                $map::insert(self, key, amount);
+            }
+
+            fn get_mut(&mut self, key: &Key) -> Option<&mut u64> {
+                $map::get_mut(self, key)
+            }
+        }
+    };
+}
+
+ledger!(HashMap);
+ledger!(BTreeMap);
+ledger!(FxHashMap);
+
+/// Clients take turns, as in a busy stream. Every 25th row of a client disputes its
+/// most recent deposit and the next one resolves it.
+fn synthetic(count: u32) -> Vec<(Key, bool)> {
+    (0..count)
+        .map(|i| {
+            let client_id = (i % CLIENTS) as ClientId;
+            let round = i / CLIENTS;
+            match round % 25 {
+                23 | 24 => ((client_id, i - CLIENTS * (round % 25 - 22)), true),
+                _ => ((client_id, i), false),
+            }
+        })
+        .collect()
+}
+
+fn replay<L: Ledger>(rows: &[(Key, bool)]) -> L {
+    let mut ledger = L::default();
+    for &(key, reference) in rows {
+        if reference {
+            if let Some(amount) = ledger.get_mut(&key) {
+                *amount += 1;
+            }
+        } else {
+            ledger.insert(key, u64::from(key.1));
+        }
+    }
+    ledger
+}
+
+fn ledger(c: &mut Criterion) {
+    let count = std::env::var("LEDGER_TXS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1_000_000);
+    let rows = synthetic(count);
+
+    let mut group = c.benchmark_group("ledger");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(count.into()));
+    group.bench_function("HashMap", |b| {
+        b.iter_batched(
+            || (),
+            |()| black_box(replay::<HashMap<_, _>>(&rows)),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("BTreeMap", |b| {
+        b.iter_batched(
+            || (),
+            |()| black_box(replay::<BTreeMap<_, _>>(&rows)),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("FxHashMap", |b| {
+        b.iter_batched(
+            || (),
+            |()| black_box(replay::<FxHashMap<_, _>>(&rows)),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ledger);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Measures end-to-end engine throughput on synthetic transactions.
+//!
+//! Run with `cargo bench --bench throughput`. `THROUGHPUT_TXS` sets the number of
+//! transactions per iteration (default one million).
+
+use std::hint::black_box;
+
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use payments_engine::domain::{ClientId, Error, Transaction, TransactionKind};
+use payments_engine::engine::Engine;
+use payments_engine::ingestion::CsvReader;
+use rust_decimal::Decimal;
+
+const CLIENTS: u32 = 10_000;
+
+/// Blocks of 50 transactions per client: mostly deposits and withdrawals, plus a
+/// dispute and a resolve of the block's second deposit.
+fn synthetic(count: u32) -> Vec<Result<Transaction, Error>> {
+    (0..count)
+        .map(|i| {
+            let client_id = (i / 50 % CLIENTS) as ClientId;
+            let kind = match i % 50 {
+                47 => TransactionKind::Dispute { amount: None },
+                48 => TransactionKind::Resolve,
+                n if n % 3 == 0 => TransactionKind::Withdrawal {
+                    amount: Decimal::new(25, 2),
+                },
+                _ => TransactionKind::Deposit {
+                    amount: Decimal::new(1_000, 2),
+                },
+            };
+            let transaction_id = match kind {
+                TransactionKind::Dispute { .. } | TransactionKind::Resolve => i - i % 50 + 1,
+                _ => i,
+            };
+            Ok(Transaction {
+                kind,
+                client_id,
+                transaction_id,
+                currency: None,
+            })
+        })
+        .collect()
+}
+
+fn throughput(c: &mut Criterion) {
+    let count = std::env::var("THROUGHPUT_TXS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1_000_000);
+
+    let mut group = c.benchmark_group("engine");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(count.into()));
+    group.bench_function("process_all", |b| {
+        b.iter_batched(
+            || synthetic(count),
+            |txs| {
+                let mut engine = Engine::builder(CsvReader::new(std::io::empty()).unwrap()).build();
+                engine.process_all(txs).unwrap();
+                black_box(engine)
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
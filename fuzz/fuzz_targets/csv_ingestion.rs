@@ -0,0 +1,39 @@
+//! Feeds arbitrary bytes through `CsvReader` and the row-to-`Transaction` conversion.
+//! Every input must come out as `Ok` or `Err` rows; any panic is a bug.
+//!
+//! Needs a nightly toolchain and `cargo install cargo-fuzz`. From the repository root:
+//!
+//! ```text
+//! cargo +nightly fuzz run csv_ingestion fuzz/corpus/csv_ingestion -- -max_len=512
+//! ```
+//!
+//! Crashing inputs land in `fuzz/artifacts/csv_ingestion/`; replay one by passing its
+//! path instead of the corpus directory.
+
+#![no_main]
+
+use std::io::Cursor;
+
+use futures::StreamExt;
+use libfuzzer_sys::fuzz_target;
+use payments_engine::domain::Money;
+use payments_engine::domain::traits::TransactionStream;
+use payments_engine::ingestion::{CsvReader, IngestionOptions};
+
+fuzz_target!(|data: &[u8]| {
+    for lenient_amounts in [false, true] {
+        let options = IngestionOptions {
+            lenient_amounts,
+            ..Default::default()
+        };
+        let Ok(mut reader) = CsvReader::with_options(Cursor::new(data.to_vec()), options) else {
+            continue;
+        };
+        let stream = reader.stream().expect("fresh reader");
+        futures::executor::block_on(stream.for_each(|_| async {}));
+    }
+
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = Money::<4>::from_decimal_str(text);
+    }
+});
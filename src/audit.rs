@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::clock::{self, SystemClock};
+use crate::domain::{AuditSink, BalanceEvent, Clock, Error};
+
+#[derive(Default, Debug)]
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {
+    fn record(&self, _event: &BalanceEvent) {}
+}
+
+/// Appends one CSV line per applied transaction:
+/// `client,tx,type,available,held,total,locked` before and after the change, then the
+/// time it was recorded.
+#[derive(Debug)]
+pub struct FileAuditSink<C = SystemClock> {
+    writer: Mutex<BufWriter<File>>,
+    clock: C,
+}
+
+impl FileAuditSink {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::open_with_clock(path, SystemClock)
+    }
+}
+
+impl<C: Clock> FileAuditSink<C> {
+    pub fn open_with_clock(path: &Path, clock: C) -> Result<Self, Error> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+            clock,
+        })
+    }
+}
+
+impl<C: Clock> AuditSink for FileAuditSink<C> {
+    fn record(&self, event: &BalanceEvent) {
+        let (b, a) = (&event.before, &event.after);
+        let now = clock::timestamp(&self.clock);
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            event.client_id,
+            event.transaction_id,
+            event.kind.name(),
+            b.available,
+            b.held,
+            b.total,
+            b.locked,
+            a.available,
+            a.held,
+            a.total,
+            a.locked,
+            now
+        ) {
+            eprintln!("Audit log write failed: {}", e);
+        }
+    }
+}
+
+impl<C> Drop for FileAuditSink<C> {
+    fn drop(&mut self) {
+        if let Ok(writer) = self.writer.get_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::domain::{AccountSnapshot, TransactionKind};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn file_sink_appends_csv_lines() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let before = AccountSnapshot {
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            total: Decimal::ZERO,
+            locked: false,
+            on_hold: Decimal::ZERO,
+        };
+        let after = AccountSnapshot {
+            available: Decimal::from(5u32),
+            total: Decimal::from(5u32),
+            ..before
+        };
+        {
+            let clock = FixedClock(Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap());
+            let sink = FileAuditSink::open_with_clock(file.path(), clock).unwrap();
+            sink.record(&BalanceEvent {
+                client_id: 1,
+                transaction_id: 7,
+                kind: TransactionKind::Deposit {
+                    amount: Decimal::from(5u32),
+                },
+                before,
+                after,
+            });
+        }
+
+        let content = std::fs::read_to_string(file.path()).unwrap();
+        assert_eq!(
+            content,
+            "1,7,deposit,0,0,0,false,5,0,5,false,2024-05-01T12:30:00.000Z\n"
+        );
+    }
+}
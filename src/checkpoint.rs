@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::domain::{AccountSnapshot, Amount, ClientId};
+use crate::output_repository::{RowFormat, write_header, write_row};
+
+/// Writes the balances table to a fresh numbered file every `every` transactions, so a
+/// long stream can be inspected while it runs. For `balances.csv` the files are
+/// `balances.csv.1`, `balances.csv.2` and so on. Nothing is cleared in between: the
+/// ledger, and so disputes of earlier transactions, carry on as usual.
+///
+/// Checkpoints have no `tx_count` column whatever `format` says, as only the output
+/// knows the counts.
+#[derive(Debug)]
+pub struct Checkpoints {
+    every: u64,
+    path: PathBuf,
+    format: RowFormat,
+    // transactions since the last checkpoint
+    since: u64,
+    written: u64,
+}
+
+impl Checkpoints {
+    /// Checkpoints after every `every` transactions, at least one.
+    pub fn new(every: u64, path: &Path) -> Self {
+        Self {
+            every: every.max(1),
+            path: path.to_path_buf(),
+            format: RowFormat::default(),
+            since: 0,
+            written: 0,
+        }
+    }
+
+    pub fn with_format(mut self, format: RowFormat) -> Self {
+        self.format = RowFormat {
+            tx_count: false,
+            ..format
+        };
+        self
+    }
+
+    /// Number of checkpoint files written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Notes a processed transaction and returns whether a checkpoint is due.
+    pub fn tick(&mut self) -> bool {
+        self.since += 1;
+        if self.since < self.every {
+            return false;
+        }
+        self.since = 0;
+        true
+    }
+
+    /// Writes `accounts`, in the order given, to the next file and returns its path.
+    pub fn write<A: Amount>(
+        &mut self,
+        accounts: &[(ClientId, AccountSnapshot<A>)],
+    ) -> io::Result<PathBuf> {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", self.written + 1));
+        let path = PathBuf::from(name);
+
+        let mut writer = BufWriter::new(File::create(&path)?);
+        write_header(&mut writer, &self.format)?;
+        for (client_id, account) in accounts {
+            write_row(&mut writer, *client_id, account, 0, &self.format)?;
+        }
+        writer.flush()?;
+        self.written += 1;
+        tracing::debug!(path = %path.display(), "checkpoint written");
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::Account;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn every_nth_transaction_writes_the_next_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("balances.csv");
+        let mut checkpoints = Checkpoints::new(2, &path).with_format(RowFormat {
+            decimals: 1,
+            tx_count: true,
+        });
+        let ticks: Vec<_> = (0..5).map(|_| checkpoints.tick()).collect();
+        assert_eq!(ticks, [false, true, false, true, false]);
+
+        let mut account = Account::<Decimal>::new();
+        account.available = Decimal::new(15, 1);
+        account.sync_total();
+        checkpoints.write(&[(1, account.snapshot())]).unwrap();
+        let second = checkpoints.write::<Decimal>(&[]).unwrap();
+
+        assert_eq!(checkpoints.written(), 2);
+        assert_eq!(second, dir.path().join("balances.csv.2"));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("balances.csv.1")).unwrap(),
+            "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n"
+        );
+    }
+}
@@ -0,0 +1,887 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+
+use crate::config::Config;
+use crate::domain::{ClientId, Error};
+use crate::ingestion::{IngestionOptions, InputFormat};
+use crate::output_repository::{MAX_OUTPUT_DECIMALS, OUTPUT_DECIMALS};
+use crate::policy::Policy;
+
+/// Printed for `--help` and after argument errors.
+pub const USAGE: &str = "\
+Usage: payments_engine [OPTIONS] <INPUT>
+       payments_engine [OPTIONS] --listen <ADDR>
+       payments_engine [OPTIONS] --repl
+
+Applies the transactions in INPUT (CSV, JSON or Parquet) and prints the resulting
+balances as CSV to stdout. INPUT may also be a directory, whose *.csv files are read
+in lexical order as one input.
+
+      --config <FILE>                 Read defaults for the options below from a TOML file
+      --no-<SWITCH>                   Turn off a switch the config file turns on, e.g.
+                                      --no-fail-fast
+
+Input:
+      --listen <ADDR>                 Read CSV transactions from a TCP connection instead
+      --repl                          Apply transactions typed on stdin, printing balances
+      --input-format <csv|json|auto>  Format of INPUT [default: csv]
+      --lenient-amounts               Accept `1e3` and `1,000.50` style amounts
+      --skip-unknown                  Drop rows with unknown transaction types
+      --continue-on-io-error          Keep reading INPUT after a failed read
+      --delimiter <CHAR>              CSV field separator, `\t` for tab [default: ,]
+      --decimal-comma                 Read `1,50` as 1.5; needs another --delimiter
+      --comment-char <CHAR>           Skip CSV lines starting with CHAR, e.g. `#`
+      --skip-until <TX>               Resume after the record that introduced TX
+      --replay-until <TX>             Stop after the record that introduced TX
+      --restore <FILE>                Seed accounts from a previously printed balances CSV
+      --repair                        Fix inconsistent restored accounts instead of failing
+
+Rules:
+      --allow-withdrawal-disputes     Let disputes reference withdrawals
+      --max-open-disputes <N>         Cap open disputes per client
+      --max-clients <N>               Reject transactions that would open account N + 1
+      --max-held <AMOUNT>             Reject disputes that would hold more than AMOUNT
+      --reject-negative-amounts       Reject deposits and withdrawals of a negative amount
+      --overdraft <AMOUNT>            Let withdrawals take available funds down to -AMOUNT
+      --dispute-window <N>            Keep transactions disputable for N newer ones only;
+                                      older ones are dropped but for their ids, which
+                                      still reject replays as duplicates
+      --withdrawal-dispute-window <N> Let a withdrawal be disputed only while it is among
+                                      its client's last N transactions; needs
+                                      --allow-withdrawal-disputes
+      --retry-buffer <N>              Park up to N transactions that reference unseen ids
+      --fail-fast                     Stop with exit code 1 at the first rejection
+
+Output:
+      --output <FILE>                 Write the balances to FILE instead of stdout
+      --tee                           With --output, also print the balances
+      --output-gzip                   Gzip the file written by --output
+      --client <ID>                   Print only this client's balances
+      --decimals <N>                  Decimal places per amount [default: 4]
+      --change-feed                   Print a row whenever an account changes
+      --with-counts                   Add a tx_count column (deposits and withdrawals)
+      --pretty                        Print an aligned table for reading instead of CSV
+      --max-resident-accounts <N>     Keep N accounts in memory, spill idle ones to disk
+      --flush-every <SECS>            Also print the balances every SECS seconds
+      --checkpoint-every <N>          Also write the balances to OUTPUT.1, OUTPUT.2, ...
+                                      every N transactions; needs --output
+      --rejects-out <FILE>            Write a row per rejected transaction to FILE
+      --dlq-rate-limit <N>            Print at most N rejections per second to stderr
+      --summary <FILE>                Write counts and end state of the run to FILE as JSON
+      --emit-touched-clients          Also print clients whose every transaction was rejected
+      --report-dust                   Print what rounding drops per account to stderr
+      --report-open-disputes          Print disputes still open at the end to stderr
+      --verify                        Fail with exit code 3 on inconsistent accounts
+      --timeout <SECS>                Stop processing after SECS seconds
+      --metrics-addr <ADDR>           Serve Prometheus metrics (metrics-http builds only)
+
+  -h, --help                          Print this help
+  -V, --version                       Print the version
+";
+
+/// Command line arguments accepted by the binary.
+#[derive(Debug, Default)]
+pub struct Args {
+    /// Print `USAGE` and exit; no other argument is checked.
+    pub help: bool,
+    /// Print the version and exit; no other argument is checked.
+    pub version: bool,
+    /// TOML file the defaults were read from; flags given here override it.
+    pub config: Option<PathBuf>,
+    /// File, or directory of CSV files, to read; absent when transactions arrive over
+    /// `--listen`.
+    pub input: Option<PathBuf>,
+    pub listen: Option<String>,
+    /// Apply transactions read from stdin one by one, printing the balances after each.
+    pub repl: bool,
+    pub flush_every: Option<Duration>,
+    pub allow_withdrawal_disputes: bool,
+    pub timeout: Option<Duration>,
+    pub restore: Option<PathBuf>,
+    /// Recompute totals and backfill held funds of restored accounts instead of
+    /// rejecting inconsistent ones.
+    pub repair: bool,
+    /// Print only this client's balances instead of the full table.
+    pub client: Option<ClientId>,
+    /// Write the balances CSV to this file instead of stdout.
+    pub output: Option<PathBuf>,
+    /// Also print to stdout what goes to `output`.
+    pub tee: bool,
+    /// Gzip the file written to `output`; stdout is never compressed.
+    pub output_gzip: bool,
+    /// Write the balances to a numbered file next to `output` every this many
+    /// transactions.
+    pub checkpoint_every: Option<u64>,
+    /// Accept `1e3` and `1,000.50` style amounts.
+    pub lenient_amounts: bool,
+    /// Drop rows with unknown transaction types instead of reporting them.
+    pub skip_unknown: bool,
+    /// Report a failed read of the input and carry on instead of stopping there.
+    pub continue_on_io_error: bool,
+    /// CSV field separator; `,` when absent.
+    pub delimiter: Option<u8>,
+    /// Read `1,50` as one and a half; needs a `--delimiter` other than `,`.
+    pub decimal_comma: bool,
+    /// Skip CSV lines that start with this byte.
+    pub comment_char: Option<u8>,
+    pub max_open_disputes: Option<usize>,
+    /// No account is opened once this many exist.
+    pub max_clients: Option<usize>,
+    /// Deposits and withdrawals stay disputable for this many newer ones.
+    pub dispute_window: Option<usize>,
+    /// A withdrawal stays disputable while it is among its client's last this many
+    /// deposits and withdrawals.
+    pub withdrawal_dispute_window: Option<usize>,
+    /// Let withdrawals take available funds down to minus this amount.
+    pub overdraft: Option<Decimal>,
+    /// Reject disputes that would take a client's held funds above this amount.
+    pub max_held: Option<Decimal>,
+    /// Check account invariants after processing and fail if any are violated.
+    pub verify: bool,
+    /// Stop at the first rejected transaction instead of reporting it and carrying on.
+    pub fail_fast: bool,
+    /// Install `NonNegativeAmountValidator`.
+    pub reject_negative_amounts: bool,
+    /// Park up to this many transactions that reference a tx id not seen yet.
+    pub retry_buffer: Option<usize>,
+    pub input_format: InputFormat,
+    /// Resume after the record that introduced this tx id.
+    pub skip_until: Option<u32>,
+    /// Stop after the record that introduced this tx id, to see the balances then.
+    pub replay_until: Option<u32>,
+    /// Give a balances row to every client a rejected transaction names.
+    pub emit_touched_clients: bool,
+    /// Print to stderr what rounding the output drops per account.
+    pub report_dust: bool,
+    /// Print to stderr the disputes still open once processing ends.
+    pub report_open_disputes: bool,
+    /// Decimal places printed per amount; four when absent.
+    pub decimals: Option<u32>,
+    /// Print a balances row whenever an account changes instead of a table at the end.
+    pub change_feed: bool,
+    /// Append a `tx_count` column with each client's deposits and withdrawals.
+    pub with_counts: bool,
+    /// Print the balances as a right-aligned table instead of CSV.
+    pub pretty: bool,
+    /// Keep at most this many accounts in memory and spill the least recently used
+    /// ones to a scratch file.
+    pub max_resident_accounts: Option<usize>,
+    /// Write a `client,tx,type,reason,code` row here for every rejected transaction.
+    pub rejects_out: Option<PathBuf>,
+    /// Print at most this many rejections per second to stderr and count the rest.
+    pub dlq_rate_limit: Option<u64>,
+    /// Write a JSON report of the run here once it ends.
+    pub summary: Option<PathBuf>,
+    /// Serve Prometheus metrics on this address.
+    #[cfg(feature = "metrics-http")]
+    pub metrics_addr: Option<String>,
+}
+
+impl Args {
+    /// Parses arguments, excluding the program name.
+    pub fn parse<I>(args: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut parsed = Args::default();
+
+        // The file's settings go first so that flags override them.
+        let args: Vec<String> = args.into_iter().collect();
+        let defaults = match config_path(&args)? {
+            Some(path) => Config::load(&path)?.to_args(),
+            None => Vec::new(),
+        };
+        let mut args = defaults.into_iter().chain(args);
+        while let Some(arg) = args.next() {
+            if let Some(switch) = arg
+                .strip_prefix("--no-")
+                .and_then(|name| parsed.config_switch(name))
+            {
+                *switch = false;
+                continue;
+            }
+            match arg.as_str() {
+                "--help" | "-h" => {
+                    parsed.help = true;
+                    return Ok(parsed);
+                }
+                "--version" | "-V" => {
+                    parsed.version = true;
+                    return Ok(parsed);
+                }
+                "--config" => parsed.config = Some(PathBuf::from(value(&arg, args.next())?)),
+                "--allow-withdrawal-disputes" => parsed.allow_withdrawal_disputes = true,
+                "--timeout" => {
+                    parsed.timeout = Some(Duration::from_secs(parsed_value(&arg, args.next())?))
+                }
+                "--listen" => parsed.listen = Some(value(&arg, args.next())?),
+                "--repl" => parsed.repl = true,
+                "--flush-every" => {
+                    parsed.flush_every = Some(Duration::from_secs(parsed_value(&arg, args.next())?))
+                }
+                "--restore" => parsed.restore = Some(PathBuf::from(value(&arg, args.next())?)),
+                "--repair" => parsed.repair = true,
+                "--client" => parsed.client = Some(parsed_value(&arg, args.next())?),
+                "--lenient-amounts" => parsed.lenient_amounts = true,
+                "--skip-unknown" => parsed.skip_unknown = true,
+                "--continue-on-io-error" => parsed.continue_on_io_error = true,
+                "--decimal-comma" => parsed.decimal_comma = true,
+                "--delimiter" => parsed.delimiter = Some(delimiter(&arg, args.next())?),
+                "--comment-char" => parsed.comment_char = Some(delimiter(&arg, args.next())?),
+                "--verify" => parsed.verify = true,
+                "--fail-fast" => parsed.fail_fast = true,
+                "--reject-negative-amounts" => parsed.reject_negative_amounts = true,
+                "--report-dust" => parsed.report_dust = true,
+                "--emit-touched-clients" => parsed.emit_touched_clients = true,
+                "--report-open-disputes" => parsed.report_open_disputes = true,
+                "--change-feed" => parsed.change_feed = true,
+                "--with-counts" => parsed.with_counts = true,
+                "--pretty" => parsed.pretty = true,
+                "--decimals" => {
+                    let decimals: u32 = parsed_value(&arg, args.next())?;
+                    if decimals > MAX_OUTPUT_DECIMALS {
+                        return Err(Error::Args(format!(
+                            "Invalid value for {}: {}",
+                            arg, decimals
+                        )));
+                    }
+                    parsed.decimals = Some(decimals);
+                }
+                "--rejects-out" => {
+                    parsed.rejects_out = Some(PathBuf::from(value(&arg, args.next())?))
+                }
+                "--dlq-rate-limit" => {
+                    let limit: u64 = parsed_value(&arg, args.next())?;
+                    if limit == 0 {
+                        return Err(Error::Args(format!("Invalid value for {}: 0", arg)));
+                    }
+                    parsed.dlq_rate_limit = Some(limit);
+                }
+                "--summary" => parsed.summary = Some(PathBuf::from(value(&arg, args.next())?)),
+                "--output" => parsed.output = Some(PathBuf::from(value(&arg, args.next())?)),
+                "--tee" => parsed.tee = true,
+                "--output-gzip" => parsed.output_gzip = true,
+                "--checkpoint-every" => {
+                    let every: u64 = parsed_value(&arg, args.next())?;
+                    if every == 0 {
+                        return Err(Error::Args(format!("Invalid value for {}: 0", arg)));
+                    }
+                    parsed.checkpoint_every = Some(every);
+                }
+                "--skip-until" => parsed.skip_until = Some(parsed_value(&arg, args.next())?),
+                "--replay-until" => parsed.replay_until = Some(parsed_value(&arg, args.next())?),
+                "--input-format" => parsed.input_format = value(&arg, args.next())?.parse()?,
+                "--max-open-disputes" => {
+                    parsed.max_open_disputes = Some(parsed_value(&arg, args.next())?)
+                }
+                "--max-clients" => parsed.max_clients = Some(parsed_value(&arg, args.next())?),
+                "--overdraft" => {
+                    let limit: Decimal = parsed_value(&arg, args.next())?;
+                    if limit.is_sign_negative() {
+                        return Err(Error::Args(format!("Invalid value for {}: {}", arg, limit)));
+                    }
+                    parsed.overdraft = Some(limit);
+                }
+                "--max-held" => {
+                    let limit: Decimal = parsed_value(&arg, args.next())?;
+                    if limit.is_sign_negative() {
+                        return Err(Error::Args(format!("Invalid value for {}: {}", arg, limit)));
+                    }
+                    parsed.max_held = Some(limit);
+                }
+                "--dispute-window" => {
+                    parsed.dispute_window = Some(parsed_value(&arg, args.next())?)
+                }
+                "--withdrawal-dispute-window" => {
+                    parsed.withdrawal_dispute_window = Some(parsed_value(&arg, args.next())?)
+                }
+                "--retry-buffer" => parsed.retry_buffer = Some(parsed_value(&arg, args.next())?),
+                "--max-resident-accounts" => {
+                    let capacity: usize = parsed_value(&arg, args.next())?;
+                    if capacity == 0 {
+                        return Err(Error::Args(format!("Invalid value for {}: 0", arg)));
+                    }
+                    parsed.max_resident_accounts = Some(capacity);
+                }
+                #[cfg(feature = "metrics-http")]
+                "--metrics-addr" => parsed.metrics_addr = Some(value(&arg, args.next())?),
+                flag if flag.starts_with("--") => {
+                    return Err(Error::Args(format!("Unknown flag: {}", flag)));
+                }
+                _ if parsed.input.is_none() => parsed.input = Some(PathBuf::from(arg)),
+                _ => return Err(Error::Args(format!("Unexpected argument: {}", arg))),
+            }
+        }
+
+        match (&parsed.input, &parsed.listen) {
+            (None, None) if !parsed.repl => {
+                Err(Error::Args("No input file was provided".to_string()))
+            }
+            (Some(_), Some(_)) => Err(Error::Args(
+                "An input file cannot be combined with --listen".to_string(),
+            )),
+            (Some(_), _) | (_, Some(_)) if parsed.repl => Err(Error::Args(
+                "--repl reads stdin and cannot be combined with an input file or --listen"
+                    .to_string(),
+            )),
+            _ if parsed.change_feed && parsed.client.is_some() => Err(Error::Args(
+                "--change-feed cannot be combined with --client".to_string(),
+            )),
+            _ if parsed.change_feed && parsed.pretty => Err(Error::Args(
+                "--change-feed cannot be combined with --pretty".to_string(),
+            )),
+            _ if parsed.withdrawal_dispute_window.is_some()
+                && !parsed.allow_withdrawal_disputes =>
+            {
+                Err(Error::Args(
+                    "--withdrawal-dispute-window needs --allow-withdrawal-disputes".to_string(),
+                ))
+            }
+            _ if parsed.tee && parsed.output.is_none() => {
+                Err(Error::Args("--tee needs --output".to_string()))
+            }
+            _ if parsed.output_gzip && parsed.output.is_none() => {
+                Err(Error::Args("--output-gzip needs --output".to_string()))
+            }
+            _ if parsed.checkpoint_every.is_some() && parsed.output.is_none() => {
+                Err(Error::Args("--checkpoint-every needs --output".to_string()))
+            }
+            _ if parsed.decimal_comma && parsed.delimiter.unwrap_or(b',') == b',' => Err(
+                Error::Args("--decimal-comma needs a --delimiter other than ','".to_string()),
+            ),
+            _ if parsed.comment_char.is_some()
+                && parsed.comment_char == Some(parsed.delimiter.unwrap_or(b',')) =>
+            {
+                Err(Error::Args(
+                    "--comment-char must differ from the delimiter".to_string(),
+                ))
+            }
+            _ => Ok(parsed),
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        Policy {
+            allow_withdrawal_disputes: self.allow_withdrawal_disputes,
+            max_open_disputes: self.max_open_disputes,
+            overdraft: self.overdraft.unwrap_or_default(),
+            max_held: self.max_held,
+            dispute_window: self.dispute_window,
+            withdrawal_dispute_window: self.withdrawal_dispute_window,
+            max_clients: self.max_clients,
+        }
+    }
+
+    /// Places printed per amount in the balances CSV.
+    pub fn output_decimals(&self) -> u32 {
+        self.decimals.unwrap_or(OUTPUT_DECIMALS)
+    }
+
+    pub fn ingestion_options(&self) -> IngestionOptions {
+        IngestionOptions {
+            lenient_amounts: self.lenient_amounts,
+            skip_unknown: self.skip_unknown,
+            delimiter: self.delimiter,
+            decimal_comma: self.decimal_comma,
+            comment: self.comment_char,
+            continue_on_io_error: self.continue_on_io_error,
+        }
+    }
+}
+
+impl Args {
+    /// A switch the config file can turn on, by its flag's name, so `--no-<name>` can
+    /// turn it off again.
+    fn config_switch(&mut self, name: &str) -> Option<&mut bool> {
+        let switch = match name {
+            "lenient-amounts" => &mut self.lenient_amounts,
+            "skip-unknown" => &mut self.skip_unknown,
+            "continue-on-io-error" => &mut self.continue_on_io_error,
+            "decimal-comma" => &mut self.decimal_comma,
+            "allow-withdrawal-disputes" => &mut self.allow_withdrawal_disputes,
+            "fail-fast" => &mut self.fail_fast,
+            "reject-negative-amounts" => &mut self.reject_negative_amounts,
+            "with-counts" => &mut self.with_counts,
+            "pretty" => &mut self.pretty,
+            "emit-touched-clients" => &mut self.emit_touched_clients,
+            "report-dust" => &mut self.report_dust,
+            "report-open-disputes" => &mut self.report_open_disputes,
+            "verify" => &mut self.verify,
+            _ => return None,
+        };
+        Some(switch)
+    }
+}
+
+/// The `--config` file among `args`, unless help or the version was asked for.
+fn config_path(args: &[String]) -> Result<Option<PathBuf>, Error> {
+    if args
+        .iter()
+        .any(|arg| matches!(arg.as_str(), "--help" | "-h" | "--version" | "-V"))
+    {
+        return Ok(None);
+    }
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return value(arg, args.next().cloned()).map(|path| Some(PathBuf::from(path)));
+        }
+    }
+    Ok(None)
+}
+
+fn value(flag: &str, value: Option<String>) -> Result<String, Error> {
+    value.ok_or_else(|| Error::Args(format!("Missing value for {}", flag)))
+}
+
+/// A single ASCII character, or `\t` for a tab.
+fn delimiter(flag: &str, raw: Option<String>) -> Result<u8, Error> {
+    let raw = value(flag, raw)?;
+    match raw.as_bytes() {
+        [b'\\', b't'] => Ok(b'\t'),
+        [byte] if byte.is_ascii() && *byte != b'"' && *byte != b'\n' => Ok(*byte),
+        _ => Err(Error::Args(format!("Invalid value for {}: {}", flag, raw))),
+    }
+}
+
+fn parsed_value<T: FromStr>(flag: &str, raw: Option<String>) -> Result<T, Error> {
+    let raw = value(flag, raw)?;
+    raw.parse()
+        .map_err(|_| Error::Args(format!("Invalid value for {}: {}", flag, raw)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, Error> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_input_and_flags() {
+        let args = parse(&["tx.csv", "--allow-withdrawal-disputes"]).unwrap();
+        assert_eq!(args.input, Some(PathBuf::from("tx.csv")));
+        assert!(args.allow_withdrawal_disputes);
+
+        let args = parse(&["tx.csv"]).unwrap();
+        assert!(!args.allow_withdrawal_disputes);
+    }
+
+    #[test]
+    fn rejects_missing_input_and_unknown_flags() {
+        assert!(matches!(parse(&[]), Err(Error::Args(_))));
+        assert!(matches!(parse(&["tx.csv", "--nope"]), Err(Error::Args(_))));
+    }
+
+    #[test]
+    fn listen_replaces_input_file() {
+        let args = parse(&["--listen", "127.0.0.1:7000", "--flush-every", "10"]).unwrap();
+        assert_eq!(args.listen.as_deref(), Some("127.0.0.1:7000"));
+        assert_eq!(args.flush_every, Some(Duration::from_secs(10)));
+        assert!(args.input.is_none());
+        assert!(matches!(
+            parse(&["tx.csv", "--listen", "127.0.0.1:7000"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn repl_replaces_input_file() {
+        let args = parse(&["--repl", "--allow-withdrawal-disputes"]).unwrap();
+        assert!(args.repl);
+        assert!(args.input.is_none());
+        assert!(!parse(&["tx.csv"]).unwrap().repl);
+        for bad in [
+            &["tx.csv", "--repl"][..],
+            &["--repl", "--listen", "127.0.0.1:7000"],
+        ] {
+            assert!(matches!(parse(bad), Err(Error::Args(_))), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_restore_and_repair() {
+        let args = parse(&["tx.csv", "--restore", "prev.csv", "--repair"]).unwrap();
+        assert_eq!(args.restore, Some(PathBuf::from("prev.csv")));
+        assert!(args.repair);
+    }
+
+    #[test]
+    fn parses_client_filter() {
+        assert_eq!(
+            parse(&["tx.csv", "--client", "42"]).unwrap().client,
+            Some(42)
+        );
+        #[cfg(not(feature = "wide-client-ids"))]
+        assert!(matches!(
+            parse(&["tx.csv", "--client", "70000"]),
+            Err(Error::Args(_))
+        ));
+        #[cfg(feature = "wide-client-ids")]
+        assert_eq!(
+            parse(&["tx.csv", "--client", "70000"]).unwrap().client,
+            Some(70_000)
+        );
+    }
+
+    #[test]
+    fn parses_lenient_amounts() {
+        assert!(
+            parse(&["tx.csv", "--lenient-amounts"])
+                .unwrap()
+                .ingestion_options()
+                .lenient_amounts
+        );
+        assert!(
+            !parse(&["tx.csv"])
+                .unwrap()
+                .ingestion_options()
+                .lenient_amounts
+        );
+    }
+
+    #[test]
+    fn parses_continue_on_io_error() {
+        let args = parse(&["tx.csv", "--continue-on-io-error"]).unwrap();
+        assert!(args.ingestion_options().continue_on_io_error);
+        assert!(!IngestionOptions::default().continue_on_io_error);
+    }
+
+    #[test]
+    fn parses_delimiter_and_decimal_comma() {
+        let options = parse(&["tx.csv", "--decimal-comma", "--delimiter", ";"])
+            .unwrap()
+            .ingestion_options();
+        assert!(options.decimal_comma);
+        assert_eq!(options.delimiter(), b';');
+        let tab = parse(&["tx.csv", "--delimiter", "\\t"]).unwrap();
+        assert_eq!(tab.ingestion_options().delimiter(), b'\t');
+        assert_eq!(
+            parse(&["tx.csv"]).unwrap().ingestion_options().delimiter(),
+            b','
+        );
+
+        for bad in [
+            &["tx.csv", "--decimal-comma"][..],
+            &["tx.csv", "--decimal-comma", "--delimiter", ","],
+            &["tx.csv", "--delimiter", ";;"],
+        ] {
+            assert!(matches!(parse(bad), Err(Error::Args(_))), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_comment_char() {
+        let args = parse(&["tx.csv", "--comment-char", "#"]).unwrap();
+        assert_eq!(args.ingestion_options().comment, Some(b'#'));
+        assert_eq!(
+            parse(&["tx.csv"]).unwrap().ingestion_options().comment,
+            None
+        );
+
+        for bad in [
+            &["tx.csv", "--comment-char"][..],
+            &["tx.csv", "--comment-char", "//"],
+            &["tx.csv", "--comment-char", ","],
+            &["tx.csv", "--comment-char", ";", "--delimiter", ";"],
+        ] {
+            assert!(matches!(parse(bad), Err(Error::Args(_))), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_max_clients() {
+        let args = parse(&["tx.csv", "--max-clients", "2"]).unwrap();
+        assert_eq!(args.policy().max_clients, Some(2));
+        assert_eq!(parse(&["tx.csv"]).unwrap().policy().max_clients, None);
+        assert!(parse(&["tx.csv", "--max-clients", "-2"]).is_err());
+    }
+
+    #[test]
+    fn parses_max_open_disputes() {
+        let args = parse(&["tx.csv", "--max-open-disputes", "3"]).unwrap();
+        assert_eq!(args.policy().max_open_disputes, Some(3));
+        assert_eq!(parse(&["tx.csv"]).unwrap().policy().max_open_disputes, None);
+        let args = parse(&["tx.csv", "--max-held", "250.50"]).unwrap();
+        assert_eq!(args.policy().max_held, Some(Decimal::new(25050, 2)));
+        assert!(parse(&["tx.csv", "--max-held", "-1"]).is_err());
+        assert!(parse(&["tx.csv", "--verify"]).unwrap().verify);
+        assert!(parse(&["tx.csv", "--fail-fast"]).unwrap().fail_fast);
+        assert!(
+            parse(&["tx.csv", "--reject-negative-amounts"])
+                .unwrap()
+                .reject_negative_amounts
+        );
+        assert!(
+            parse(&["tx.csv", "--emit-touched-clients"])
+                .unwrap()
+                .emit_touched_clients
+        );
+        assert!(
+            parse(&["tx.csv", "--report-open-disputes"])
+                .unwrap()
+                .report_open_disputes
+        );
+    }
+
+    #[test]
+    fn parses_decimals() {
+        assert_eq!(
+            parse(&["tx.csv", "--decimals", "2"])
+                .unwrap()
+                .output_decimals(),
+            2
+        );
+        assert_eq!(parse(&["tx.csv"]).unwrap().output_decimals(), 4);
+        assert!(matches!(
+            parse(&["tx.csv", "--decimals", "-1"]),
+            Err(Error::Args(_))
+        ));
+        assert!(matches!(
+            parse(&["tx.csv", "--decimals", "29"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_change_feed() {
+        assert!(parse(&["tx.csv", "--with-counts"]).unwrap().with_counts);
+        assert!(parse(&["tx.csv", "--change-feed"]).unwrap().change_feed);
+        assert!(!parse(&["tx.csv"]).unwrap().change_feed);
+        assert!(matches!(
+            parse(&["tx.csv", "--change-feed", "--client", "1"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_pretty() {
+        assert!(parse(&["tx.csv", "--pretty"]).unwrap().pretty);
+        assert!(!parse(&["tx.csv"]).unwrap().pretty);
+        assert!(matches!(
+            parse(&["tx.csv", "--pretty", "--change-feed"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_withdrawal_dispute_window() {
+        let args = parse(&[
+            "tx.csv",
+            "--allow-withdrawal-disputes",
+            "--withdrawal-dispute-window",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(args.policy().withdrawal_dispute_window, Some(3));
+        assert!(matches!(
+            parse(&["tx.csv", "--withdrawal-dispute-window", "3"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_dispute_window() {
+        let args = parse(&["tx.csv", "--dispute-window", "1000"]).unwrap();
+        assert_eq!(args.policy().dispute_window, Some(1000));
+        assert_eq!(parse(&["tx.csv"]).unwrap().policy().dispute_window, None);
+        assert!(matches!(
+            parse(&["tx.csv", "--dispute-window", "-5"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_max_resident_accounts() {
+        let args = parse(&["tx.csv", "--max-resident-accounts", "10000"]).unwrap();
+        assert_eq!(args.max_resident_accounts, Some(10000));
+        assert!(matches!(
+            parse(&["tx.csv", "--max-resident-accounts", "0"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn help_and_version_skip_the_other_checks() {
+        assert!(parse(&["--help"]).unwrap().help);
+        assert!(parse(&["tx.csv", "-h", "--no-such-flag"]).unwrap().help);
+        assert!(parse(&["--version"]).unwrap().version);
+        assert!(parse(&["-V"]).unwrap().version);
+        assert!(!parse(&["tx.csv"]).unwrap().help);
+    }
+
+    #[test]
+    fn parses_overdraft() {
+        let args = parse(&["tx.csv", "--overdraft", "25.5"]).unwrap();
+        assert_eq!(args.policy().overdraft, Decimal::new(255, 1));
+        assert_eq!(
+            parse(&["tx.csv"]).unwrap().policy().overdraft,
+            Decimal::ZERO
+        );
+        assert!(matches!(
+            parse(&["tx.csv", "--overdraft", "lots"]),
+            Err(Error::Args(_))
+        ));
+        assert!(matches!(
+            parse(&["tx.csv", "--overdraft", "-1"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_rejects_out() {
+        let args = parse(&["tx.csv", "--rejects-out", "rejects.csv"]).unwrap();
+        assert_eq!(args.rejects_out, Some(PathBuf::from("rejects.csv")));
+        assert!(parse(&["tx.csv"]).unwrap().rejects_out.is_none());
+    }
+
+    #[test]
+    fn parses_dlq_rate_limit() {
+        let args = parse(&["tx.csv", "--dlq-rate-limit", "100"]).unwrap();
+        assert_eq!(args.dlq_rate_limit, Some(100));
+        assert!(matches!(
+            parse(&["tx.csv", "--dlq-rate-limit", "0"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_summary() {
+        let args = parse(&["tx.csv", "--summary", "summary.json"]).unwrap();
+        assert_eq!(args.summary, Some(PathBuf::from("summary.json")));
+        assert!(matches!(
+            parse(&["tx.csv", "--summary"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_output_and_tee() {
+        let args = parse(&["tx.csv", "--output", "balances.csv", "--tee"]).unwrap();
+        assert_eq!(args.output, Some(PathBuf::from("balances.csv")));
+        assert!(args.tee);
+        assert!(matches!(
+            parse(&["tx.csv", "--tee"]),
+            Err(Error::Args(msg)) if msg == "--tee needs --output"
+        ));
+        assert!(
+            parse(&["tx.csv", "--output", "balances.csv.gz", "--output-gzip"])
+                .unwrap()
+                .output_gzip
+        );
+        assert!(matches!(
+            parse(&["tx.csv", "--output-gzip"]),
+            Err(Error::Args(msg)) if msg == "--output-gzip needs --output"
+        ));
+        let args = parse(&["tx.csv", "--output", "out.csv", "--checkpoint-every", "100"]);
+        assert_eq!(args.unwrap().checkpoint_every, Some(100));
+        assert!(matches!(
+            parse(&["tx.csv", "--checkpoint-every", "100"]),
+            Err(Error::Args(msg)) if msg == "--checkpoint-every needs --output"
+        ));
+        assert!(parse(&["tx.csv", "--output", "out.csv", "--checkpoint-every", "0"]).is_err());
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.toml");
+        std::fs::write(
+            &path,
+            "decimals = 2\noverdraft = \"10\"\nallow-withdrawal-disputes = true\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+
+        let args = parse(&["tx.csv", "--config", config]).unwrap();
+        assert_eq!(args.config.as_deref(), Some(path.as_path()));
+        assert_eq!(args.output_decimals(), 2);
+        assert_eq!(args.policy().overdraft, Decimal::TEN);
+        assert!(args.policy().allow_withdrawal_disputes);
+
+        let args = parse(&["--decimals", "6", "tx.csv", "--config", config]).unwrap();
+        assert_eq!(args.output_decimals(), 6);
+        assert_eq!(args.policy().overdraft, Decimal::TEN);
+
+        std::fs::write(&path, "decimals = 29\n").unwrap();
+        assert!(matches!(
+            parse(&["tx.csv", "--config", config]),
+            Err(Error::Args(msg)) if msg == "Invalid value for --decimals: 29"
+        ));
+        assert!(parse(&["--config", config, "--help"]).unwrap().help);
+        assert!(parse(&["tx.csv", "--config"]).is_err());
+    }
+
+    #[test]
+    fn switches_from_the_config_file_can_be_turned_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.toml");
+        std::fs::write(
+            &path,
+            "fail-fast = true
+allow-withdrawal-disputes = true
+",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+
+        let args = parse(&["tx.csv", "--config", config, "--no-fail-fast"]).unwrap();
+        assert!(!args.fail_fast);
+        assert!(args.allow_withdrawal_disputes);
+        // the last word wins, as for any other flag
+        let args = parse(&["tx.csv", "--no-verify", "--verify"]).unwrap();
+        assert!(args.verify);
+        assert!(matches!(
+            parse(&["tx.csv", "--no-tee"]),
+            Err(Error::Args(msg)) if msg == "Unknown flag: --no-tee"
+        ));
+    }
+
+    #[test]
+    fn parses_skip_until() {
+        let args = parse(&["tx.csv", "--skip-until", "41"]).unwrap();
+        assert_eq!(args.skip_until, Some(41));
+        assert!(parse(&["tx.csv", "--skip-until"]).is_err());
+        let args = parse(&["tx.csv", "--replay-until", "7"]).unwrap();
+        assert_eq!(args.replay_until, Some(7));
+        assert!(parse(&["tx.csv", "--replay-until", "last"]).is_err());
+    }
+
+    #[test]
+    fn parses_input_format() {
+        assert_eq!(parse(&["tx.csv"]).unwrap().input_format, InputFormat::Csv);
+        let args = parse(&["tx.json", "--input-format", "auto"]).unwrap();
+        assert_eq!(args.input_format, InputFormat::Auto);
+        assert!(parse(&["tx.csv", "--input-format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn parses_retry_buffer() {
+        let args = parse(&["tx.csv", "--retry-buffer", "64"]).unwrap();
+        assert_eq!(args.retry_buffer, Some(64));
+        assert!(parse(&["tx.csv", "--retry-buffer", "-1"]).is_err());
+        assert!(matches!(
+            parse(&["tx.csv", "--max-open-disputes", "-1"]),
+            Err(Error::Args(_))
+        ));
+    }
+
+    #[test]
+    fn parses_timeout_in_seconds() {
+        let args = parse(&["--timeout", "5", "tx.csv"]).unwrap();
+        assert_eq!(args.timeout, Some(Duration::from_secs(5)));
+        assert!(parse(&["tx.csv"]).unwrap().timeout.is_none());
+        assert!(matches!(
+            parse(&["tx.csv", "--timeout"]),
+            Err(Error::Args(_))
+        ));
+        assert!(matches!(
+            parse(&["tx.csv", "--timeout", "soon"]),
+            Err(Error::Args(_))
+        ));
+    }
+}
@@ -0,0 +1,28 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::domain::Clock;
+
+/// Wall-clock time; the default everywhere.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Always reports the same instant, so tests can assert on exact timestamps.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// RFC 3339 in UTC with milliseconds, e.g. `2024-05-01T12:00:00.000Z`.
+pub fn timestamp<C: Clock + ?Sized>(clock: &C) -> String {
+    clock.now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
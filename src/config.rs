@@ -0,0 +1,162 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::domain::Error;
+
+/// Defaults for the command line, read from a TOML file passed with `--config`. Keys are
+/// named after the flags they stand in for, e.g. `max-open-disputes = 3`, and flags given
+/// on the command line take precedence. A switch set in the file is turned off with
+/// `--no-<flag>`, e.g. `--no-fail-fast`.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub input_format: Option<String>,
+    pub lenient_amounts: Option<bool>,
+    pub skip_unknown: Option<bool>,
+    pub continue_on_io_error: Option<bool>,
+    pub delimiter: Option<String>,
+    pub decimal_comma: Option<bool>,
+    pub comment_char: Option<String>,
+    pub allow_withdrawal_disputes: Option<bool>,
+    pub max_open_disputes: Option<usize>,
+    pub max_clients: Option<usize>,
+    /// A string such as `"25.50"`, so the limit stays exact.
+    pub overdraft: Option<String>,
+    /// A string like `overdraft`.
+    pub max_held: Option<String>,
+    pub dispute_window: Option<usize>,
+    pub withdrawal_dispute_window: Option<usize>,
+    pub retry_buffer: Option<usize>,
+    pub fail_fast: Option<bool>,
+    pub reject_negative_amounts: Option<bool>,
+    pub decimals: Option<u32>,
+    pub checkpoint_every: Option<u64>,
+    pub dlq_rate_limit: Option<u64>,
+    pub with_counts: Option<bool>,
+    pub pretty: Option<bool>,
+    pub emit_touched_clients: Option<bool>,
+    pub max_resident_accounts: Option<usize>,
+    pub report_dust: Option<bool>,
+    pub report_open_disputes: Option<bool>,
+    pub verify: Option<bool>,
+    /// Seconds.
+    pub timeout: Option<u64>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::Args(format!("Invalid config file {}: {}", path.display(), e))
+        };
+        let text = fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        toml::from_str(&text).map_err(|e| invalid(&e))
+    }
+
+    /// The file's settings as the flags they stand in for, so they are checked just
+    /// like flags and anything given after them wins.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut value = |flag: &str, value: Option<String>| {
+            if let Some(value) = value {
+                args.push(format!("--{}", flag));
+                args.push(value);
+            }
+        };
+        value("input-format", self.input_format.clone());
+        value("delimiter", self.delimiter.clone());
+        value("comment-char", self.comment_char.clone());
+        value(
+            "max-open-disputes",
+            self.max_open_disputes.map(|n| n.to_string()),
+        );
+        value("max-clients", self.max_clients.map(|n| n.to_string()));
+        value("overdraft", self.overdraft.clone());
+        value("max-held", self.max_held.clone());
+        value("dispute-window", self.dispute_window.map(|n| n.to_string()));
+        value(
+            "withdrawal-dispute-window",
+            self.withdrawal_dispute_window.map(|n| n.to_string()),
+        );
+        value("retry-buffer", self.retry_buffer.map(|n| n.to_string()));
+        value("decimals", self.decimals.map(|n| n.to_string()));
+        value(
+            "checkpoint-every",
+            self.checkpoint_every.map(|n| n.to_string()),
+        );
+        value("dlq-rate-limit", self.dlq_rate_limit.map(|n| n.to_string()));
+        value(
+            "max-resident-accounts",
+            self.max_resident_accounts.map(|n| n.to_string()),
+        );
+        value("timeout", self.timeout.map(|secs| secs.to_string()));
+
+        for (flag, set) in [
+            ("lenient-amounts", self.lenient_amounts),
+            ("skip-unknown", self.skip_unknown),
+            ("continue-on-io-error", self.continue_on_io_error),
+            ("decimal-comma", self.decimal_comma),
+            ("allow-withdrawal-disputes", self.allow_withdrawal_disputes),
+            ("fail-fast", self.fail_fast),
+            ("reject-negative-amounts", self.reject_negative_amounts),
+            ("with-counts", self.with_counts),
+            ("pretty", self.pretty),
+            ("emit-touched-clients", self.emit_touched_clients),
+            ("report-dust", self.report_dust),
+            ("report-open-disputes", self.report_open_disputes),
+            ("verify", self.verify),
+        ] {
+            if set == Some(true) {
+                args.push(format!("--{}", flag));
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_become_the_flags_they_stand_in_for() {
+        let config: Config = toml::from_str(
+            r#"
+            input-format = "auto"
+            overdraft = "25.50"
+            decimals = 2
+            allow-withdrawal-disputes = true
+            verify = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.to_args(),
+            [
+                "--input-format",
+                "auto",
+                "--overdraft",
+                "25.50",
+                "--decimals",
+                "2",
+                "--allow-withdrawal-disputes"
+            ]
+        );
+        assert!(Config::default().to_args().is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_and_unreadable_files_are_argument_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.toml");
+        fs::write(&path, "max-open-dispute = 3\n").unwrap();
+        let err = Config::load(&path).unwrap_err();
+        assert!(
+            matches!(&err, Error::Args(msg) if msg.contains("unknown field `max-open-dispute`"))
+        );
+
+        let missing = Config::load(&dir.path().join("missing.toml")).unwrap_err();
+        assert!(matches!(missing, Error::Args(_)));
+    }
+}
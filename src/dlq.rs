@@ -1,10 +1,430 @@
-use crate::domain::{DeadLetterQueue, Error};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::clock::{self, SystemClock};
+use crate::domain::{AsyncDeadLetterQueue, Clock, DeadLetterQueue, Error, Transaction};
 
 #[derive(Default, Debug)]
 pub struct StdErrDLQ {}
 
 impl DeadLetterQueue for StdErrDLQ {
     fn report(&self, error: &Error) {
-        eprintln!("DLQ Report - Error: {}", error);
+        if error.is_retryable() {
+            eprintln!(
+                "DLQ Report - Rejected (retryable) [{}]: {}",
+                error.code(),
+                error
+            );
+        } else {
+            eprintln!("DLQ Report - Error [{}]: {}", error.code(), error);
+        }
+    }
+}
+
+/// Discards every report.
+#[derive(Default, Debug)]
+pub struct NoopDLQ;
+
+impl DeadLetterQueue for NoopDLQ {
+    fn report(&self, _error: &Error) {}
+}
+
+/// Lets a synchronous queue stand in where an `AsyncDeadLetterQueue` is expected. Its
+/// reports run inline and block the task, which suits cheap queues like `StdErrDLQ`.
+#[derive(Default, Debug)]
+pub struct BlockingDLQ<D>(pub D);
+
+impl<D: DeadLetterQueue + Send + Sync> AsyncDeadLetterQueue for BlockingDLQ<D> {
+    async fn report(&self, error: &Error) {
+        self.0.report(error);
+    }
+
+    async fn report_rejected(&self, transaction: &Transaction, error: &Error) {
+        self.0.report_rejected(transaction, error);
+    }
+}
+
+/// Hands every report to each of its queues in the order they were added, e.g. to log
+/// to stderr and keep a rejects file at once.
+#[derive(Default)]
+pub struct CompositeDLQ(Vec<Box<dyn DeadLetterQueue>>);
+
+impl CompositeDLQ {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<D: DeadLetterQueue + 'static>(mut self, dlq: D) -> Self {
+        self.push(dlq);
+        self
+    }
+
+    pub fn push<D: DeadLetterQueue + 'static>(&mut self, dlq: D) {
+        self.0.push(Box::new(dlq));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Debug for CompositeDLQ {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompositeDLQ")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
+
+impl DeadLetterQueue for CompositeDLQ {
+    fn report(&self, error: &Error) {
+        for dlq in &self.0 {
+            dlq.report(error);
+        }
+    }
+
+    fn report_rejected(&self, transaction: &Transaction, error: &Error) {
+        for dlq in &self.0 {
+            dlq.report_rejected(transaction, error);
+        }
+    }
+}
+
+/// Writes a `client,tx,type,reason,code,timestamp` CSV line for every report, then
+/// hands it on to `inner`. Records that failed before they could be parsed leave the
+/// first three columns empty.
+#[derive(Debug)]
+pub struct RejectsDLQ<D, W: Write = BufWriter<File>, C = SystemClock> {
+    inner: D,
+    writer: Mutex<W>,
+    clock: C,
+}
+
+impl<D> RejectsDLQ<D> {
+    /// Creates or truncates the file at `path`.
+    pub fn create(path: &Path, inner: D) -> Result<Self, Error> {
+        Self::create_with_clock(path, inner, SystemClock)
+    }
+}
+
+impl<D, C: Clock> RejectsDLQ<D, BufWriter<File>, C> {
+    pub fn create_with_clock(path: &Path, inner: D, clock: C) -> Result<Self, Error> {
+        Self::with_writer(BufWriter::new(File::create(path)?), inner, clock)
+    }
+}
+
+impl<D, W: Write, C: Clock> RejectsDLQ<D, W, C> {
+    pub fn with_writer(mut writer: W, inner: D, clock: C) -> Result<Self, Error> {
+        writeln!(writer, "client,tx,type,reason,code,timestamp")?;
+        Ok(Self {
+            inner,
+            writer: Mutex::new(writer),
+            clock,
+        })
+    }
+
+    fn write(&self, transaction: Option<&Transaction>, error: &Error) {
+        let now = clock::timestamp(&self.clock);
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let written = match transaction {
+            Some(tx) => writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                tx.client_id,
+                tx.transaction_id,
+                tx.kind.name(),
+                error.reason(),
+                error.code(),
+                now
+            ),
+            None => writeln!(writer, ",,,{},{},{}", error.reason(), error.code(), now),
+        };
+        if let Err(e) = written.and_then(|()| writer.flush()) {
+            eprintln!("Rejects file write failed: {}", e);
+        }
+    }
+}
+
+impl<D: DeadLetterQueue, W: Write, C: Clock> DeadLetterQueue for RejectsDLQ<D, W, C> {
+    fn report(&self, error: &Error) {
+        self.write(None, error);
+        self.inner.report(error);
+    }
+
+    fn report_rejected(&self, transaction: &Transaction, error: &Error) {
+        self.write(Some(transaction), error);
+        self.inner.report_rejected(transaction, error);
+    }
+}
+
+/// Hands on at most `limit` reports per clock second to `inner` and drops the rest, so
+/// a burst of bad rows cannot flood the log. Once a second with dropped reports is over,
+/// the next report first writes `DLQ Report - Suppressed <M> errors` to `W`; the last
+/// count is written when the queue is dropped.
+///
+/// Meant for reports read by people, such as `StdErrDLQ`. A rejects file should see
+/// every report, so it belongs outside the wrapper.
+#[derive(Debug)]
+pub struct RateLimitedDLQ<D, C = SystemClock, W: Write = io::Stderr> {
+    inner: D,
+    limit: u64,
+    clock: C,
+    window: Mutex<RateWindow<W>>,
+}
+
+#[derive(Debug)]
+struct RateWindow<W> {
+    // clock second the counts below are for
+    second: i64,
+    forwarded: u64,
+    // suppressed this second, not yet written in a notice
+    pending: u64,
+    // suppressed over the queue's whole life
+    total_suppressed: u64,
+    writer: W,
+}
+
+impl<D> RateLimitedDLQ<D> {
+    pub fn new(inner: D, limit: u64) -> Self {
+        Self::with_clock(inner, limit, SystemClock, io::stderr())
+    }
+}
+
+impl<D, C: Clock, W: Write> RateLimitedDLQ<D, C, W> {
+    /// Writes the suppression notices to `writer` instead of stderr.
+    pub fn with_clock(inner: D, limit: u64, clock: C, writer: W) -> Self {
+        Self {
+            inner,
+            limit,
+            clock,
+            window: Mutex::new(RateWindow {
+                second: i64::MIN,
+                forwarded: 0,
+                pending: 0,
+                total_suppressed: 0,
+                writer,
+            }),
+        }
+    }
+
+    /// Reports dropped so far.
+    pub fn suppressed(&self) -> u64 {
+        self.lock().total_suppressed
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RateWindow<W>> {
+        self.window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts a report and returns whether it may be handed on.
+    fn admit(&self) -> bool {
+        let second = self.clock.now().timestamp();
+        let mut window = self.lock();
+        if window.second != second {
+            window.notify();
+            window.second = second;
+            window.forwarded = 0;
+        }
+        if window.forwarded < self.limit {
+            window.forwarded += 1;
+            true
+        } else {
+            window.pending += 1;
+            window.total_suppressed += 1;
+            false
+        }
+    }
+}
+
+impl<W: Write> RateWindow<W> {
+    /// Writes the suppression notice for the current second, if anything was dropped.
+    fn notify(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        let written = writeln!(
+            self.writer,
+            "DLQ Report - Suppressed {} errors",
+            self.pending
+        );
+        if let Err(e) = written.and_then(|()| self.writer.flush()) {
+            eprintln!("DLQ suppression notice failed: {}", e);
+        }
+        self.pending = 0;
+    }
+}
+
+impl<D: DeadLetterQueue, C: Clock, W: Write> DeadLetterQueue for RateLimitedDLQ<D, C, W> {
+    fn report(&self, error: &Error) {
+        if self.admit() {
+            self.inner.report(error);
+        }
+    }
+
+    fn report_rejected(&self, transaction: &Transaction, error: &Error) {
+        if self.admit() {
+            self.inner.report_rejected(transaction, error);
+        }
+    }
+}
+
+impl<D, C, W: Write> Drop for RateLimitedDLQ<D, C, W> {
+    fn drop(&mut self) {
+        self.window
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::engine::Engine;
+    use crate::ingestion::CsvReader;
+
+    /// Shares what it records so the test can read it after the DLQ moves into the engine.
+    #[derive(Default, Clone)]
+    struct RecordingDLQ(Arc<Mutex<Vec<String>>>);
+
+    impl DeadLetterQueue for RecordingDLQ {
+        fn report(&self, error: &Error) {
+            self.0.lock().unwrap().push(error.reason().to_string());
+        }
+
+        fn report_rejected(&self, transaction: &Transaction, error: &Error) {
+            self.0.lock().unwrap().push(format!(
+                "{}:{}",
+                transaction.transaction_id,
+                error.reason()
+            ));
+        }
+    }
+
+    #[test]
+    fn composite_reports_to_every_queue() {
+        let input = "type,client,tx,amount\n\
+                     withdrawal,1,1,9.0\n\
+                     deposit,2,x,1.0\n";
+        let (first, second) = (RecordingDLQ::default(), RecordingDLQ::default());
+        let composite = CompositeDLQ::new()
+            .with(first.clone())
+            .with(Box::new(second.clone()) as Box<dyn DeadLetterQueue>);
+        assert_eq!(composite.len(), 2);
+
+        let mut engine = Engine::builder(CsvReader::new(input.as_bytes()).unwrap())
+            .dlq(composite)
+            .build();
+        futures::executor::block_on(engine.process()).unwrap();
+
+        let expected = vec!["1:insufficient_funds".to_string(), "ingestion".to_string()];
+        assert_eq!(*first.0.lock().unwrap(), expected);
+        assert_eq!(*second.0.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn blocking_adapter_forwards_to_the_sync_queue() {
+        let input = "type,client,tx,amount\n\
+                     withdrawal,1,1,9.0\n\
+                     deposit,2,x,1.0\n";
+        let recorded = RecordingDLQ::default();
+        let mut engine = Engine::builder(CsvReader::new(input.as_bytes()).unwrap())
+            .async_dlq(BlockingDLQ(recorded.clone()))
+            .build();
+        futures::executor::block_on(engine.process()).unwrap();
+
+        assert_eq!(
+            *recorded.0.lock().unwrap(),
+            vec!["1:insufficient_funds".to_string(), "ingestion".to_string()]
+        );
+    }
+
+    #[test]
+    fn rejects_are_written_with_their_reason() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     withdrawal,1,2,9.0\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,x,1.0\n";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rejects.csv");
+        let clock = FixedClock(Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap());
+        let mut engine = Engine::builder(CsvReader::new(input.as_bytes()).unwrap())
+            .dlq(RejectsDLQ::create_with_clock(&path, NoopDLQ, clock).unwrap())
+            .build();
+        futures::executor::block_on(engine.process()).unwrap();
+        drop(engine);
+
+        let rejects = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            rejects,
+            "client,tx,type,reason,code,timestamp\n\
+             1,2,withdrawal,insufficient_funds,ENG_INSUFFICIENT_FUNDS,2024-05-01T12:30:00.000Z\n\
+             1,1,deposit,duplicate_transaction,ENG_DUPLICATE_TX,2024-05-01T12:30:00.000Z\n\
+             ,,,ingestion,ING_BAD_ROW,2024-05-01T12:30:00.000Z\n"
+        );
+    }
+
+    #[test]
+    fn rate_limit_suppresses_a_burst_past_the_limit() {
+        let recorded = RecordingDLQ::default();
+        let clock = FixedClock(Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap());
+        let mut notices = Vec::new();
+        let dlq = RateLimitedDLQ::with_clock(recorded.clone(), 3, clock, &mut notices);
+        for _ in 0..10 {
+            dlq.report(&Error::Ingestion("bad row".to_string()));
+        }
+
+        assert_eq!(dlq.suppressed(), 7);
+        assert_eq!(recorded.0.lock().unwrap().len(), 3);
+        drop(dlq);
+        assert_eq!(
+            String::from_utf8(notices).unwrap(),
+            "DLQ Report - Suppressed 7 errors\n"
+        );
+    }
+
+    /// A clock the test moves forward by hand.
+    #[derive(Clone, Default)]
+    struct SteppedClock(Arc<std::sync::atomic::AtomicI64>);
+
+    impl Clock for SteppedClock {
+        fn now(&self) -> chrono::DateTime<Utc> {
+            let secs = self.0.load(std::sync::atomic::Ordering::Relaxed);
+            Utc.timestamp_opt(secs, 0).unwrap()
+        }
+    }
+
+    #[test]
+    fn rate_limit_starts_over_every_second() {
+        let recorded = RecordingDLQ::default();
+        let clock = SteppedClock::default();
+        let mut notices = Vec::new();
+        let dlq = RateLimitedDLQ::with_clock(recorded.clone(), 2, clock.clone(), &mut notices);
+        let error = Error::Ingestion("bad row".to_string());
+        for second in [0, 0, 0, 0, 1, 1, 2] {
+            clock.0.store(second, std::sync::atomic::Ordering::Relaxed);
+            dlq.report(&error);
+        }
+
+        // two suppressed in the first second, none after
+        assert_eq!(dlq.suppressed(), 2);
+        assert_eq!(recorded.0.lock().unwrap().len(), 5);
+        drop(dlq);
+        assert_eq!(
+            String::from_utf8(notices).unwrap(),
+            "DLQ Report - Suppressed 2 errors\n"
+        );
     }
 }
@@ -1,24 +1,144 @@
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
-pub struct Account {
-    pub available: Decimal, // funds available for withdrawal
-    pub held: Decimal,      // funds held due to disputes
-    pub total: Decimal,     // total funds = available + held
-    pub locked: bool,       // account frozen due to chargeback
+use crate::domain::amount::as_string;
+use crate::domain::{Amount, EngineError, Error};
+
+/// Balances keep the full precision of the amount type; only `format_amount` rounds,
+/// when they are printed. Serialized amounts are strings with four places, like the
+/// balances CSV prints them.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct Account<A = Decimal> {
+    #[serde(with = "as_string")]
+    pub available: A, // funds available for withdrawal
+    #[serde(with = "as_string")]
+    pub held: A, // funds held due to disputes
+    #[serde(with = "as_string")]
+    pub total: A, // total funds = available + held
+    pub locked: bool, // account frozen due to chargeback or an administrative freeze
+    pub frozen: bool, // locked by a `freeze`, so an `unfreeze` may lift it
+    pub currency: Option<String>, // set by the first transaction that names one
+    #[serde(with = "as_string")]
+    pub on_hold: A, // part of `held` placed by manual holds rather than disputes
 }
 
-impl Account {
+impl<A: Amount> Account<A> {
     pub fn new() -> Self {
         Self {
-            available: Decimal::ZERO,
-            held: Decimal::ZERO,
-            total: Decimal::ZERO,
+            available: A::zero(),
+            held: A::zero(),
+            total: A::zero(),
             locked: false,
+            frozen: false,
+            currency: None,
+            on_hold: A::zero(),
         }
     }
 
+    /// Recomputes `total`. It is left alone if `available + held` is out of range,
+    /// which `validate` then reports.
     pub fn sync_total(&mut self) {
-        self.total = self.available + self.held;
+        if let Some(total) = self.available.checked_add(self.held) {
+            self.total = total;
+        }
+    }
+
+    /// Checks the `total = available + held` invariant, e.g. for accounts loaded from a snapshot.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.available.checked_add(self.held) != Some(self.total) {
+            return Err(EngineError::InconsistentTotal {
+                available: self.available.to_decimal(),
+                held: self.held.to_decimal(),
+                total: self.total.to_decimal(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// See `AccountSnapshot::projected_available`.
+    pub fn projected_available(&self, disputed: A) -> Option<(A, A, A)> {
+        self.snapshot().projected_available(disputed)
+    }
+
+    pub fn snapshot(&self) -> AccountSnapshot<A> {
+        AccountSnapshot {
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            on_hold: self.on_hold,
+        }
+    }
+}
+
+/// Point-in-time copy of an account's balances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountSnapshot<A = Decimal> {
+    pub available: A,
+    pub held: A,
+    pub total: A,
+    pub locked: bool,
+    pub on_hold: A,
+}
+
+impl<A: Amount> AccountSnapshot<A> {
+    /// Available funds now, if every open dispute were resolved, and if every one were
+    /// charged back, given `disputed`, the signed sum the client's open disputes hold.
+    /// A chargeback takes held funds only, so the last figure is today's. `None` if
+    /// resolving would overflow.
+    pub fn projected_available(&self, disputed: A) -> Option<(A, A, A)> {
+        let resolved = self.available.checked_add(disputed)?;
+        Some((self.available, resolved, self.available))
+    }
+
+    /// The same balances as exact decimals, e.g. for audit events.
+    pub fn to_decimal(&self) -> AccountSnapshot {
+        AccountSnapshot {
+            available: self.available.to_decimal(),
+            held: self.held.to_decimal(),
+            total: self.total.to_decimal(),
+            locked: self.locked,
+            on_hold: self.on_hold.to_decimal(),
+        }
+    }
+}
+
+impl<A: Amount> Default for Account<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::Money;
+
+    #[test]
+    fn accounts_round_trip_through_json() {
+        let account = Account {
+            available: Money::<4>(15_000),
+            held: Money(2_500),
+            total: Money(17_500),
+            locked: true,
+            frozen: false,
+            currency: Some("USD".to_string()),
+            on_hold: Money(0),
+        };
+        let json = serde_json::to_string(&account).unwrap();
+        assert_eq!(
+            json,
+            r#"{"available":"1.5000","held":"0.2500","total":"1.7500","locked":true,"frozen":false,"currency":"USD","on_hold":"0.0000"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Account<Money<4>>>(&json).unwrap(),
+            account
+        );
+
+        let fresh = Account::<Decimal>::new();
+        let json = serde_json::to_string(&fresh).unwrap();
+        assert_eq!(serde_json::from_str::<Account>(&json).unwrap(), fresh);
     }
 }
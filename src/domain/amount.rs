@@ -0,0 +1,230 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::domain::{Money, round_half_even};
+
+/// Numeric type the engine keeps balances in: exact `Decimal`, or fixed-point `Money`
+/// for speed. Input arrives as `Decimal` and is converted once per transaction.
+pub trait Amount: Copy + PartialEq + fmt::Debug + fmt::Display + Send + Sync + 'static {
+    fn zero() -> Self;
+
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    fn compare(&self, other: &Self) -> Ordering;
+
+    /// Rounds to `decimals` places, ties to even, for printing.
+    fn display_round(self, decimals: u32) -> Decimal;
+
+    /// `None` when `value` does not fit.
+    fn from_decimal(value: Decimal) -> Option<Self>;
+
+    fn to_decimal(self) -> Decimal;
+
+    /// Amounts from `from_decimal` can always be negated.
+    fn neg(self) -> Self;
+
+    fn is_negative(&self) -> bool {
+        self.compare(&Self::zero()).is_lt()
+    }
+
+    fn abs(self) -> Self {
+        if self.is_negative() { self.neg() } else { self }
+    }
+}
+
+impl Amount for Decimal {
+    fn zero() -> Self {
+        Decimal::ZERO
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Decimal::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Decimal::checked_sub(self, other)
+    }
+
+    fn compare(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+
+    fn display_round(self, decimals: u32) -> Decimal {
+        round_half_even(self, decimals)
+    }
+
+    fn from_decimal(value: Decimal) -> Option<Self> {
+        Some(value)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        self
+    }
+
+    fn neg(self) -> Self {
+        -self
+    }
+}
+
+/// Amounts with more than `DECIMALS` places are rounded half to even on the way in.
+impl<const DECIMALS: u32> Amount for Money<DECIMALS> {
+    fn zero() -> Self {
+        Money::zero()
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Money::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Money::checked_sub(self, other)
+    }
+
+    fn compare(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+
+    fn display_round(self, decimals: u32) -> Decimal {
+        round_half_even(self.to_decimal(), decimals)
+    }
+
+    fn from_decimal(value: Decimal) -> Option<Self> {
+        // i64::MIN has no positive counterpart, so it is kept out to make `neg` total.
+        Self::from_scaled_i128(value.mantissa(), value.scale()).filter(|m| m.0 != i64::MIN)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        Decimal::new(self.0, DECIMALS)
+    }
+
+    fn neg(self) -> Self {
+        -self
+    }
+}
+
+/// Places an amount is serialized with, as in the balances CSV.
+pub const SERIALIZED_DECIMALS: u32 = 4;
+
+/// Serializes an amount as a string with `SERIALIZED_DECIMALS` places, `"1.5000"`, so
+/// no JSON number ever carries it through a float. For `#[serde(with = ...)]`.
+pub mod as_string {
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    use super::*;
+
+    pub fn serialize<A: Amount, S: Serializer>(
+        amount: &A,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut amount = amount.display_round(SERIALIZED_DECIMALS);
+        amount.rescale(SERIALIZED_DECIMALS);
+        serializer.collect_str(&amount)
+    }
+
+    pub fn deserialize<'de, A: Amount, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<A, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        let value: Decimal = raw
+            .parse()
+            .map_err(|_| de::Error::custom(format!("Invalid amount: {}", raw)))?;
+        A::from_decimal(value)
+            .ok_or_else(|| de::Error::custom(format!("Amount out of range: {}", raw)))
+    }
+}
+
+/// `as_string` for an optional amount, `null` when absent.
+pub mod option_as_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::*;
+
+    pub fn serialize<A: Amount, S: Serializer>(
+        amount: &Option<A>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match amount {
+            Some(amount) => as_string::serialize(amount, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, A: Amount, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<A>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapped<A: Amount>(#[serde(with = "as_string", bound = "")] A);
+
+        let amount = Option::<Wrapped<A>>::deserialize(deserializer)?;
+        Ok(amount.map(|Wrapped(amount)| amount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn money_round_trips_through_decimal() {
+        let money = Money::<4>::from_decimal(Decimal::new(-123_456, 4)).unwrap();
+        assert_eq!(money, Money(-123_456));
+        assert_eq!(money.to_decimal(), Decimal::new(-123_456, 4));
+        assert_eq!(money.abs(), Money(123_456));
+
+        // rounded on the way in, like `from_decimal_str`
+        assert_eq!(
+            Money::<4>::from_decimal(Decimal::new(123_455, 5)),
+            Some(Money(12_346))
+        );
+        assert_eq!(Money::<4>::from_decimal(Decimal::MAX), None);
+        assert_eq!(Money::<4>(i64::MAX).checked_add(Money(1)), None);
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    #[serde(bound = "A: Amount")]
+    struct Row<A: Amount> {
+        #[serde(with = "as_string")]
+        amount: A,
+        #[serde(with = "option_as_string")]
+        partial: Option<A>,
+    }
+
+    #[test]
+    fn amounts_serialize_as_four_place_strings() {
+        let row = Row {
+            amount: Decimal::new(15, 1),
+            partial: None,
+        };
+        let json = serde_json::to_string(&row).unwrap();
+        assert_eq!(json, r#"{"amount":"1.5000","partial":null}"#);
+        assert_eq!(serde_json::from_str::<Row<Decimal>>(&json).unwrap(), row);
+
+        let row = Row {
+            amount: Money::<4>(-12_345),
+            partial: Some(Money(1)),
+        };
+        let json = serde_json::to_string(&row).unwrap();
+        assert_eq!(json, r#"{"amount":"-1.2345","partial":"0.0001"}"#);
+        assert_eq!(serde_json::from_str::<Row<Money<4>>>(&json).unwrap(), row);
+
+        for bad in [
+            r#"{"amount":"1.x","partial":null}"#,
+            r#"{"amount":1.5,"partial":null}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<Row<Decimal>>(bad).is_err(),
+                "{}",
+                bad
+            );
+        }
+        let err = serde_json::from_str::<Row<Money<4>>>(
+            r#"{"amount":"99999999999999999999","partial":null}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Amount out of range"), "{}", err);
+    }
+}
@@ -0,0 +1,11 @@
+use crate::domain::{AccountSnapshot, ClientId, TransactionKind};
+
+/// A successfully applied transaction and the balances it changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceEvent {
+    pub client_id: ClientId,
+    pub transaction_id: u32,
+    pub kind: TransactionKind,
+    pub before: AccountSnapshot,
+    pub after: AccountSnapshot,
+}
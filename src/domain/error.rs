@@ -1,3 +1,5 @@
+use crate::domain::ClientId;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -6,6 +8,332 @@ pub enum Error {
     #[error("Ingestion failed with: {0}")]
     Ingestion(String),
 
+    #[error("Ingestion failed with: Invalid amount: {0}")]
+    InvalidAmount(String),
+
+    /// A well-formed amount too large for a `Decimal`.
+    #[error("Ingestion failed with: Amount out of range: {0}")]
+    AmountOutOfRange(String),
+
+    #[error("Ingestion failed with: Invalid transaction type: {0}")]
+    UnknownType(String),
+
     #[error("Engine failed with: {0}")]
-    Engine(String),
+    Engine(#[from] EngineError),
+
+    #[error("Transaction stream was already consumed")]
+    AlreadyConsumed,
+
+    #[error("Processing timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
+    #[error("Processing interrupted")]
+    Interrupted,
+
+    #[error("Invalid arguments: {0}")]
+    Args(String),
+
+    /// The first rejection of a fail-fast run, which stopped processing there. `row`
+    /// is the rejected transaction, or a note that the row could not be read.
+    #[error("Stopped at the first rejection ({row}): {source}")]
+    FailedFast { row: String, source: Box<Error> },
+}
+
+impl Error {
+    /// Rejections that are usually benign, such as a replayed transaction id, and can be
+    /// bucketed apart from genuine failures.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::Engine(EngineError::DuplicateTransaction(_)))
+    }
+
+    /// Short machine-readable reason, e.g. for the rejects file.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::IO(_) => "io",
+            Error::Ingestion(_)
+            | Error::InvalidAmount(_)
+            | Error::AmountOutOfRange(_)
+            | Error::UnknownType(_) => "ingestion",
+            Error::Engine(e) => e.reason(),
+            Error::AlreadyConsumed => "already_consumed",
+            Error::Timeout(_) => "timeout",
+            Error::Interrupted => "interrupted",
+            Error::Args(_) => "args",
+            Error::FailedFast { source, .. } => source.reason(),
+        }
+    }
+
+    /// Stable upper-case code, prefixed by where the error arose, e.g.
+    /// `ENG_INSUFFICIENT_FUNDS`, so reports can be grepped for.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::IO(_) => "IO_ERROR",
+            Error::Ingestion(_) => "ING_BAD_ROW",
+            Error::InvalidAmount(_) => "ING_BAD_AMOUNT",
+            Error::AmountOutOfRange(_) => "ING_AMOUNT_OUT_OF_RANGE",
+            Error::UnknownType(_) => "ING_UNKNOWN_TYPE",
+            Error::Engine(e) => e.code(),
+            Error::AlreadyConsumed => "ING_ALREADY_CONSUMED",
+            Error::Timeout(_) => "RUN_TIMEOUT",
+            Error::Interrupted => "RUN_INTERRUPTED",
+            Error::Args(_) => "ARGS_INVALID",
+            Error::FailedFast { source, .. } => source.code(),
+        }
+    }
+}
+
+/// Reasons the engine rejects an otherwise well-formed transaction.
+#[derive(Debug, thiserror::Error)]
+pub enum EngineError {
+    #[error("{0} account is locked")]
+    AccountLocked(ClientId),
+
+    #[error("{0} account is locked by a chargeback and cannot be unfrozen")]
+    ChargebackLocked(ClientId),
+
+    #[error("Insufficient funds for client {0}")]
+    InsufficientFunds(ClientId),
+
+    #[error("Transaction ID {0} already exists")]
+    DuplicateTransaction(u32),
+
+    /// The referenced tx id was never seen, which may also mean it lives in input
+    /// that has not been loaded.
+    #[error("Referenced transaction {0} not found")]
+    UnknownReference(u32),
+
+    #[error("Transaction {0} is not disputed")]
+    NotDisputed(u32),
+
+    #[error("Transaction {0} is already disputed")]
+    AlreadyDisputed(u32),
+
+    #[error("Disputed amount must be positive and at most the amount of transaction {0}")]
+    InvalidDisputeAmount(u32),
+
+    #[error("Client {0} cannot release more than it has on hold")]
+    ReleaseExceedsHold(ClientId),
+
+    #[error("Hold and release amounts must be positive, got one for client {0}")]
+    InvalidHoldAmount(ClientId),
+
+    /// The engine's amount type cannot hold an amount of the transaction, e.g. one past
+    /// `i64` minor units for `Money`.
+    #[error("Transaction {transaction_id} has an amount out of range for the engine: {amount}")]
+    UnrepresentableAmount {
+        transaction_id: u32,
+        amount: rust_decimal::Decimal,
+    },
+
+    #[error("Client {0} balance would overflow")]
+    BalanceOverflow(ClientId),
+
+    /// Accounts hold more or less than came in, i.e. a handler created or destroyed money.
+    #[error("Account totals sum to {actual}, but the funds that came in net to {expected}")]
+    FundsNotConserved {
+        expected: rust_decimal::Decimal,
+        actual: rust_decimal::Decimal,
+    },
+
+    #[error("Disputing withdrawal {0} is not allowed by policy")]
+    WithdrawalDisputeNotAllowed(u32),
+
+    #[error("Withdrawal {0} is too old to be disputed")]
+    OutsideDisputeWindow(u32),
+
+    #[error("Client {0} already has the maximum number of open disputes")]
+    TooManyOpenDisputes(ClientId),
+
+    #[error("Client {0} has no account and the maximum number of clients is reached")]
+    TooManyClients(ClientId),
+
+    #[error("Dispute would take client {0} held funds above the limit")]
+    HeldLimitExceeded(ClientId),
+
+    #[error("Client {client_id} holds {expected}, not {found}")]
+    CurrencyMismatch {
+        client_id: ClientId,
+        expected: String,
+        found: String,
+    },
+
+    /// Raised by a `Validator` plugin.
+    #[error("Transaction {transaction_id} rejected: {reason}")]
+    Rejected { transaction_id: u32, reason: String },
+
+    #[error("Account total {total} does not equal available {available} + held {held}")]
+    InconsistentTotal {
+        available: rust_decimal::Decimal,
+        held: rust_decimal::Decimal,
+        total: rust_decimal::Decimal,
+    },
+}
+
+impl EngineError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            EngineError::AccountLocked(_) => "account_locked",
+            EngineError::ChargebackLocked(_) => "chargeback_locked",
+            EngineError::InsufficientFunds(_) => "insufficient_funds",
+            EngineError::DuplicateTransaction(_) => "duplicate_transaction",
+            EngineError::UnknownReference(_) => "unknown_reference",
+            EngineError::NotDisputed(_) => "not_disputed",
+            EngineError::AlreadyDisputed(_) => "already_disputed",
+            EngineError::InvalidDisputeAmount(_) => "invalid_dispute_amount",
+            EngineError::ReleaseExceedsHold(_) => "release_exceeds_hold",
+            EngineError::InvalidHoldAmount(_) => "invalid_hold_amount",
+            EngineError::UnrepresentableAmount { .. } => "unrepresentable_amount",
+            EngineError::BalanceOverflow(_) => "balance_overflow",
+            EngineError::FundsNotConserved { .. } => "funds_not_conserved",
+            EngineError::WithdrawalDisputeNotAllowed(_) => "withdrawal_dispute_not_allowed",
+            EngineError::OutsideDisputeWindow(_) => "outside_dispute_window",
+            EngineError::TooManyOpenDisputes(_) => "too_many_open_disputes",
+            EngineError::TooManyClients(_) => "too_many_clients",
+            EngineError::HeldLimitExceeded(_) => "held_limit_exceeded",
+            EngineError::CurrencyMismatch { .. } => "currency_mismatch",
+            EngineError::Rejected { .. } => "rejected",
+            EngineError::InconsistentTotal { .. } => "inconsistent_total",
+        }
+    }
+
+    /// See `Error::code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::AccountLocked(_) => "ENG_ACCOUNT_LOCKED",
+            EngineError::ChargebackLocked(_) => "ENG_CHARGEBACK_LOCKED",
+            EngineError::InsufficientFunds(_) => "ENG_INSUFFICIENT_FUNDS",
+            EngineError::DuplicateTransaction(_) => "ENG_DUPLICATE_TX",
+            EngineError::UnknownReference(_) => "ENG_UNKNOWN_REFERENCE",
+            EngineError::NotDisputed(_) => "ENG_NOT_DISPUTED",
+            EngineError::AlreadyDisputed(_) => "ENG_ALREADY_DISPUTED",
+            EngineError::InvalidDisputeAmount(_) => "ENG_BAD_DISPUTE_AMOUNT",
+            EngineError::ReleaseExceedsHold(_) => "ENG_RELEASE_EXCEEDS_HOLD",
+            EngineError::InvalidHoldAmount(_) => "ENG_BAD_HOLD_AMOUNT",
+            EngineError::UnrepresentableAmount { .. } => "ENG_UNREPRESENTABLE_AMOUNT",
+            EngineError::BalanceOverflow(_) => "ENG_BALANCE_OVERFLOW",
+            EngineError::FundsNotConserved { .. } => "ENG_FUNDS_NOT_CONSERVED",
+            EngineError::WithdrawalDisputeNotAllowed(_) => "ENG_WITHDRAWAL_DISPUTE_NOT_ALLOWED",
+            EngineError::OutsideDisputeWindow(_) => "ENG_OUTSIDE_DISPUTE_WINDOW",
+            EngineError::TooManyOpenDisputes(_) => "ENG_TOO_MANY_OPEN_DISPUTES",
+            EngineError::TooManyClients(_) => "ENG_TOO_MANY_CLIENTS",
+            EngineError::HeldLimitExceeded(_) => "ENG_HELD_LIMIT_EXCEEDED",
+            EngineError::CurrencyMismatch { .. } => "ENG_CURRENCY_MISMATCH",
+            EngineError::Rejected { .. } => "ENG_REJECTED",
+            EngineError::InconsistentTotal { .. } => "ENG_INCONSISTENT_TOTAL",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn every_variant_maps_to_its_code() {
+        let cases = [
+            (Error::IO(std::io::Error::other("disk")), "IO_ERROR"),
+            (Error::InvalidAmount("1e3".to_string()), "ING_BAD_AMOUNT"),
+            (
+                Error::AmountOutOfRange("1e40".to_string()),
+                "ING_AMOUNT_OUT_OF_RANGE",
+            ),
+            (Error::UnknownType("refund".to_string()), "ING_UNKNOWN_TYPE"),
+            (
+                Error::Ingestion("CSV deserialization error: x".to_string()),
+                "ING_BAD_ROW",
+            ),
+            (Error::AlreadyConsumed, "ING_ALREADY_CONSUMED"),
+            (
+                Error::Timeout(std::time::Duration::from_secs(1)),
+                "RUN_TIMEOUT",
+            ),
+            (Error::Interrupted, "RUN_INTERRUPTED"),
+            (Error::Args("--decimals".to_string()), "ARGS_INVALID"),
+            (
+                Error::FailedFast {
+                    row: "withdrawal of client 1 tx 2".to_string(),
+                    source: Box::new(EngineError::InsufficientFunds(1).into()),
+                },
+                "ENG_INSUFFICIENT_FUNDS",
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.code(), code, "{}", error);
+        }
+
+        let engine = [
+            (EngineError::AccountLocked(1), "ENG_ACCOUNT_LOCKED"),
+            (EngineError::ChargebackLocked(1), "ENG_CHARGEBACK_LOCKED"),
+            (EngineError::InsufficientFunds(1), "ENG_INSUFFICIENT_FUNDS"),
+            (EngineError::DuplicateTransaction(1), "ENG_DUPLICATE_TX"),
+            (EngineError::UnknownReference(1), "ENG_UNKNOWN_REFERENCE"),
+            (EngineError::NotDisputed(1), "ENG_NOT_DISPUTED"),
+            (EngineError::AlreadyDisputed(1), "ENG_ALREADY_DISPUTED"),
+            (
+                EngineError::InvalidDisputeAmount(1),
+                "ENG_BAD_DISPUTE_AMOUNT",
+            ),
+            (
+                EngineError::ReleaseExceedsHold(1),
+                "ENG_RELEASE_EXCEEDS_HOLD",
+            ),
+            (EngineError::InvalidHoldAmount(1), "ENG_BAD_HOLD_AMOUNT"),
+            (
+                EngineError::UnrepresentableAmount {
+                    transaction_id: 1,
+                    amount: Decimal::MAX,
+                },
+                "ENG_UNREPRESENTABLE_AMOUNT",
+            ),
+            (EngineError::BalanceOverflow(1), "ENG_BALANCE_OVERFLOW"),
+            (
+                EngineError::FundsNotConserved {
+                    expected: Decimal::ONE,
+                    actual: Decimal::ZERO,
+                },
+                "ENG_FUNDS_NOT_CONSERVED",
+            ),
+            (
+                EngineError::WithdrawalDisputeNotAllowed(1),
+                "ENG_WITHDRAWAL_DISPUTE_NOT_ALLOWED",
+            ),
+            (
+                EngineError::OutsideDisputeWindow(1),
+                "ENG_OUTSIDE_DISPUTE_WINDOW",
+            ),
+            (
+                EngineError::TooManyOpenDisputes(1),
+                "ENG_TOO_MANY_OPEN_DISPUTES",
+            ),
+            (EngineError::TooManyClients(1), "ENG_TOO_MANY_CLIENTS"),
+            (EngineError::HeldLimitExceeded(1), "ENG_HELD_LIMIT_EXCEEDED"),
+            (
+                EngineError::CurrencyMismatch {
+                    client_id: 1,
+                    expected: "EUR".to_string(),
+                    found: "GBP".to_string(),
+                },
+                "ENG_CURRENCY_MISMATCH",
+            ),
+            (
+                EngineError::Rejected {
+                    transaction_id: 1,
+                    reason: "too large".to_string(),
+                },
+                "ENG_REJECTED",
+            ),
+            (
+                EngineError::InconsistentTotal {
+                    available: Decimal::ONE,
+                    held: Decimal::ONE,
+                    total: Decimal::ONE,
+                },
+                "ENG_INCONSISTENT_TOTAL",
+            ),
+        ];
+        for (error, code) in engine {
+            assert_eq!(Error::from(error).code(), code);
+        }
+    }
 }
@@ -1,9 +1,17 @@
 pub mod account;
+pub mod amount;
+pub mod audit;
 pub mod error;
+pub mod money;
 pub mod traits;
 pub mod transaction;
 
-pub use account::Account;
-pub use error::Error;
-pub use traits::{DeadLetterQueue, OutputRepository};
-pub use transaction::{Transaction, TransactionKind};
+pub use account::{Account, AccountSnapshot};
+pub use amount::Amount;
+pub use audit::BalanceEvent;
+pub use error::{EngineError, Error};
+pub use money::{Money, round_half_even};
+pub use traits::{
+    AsyncDeadLetterQueue, AuditSink, Clock, DeadLetterQueue, OutputRepository, Validator,
+};
+pub use transaction::{ClientId, Transaction, TransactionKind};
@@ -1,11 +1,42 @@
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+
+use rust_decimal::Decimal;
 use serde::{Deserialize, Deserializer};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Money(pub i64);
+/// Divides by `factor`, rounding half to even: `25 / 10` is `2`, `35 / 10` is `4`.
+/// Shared by `Money` and `round_half_even` so both numeric types break ties alike.
+fn div_half_even(value: i128, factor: i128) -> i128 {
+    let div = value / factor; // truncated toward zero
+    let rem = (value % factor).abs();
+    let half = factor / 2;
+    let away = if value.is_negative() { -1 } else { 1 };
+    if rem > half || (rem == half && factor % 2 == 0 && div & 1 != 0) {
+        div + away
+    } else {
+        div
+    }
+}
+
+/// Rounds `amount` to `decimals` places, ties to even, the same way `Money` does.
+pub fn round_half_even(amount: Decimal, decimals: u32) -> Decimal {
+    if amount.scale() <= decimals {
+        return amount;
+    }
+    // A `Decimal` scale is at most 28, so the factor fits in an i128.
+    let factor = 10i128.pow(amount.scale() - decimals);
+    Decimal::from_i128_with_scale(div_half_even(amount.mantissa(), factor), decimals)
+}
+
+/// Fixed-point amount stored as minor units with `DECIMALS` fractional digits.
+///
+/// `+`, `-` and unary `-` panic on overflow in every build rather than wrap; use the
+/// `checked_` methods where an overflow is expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Money<const DECIMALS: u32 = 4>(pub i64);
 
-impl Money {
-    pub const SCALE: i64 = 10_000; // 4 decimal places
-    pub const TARGET_DECIMALS: u32 = 4;
+impl<const DECIMALS: u32> Money<DECIMALS> {
+    pub const SCALE: i64 = 10i64.pow(DECIMALS);
+    pub const TARGET_DECIMALS: u32 = DECIMALS;
 
     pub fn zero() -> Self {
         Self(0)
@@ -14,6 +45,19 @@ impl Money {
         self.0
     }
 
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    /// `None` only for `i64::MIN` minor units, which has no positive counterpart.
+    pub fn checked_neg(self) -> Option<Self> {
+        self.0.checked_neg().map(Self)
+    }
+
     pub fn from_scaled_i128(value: i128, scale: u32) -> Option<Self> {
         if scale == Self::TARGET_DECIMALS {
             if value < i128::from(i64::MIN) || value > i128::from(i64::MAX) {
@@ -22,8 +66,8 @@ impl Money {
             return Some(Self(value as i64));
         }
         if scale < Self::TARGET_DECIMALS {
-            let diff = (Self::TARGET_DECIMALS - scale) as u32;
-            let factor = 10i128.pow(diff);
+            let diff = Self::TARGET_DECIMALS - scale;
+            let factor = 10i128.checked_pow(diff)?;
             let widened = value.checked_mul(factor)?;
             if widened < i128::from(i64::MIN) || widened > i128::from(i64::MAX) {
                 return None;
@@ -31,91 +75,125 @@ impl Money {
             return Some(Self(widened as i64));
         }
         // scale > TARGET_DECIMALS: need rounding
-        let diff = (scale - Self::TARGET_DECIMALS) as u32;
-        let factor = 10i128.pow(diff);
-        let div = value / factor; // truncated toward zero
-        let rem = value % factor;
-        if rem == 0 {
-            if div < i128::from(i64::MIN) || div > i128::from(i64::MAX) {
-                return None;
-            }
-            return Some(Self(div as i64));
-        }
-        let half = factor / 2;
-        let abs_rem = rem.abs();
-        let mut adjusted = div;
-        if abs_rem > half {
-            adjusted += if value.is_negative() { -1 } else { 1 };
-        } else if abs_rem == half {
-            // tie -> bankers (round half to even)
-            if div & 1 != 0 {
-                // previous is odd -> increment toward magnitude
-                adjusted += if value.is_negative() { -1 } else { 1 };
-            }
-        }
+        let diff = scale - Self::TARGET_DECIMALS;
+        // Past i128 range every representable value is below half a unit.
+        let Some(factor) = 10i128.checked_pow(diff) else {
+            return Some(Self(0));
+        };
+        let adjusted = div_half_even(value, factor);
         if adjusted < i128::from(i64::MIN) || adjusted > i128::from(i64::MAX) {
             return None;
         }
         Some(Self(adjusted as i64))
     }
 
+    /// Parses `[-+]?digits(.digits)?`, rounding extra fractional digits half-to-even.
+    /// Leading and trailing zeros are fine (`007.50`) and surrounding whitespace is
+    /// trimmed. Both sides of a point need a digit, so `.5` and `5.` are rejected, as
+    /// are a second sign (`+-5`), `1.2.3`, exponents, separators and whitespace inside,
+    /// and values that do not fit in `i64` minor units.
     pub fn from_decimal_str(s: &str) -> Option<Self> {
         let s = s.trim();
+        let (neg, body) = match (s.strip_prefix('-'), s.strip_prefix('+')) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => (false, s),
+        };
+        let (int_part, frac_part) = match body.split_once('.') {
+            Some((_, "")) => return None,
+            Some((int_part, frac_part)) => (int_part, frac_part),
+            None => (body, ""),
+        };
 
-        if s.is_empty() {
-            return None;
-        }
-        let neg = s.starts_with('-');
-        let body = s.trim_start_matches('-');
-        let mut parts = body.split('.');
-        let int_part = parts.next()?;
-        if int_part.is_empty() {
+        // `str::parse` alone would let signs through, e.g. `+5` or `1.-5`.
+        let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(int_part) || !(frac_part.is_empty() || is_digits(frac_part)) {
             return None;
         }
-        let int_val: i128 = int_part.parse().ok()?;
-        let frac_opt = parts.next();
-        if parts.next().is_some() {
-            return None;
-        }
-        let (raw, scale) = if let Some(frac) = frac_opt {
-            if frac.is_empty() {
-                (int_val, 0)
-            } else {
-                (
-                    int_val * 10i128.pow(frac.len() as u32) + frac.parse::<i128>().ok()?,
-                    frac.len() as u32,
-                )
-            }
+
+        // Past one digit beyond the target scale only "is anything left" matters for
+        // rounding, so long fractions collapse to a sticky `1` instead of overflowing.
+        let frac_part = frac_part.trim_end_matches('0');
+        let keep = DECIMALS as usize + 1;
+        let frac_part = if frac_part.len() > keep {
+            format!("{}1", &frac_part[..keep])
         } else {
-            (int_val, 0)
+            frac_part.to_string()
         };
-        let signed = if neg { -raw } else { raw };
-        Money::from_scaled_i128(signed, scale)
+
+        let raw: i128 = [int_part, &frac_part].concat().parse().ok()?;
+        let scale = u32::try_from(frac_part.len()).ok()?;
+        Self::from_scaled_i128(if neg { -raw } else { raw }, scale)
     }
 }
 
-impl core::fmt::Display for Money {
+impl<const DECIMALS: u32> core::fmt::Display for Money<DECIMALS> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let minor = self.0;
-        let neg = minor < 0;
-        let abs = minor.abs();
-        let int_part = abs / Self::SCALE;
-        let frac_part = abs % Self::SCALE;
-        if neg {
-            write!(f, "-{}.{:04}", int_part, frac_part)
+        let sign = if minor < 0 { "-" } else { "" };
+        let abs = minor.unsigned_abs();
+        let scale = Self::SCALE as u64;
+        let int_part = abs / scale;
+        let frac_part = abs % scale;
+        if DECIMALS == 0 {
+            write!(f, "{}{}", sign, int_part)
         } else {
-            write!(f, "{}.{:04}", int_part, frac_part)
+            write!(
+                f,
+                "{}{}.{:0width$}",
+                sign,
+                int_part,
+                frac_part,
+                width = DECIMALS as usize
+            )
         }
     }
 }
 
-impl<'de> Deserialize<'de> for Money {
+impl<const DECIMALS: u32> Add for Money<DECIMALS> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        self.checked_add(other).expect("Money addition overflowed")
+    }
+}
+
+impl<const DECIMALS: u32> Sub for Money<DECIMALS> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.checked_sub(other)
+            .expect("Money subtraction overflowed")
+    }
+}
+
+impl<const DECIMALS: u32> Neg for Money<DECIMALS> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        self.checked_neg().expect("Money negation overflowed")
+    }
+}
+
+impl<const DECIMALS: u32> AddAssign for Money<DECIMALS> {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl<const DECIMALS: u32> SubAssign for Money<DECIMALS> {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+
+impl<'de, const DECIMALS: u32> Deserialize<'de> for Money<DECIMALS> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Money::from_decimal_str(&s)
+        Self::from_decimal_str(&s)
             .ok_or_else(|| serde::de::Error::custom(format!("Invalid Money format: {}", s)))
     }
 }
@@ -123,15 +201,223 @@ impl<'de> Deserialize<'de> for Money {
 #[cfg(test)]
 mod tests {
     use super::Money;
+    #[test]
+    fn arithmetic_is_checked_at_the_i64_boundaries() {
+        let max = Money::<4>(i64::MAX);
+        let min = Money::<4>(i64::MIN);
+        let one = Money::<4>(1);
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(max.checked_sub(one), Some(Money(i64::MAX - 1)));
+        assert_eq!(min.checked_sub(one), None);
+        assert_eq!(min.checked_add(one), Some(Money(i64::MIN + 1)));
+        assert_eq!(min.checked_neg(), None);
+        assert_eq!(max.checked_neg(), Some(Money(i64::MIN + 1)));
+
+        assert_eq!(max - one + one, max);
+        assert_eq!(-(min + one), max);
+        let mut balance = Money::<4>(5);
+        balance -= Money(7);
+        assert_eq!(balance, Money(-2));
+        balance += Money(2);
+        assert_eq!(balance, Money::zero());
+    }
+
+    #[test]
+    #[should_panic(expected = "Money addition overflowed")]
+    fn overflowing_addition_panics() {
+        let _ = Money::<4>(i64::MAX) + Money(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Money subtraction overflowed")]
+    fn overflowing_subtraction_panics() {
+        let _ = Money::<4>(i64::MIN) - Money(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Money negation overflowed")]
+    fn negating_the_minimum_panics() {
+        let _ = -Money::<4>(i64::MIN);
+    }
+
+    #[test]
+    fn orders_by_value() {
+        let mut amounts = [Money::<2>(150), Money(-3), Money(0), Money(i64::MIN)];
+        amounts.sort();
+        assert_eq!(amounts, [Money(i64::MIN), Money(-3), Money(0), Money(150)]);
+        assert!(Money::<2>(-1) < Money::zero());
+        assert_eq!(Money::<2>(7).max(Money(9)), Money(9));
+    }
+
     #[test]
     fn bankers_round_half_even() {
-        let v = Money::from_scaled_i128(1_23445, 5).unwrap(); // 1.23445 -> 1.2344
+        let v = Money::<4>::from_scaled_i128(1_23445, 5).unwrap(); // 1.23445 -> 1.2344
         assert_eq!(format!("{}", v), "1.2344");
-        let v = Money::from_scaled_i128(1_23455, 5).unwrap(); // 1.23455 -> 1.2346
+        let v = Money::<4>::from_scaled_i128(1_23455, 5).unwrap(); // 1.23455 -> 1.2346
         assert_eq!(format!("{}", v), "1.2346");
-        let v = Money::from_scaled_i128(-1_23445, 5).unwrap();
+        let v = Money::<4>::from_scaled_i128(-1_23445, 5).unwrap();
         assert_eq!(format!("{}", v), "-1.2344");
-        let v = Money::from_scaled_i128(-1_23455, 5).unwrap();
+        let v = Money::<4>::from_scaled_i128(-1_23455, 5).unwrap();
         assert_eq!(format!("{}", v), "-1.2346");
     }
+
+    #[test]
+    fn two_decimal_formatting_and_rounding() {
+        let v = Money::<2>::from_decimal_str("12.345").unwrap(); // tie -> even
+        assert_eq!(v.as_minor(), 1234);
+        assert_eq!(format!("{}", v), "12.34");
+        let v = Money::<2>::from_decimal_str("12.355").unwrap();
+        assert_eq!(format!("{}", v), "12.36");
+        let v = Money::<2>::from_decimal_str("-0.5").unwrap();
+        assert_eq!(format!("{}", v), "-0.50");
+        assert_eq!(Money::<2>::SCALE, 100);
+    }
+
+    #[test]
+    fn eight_decimal_formatting_and_rounding() {
+        let v = Money::<8>::from_decimal_str("0.000000015").unwrap(); // tie -> even
+        assert_eq!(format!("{}", v), "0.00000002");
+        let v = Money::<8>::from_decimal_str("1.5").unwrap();
+        assert_eq!(v.as_minor(), 150_000_000);
+        assert_eq!(format!("{}", v), "1.50000000");
+    }
+
+    #[test]
+    fn default_scale_is_four_decimals() {
+        let v: Money = Money::from_decimal_str("1.5").unwrap();
+        assert_eq!(format!("{}", v), "1.5000");
+        assert_eq!(Money::<4>::SCALE, 10_000);
+    }
+
+    #[test]
+    fn rejects_malformed_signs_and_separators() {
+        for s in [
+            "", "-", ".", "+", "-+5", "+-5", "++5", "--5", "-.5", ".5", "5.", "-5.", "1.2.3",
+            "1.-5", "1.+5", "1e3", "1 000", "1,5", " - 5", "0x10",
+        ] {
+            assert_eq!(Money::<4>::from_decimal_str(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn accepts_plain_decimals() {
+        let parse = |s| Money::<4>::from_decimal_str(s).map(|m| m.as_minor());
+        assert_eq!(parse("5"), Some(50_000));
+        assert_eq!(parse("-5"), Some(-50_000));
+        assert_eq!(parse(" 0.5 "), Some(5_000));
+        assert_eq!(parse("-0.00005"), Some(0));
+        assert_eq!(parse("007.10"), Some(71_000));
+    }
+
+    #[test]
+    fn acceptance_rules() {
+        let parse = |s| Money::<4>::from_decimal_str(s).map(|m| m.as_minor());
+        for (input, expected) in [
+            // leading zeros
+            ("007.50", Some(75_000)),
+            ("00", Some(0)),
+            ("-007", Some(-70_000)),
+            ("0.5", Some(5_000)),
+            // trailing zeros, also past the scale
+            ("1.50000000", Some(15_000)),
+            ("10", Some(100_000)),
+            // one optional sign
+            ("+5", Some(50_000)),
+            ("+0.0001", Some(1)),
+            ("-0", Some(0)),
+            ("+-5", None),
+            ("- 5", None),
+            ("+ 5", None),
+            // a digit is needed on both sides of the point
+            (".5", None),
+            ("+.5", None),
+            ("5.", None),
+            ("+5.", None),
+            ("0.", None),
+            // surrounding whitespace only
+            ("\t+2.5 ", Some(25_000)),
+            ("2 .5", None),
+            ("2. 5", None),
+        ] {
+            assert_eq!(parse(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn out_of_range_is_none_not_a_panic() {
+        assert_eq!(Money::<4>::from_decimal_str("922337203685477.5808"), None);
+        assert_eq!(
+            Money::<4>::from_decimal_str("-922337203685477.5808").map(|m| m.as_minor()),
+            Some(i64::MIN)
+        );
+        assert_eq!(Money::<4>::from_decimal_str(&"9".repeat(60)), None);
+        let tiny = format!("0.{}1", "0".repeat(60));
+        assert_eq!(Money::<4>::from_decimal_str(&tiny), Some(Money(0)));
+    }
+
+    #[test]
+    fn long_fractions_still_round_half_even() {
+        let parse = |s: String| Money::<4>::from_decimal_str(&s).map(|m| m.as_minor());
+        assert_eq!(parse(format!("0.12345{}", "0".repeat(50))), Some(1234));
+        assert_eq!(parse(format!("0.12345{}1", "0".repeat(50))), Some(1235));
+        assert_eq!(parse(format!("-0.12345{}1", "0".repeat(50))), Some(-1235));
+    }
+
+    mod properties {
+        use super::Money;
+        use crate::domain::round_half_even;
+        use proptest::prelude::*;
+        use rust_decimal::Decimal;
+
+        /// The grammar `from_decimal_str` is meant to accept.
+        fn well_formed(s: &str) -> bool {
+            let body = s.trim();
+            let body = body
+                .strip_prefix('-')
+                .or_else(|| body.strip_prefix('+'))
+                .unwrap_or(body);
+            let digits = |p: &str| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit());
+            match body.split_once('.') {
+                Some((int_part, frac_part)) => digits(int_part) && digits(frac_part),
+                None => digits(body),
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn display_round_trips_four_decimals(minor in any::<i64>()) {
+                let m = Money::<4>(minor);
+                prop_assert_eq!(Money::<4>::from_decimal_str(&m.to_string()), Some(m));
+            }
+
+            #[test]
+            fn display_round_trips_other_scales(minor in any::<i64>()) {
+                prop_assert_eq!(Money::<0>::from_decimal_str(&Money::<0>(minor).to_string()), Some(Money(minor)));
+                prop_assert_eq!(Money::<2>::from_decimal_str(&Money::<2>(minor).to_string()), Some(Money(minor)));
+                prop_assert_eq!(Money::<8>::from_decimal_str(&Money::<8>(minor).to_string()), Some(Money(minor)));
+            }
+
+            #[test]
+            fn decimal_rounding_matches_money(mantissa in any::<i64>(), scale in 4u32..12) {
+                let rounded = round_half_even(Decimal::new(mantissa, scale), 4);
+                let money = Money::<4>::from_scaled_i128(mantissa.into(), scale).unwrap();
+                prop_assert_eq!(rounded, Decimal::new(money.as_minor(), 4));
+            }
+
+            #[test]
+            fn arbitrary_input_never_panics(s in "\\PC*") {
+                if Money::<4>::from_decimal_str(&s).is_some() {
+                    prop_assert!(well_formed(&s), "accepted {:?}", s);
+                }
+            }
+
+            #[test]
+            fn decimal_like_input_is_accepted_only_when_well_formed(s in "[-+.0-9e]{0,12}") {
+                let parsed = Money::<4>::from_decimal_str(&s);
+                if !well_formed(&s) {
+                    prop_assert_eq!(parsed, None);
+                }
+            }
+        }
+    }
 }
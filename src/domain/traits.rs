@@ -1,31 +1,172 @@
+use std::future::Future;
+
+use chrono::{DateTime, Utc};
 use futures::Stream;
+use rust_decimal::Decimal;
 
-use crate::domain::{Account, Error, Transaction};
+use crate::domain::{Account, AccountSnapshot, Amount, BalanceEvent, ClientId, Error, Transaction};
 
 pub trait TransactionStream {
     type TxStream: Stream<Item = Result<Transaction, Error>> + Send + Unpin + 'static;
-    fn stream(&mut self) -> Self::TxStream;
+    /// Hands out the transactions. Streams are single-use: a second call fails with
+    /// `Error::AlreadyConsumed`.
+    fn stream(&mut self) -> Result<Self::TxStream, Error>;
+}
+
+/// Source of the timestamps written to rejects and audit files.
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
 }
 
 pub trait DeadLetterQueue {
     fn report(&self, error: &Error);
+
+    /// Reports an error raised while applying `transaction`. Queues that have no use
+    /// for the transaction fall back to `report`.
+    fn report_rejected(&self, transaction: &Transaction, error: &Error) {
+        let _ = transaction;
+        self.report(error);
+    }
+}
+
+impl<D: DeadLetterQueue + ?Sized> DeadLetterQueue for Box<D> {
+    fn report(&self, error: &Error) {
+        (**self).report(error);
+    }
+
+    fn report_rejected(&self, transaction: &Transaction, error: &Error) {
+        (**self).report_rejected(transaction, error);
+    }
+}
+
+/// `DeadLetterQueue` for queues that write to a file or the network, so a report can
+/// await its I/O instead of blocking the runtime. `dlq::BlockingDLQ` adapts a
+/// synchronous queue.
+pub trait AsyncDeadLetterQueue: Send + Sync {
+    fn report(&self, error: &Error) -> impl Future<Output = ()> + Send;
+
+    /// Reports an error raised while applying `transaction`. Queues that have no use
+    /// for the transaction fall back to `report`.
+    fn report_rejected(
+        &self,
+        transaction: &Transaction,
+        error: &Error,
+    ) -> impl Future<Output = ()> + Send {
+        let _ = transaction;
+        self.report(error)
+    }
+}
+
+pub trait AuditSink {
+    fn record(&self, event: &BalanceEvent);
+}
+
+/// Custom pre-checks run before a transaction touches any state. A rejection is
+/// reported to the dead letter queue like any other engine error.
+pub trait Validator<A: Amount = Decimal> {
+    fn validate(
+        &self,
+        tx: &Transaction<A>,
+        repo: &dyn OutputRepository<Amount = A>,
+    ) -> Result<(), Error>;
 }
 
 pub trait OutputRepository {
-    fn get_or_create_account(&mut self, client_id: &u16) -> &mut Account;
-    fn flush(&mut self);
+    /// Money type balances and ledger amounts are kept in.
+    type Amount: Amount;
+
+    /// Fails if the account exists but cannot be read back, e.g. from a spill file.
+    fn get_or_create_account(
+        &mut self,
+        client_id: &ClientId,
+    ) -> Result<&mut Account<Self::Amount>, Error>;
+
+    /// Looks up an account without creating it.
+    fn get_account(&self, client_id: ClientId) -> Option<AccountSnapshot<Self::Amount>>;
+
+    /// Every known account.
+    fn accounts(&self) -> Vec<(ClientId, AccountSnapshot<Self::Amount>)>;
+
+    /// How many accounts `accounts` would list, without reading them all.
+    fn account_count(&self) -> usize {
+        self.accounts().len()
+    }
+
+    /// Replaces the account for `client_id`, e.g. when seeding from a snapshot.
+    fn restore_account(&mut self, client_id: ClientId, account: Account<Self::Amount>);
+
+    /// Forgets every account and ledger entry, e.g. before reusing the repository.
+    fn clear(&mut self);
+
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// Called with the new balances each time a transaction changes an account, in
+    /// processing order. Outputs without a change feed ignore it.
+    fn emit_account_change(
+        &mut self,
+        client_id: ClientId,
+        account: &AccountSnapshot<Self::Amount>,
+    ) {
+        let _ = (client_id, account);
+    }
 
+    /// Adds `transaction` to the ledger. Tx ids are per client, so the ledger is keyed
+    /// by `(transaction.client_id, transaction_id)` and two clients may reuse an id.
     fn report_transaction(
         &mut self,
         transaction_id: &u32,
-        transaction: &Transaction,
+        transaction: &Transaction<Self::Amount>,
     ) -> Result<(), Error>;
 
-    fn get_transaction(&mut self, transaction_id: u32) -> Option<&Transaction>;
+    fn get_transaction(
+        &self,
+        client_id: ClientId,
+        transaction_id: u32,
+    ) -> Option<&Transaction<Self::Amount>>;
+
+    fn client_transactions(&self, client_id: ClientId) -> Vec<&Transaction<Self::Amount>>;
+
+    /// Drops `transaction_id` from the ledger, e.g. once it can no longer be disputed.
+    fn forget_transaction(&mut self, client_id: ClientId, transaction_id: u32);
+
+    /// Opens a dispute over `amount`, the signed part of the transaction's delta that
+    /// was moved to held. A transaction already under dispute keeps its amount.
+    fn mark_transaction_disputed(
+        &mut self,
+        client_id: ClientId,
+        transaction_id: u32,
+        amount: Self::Amount,
+    );
+
+    fn mark_transaction_resolved(&mut self, client_id: ClientId, transaction_id: u32);
+
+    /// Signed amount held by the open dispute on `transaction_id`, if any.
+    fn disputed_amount(&self, client_id: ClientId, transaction_id: u32) -> Option<Self::Amount>;
+
+    fn has_dispute(&self, client_id: ClientId, transaction_id: u32) -> bool {
+        self.disputed_amount(client_id, transaction_id).is_some()
+    }
 
-    fn mark_transaction_disputed(&mut self, transaction_id: u32);
+    /// Number of the client's transactions currently under dispute.
+    fn open_disputes(&self, client_id: ClientId) -> usize;
 
-    fn mark_transaction_resolved(&mut self, transaction_id: u32);
+    /// Every open dispute as `(client_id, transaction_id, signed amount)`, in no
+    /// particular order.
+    fn disputes(&self) -> Vec<(ClientId, u32, Self::Amount)>;
 
-    fn has_dispute(&self, transaction_id: u32) -> bool;
+    /// The client's available funds now, if all its open disputes were resolved, and if
+    /// all were charged back, for risk displays. `None` for an unknown client or if a
+    /// figure is out of range.
+    fn projected_available(
+        &self,
+        client_id: ClientId,
+    ) -> Option<(Self::Amount, Self::Amount, Self::Amount)> {
+        let account = self.get_account(client_id)?;
+        let disputed = self
+            .client_transactions(client_id)
+            .into_iter()
+            .filter_map(|tx| self.disputed_amount(client_id, tx.transaction_id))
+            .try_fold(Self::Amount::zero(), Amount::checked_add)?;
+        account.projected_available(disputed)
+    }
 }
@@ -1,22 +1,152 @@
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy)]
-pub enum TransactionKind {
-    Deposit { amount: Decimal },
-    Withdrawal { amount: Decimal },
-    Dispute,
+use crate::domain::Amount;
+use crate::domain::amount::{as_string, option_as_string};
+
+/// Client identifier. `u16` keeps accounts small; the `wide-client-ids` feature widens
+/// it to `u32` for deployments with more than 65,535 clients.
+#[cfg(not(feature = "wide-client-ids"))]
+pub type ClientId = u16;
+#[cfg(feature = "wide-client-ids")]
+pub type ClientId = u32;
+
+/// Serialized tagged by its `name` in a `type` field, `{"type":"deposit","amount":"1.5000"}`,
+/// with amounts as strings like `Account`'s.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", bound = "A: Amount")]
+pub enum TransactionKind<A = Decimal> {
+    Deposit {
+        #[serde(with = "as_string")]
+        amount: A,
+    },
+    Withdrawal {
+        #[serde(with = "as_string")]
+        amount: A,
+    },
+    /// Disputes `amount` of the referenced transaction, or all of it when `None`.
+    Dispute {
+        #[serde(
+            with = "option_as_string",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        amount: Option<A>,
+    },
     Resolve,
     Chargeback,
+    /// Administrative lock, independent of disputes.
+    Freeze,
+    /// Lifts a `Freeze`. Accounts locked by a chargeback stay locked.
+    Unfreeze,
+    /// Manual hold, e.g. escrow: moves funds from available to held without a dispute.
+    Hold {
+        #[serde(with = "as_string")]
+        amount: A,
+    },
+    /// Returns funds put on hold by `Hold` to available.
+    Release {
+        #[serde(with = "as_string")]
+        amount: A,
+    },
+}
+
+impl<A: Amount> TransactionKind<A> {
+    /// Signed effect on the client's funds: deposits add, withdrawals subtract. `None`
+    /// for kinds that only refer to other transactions or move funds within an account.
+    pub fn delta(&self) -> Option<A> {
+        match *self {
+            TransactionKind::Deposit { amount } => Some(amount),
+            TransactionKind::Withdrawal { amount } => Some(amount.neg()),
+            _ => None,
+        }
+    }
+
+    /// The amount the row carries, if any: a partial dispute's, not the disputed
+    /// transaction's.
+    pub fn amount(&self) -> Option<A> {
+        match *self {
+            TransactionKind::Deposit { amount }
+            | TransactionKind::Withdrawal { amount }
+            | TransactionKind::Hold { amount }
+            | TransactionKind::Release { amount } => Some(amount),
+            TransactionKind::Dispute { amount } => amount,
+            TransactionKind::Resolve
+            | TransactionKind::Chargeback
+            | TransactionKind::Freeze
+            | TransactionKind::Unfreeze => None,
+        }
+    }
+
+    /// Converts every amount with `f`, failing if any conversion does.
+    pub fn try_map<B>(self, f: impl Fn(A) -> Option<B>) -> Option<TransactionKind<B>> {
+        Some(match self {
+            TransactionKind::Deposit { amount } => TransactionKind::Deposit { amount: f(amount)? },
+            TransactionKind::Withdrawal { amount } => {
+                TransactionKind::Withdrawal { amount: f(amount)? }
+            }
+            TransactionKind::Dispute { amount } => TransactionKind::Dispute {
+                amount: match amount {
+                    Some(amount) => Some(f(amount)?),
+                    None => None,
+                },
+            },
+            TransactionKind::Resolve => TransactionKind::Resolve,
+            TransactionKind::Chargeback => TransactionKind::Chargeback,
+            TransactionKind::Freeze => TransactionKind::Freeze,
+            TransactionKind::Unfreeze => TransactionKind::Unfreeze,
+            TransactionKind::Hold { amount } => TransactionKind::Hold { amount: f(amount)? },
+            TransactionKind::Release { amount } => TransactionKind::Release { amount: f(amount)? },
+        })
+    }
+}
+
+impl<A> TransactionKind<A> {
+    /// Lowercase name as it appears in the input `type` column.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionKind::Deposit { .. } => "deposit",
+            TransactionKind::Withdrawal { .. } => "withdrawal",
+            TransactionKind::Dispute { .. } => "dispute",
+            TransactionKind::Resolve => "resolve",
+            TransactionKind::Chargeback => "chargeback",
+            TransactionKind::Freeze => "freeze",
+            TransactionKind::Unfreeze => "unfreeze",
+            TransactionKind::Hold { .. } => "hold",
+            TransactionKind::Release { .. } => "release",
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Transaction {
-    pub kind: TransactionKind,
-    pub client_id: u16,
+/// Serialized flat with the input's column names,
+/// `{"type":"deposit","client":1,"tx":2,"amount":"1.5000"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct Transaction<A = Decimal> {
+    #[serde(flatten)]
+    pub kind: TransactionKind<A>,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(rename = "tx")]
     pub transaction_id: u32,
+    /// Currency code from the optional `currency` column, upper-cased.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
 }
 
-impl core::fmt::Display for Transaction {
+impl<A: Amount> Transaction<A> {
+    /// The same transaction in another amount type, `None` if an amount does not fit.
+    pub fn try_map<B>(&self, f: impl Fn(A) -> Option<B>) -> Option<Transaction<B>> {
+        Some(Transaction {
+            kind: self.kind.try_map(f)?,
+            client_id: self.client_id,
+            transaction_id: self.transaction_id,
+            currency: self.currency.clone(),
+        })
+    }
+}
+
+impl<A: Amount> core::fmt::Display for Transaction<A> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self.kind {
             TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount } => {
@@ -34,3 +164,79 @@ impl core::fmt::Display for Transaction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::Money;
+
+    fn round_trip<A: Amount>(tx: &Transaction<A>, json: &str) {
+        assert_eq!(serde_json::to_string(tx).unwrap(), json);
+        assert_eq!(&serde_json::from_str::<Transaction<A>>(json).unwrap(), tx);
+    }
+
+    #[test]
+    fn transactions_serialize_with_the_input_column_names() {
+        let tx = |kind| Transaction {
+            kind,
+            client_id: 1,
+            transaction_id: 2,
+            currency: None,
+        };
+        round_trip(
+            &tx(TransactionKind::Deposit {
+                amount: Decimal::new(15, 1),
+            }),
+            r#"{"type":"deposit","amount":"1.5000","client":1,"tx":2}"#,
+        );
+        round_trip(
+            &tx(TransactionKind::Dispute { amount: None }),
+            r#"{"type":"dispute","client":1,"tx":2}"#,
+        );
+        round_trip(
+            &tx(TransactionKind::Dispute {
+                amount: Some(Decimal::ONE),
+            }),
+            r#"{"type":"dispute","amount":"1.0000","client":1,"tx":2}"#,
+        );
+        round_trip(
+            &tx(TransactionKind::Chargeback),
+            r#"{"type":"chargeback","client":1,"tx":2}"#,
+        );
+        round_trip(
+            &Transaction {
+                kind: TransactionKind::Withdrawal {
+                    amount: Money::<4>(5),
+                },
+                client_id: 1,
+                transaction_id: 2,
+                currency: Some("EUR".to_string()),
+            },
+            r#"{"type":"withdrawal","amount":"0.0005","client":1,"tx":2,"currency":"EUR"}"#,
+        );
+    }
+
+    #[test]
+    fn every_kind_is_tagged_with_its_name() {
+        let amount = Decimal::ONE;
+        for kind in [
+            TransactionKind::Deposit { amount },
+            TransactionKind::Withdrawal { amount },
+            TransactionKind::Dispute { amount: None },
+            TransactionKind::Resolve,
+            TransactionKind::Chargeback,
+            TransactionKind::Freeze,
+            TransactionKind::Unfreeze,
+            TransactionKind::Hold { amount },
+            TransactionKind::Release { amount },
+        ] {
+            let json = serde_json::to_value(kind).unwrap();
+            assert_eq!(json["type"], kind.name());
+            assert_eq!(
+                serde_json::from_value::<TransactionKind>(json).unwrap(),
+                kind
+            );
+        }
+        assert!(serde_json::from_str::<TransactionKind>(r#"{"type":"refund"}"#).is_err());
+    }
+}
@@ -1,20 +1,113 @@
+use crate::audit::NoopAuditSink;
+use crate::checkpoint::Checkpoints;
+use crate::dlq::NoopDLQ;
 use crate::domain::{
-    Error, Transaction, TransactionKind,
-    traits::{DeadLetterQueue, OutputRepository, TransactionStream},
+    Account, AccountSnapshot, Amount, BalanceEvent, ClientId, EngineError, Error, Transaction,
+    TransactionKind,
+    traits::{
+        AsyncDeadLetterQueue, AuditSink, DeadLetterQueue, OutputRepository, TransactionStream,
+        Validator,
+    },
 };
+use crate::metrics::Metrics;
+use crate::output_repository::{MemoryOutput, WriterOutput};
+use crate::policy::Policy;
+use crate::retention::{DisputeWindow, RecentTransactions};
+use crate::retry::RetryBuffer;
+use crate::summary::Summary;
+use crate::validation::ValidatorChain;
 
-use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream, StreamExt};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A way in which an account disagrees with itself or with the ledger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// `total` is not `available + held`.
+    Total {
+        client_id: ClientId,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    },
+    /// `held` is not the sum of the client's open disputes and manual holds. Held funds
+    /// restored from a snapshot without their ledger show up here too.
+    Held {
+        client_id: ClientId,
+        held: Decimal,
+        disputed: Decimal,
+        on_hold: Decimal,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::Total {
+                client_id,
+                available,
+                held,
+                total,
+            } => write!(
+                f,
+                "Client {}: total {} does not equal available {} + held {}",
+                client_id, total, available, held
+            ),
+            Inconsistency::Held {
+                client_id,
+                held,
+                disputed,
+                on_hold,
+            } => {
+                write!(
+                    f,
+                    "Client {}: held {} does not equal open disputes {}",
+                    client_id, held, disputed
+                )?;
+                if !on_hold.is_zero() {
+                    write!(f, " + manual holds {}", on_hold)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct Engine<I, O, D>
+pub struct Engine<I, O, D, A = NoopAuditSink>
 where
     I: TransactionStream,
     O: OutputRepository,
     D: DeadLetterQueue,
+    A: AuditSink,
 {
     ingestion: I,
     output_repository: O,
     dlq: D,
+    // replaces `dlq` when set
+    async_dlq: Option<AsyncReports>,
+    audit: A,
+    policy: Policy,
+    validators: ValidatorChain<O::Amount>,
+    retry: Option<RetryBuffer>,
+    tally: Tally,
+    metrics: Arc<Metrics>,
+    high_water_mark: Option<u32>,
+    flow: FundsFlow,
+    window: Option<DisputeWindow>,
+    // recent transactions per client, for `Policy::withdrawal_dispute_window`
+    recent: Option<RecentTransactions>,
+    fail_fast: bool,
+    // what stops the run, until `process` returns it: the rejection a fail-fast run
+    // stops at, or a checkpoint that could not be written
+    failed: Option<Error>,
+    touched_clients: bool,
+    checkpoints: Option<Checkpoints>,
 }
 
 impl<I, O, D> Engine<I, O, D>
@@ -23,342 +116,4059 @@ where
     O: OutputRepository,
     D: DeadLetterQueue,
 {
+    /// Balances already in `output_repository`, e.g. one reopened from a database,
+    /// count as restored for `conservation_check`.
     pub fn new(ingestion: I, output_repository: O, dlq: D) -> Self {
+        let mut flow = FundsFlow::default();
+        for (_, account) in output_repository.accounts() {
+            flow.add(account.total.to_decimal());
+        }
         Self {
             ingestion,
             output_repository,
             dlq,
+            async_dlq: None,
+            audit: NoopAuditSink,
+            policy: Policy::default(),
+            validators: ValidatorChain::default(),
+            retry: None,
+            tally: Tally::default(),
+            metrics: Arc::default(),
+            high_water_mark: None,
+            flow,
+            window: None,
+            recent: None,
+            fail_fast: false,
+            failed: None,
+            touched_clients: false,
+            checkpoints: None,
+        }
+    }
+}
+
+impl<I, O, D, A> Engine<I, O, D, A>
+where
+    I: TransactionStream,
+    O: OutputRepository,
+    D: DeadLetterQueue,
+    A: AuditSink,
+{
+    pub fn with_audit_sink<S: AuditSink>(self, audit: S) -> Engine<I, O, D, S> {
+        Engine {
+            ingestion: self.ingestion,
+            output_repository: self.output_repository,
+            dlq: self.dlq,
+            async_dlq: self.async_dlq,
+            audit,
+            policy: self.policy,
+            validators: self.validators,
+            retry: self.retry,
+            tally: self.tally,
+            metrics: self.metrics,
+            high_water_mark: self.high_water_mark,
+            flow: self.flow,
+            window: self.window,
+            recent: self.recent,
+            fail_fast: self.fail_fast,
+            failed: self.failed,
+            touched_clients: self.touched_clients,
+            checkpoints: self.checkpoints,
         }
     }
 
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.window = policy.dispute_window.map(DisputeWindow::new);
+        self.recent = policy
+            .withdrawal_dispute_window
+            .map(RecentTransactions::new);
+        self.policy = policy;
+        self
+    }
+
+    /// Adds a validator after any already registered.
+    pub fn with_validator<V: Validator<O::Amount> + 'static>(mut self, validator: V) -> Self {
+        self.validators.push(validator);
+        self
+    }
+
+    /// Parks transactions that reference a not yet seen tx id instead of rejecting them
+    /// right away. Whatever is still parked when the input ends goes to the DLQ.
+    pub fn with_retry_buffer(mut self, retry: RetryBuffer) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Reports rejections to `dlq` instead of the synchronous DLQ. `process` awaits
+    /// each report before reading on; `process_all` blocks on them, so it must not be
+    /// called on a runtime thread that `dlq` depends on.
+    pub fn with_async_dlq<Q: AsyncDeadLetterQueue + 'static>(mut self, dlq: Q) -> Self {
+        self.async_dlq = Some(AsyncReports {
+            dlq: Box::new(dlq),
+            pending: Vec::new(),
+        });
+        self
+    }
+
+    /// Stops processing at the first rejection, which `process` then returns wrapped in
+    /// `Error::FailedFast` instead of reporting it to the DLQ. Everything applied before
+    /// it is kept; nothing after it is read.
+    pub fn with_fail_fast(mut self) -> Self {
+        self.fail_fast = true;
+        self
+    }
+
+    /// Gives every client a rejected transaction names an account, so it shows up in the
+    /// output even if nothing of it was ever applied, e.g. when its deposit was dropped
+    /// upstream. Rows that could not be read name no client and are not covered, and
+    /// neither are clients turned away by `Policy::max_clients`.
+    pub fn with_emit_touched_clients(mut self) -> Self {
+        self.touched_clients = true;
+        self
+    }
+
+    /// Writes the balances to a checkpoint file every so many transactions read, see
+    /// `Checkpoints`. A checkpoint that cannot be written stops processing with the
+    /// error.
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    #[tracing::instrument(skip_all)]
     pub async fn process(&mut self) -> Result<(), Error> {
-        let mut res = self.ingestion.stream();
+        let mut res = self.ingestion.stream()?;
 
         while let Some(tx) = res.next().await {
-            match tx {
-                Ok(tx) => match self.apply_transaction(tx) {
-                    Ok(()) => {}
-                    Err(e) => self.dlq.report(&e),
-                },
-                Err(e) => self.dlq.report(&e),
-            }
+            self.handle(tx);
+            self.deliver_reports().await;
+            self.check_failed()?;
         }
 
-        Ok(())
+        self.finish();
+        self.deliver_reports().await;
+        self.check_failed()
     }
 
-    fn apply_transaction(&mut self, tx: Transaction) -> Result<(), Error> {
-        {
-            let account = self.output_repository.get_or_create_account(&tx.client_id);
+    /// Like `process`, but flushes the output each time `flushes` yields, e.g. on a
+    /// control command or a timer, so long-running streams expose their state.
+    #[tracing::instrument(skip_all)]
+    pub async fn process_with_flushes<F>(&mut self, flushes: F) -> Result<(), Error>
+    where
+        F: Stream<Item = ()> + Unpin,
+    {
+        let mut res = self.ingestion.stream()?;
+        let mut flushes = flushes.fuse();
 
-            if account.locked {
-                return Err(Error::Engine(
-                    tx.client_id.to_string() + " account is locked",
-                ));
+        loop {
+            tokio::select! {
+                // Pending flushes go first so they observe every transaction read before them.
+                biased;
+                Some(()) = flushes.next() => {
+                    tracing::debug!("flush requested");
+                    self.flush()?
+                }
+                tx = res.next() => match tx {
+                    Some(tx) => {
+                        self.handle(tx);
+                        self.deliver_reports().await;
+                        self.check_failed()?;
+                    }
+                    None => break,
+                },
             }
         }
 
-        match tx.kind {
-            TransactionKind::Deposit { amount } => self.deposit(&tx, amount),
-            TransactionKind::Withdrawal { amount } => self.withraw(&tx, amount),
-            TransactionKind::Dispute => self.dispute(&tx),
-            TransactionKind::Resolve => self.resolve(&tx),
-            TransactionKind::Chargeback => self.chargeback(tx),
+        self.finish();
+        self.deliver_reports().await;
+        self.check_failed()
+    }
+
+    /// Runs `process` but gives up once `limit` has elapsed, e.g. when the input
+    /// stream stalls. State applied so far is kept, so the caller can still flush it.
+    pub async fn process_with_timeout(&mut self, limit: Duration) -> Result<(), Error> {
+        match tokio::time::timeout(limit, self.process()).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::Timeout(limit)),
         }
     }
 
-    fn deposit(&mut self, tx: &Transaction, amount: rust_decimal::Decimal) -> Result<(), Error> {
-        match self
-            .output_repository
-            .report_transaction(&tx.transaction_id, tx)
-        {
-            Ok(_) => {
-                let account = self.output_repository.get_or_create_account(&tx.client_id);
-                account.available += amount;
+    /// Drops all accounts, ledger entries and parked transactions so the engine can
+    /// start over on new input. Metrics keep counting across resets.
+    pub fn reset(&mut self) {
+        self.output_repository.clear();
+        if let Some(retry) = self.retry.as_mut() {
+            retry.drain();
+        }
+        self.tally = Tally::default();
+        self.high_water_mark = None;
+        self.flow = FundsFlow::default();
+        if let Some(window) = self.window.as_mut() {
+            window.clear();
+        }
+        if let Some(recent) = self.recent.as_mut() {
+            recent.clear();
+        }
+    }
+
+    /// Swaps in the ingestion for the next `process` run, returning the old one.
+    pub fn replace_ingestion(&mut self, ingestion: I) -> I {
+        std::mem::replace(&mut self.ingestion, ingestion)
+    }
+
+    /// Seeds accounts, e.g. from a snapshot. Accounts whose total does not match
+    /// `available + held` are rejected, or recomputed from those when `repair` is set.
+    /// `repair` also backfills `held` from the ledger, see `backfill_held`, for clients
+    /// the ledger has transactions of.
+    /// Nothing is restored if any account is rejected.
+    pub fn restore<T>(&mut self, accounts: T, repair: bool) -> Result<(), Error>
+    where
+        T: IntoIterator<Item = (ClientId, Account<O::Amount>)>,
+    {
+        let mut accounts: Vec<_> = accounts.into_iter().collect();
+        for (client_id, account) in accounts.iter_mut() {
+            if account.validate().is_err() && repair {
                 account.sync_total();
-                Ok(())
             }
-            Err(e) => Err(e),
+            account.validate()?;
+            if repair {
+                self.backfill_held(*client_id, account)?;
+            }
         }
-    }
 
-    fn withraw(&mut self, tx: &Transaction, amount: rust_decimal::Decimal) -> Result<(), Error> {
-        match self
-            .output_repository
-            .report_transaction(&tx.transaction_id, tx)
-        {
-            Ok(_) => {
-                let account = self.output_repository.get_or_create_account(&tx.client_id);
+        for (client_id, account) in accounts {
+            // restored funds arrived from outside just like deposits
+            self.flow.add(account.total.to_decimal());
+            self.output_repository.restore_account(client_id, account);
+        }
+        Ok(())
+    }
 
-                if account.available < amount {
-                    return Err(Error::Engine(
-                        format!("Insufficient funds for client {}", tx.client_id).to_owned(),
-                    ));
-                }
+    /// Recomputes `held` as the client's open disputes in the ledger plus its manual
+    /// holds, for snapshots written before disputes were tracked. The total is kept, so
+    /// whatever `held` gains or loses comes out of or goes back to `available`. A client
+    /// the ledger knows nothing of, e.g. when no ledger was loaded at all, keeps the
+    /// snapshot's `held`: no open disputes there is no evidence the funds are free.
+    fn backfill_held(
+        &self,
+        client_id: ClientId,
+        account: &mut Account<O::Amount>,
+    ) -> Result<(), Error> {
+        let repo = &self.output_repository;
+        let overflow = || Error::from(EngineError::BalanceOverflow(client_id));
+        let transactions = repo.client_transactions(client_id);
+        if transactions.is_empty() {
+            return Ok(());
+        }
+        let held = transactions
+            .into_iter()
+            .filter_map(|tx| repo.disputed_amount(client_id, tx.transaction_id))
+            .try_fold(account.on_hold, Amount::checked_add)
+            .ok_or_else(overflow)?;
+        if held == account.held {
+            return Ok(());
+        }
 
-                account.available -= amount;
-                account.sync_total();
+        let available = account.total.checked_sub(held).ok_or_else(overflow)?;
+        tracing::warn!(
+            client = client_id,
+            from = %account.held,
+            to = %held,
+            "held backfilled from the ledger"
+        );
+        account.available = available;
+        account.held = held;
+        Ok(())
+    }
 
-                Ok(())
-            }
-            Err(e) => Err(e),
+    /// Synchronous counterpart of `process` for callers that already hold the
+    /// transactions in memory and have no async runtime at hand.
+    #[tracing::instrument(skip_all)]
+    pub fn process_all<T>(&mut self, txs: T) -> Result<(), Error>
+    where
+        T: IntoIterator<Item = Result<Transaction, Error>>,
+    {
+        for tx in txs {
+            self.handle(tx);
+            futures::executor::block_on(self.deliver_reports());
+            self.check_failed()?;
         }
+
+        self.finish();
+        futures::executor::block_on(self.deliver_reports());
+        self.check_failed()
     }
 
-    fn dispute(&mut self, tx: &Transaction) -> Result<(), Error> {
-        let disputed_tx = self
+    /// Applies a single transaction and returns its client's balances afterwards, e.g.
+    /// to test a rule in isolation. A rejection is returned rather than reported to the
+    /// DLQ, and nothing is parked for a retry or counted in the run's tally.
+    pub fn apply_and_get(&mut self, tx: Transaction) -> Result<AccountSnapshot<O::Amount>, Error> {
+        self.apply_transaction(&tx)?;
+        Ok(self
             .output_repository
-            .get_transaction(tx.transaction_id)
-            .ok_or_else(|| Error::Engine("Referenced transaction not found".to_string()))?;
+            .get_or_create_account(&tx.client_id)?
+            .snapshot())
+    }
 
-        if disputed_tx.client_id != tx.client_id {
-            return Err(Error::Engine(
-                "Transaction client ID does not match dispute client ID".to_string(),
-            ));
+    /// Applies `tx`, parks it for a retry, or reports it to the DLQ.
+    fn handle(&mut self, tx: Result<Transaction, Error>) {
+        match tx {
+            Ok(tx) => self.attempt(tx, 0),
+            Err(e) => self.reject(None, e),
         }
-
-        if let TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount } =
-            disputed_tx.kind
+        if let Some(checkpoints) = self.checkpoints.as_mut()
+            && checkpoints.tick()
+            && let Err(e) = checkpoints.write(&self.output_repository.accounts())
         {
-            {
-                self.output_repository
-                    .mark_transaction_disputed(tx.transaction_id);
+            self.failed.get_or_insert(e.into());
+        }
+    }
+
+    fn attempt(&mut self, tx: Transaction, attempts: u32) {
+        let _span = tracing::debug_span!(
+            "transaction",
+            client = tx.client_id,
+            tx = tx.transaction_id,
+            kind = tx.kind.name()
+        )
+        .entered();
+
+        match self.apply_transaction(&tx) {
+            Ok(()) => {
+                tracing::debug!("transaction applied");
+                self.tally.applied += 1;
+                self.metrics.record_processed(tx.kind.name());
+                if let TransactionKind::Deposit { .. } | TransactionKind::Withdrawal { .. } =
+                    tx.kind
+                {
+                    self.replay_parked(tx.client_id, tx.transaction_id);
+                }
+            }
+            Err(e @ Error::Engine(EngineError::UnknownReference(_))) => {
+                let tx = match self.retry.as_mut() {
+                    Some(retry) => match retry.park(tx, attempts) {
+                        Ok(()) => {
+                            tracing::debug!("transaction parked until its reference appears");
+                            return;
+                        }
+                        Err(tx) => tx,
+                    },
+                    None => tx,
+                };
+                self.reject(Some(&tx), e);
             }
-            let account = self.output_repository.get_or_create_account(&tx.client_id);
-            account.available -= amount;
-            account.held += amount;
+            Err(e) => self.reject(Some(&tx), e),
         }
+    }
 
-        Ok(())
+    /// Replays whatever was waiting on `transaction_id`, now that it has been recorded.
+    fn replay_parked(&mut self, client_id: ClientId, transaction_id: u32) {
+        let parked = match self.retry.as_mut() {
+            Some(retry) => retry.take(client_id, transaction_id),
+            None => return,
+        };
+        for p in parked {
+            self.attempt(p.transaction, p.attempts + 1);
+        }
     }
 
-    fn resolve(&mut self, tx: &Transaction) -> Result<(), Error> {
+    fn reject(&mut self, tx: Option<&Transaction>, error: Error) {
+        tracing::debug!(%error, "transaction rejected");
+        self.tally.rejected += 1;
+        self.metrics.record_rejected(tx.map(|tx| tx.kind.name()));
+        // a client turned away for want of room gets no account this way either
+        if let (true, Some(tx)) = (self.touched_clients, tx)
+            && !matches!(error, Error::Engine(EngineError::TooManyClients(_)))
         {
-            if !self.output_repository.has_dispute(tx.transaction_id) {
-                return Err(Error::Engine("Transaction is not disputed".to_string()));
-            }
+            // one that cannot be read back exists already, so there is nothing to open
+            let _ = self.output_repository.get_or_create_account(&tx.client_id);
+        }
+        // later rejections in the same step, e.g. of replayed parked transactions, are
+        // reported as usual
+        if self.fail_fast && self.failed.is_none() {
+            let row = match tx {
+                Some(tx) => format!(
+                    "{} of client {} tx {}",
+                    tx.kind.name(),
+                    tx.client_id,
+                    tx.transaction_id
+                ),
+                None => "unreadable row".to_string(),
+            };
+            self.failed = Some(Error::FailedFast {
+                row,
+                source: Box::new(error),
+            });
+            return;
         }
+        match (&mut self.async_dlq, tx) {
+            (Some(reports), tx) => reports.pending.push((tx.cloned(), error)),
+            (None, Some(tx)) => self.dlq.report_rejected(tx, &error),
+            (None, None) => self.dlq.report(&error),
+        }
+    }
 
-        let resolved_tx = self
-            .output_repository
-            .get_transaction(tx.transaction_id)
-            .ok_or_else(|| Error::Engine("Referenced transaction not found".to_string()))?;
+    /// Returns the rejection a fail-fast run stopped at, if any.
+    fn check_failed(&mut self) -> Result<(), Error> {
+        match self.failed.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 
-        if resolved_tx.client_id != tx.client_id {
-            return Err(Error::Engine(
-                "Transaction client ID does not match resolve client ID".to_string(),
-            ));
+    /// Hands the rejections queued for the async DLQ to it, in order.
+    async fn deliver_reports(&mut self) {
+        let Some(reports) = self.async_dlq.as_mut() else {
+            return;
+        };
+        for (tx, error) in std::mem::take(&mut reports.pending) {
+            reports.dlq.report(tx.as_ref(), &error).await;
         }
+    }
 
-        if let TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount } =
-            resolved_tx.kind
-        {
-            {
-                self.output_repository
-                    .mark_transaction_resolved(tx.transaction_id);
+    /// Ends a processing run: anything still parked can no longer be resolved.
+    fn finish(&mut self) {
+        let parked = self.retry.as_mut().map(RetryBuffer::drain);
+        for p in parked.into_iter().flatten() {
+            let error = EngineError::UnknownReference(p.transaction.transaction_id).into();
+            self.reject(Some(&p.transaction), error);
+        }
+        std::mem::take(&mut self.tally).log(self.high_water_mark);
+    }
+
+    fn apply_transaction(&mut self, input: &Transaction) -> Result<(), Error> {
+        // Converted once up front; the DLQ, retry buffer and audit keep the input as read.
+        let tx = &input.try_map(O::Amount::from_decimal).ok_or_else(|| {
+            EngineError::UnrepresentableAmount {
+                transaction_id: input.transaction_id,
+                amount: input.kind.amount().unwrap_or_default(),
+            }
+        })?;
+        self.validators.validate(tx, &self.output_repository)?;
+
+        // Worked out from the ledger alone, so the account is only looked up once below.
+        let change = match tx.kind {
+            TransactionKind::Deposit { .. } | TransactionKind::Withdrawal { .. } => {
+                self.transfer(tx)
             }
+            TransactionKind::Dispute { amount } => self.dispute(tx, amount),
+            TransactionKind::Resolve => self.resolve(tx),
+            TransactionKind::Chargeback => self.chargeback(tx),
+            TransactionKind::Freeze => Ok(Change::Freeze),
+            TransactionKind::Unfreeze => Ok(Change::Unfreeze),
+            TransactionKind::Hold { amount } => Ok(Change::Hold(amount)),
+            TransactionKind::Release { amount } => Ok(Change::Release(amount)),
+        };
 
-            let account = self.output_repository.get_or_create_account(&tx.client_id);
-            account.available += amount;
-            account.held -= amount;
+        if let Some(max) = self.policy.max_clients
+            && self.output_repository.get_account(tx.client_id).is_none()
+            && self.output_repository.account_count() >= max
+        {
+            return Err(EngineError::TooManyClients(tx.client_id).into());
         }
-        Ok(())
-    }
+        // A rejection opens no account, see `with_emit_touched_clients`. A new account
+        // can be neither locked nor tied to a currency, so trying the change on a blank
+        // one tells the outcome.
+        let change = if self.output_repository.get_account(tx.client_id).is_none() {
+            let change = change?;
+            change.apply(&mut Account::new(), tx.client_id, &self.policy)?;
+            Ok(change)
+        } else {
+            change
+        };
+        let account = self
+            .output_repository
+            .get_or_create_account(&tx.client_id)?;
 
-    fn chargeback(&mut self, tx: Transaction) -> Result<(), Error> {
+        // An unfreeze is the only way back from a locked account. Disputes already open
+        // when a chargeback locked it still get to settle; a freeze stops those too.
+        let settles_dispute = matches!(
+            tx.kind,
+            TransactionKind::Resolve | TransactionKind::Chargeback
+        ) && !account.frozen;
+        if account.locked && tx.kind != TransactionKind::Unfreeze && !settles_dispute {
+            return Err(EngineError::AccountLocked(tx.client_id).into());
+        }
+        if let (Some(expected), Some(found)) = (&account.currency, &tx.currency)
+            && expected != found
         {
-            if !self.output_repository.has_dispute(tx.transaction_id) {
-                return Err(Error::Engine("Transaction is not disputed".to_string()));
+            return Err(EngineError::CurrencyMismatch {
+                client_id: tx.client_id,
+                expected: expected.clone(),
+                found: found.clone(),
             }
+            .into());
         }
+        let change = change?;
 
-        let chargeback_tx = self
-            .output_repository
-            .get_transaction(tx.transaction_id)
-            .ok_or_else(|| Error::Engine("Referenced transaction not found".to_string()))?;
+        let before = account.snapshot();
+        let applied = change.apply(account, tx.client_id, &self.policy);
+        if applied.is_ok() && account.currency.is_none() {
+            account.currency = tx.currency.clone();
+        }
+        let after = account.snapshot();
 
-        if chargeback_tx.client_id != tx.client_id {
-            return Err(Error::Engine(
-                "Transaction client ID does not match resolve client ID".to_string(),
-            ));
+        // Only recorded once applied, so a bounced withdrawal leaves its id free for a retry.
+        applied?;
+        self.record(tx, &change)?;
+
+        if after.locked && !before.locked {
+            self.metrics.record_locked();
+        }
+        match change {
+            Change::Transfer(delta) => {
+                self.flow.add(delta.to_decimal());
+                self.high_water_mark = self.high_water_mark.max(Some(tx.transaction_id));
+            }
+            Change::Chargeback(delta) => self.flow.charge_back(delta.to_decimal()),
+            _ => {}
+        }
+        if after != before {
+            self.output_repository
+                .emit_account_change(tx.client_id, &after);
         }
+        self.audit.record(&BalanceEvent {
+            client_id: tx.client_id,
+            transaction_id: tx.transaction_id,
+            kind: input.kind,
+            before: before.to_decimal(),
+            after: after.to_decimal(),
+        });
+        Ok(())
+    }
 
-        if let TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount } =
-            chargeback_tx.kind
-        {
-            // (Only if orig_tx was under dispute)
-            let account = self.output_repository.get_or_create_account(&tx.client_id);
-            account.available += amount;
-            account.held -= amount;
-            account.locked = true;
+    /// Writes the ledger side of `change`. Duplicate ids were already turned away while
+    /// planning, so this cannot fail after the account has been updated.
+    fn record(
+        &mut self,
+        tx: &Transaction<O::Amount>,
+        change: &Change<O::Amount>,
+    ) -> Result<(), Error> {
+        let repo = &mut self.output_repository;
+        let key = (tx.client_id, tx.transaction_id);
+        match change {
+            Change::Transfer(_) => {
+                repo.report_transaction(&tx.transaction_id, tx)?;
+                if let Some(recent) = self.recent.as_mut() {
+                    recent.record(key);
+                }
+                if let Some(window) = self.window.as_mut() {
+                    let expired =
+                        window.record(key, |(client_id, id)| repo.has_dispute(client_id, id));
+                    for (client_id, id) in expired {
+                        repo.forget_transaction(client_id, id);
+                    }
+                }
+            }
+            Change::Dispute(delta) => {
+                repo.mark_transaction_disputed(tx.client_id, tx.transaction_id, *delta)
+            }
+            // A chargeback closes the dispute just like a resolve does.
+            Change::Resolve(_) | Change::Chargeback(_) => {
+                repo.mark_transaction_resolved(tx.client_id, tx.transaction_id);
+                if let Some(window) = self.window.as_mut()
+                    && window.settle(key)
+                {
+                    repo.forget_transaction(tx.client_id, tx.transaction_id);
+                }
+            }
+            Change::Freeze
+            | Change::Unfreeze
+            | Change::Hold(_)
+            | Change::Release(_)
+            | Change::Nothing => {}
         }
         Ok(())
     }
 
-    pub fn flush(&mut self) {
-        self.output_repository.flush();
+    /// A deposit or withdrawal, as the signed amount it moves.
+    fn transfer(&self, tx: &Transaction<O::Amount>) -> Result<Change<O::Amount>, Error> {
+        let key = (tx.client_id, tx.transaction_id);
+        let forgotten = self
+            .window
+            .as_ref()
+            .is_some_and(|window| window.forgot(key));
+        if forgotten
+            || self
+                .output_repository
+                .get_transaction(tx.client_id, tx.transaction_id)
+                .is_some()
+        {
+            return Err(EngineError::DuplicateTransaction(tx.transaction_id).into());
+        }
+        Ok(tx.kind.delta().map_or(Change::Nothing, Change::Transfer))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::output_repository::StdOutOutput;
-    use futures::stream::{self, Stream};
-    use rust_decimal::Decimal;
-    use std::pin::Pin;
+    /// Kind of the transaction `tx` refers to, copied out of the ledger so the account
+    /// can be mutated afterwards. Only the client's own transactions can be referenced.
+    fn referenced_kind(
+        &self,
+        tx: &Transaction<O::Amount>,
+    ) -> Result<TransactionKind<O::Amount>, Error> {
+        self.output_repository
+            .get_transaction(tx.client_id, tx.transaction_id)
+            .map(|referenced| referenced.kind)
+            .ok_or_else(|| EngineError::UnknownReference(tx.transaction_id).into())
+    }
 
-    #[derive(Debug, Default)]
-    struct NoopIngestion;
+    /// Disputes `amount` of the referenced transaction, or all of it.
+    fn dispute(
+        &self,
+        tx: &Transaction<O::Amount>,
+        amount: Option<O::Amount>,
+    ) -> Result<Change<O::Amount>, Error> {
+        let kind = self.referenced_kind(tx)?;
 
-    impl TransactionStream for NoopIngestion {
-        type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
-        fn stream(&mut self) -> Self::TxStream {
-            Box::pin(stream::iter(Vec::<Result<Transaction, Error>>::new()))
+        // One dispute at a time: its resolve or chargeback settles what it holds.
+        if self
+            .output_repository
+            .has_dispute(tx.client_id, tx.transaction_id)
+        {
+            return Err(EngineError::AlreadyDisputed(tx.transaction_id).into());
+        }
+
+        if matches!(kind, TransactionKind::Withdrawal { .. })
+            && !self.policy.allow_withdrawal_disputes
+        {
+            return Err(EngineError::WithdrawalDisputeNotAllowed(tx.transaction_id).into());
         }
-    }
 
-    #[derive(Default, Debug)]
-    struct NoopDLQ;
+        if matches!(kind, TransactionKind::Withdrawal { .. })
+            && let Some(recent) = &self.recent
+            && !recent.contains((tx.client_id, tx.transaction_id))
+        {
+            return Err(EngineError::OutsideDisputeWindow(tx.transaction_id).into());
+        }
+
+        if let Some(max) = self.policy.max_open_disputes
+            && self.output_repository.open_disputes(tx.client_id) >= max
+        {
+            return Err(EngineError::TooManyOpenDisputes(tx.client_id).into());
+        }
 
-    impl DeadLetterQueue for NoopDLQ {
-        fn report(&self, _error: &Error) {}
+        let Some(delta) = kind.delta() else {
+            return Ok(Change::Nothing);
+        };
+        Ok(Change::Dispute(match amount {
+            None => delta,
+            Some(amount)
+                if amount.compare(&O::Amount::zero()).is_gt()
+                    && amount.compare(&delta.abs()).is_le() =>
+            {
+                if delta.is_negative() {
+                    amount.neg()
+                } else {
+                    amount
+                }
+            }
+            Some(_) => {
+                return Err(EngineError::InvalidDisputeAmount(tx.transaction_id).into());
+            }
+        }))
     }
 
-    fn mk_engine() -> Engine<NoopIngestion, StdOutOutput, NoopDLQ> {
-        Engine::new(NoopIngestion, StdOutOutput::new(), NoopDLQ)
+    /// Signed amount held by the dispute that `tx` settles.
+    fn disputed(&self, tx: &Transaction<O::Amount>) -> Result<O::Amount, Error> {
+        // An unknown tx id is "not found", not "not disputed".
+        self.referenced_kind(tx)?;
+        self.output_repository
+            .disputed_amount(tx.client_id, tx.transaction_id)
+            .ok_or_else(|| EngineError::NotDisputed(tx.transaction_id).into())
     }
 
-    #[test]
-    fn deposit_increases_available_and_total() {
-        let mut engine = mk_engine();
-        let tx = Transaction {
-            kind: TransactionKind::Deposit {
-                amount: Decimal::from(100u32),
-            },
-            client_id: 1,
-            transaction_id: 1,
-        };
+    fn resolve(&self, tx: &Transaction<O::Amount>) -> Result<Change<O::Amount>, Error> {
+        self.disputed(tx).map(Change::Resolve)
+    }
 
-        engine
-            .deposit(&tx, Decimal::from(100u32))
-            .expect("deposit ok");
+    fn chargeback(&self, tx: &Transaction<O::Amount>) -> Result<Change<O::Amount>, Error> {
+        self.disputed(tx).map(Change::Chargeback)
+    }
 
-        let acct = engine.output_repository.get_or_create_account(&1);
-        assert_eq!(acct.available, Decimal::from(100u32));
-        assert_eq!(acct.held, Decimal::from(0u32));
-        assert_eq!(acct.total, Decimal::from(100u32));
-        assert!(!acct.locked);
+    /// Writes the output, then fails if `conservation_check` does, so a bug that made
+    /// or lost money does not go unnoticed.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.output_repository.flush()?;
+        self.conservation_check()
     }
 
-    #[test]
-    fn withdrawal_with_insufficient_funds_errors_and_does_not_change_balances() {
-        let mut engine = mk_engine();
-        let tx = Transaction {
-            kind: TransactionKind::Withdrawal {
-                amount: Decimal::from(50u32),
-            },
-            client_id: 1,
-            transaction_id: 2,
+    /// Checks that the accounts hold exactly what came in: deposits and restored
+    /// balances, less withdrawals and chargebacks. Nothing else may create or destroy
+    /// money.
+    ///
+    /// Passes without checking once the sums no longer fit in a `Decimal`.
+    pub fn conservation_check(&self) -> Result<(), Error> {
+        let expected = match self.flow.net.checked_sub(self.flow.charged_back) {
+            Some(expected) if !self.flow.overflowed => expected,
+            _ => return Ok(()),
+        };
+        let actual = self
+            .output_repository
+            .accounts()
+            .into_iter()
+            .try_fold(Decimal::ZERO, |sum, (_, account)| {
+                sum.checked_add(account.total.to_decimal())
+            });
+        let Some(actual) = actual else {
+            return Ok(());
         };
+        if actual != expected {
+            return Err(EngineError::FundsNotConserved { expected, actual }.into());
+        }
+        Ok(())
+    }
 
-        let res = engine.withraw(&tx, Decimal::from(50u32));
-        assert!(res.is_err());
+    /// Cross-checks every account against itself and the ledger, returning all offenders.
+    pub fn verify_invariants(&self) -> Result<(), Vec<Inconsistency>> {
+        let repo = &self.output_repository;
+        let mut offenders = Vec::new();
 
-        let acct = engine.output_repository.get_or_create_account(&1);
-        assert_eq!(acct.available, Decimal::from(0u32));
-        assert_eq!(acct.held, Decimal::from(0u32));
-        assert_eq!(acct.total, Decimal::from(0u32));
-    }
+        for (client_id, account) in repo.accounts() {
+            let account = account.to_decimal();
+            if account.available + account.held != account.total {
+                offenders.push(Inconsistency::Total {
+                    client_id,
+                    available: account.available,
+                    held: account.held,
+                    total: account.total,
+                });
+            }
 
-    #[test]
-    fn dispute_moves_available_to_held_and_marks_disputed() {
-        let mut engine = mk_engine();
-        let dep = Transaction {
-            kind: TransactionKind::Deposit {
-                amount: Decimal::from(75u32),
-            },
-            client_id: 1,
-            transaction_id: 10,
-        };
-        engine.deposit(&dep, Decimal::from(75u32)).unwrap();
+            let disputed: Decimal = repo
+                .client_transactions(client_id)
+                .into_iter()
+                .filter_map(|tx| repo.disputed_amount(client_id, tx.transaction_id))
+                .map(Amount::to_decimal)
+                .sum();
+            if disputed + account.on_hold != account.held {
+                offenders.push(Inconsistency::Held {
+                    client_id,
+                    held: account.held,
+                    disputed,
+                    on_hold: account.on_hold,
+                });
+            }
+        }
 
-        let dispute = Transaction {
-            kind: TransactionKind::Dispute,
-            client_id: 1,
-            transaction_id: 10,
-        };
-        engine.dispute(&dispute).expect("dispute ok");
+        if offenders.is_empty() {
+            Ok(())
+        } else {
+            Err(offenders)
+        }
+    }
 
-        let acct = engine.output_repository.get_or_create_account(&1);
-        assert_eq!(acct.available, Decimal::from(0u32));
-        assert_eq!(acct.held, Decimal::from(75u32));
-        assert_eq!(acct.available + acct.held, acct.total);
-        assert!(engine.output_repository.has_dispute(10));
+    /// Disputes still open, e.g. at the end of the input, as `(client_id, tx id, amount)`
+    /// sorted by client and tx id. A disputed withdrawal holds a negative amount.
+    pub fn open_disputes(&self) -> Vec<(ClientId, u32, O::Amount)> {
+        let mut disputes = self.output_repository.disputes();
+        disputes.sort_by_key(|&(client_id, transaction_id, _)| (client_id, transaction_id));
+        disputes
     }
 
-    #[test]
-    fn resolve_moves_held_back_and_clears_dispute() {
-        let mut engine = mk_engine();
-        let dep = Transaction {
-            kind: TransactionKind::Deposit {
-                amount: Decimal::from(40u32),
-            },
-            client_id: 2,
-            transaction_id: 20,
-        };
-        engine.deposit(&dep, Decimal::from(40u32)).unwrap();
-        let dispute = Transaction {
-            kind: TransactionKind::Dispute,
-            client_id: 2,
-            transaction_id: 20,
-        };
-        engine.dispute(&dispute).unwrap();
+    pub fn ingestion(&self) -> &I {
+        &self.ingestion
+    }
 
-        let resolve = Transaction {
-            kind: TransactionKind::Resolve,
-            client_id: 2,
-            transaction_id: 20,
-        };
-        engine.resolve(&resolve).expect("resolve ok");
+    pub fn output_repository(&self) -> &O {
+        &self.output_repository
+    }
 
-        let acct = engine.output_repository.get_or_create_account(&2);
-        assert_eq!(acct.available, Decimal::from(40u32));
-        assert_eq!(acct.held, Decimal::from(0u32));
-        assert_eq!(acct.available + acct.held, acct.total);
-        assert!(!engine.output_repository.has_dispute(20));
+    pub fn output_repository_mut(&mut self) -> &mut O {
+        &mut self.output_repository
     }
 
-    #[test]
-    fn chargeback_locks_account_and_adjusts_balances() {
-        let mut engine = mk_engine();
-        let dep = Transaction {
-            kind: TransactionKind::Deposit {
-                amount: Decimal::from(60u32),
-            },
-            client_id: 3,
-            transaction_id: 30,
-        };
-        engine.deposit(&dep, Decimal::from(60u32)).unwrap();
-        let dispute = Transaction {
-            kind: TransactionKind::Dispute,
-            client_id: 3,
-            transaction_id: 30,
-        };
-        engine.dispute(&dispute).unwrap();
+    /// Highest id among the deposits and withdrawals applied so far. With ids that only
+    /// grow, a restarted run can pass it to `--skip-until` to resume after it.
+    pub fn high_water_mark(&self) -> Option<u32> {
+        self.high_water_mark
+    }
 
-        // Perform chargeback
-        let chargeback = Transaction {
-            kind: TransactionKind::Chargeback,
-            client_id: 3,
-            transaction_id: 30,
+    /// Counters that stay readable, e.g. from a metrics endpoint, while the engine runs.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The metrics and the state the engine is in now, for a run that took `duration`.
+    pub fn summary(&self, duration: Duration) -> Summary {
+        Summary {
+            processed: self.metrics.processed_by_kind(),
+            rejected: self.metrics.rejected_by_kind(),
+            processed_total: self.metrics.processed(),
+            rejected_total: self.metrics.rejected(),
+            locked_accounts: self
+                .output_repository
+                .accounts()
+                .iter()
+                .filter(|(_, account)| account.locked)
+                .count(),
+            open_disputes: self.output_repository.disputes().len(),
+            high_water_mark: self.high_water_mark,
+            duration_secs: duration.as_secs_f64(),
+        }
+    }
+}
+
+impl<I, W, M, D, A> Engine<I, WriterOutput<W, M>, D, A>
+where
+    I: TransactionStream,
+    W: Write,
+    M: Amount,
+    D: DeadLetterQueue,
+    A: AuditSink,
+{
+    /// Writes only `client_id`'s row, as `WriterOutput::flush_client` does, then fails
+    /// if `conservation_check` does, like `flush`.
+    pub fn flush_client(&mut self, client_id: ClientId) -> Result<bool, Error> {
+        let found = self.output_repository.flush_client(client_id)?;
+        self.conservation_check()?;
+        Ok(found)
+    }
+}
+
+/// What a transaction does to its account, decided before the account is touched.
+/// Amounts are signed deltas: a dispute, resolve or chargeback reverses the referenced
+/// deposit (positive) or withdrawal (negative) the same way.
+#[derive(Debug, Clone, Copy)]
+enum Change<A> {
+    Transfer(A),
+    Dispute(A),
+    Resolve(A),
+    Chargeback(A),
+    Freeze,
+    Unfreeze,
+    Hold(A),
+    Release(A),
+    /// The referenced transaction carries no amount.
+    Nothing,
+}
+
+impl<A: Amount> Change<A> {
+    /// Every new balance is computed before any is written, so a change that would
+    /// overflow the amount type leaves the account untouched.
+    fn apply(
+        self,
+        account: &mut Account<A>,
+        client_id: ClientId,
+        policy: &Policy,
+    ) -> Result<(), Error> {
+        let overflow = || Error::from(EngineError::BalanceOverflow(client_id));
+        match self {
+            Change::Transfer(delta) => {
+                let available = account.available.checked_add(delta).ok_or_else(overflow)?;
+                // Only a withdrawal can bounce; a deposit may land on negative funds.
+                if delta.is_negative() && available.to_decimal() < -policy.overdraft {
+                    return Err(EngineError::InsufficientFunds(client_id).into());
+                }
+                let total = available.checked_add(account.held).ok_or_else(overflow)?;
+                account.available = available;
+                account.total = total;
+            }
+            Change::Dispute(delta) => {
+                let available = account.available.checked_sub(delta).ok_or_else(overflow)?;
+                let held = account.held.checked_add(delta).ok_or_else(overflow)?;
+                if let Some(max) = policy.max_held
+                    && held.to_decimal() > max
+                {
+                    return Err(EngineError::HeldLimitExceeded(client_id).into());
+                }
+                account.available = available;
+                account.held = held;
+            }
+            Change::Resolve(delta) => {
+                let available = account.available.checked_add(delta).ok_or_else(overflow)?;
+                let held = account.held.checked_sub(delta).ok_or_else(overflow)?;
+                account.available = available;
+                account.held = held;
+            }
+            Change::Chargeback(delta) => {
+                // The disputed funds leave the account for good.
+                let held = account.held.checked_sub(delta).ok_or_else(overflow)?;
+                let total = account.total.checked_sub(delta).ok_or_else(overflow)?;
+                account.held = held;
+                account.total = total;
+                account.locked = true;
+            }
+            Change::Freeze => {
+                account.locked = true;
+                account.frozen = true;
+            }
+            Change::Unfreeze => {
+                if account.locked && !account.frozen {
+                    return Err(EngineError::ChargebackLocked(client_id).into());
+                }
+                account.locked = false;
+                account.frozen = false;
+            }
+            Change::Hold(amount) | Change::Release(amount)
+                if !amount.compare(&A::zero()).is_gt() =>
+            {
+                return Err(EngineError::InvalidHoldAmount(client_id).into());
+            }
+            Change::Hold(amount) => {
+                if account.available.compare(&amount).is_lt() {
+                    return Err(EngineError::InsufficientFunds(client_id).into());
+                }
+                let available = account.available.checked_sub(amount).ok_or_else(overflow)?;
+                let held = account.held.checked_add(amount).ok_or_else(overflow)?;
+                let on_hold = account.on_hold.checked_add(amount).ok_or_else(overflow)?;
+                account.available = available;
+                account.held = held;
+                account.on_hold = on_hold;
+            }
+            Change::Release(amount) => {
+                // Only manual holds can be released; disputed funds wait for their dispute.
+                if account.on_hold.compare(&amount).is_lt() {
+                    return Err(EngineError::ReleaseExceedsHold(client_id).into());
+                }
+                let available = account.available.checked_add(amount).ok_or_else(overflow)?;
+                let held = account.held.checked_sub(amount).ok_or_else(overflow)?;
+                let on_hold = account.on_hold.checked_sub(amount).ok_or_else(overflow)?;
+                account.available = available;
+                account.held = held;
+                account.on_hold = on_hold;
+            }
+            Change::Nothing => {}
+        }
+        Ok(())
+    }
+}
+
+/// An `AsyncDeadLetterQueue` and the rejections still to be handed to it. Rejections
+/// are raised in synchronous code, so they wait here until the processing loop can
+/// await their reports.
+struct AsyncReports {
+    dlq: Box<dyn DynAsyncDLQ>,
+    pending: Vec<(Option<Transaction>, Error)>,
+}
+
+impl fmt::Debug for AsyncReports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncReports")
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+/// `AsyncDeadLetterQueue` made object safe by boxing its futures.
+trait DynAsyncDLQ: Send + Sync {
+    fn report<'a>(&'a self, tx: Option<&'a Transaction>, error: &'a Error) -> BoxFuture<'a, ()>;
+}
+
+impl<Q: AsyncDeadLetterQueue> DynAsyncDLQ for Q {
+    fn report<'a>(&'a self, tx: Option<&'a Transaction>, error: &'a Error) -> BoxFuture<'a, ()> {
+        match tx {
+            Some(tx) => self.report_rejected(tx, error).boxed(),
+            None => AsyncDeadLetterQueue::report(self, error).boxed(),
+        }
+    }
+}
+
+/// Money that crossed the engine's boundary, for `Engine::conservation_check`.
+#[derive(Debug, Default, Clone, Copy)]
+struct FundsFlow {
+    /// Deposits and restored balances minus withdrawals.
+    net: Decimal,
+    /// Signed amounts taken out by chargebacks.
+    charged_back: Decimal,
+    /// A sum outgrew `Decimal`, as balances near its limit across clients can, so the
+    /// flow is no longer known.
+    overflowed: bool,
+}
+
+impl FundsFlow {
+    fn add(&mut self, amount: Decimal) {
+        match self.net.checked_add(amount) {
+            Some(net) => self.net = net,
+            None => self.overflowed = true,
+        }
+    }
+
+    fn charge_back(&mut self, amount: Decimal) {
+        match self.charged_back.checked_add(amount) {
+            Some(charged_back) => self.charged_back = charged_back,
+            None => self.overflowed = true,
+        }
+    }
+}
+
+/// Outcome counts for one processing run, logged once it finishes.
+#[derive(Debug, Default)]
+struct Tally {
+    applied: u64,
+    rejected: u64,
+}
+
+impl Tally {
+    fn log(&self, high_water_mark: Option<u32>) {
+        tracing::info!(
+            applied = self.applied,
+            rejected = self.rejected,
+            high_water_mark,
+            "processing finished"
+        );
+    }
+}
+
+impl<I: TransactionStream> Engine<I, MemoryOutput, NoopDLQ> {
+    pub fn builder(ingestion: I) -> EngineBuilder<I> {
+        EngineBuilder::new(ingestion)
+    }
+}
+
+/// Fluent construction of an `Engine`; every component but the ingestion has a default.
+#[derive(Debug)]
+pub struct EngineBuilder<I, O = MemoryOutput, D = NoopDLQ, A = NoopAuditSink>
+where
+    O: OutputRepository,
+{
+    ingestion: I,
+    output_repository: O,
+    dlq: D,
+    async_dlq: Option<AsyncReports>,
+    audit: A,
+    policy: Policy,
+    validators: ValidatorChain<O::Amount>,
+    retry: Option<RetryBuffer>,
+    fail_fast: bool,
+    touched_clients: bool,
+    checkpoints: Option<Checkpoints>,
+}
+
+impl<I: TransactionStream> EngineBuilder<I> {
+    pub fn new(ingestion: I) -> Self {
+        Self {
+            ingestion,
+            output_repository: MemoryOutput::new(),
+            dlq: NoopDLQ,
+            async_dlq: None,
+            audit: NoopAuditSink,
+            policy: Policy::default(),
+            validators: ValidatorChain::default(),
+            retry: None,
+            fail_fast: false,
+            touched_clients: false,
+            checkpoints: None,
+        }
+    }
+}
+
+impl<I, O, D, A> EngineBuilder<I, O, D, A>
+where
+    I: TransactionStream,
+    O: OutputRepository,
+    D: DeadLetterQueue,
+    A: AuditSink,
+{
+    /// Validators already added are typed by the current output's amount, so the new
+    /// output must keep it.
+    pub fn output<O2>(self, output_repository: O2) -> EngineBuilder<I, O2, D, A>
+    where
+        O2: OutputRepository<Amount = O::Amount>,
+    {
+        EngineBuilder {
+            ingestion: self.ingestion,
+            output_repository,
+            dlq: self.dlq,
+            async_dlq: self.async_dlq,
+            audit: self.audit,
+            policy: self.policy,
+            validators: self.validators,
+            retry: self.retry,
+            fail_fast: self.fail_fast,
+            touched_clients: self.touched_clients,
+            checkpoints: self.checkpoints,
+        }
+    }
+
+    pub fn dlq<D2: DeadLetterQueue>(self, dlq: D2) -> EngineBuilder<I, O, D2, A> {
+        EngineBuilder {
+            ingestion: self.ingestion,
+            output_repository: self.output_repository,
+            dlq,
+            async_dlq: self.async_dlq,
+            audit: self.audit,
+            policy: self.policy,
+            validators: self.validators,
+            retry: self.retry,
+            fail_fast: self.fail_fast,
+            touched_clients: self.touched_clients,
+            checkpoints: self.checkpoints,
+        }
+    }
+
+    pub fn audit_sink<A2: AuditSink>(self, audit: A2) -> EngineBuilder<I, O, D, A2> {
+        EngineBuilder {
+            ingestion: self.ingestion,
+            output_repository: self.output_repository,
+            dlq: self.dlq,
+            async_dlq: self.async_dlq,
+            audit,
+            policy: self.policy,
+            validators: self.validators,
+            retry: self.retry,
+            fail_fast: self.fail_fast,
+            touched_clients: self.touched_clients,
+            checkpoints: self.checkpoints,
+        }
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn validator<V: Validator<O::Amount> + 'static>(mut self, validator: V) -> Self {
+        self.validators.push(validator);
+        self
+    }
+
+    pub fn retry_buffer(mut self, retry: RetryBuffer) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// See `Engine::with_fail_fast`.
+    pub fn fail_fast(mut self) -> Self {
+        self.fail_fast = true;
+        self
+    }
+
+    /// See `Engine::with_emit_touched_clients`.
+    pub fn emit_touched_clients(mut self) -> Self {
+        self.touched_clients = true;
+        self
+    }
+
+    /// See `Engine::with_checkpoints`.
+    pub fn checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    /// See `Engine::with_async_dlq`.
+    pub fn async_dlq<Q: AsyncDeadLetterQueue + 'static>(mut self, dlq: Q) -> Self {
+        self.async_dlq = Some(AsyncReports {
+            dlq: Box::new(dlq),
+            pending: Vec::new(),
+        });
+        self
+    }
+
+    pub fn build(self) -> Engine<I, O, D, A> {
+        let mut engine = Engine::new(self.ingestion, self.output_repository, self.dlq)
+            .with_audit_sink(self.audit)
+            .with_policy(self.policy);
+        engine.validators = self.validators;
+        engine.retry = self.retry;
+        engine.async_dlq = self.async_dlq;
+        engine.fail_fast = self.fail_fast;
+        engine.touched_clients = self.touched_clients;
+        engine.checkpoints = self.checkpoints;
+        engine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_repository::StdOutOutput;
+    use futures::stream::{self, Stream};
+    use rust_decimal::Decimal;
+    use std::pin::Pin;
+
+    #[derive(Debug, Default)]
+    struct NoopIngestion;
+
+    impl TransactionStream for NoopIngestion {
+        type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+        fn stream(&mut self) -> Result<Self::TxStream, Error> {
+            Ok(Box::pin(stream::iter(
+                Vec::<Result<Transaction, Error>>::new(),
+            )))
+        }
+    }
+
+    fn mk_engine() -> Engine<NoopIngestion, StdOutOutput, NoopDLQ> {
+        Engine::new(NoopIngestion, StdOutOutput::new(), NoopDLQ)
+    }
+
+    #[test]
+    fn deposit_increases_available_and_total() {
+        let mut engine = mk_engine();
+        let tx = Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(100u32),
+            },
+            client_id: 1,
+            transaction_id: 1,
+            currency: None,
         };
-        engine.chargeback(chargeback).expect("chargeback ok");
 
-        let acct = engine.output_repository.get_or_create_account(&3);
-        assert!(acct.locked);
-        assert_eq!(acct.available, Decimal::from(60u32));
+        engine.apply_transaction(&tx).expect("deposit ok");
+
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.available, Decimal::from(100u32));
+        assert_eq!(acct.held, Decimal::from(0u32));
+        assert_eq!(acct.total, Decimal::from(100u32));
+        assert!(!acct.locked);
+    }
+
+    #[test]
+    fn withdrawal_with_insufficient_funds_errors_and_does_not_change_balances() {
+        let mut engine = mk_engine();
+        let tx = Transaction {
+            kind: TransactionKind::Withdrawal {
+                amount: Decimal::from(50u32),
+            },
+            client_id: 1,
+            transaction_id: 2,
+            currency: None,
+        };
+
+        let res = engine.apply_transaction(&tx);
+        assert!(res.is_err());
+
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.available, Decimal::from(0u32));
+        assert_eq!(acct.held, Decimal::from(0u32));
+        assert_eq!(acct.total, Decimal::from(0u32));
+    }
+
+    #[test]
+    fn bounced_withdrawal_is_not_recorded_and_can_be_retried() {
+        let mut engine = mk_engine();
+        let withdrawal = Transaction {
+            kind: TransactionKind::Withdrawal {
+                amount: Decimal::from(50u32),
+            },
+            client_id: 1,
+            transaction_id: 2,
+            currency: None,
+        };
+        assert!(engine.apply_transaction(&withdrawal).is_err());
+        assert!(engine.output_repository.get_transaction(1, 2).is_none());
+        assert!(engine.output_repository.client_transactions(1).is_empty());
+
+        let deposit = Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(80u32),
+            },
+            client_id: 1,
+            transaction_id: 1,
+            currency: None,
+        };
+        engine.apply_transaction(&deposit).unwrap();
+        engine
+            .apply_transaction(&withdrawal)
+            .expect("retried withdrawal ok");
+
+        let acct = engine.output_repository.get_account(1).unwrap();
+        assert_eq!(acct.available, Decimal::from(30u32));
+        assert!(engine.output_repository.get_transaction(1, 2).is_some());
+    }
+
+    #[test]
+    fn apply_and_get_returns_the_clients_balances() {
+        let mut engine = mk_engine();
+        let after = engine
+            .apply_and_get(Transaction {
+                kind: TransactionKind::Deposit {
+                    amount: Decimal::from(7u32),
+                },
+                client_id: 1,
+                transaction_id: 1,
+                currency: None,
+            })
+            .unwrap();
+        assert_eq!((after.available, after.held), (7.into(), 0.into()));
+        assert_eq!(after.total, Decimal::from(7u32));
+        assert!(!after.locked);
+
+        let err = engine
+            .apply_and_get(Transaction {
+                kind: TransactionKind::Withdrawal {
+                    amount: Decimal::from(9u32),
+                },
+                client_id: 1,
+                transaction_id: 2,
+                currency: None,
+            })
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Engine(EngineError::InsufficientFunds(1))
+        ));
+        assert_eq!(engine.output_repository.get_account(1), Some(after));
+    }
+
+    #[test]
+    fn duplicate_transaction_id_is_a_retryable_rejection() {
+        let mut engine = mk_engine();
+        let tx = Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(5u32),
+            },
+            client_id: 1,
+            transaction_id: 3,
+            currency: None,
+        };
+        engine.apply_transaction(&tx.clone()).unwrap();
+
+        let err = engine.apply_transaction(&tx).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Engine(EngineError::DuplicateTransaction(3))
+        ));
+        assert!(err.is_retryable());
+        assert!(!Error::Engine(EngineError::InsufficientFunds(1)).is_retryable());
+
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.available, Decimal::from(5u32));
+    }
+
+    #[test]
+    fn dispute_moves_available_to_held_and_marks_disputed() {
+        let mut engine = mk_engine();
+        let dep = Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(75u32),
+            },
+            client_id: 1,
+            transaction_id: 10,
+            currency: None,
+        };
+        engine.apply_transaction(&dep).unwrap();
+
+        let dispute = Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id: 1,
+            transaction_id: 10,
+            currency: None,
+        };
+        engine.apply_transaction(&dispute).expect("dispute ok");
+
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.available, Decimal::from(0u32));
+        assert_eq!(acct.held, Decimal::from(75u32));
+        assert_eq!(acct.available + acct.held, acct.total);
+        assert!(engine.output_repository.has_dispute(1, 10));
+    }
+
+    #[test]
+    fn resolve_moves_held_back_and_clears_dispute() {
+        let mut engine = mk_engine();
+        let dep = Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(40u32),
+            },
+            client_id: 2,
+            transaction_id: 20,
+            currency: None,
+        };
+        engine.apply_transaction(&dep).unwrap();
+        let dispute = Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id: 2,
+            transaction_id: 20,
+            currency: None,
+        };
+        engine.apply_transaction(&dispute).unwrap();
+
+        let resolve = Transaction {
+            kind: TransactionKind::Resolve,
+            client_id: 2,
+            transaction_id: 20,
+            currency: None,
+        };
+        engine.apply_transaction(&resolve).expect("resolve ok");
+
+        let acct = engine.output_repository.get_or_create_account(&2).unwrap();
+        assert_eq!(acct.available, Decimal::from(40u32));
         assert_eq!(acct.held, Decimal::from(0u32));
-        assert_eq!(acct.total, Decimal::from(60u32));
+        assert_eq!(acct.available + acct.held, acct.total);
+        assert!(!engine.output_repository.has_dispute(2, 20));
+    }
+
+    #[test]
+    fn chargeback_locks_account_and_adjusts_balances() {
+        let mut engine = mk_engine();
+        let dep = Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(60u32),
+            },
+            client_id: 3,
+            transaction_id: 30,
+            currency: None,
+        };
+        engine.apply_transaction(&dep).unwrap();
+        let dispute = Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id: 3,
+            transaction_id: 30,
+            currency: None,
+        };
+        engine.apply_transaction(&dispute).unwrap();
+
+        // Perform chargeback
+        let chargeback = Transaction {
+            kind: TransactionKind::Chargeback,
+            client_id: 3,
+            transaction_id: 30,
+            currency: None,
+        };
+        engine
+            .apply_transaction(&chargeback)
+            .expect("chargeback ok");
+
+        let acct = engine.output_repository.get_or_create_account(&3).unwrap();
+        assert!(acct.locked);
+        assert_eq!(acct.available, Decimal::from(0u32));
+        assert_eq!(acct.held, Decimal::from(0u32));
+        assert_eq!(acct.total, Decimal::from(0u32));
+    }
+
+    #[test]
+    fn dispute_of_unseen_transaction_is_unknown_reference() {
+        let mut engine = mk_engine();
+        let dispute = Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id: 1,
+            transaction_id: 99,
+            currency: None,
+        };
+
+        assert!(matches!(
+            engine.apply_transaction(&dispute),
+            Err(Error::Engine(EngineError::UnknownReference(99)))
+        ));
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.held, Decimal::from(0u32));
+    }
+
+    #[test]
+    fn resolve_tells_unknown_apart_from_undisputed() {
+        let mut engine = mk_engine();
+        engine.process_all(vec![deposit_of(1, 1, 5)]).unwrap();
+        let resolve = |transaction_id| Transaction {
+            kind: TransactionKind::Resolve,
+            client_id: 1,
+            transaction_id,
+            currency: None,
+        };
+
+        assert!(matches!(
+            engine.apply_transaction(&resolve(99)),
+            Err(Error::Engine(EngineError::UnknownReference(99)))
+        ));
+        assert!(matches!(
+            engine.apply_transaction(&resolve(1)),
+            Err(Error::Engine(EngineError::NotDisputed(1)))
+        ));
+        let acct = engine.output_repository.get_account(1).unwrap();
+        assert_eq!(acct.available, Decimal::from(5u32));
+        assert_eq!(acct.held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn chargeback_of_unknown_transaction_is_not_found() {
+        let mut engine = mk_engine();
+        let chargeback = Transaction {
+            kind: TransactionKind::Chargeback,
+            client_id: 1,
+            transaction_id: 99,
+            currency: None,
+        };
+
+        assert!(matches!(
+            engine.apply_transaction(&chargeback),
+            Err(Error::Engine(EngineError::UnknownReference(99)))
+        ));
+    }
+
+    fn admin(kind: TransactionKind, client_id: ClientId) -> Result<Transaction, Error> {
+        Ok(Transaction {
+            kind,
+            client_id,
+            transaction_id: 0,
+            currency: None,
+        })
+    }
+
+    fn deposit_of(
+        client_id: ClientId,
+        transaction_id: u32,
+        amount: u32,
+    ) -> Result<Transaction, Error> {
+        Ok(Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(amount),
+            },
+            client_id,
+            transaction_id,
+            currency: None,
+        })
+    }
+
+    #[cfg(feature = "wide-client-ids")]
+    #[test]
+    fn client_ids_past_u16_get_their_own_accounts() {
+        let mut engine = Engine::builder(NoopIngestion).build();
+        engine
+            .process_all(vec![
+                deposit_of(70_000, 1, 5),
+                deposit_of(70_000 % 65_536, 2, 3),
+                dispute_of(70_000, 1),
+            ])
+            .unwrap();
+
+        let repo = engine.output_repository();
+        let wide = repo.get_account(70_000).unwrap();
+        assert_eq!((wide.available, wide.held), (0.into(), 5.into()));
+        assert_eq!(repo.get_account(4_464).unwrap().available, 3.into());
+    }
+
+    #[test]
+    fn high_water_mark_follows_applied_deposits_and_withdrawals() {
+        let mut engine = mk_engine();
+        assert_eq!(engine.high_water_mark(), None);
+
+        engine
+            .process_all(vec![
+                deposit_of(28, 282, 5),
+                deposit_of(28, 280, 5),
+                dispute_of(28, 280),
+                Ok(Transaction {
+                    kind: TransactionKind::Withdrawal {
+                        amount: Decimal::from(50u32),
+                    },
+                    client_id: 28,
+                    transaction_id: 290, // bounces: not counted
+                    currency: None,
+                }),
+                admin(TransactionKind::Freeze, 29),
+            ])
+            .unwrap();
+        assert_eq!(engine.high_water_mark(), Some(282));
+    }
+
+    fn partial_dispute_of(
+        client_id: ClientId,
+        transaction_id: u32,
+        amount: u32,
+    ) -> Result<Transaction, Error> {
+        Ok(Transaction {
+            kind: TransactionKind::Dispute {
+                amount: Some(Decimal::from(amount)),
+            },
+            client_id,
+            transaction_id,
+            currency: None,
+        })
+    }
+
+    #[test]
+    fn partial_dispute_holds_only_its_amount_until_resolved() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(31, 310, 10),
+                partial_dispute_of(31, 310, 4),
+            ])
+            .unwrap();
+        let acct = engine.output_repository.get_account(31).unwrap();
+        assert_eq!((acct.available, acct.held), (6.into(), 4.into()));
+        assert_eq!(
+            engine.output_repository.disputed_amount(31, 310),
+            Some(4.into())
+        );
+        assert!(engine.verify_invariants().is_ok());
+
+        engine
+            .process_all(vec![Ok(Transaction {
+                kind: TransactionKind::Resolve,
+                client_id: 31,
+                transaction_id: 310,
+                currency: None,
+            })])
+            .unwrap();
+        let acct = engine.output_repository.get_account(31).unwrap();
+        assert_eq!((acct.available, acct.held), (10.into(), 0.into()));
+        assert!(engine.dlq.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn chargeback_settles_only_the_partially_held_amount() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(32, 320, 10),
+                deposit_of(32, 321, 5),
+                partial_dispute_of(32, 320, 4),
+                dispute_of(32, 321),
+                Ok(Transaction {
+                    kind: TransactionKind::Chargeback,
+                    client_id: 32,
+                    transaction_id: 320,
+                    currency: None,
+                }),
+            ])
+            .unwrap();
+
+        // only tx 321's full 5 is still held
+        let acct = engine.output_repository.get_account(32).unwrap();
+        assert_eq!(acct.held, 5.into());
+        assert!(acct.locked);
+        assert!(!engine.output_repository.has_dispute(32, 320));
+    }
+
+    #[test]
+    fn open_disputes_still_settle_after_a_chargeback_locks_the_account() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        let settle = |kind, transaction_id| {
+            Ok(Transaction {
+                kind,
+                client_id: 33,
+                transaction_id,
+                currency: None,
+            })
+        };
+        engine
+            .process_all(vec![
+                deposit_of(33, 330, 10),
+                deposit_of(33, 331, 5),
+                dispute_of(33, 330),
+                dispute_of(33, 331),
+                settle(TransactionKind::Chargeback, 330),
+                settle(TransactionKind::Resolve, 331),
+                deposit_of(33, 332, 1), // new funds stay blocked
+            ])
+            .unwrap();
+
+        let acct = engine.output_repository.get_account(33).unwrap();
+        assert!(acct.locked);
+        assert_eq!((acct.available, acct.held), (5.into(), 0.into()));
+        assert_eq!(acct.total, 5.into());
+        assert!(!engine.output_repository.has_dispute(33, 331));
+        assert_eq!(
+            *engine.dlq.0.lock().unwrap(),
+            ["Engine failed with: 33 account is locked"]
+        );
+    }
+
+    #[test]
+    fn a_disputed_transaction_cannot_be_disputed_again() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(34, 340, 10),
+                partial_dispute_of(34, 340, 4),
+                dispute_of(34, 340),
+                partial_dispute_of(34, 340, 2),
+                Ok(Transaction {
+                    kind: TransactionKind::Resolve,
+                    client_id: 34,
+                    transaction_id: 340,
+                    currency: None,
+                }),
+            ])
+            .unwrap();
+
+        // the resolve released everything the one dispute held
+        let acct = engine.output_repository.get_account(34).unwrap();
+        assert_eq!((acct.available, acct.held), (10.into(), 0.into()));
+        assert_eq!(
+            *engine.dlq.0.lock().unwrap(),
+            ["Engine failed with: Transaction 340 is already disputed"; 2]
+        );
+        assert!(engine.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn dispute_amounts_must_be_within_the_transaction() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(33, 330, 10),
+                partial_dispute_of(33, 330, 11),
+                partial_dispute_of(33, 330, 0),
+            ])
+            .unwrap();
+
+        let rejected = engine.dlq.0.lock().unwrap();
+        assert_eq!(rejected.len(), 2);
+        assert!(
+            rejected
+                .iter()
+                .all(|e| e.contains("at most the amount of transaction 330"))
+        );
+        assert!(!engine.output_repository.has_dispute(33, 330));
+    }
+
+    #[test]
+    fn repeated_dispute_cycles_leave_no_rounding_error() {
+        // More decimals than the output keeps; only the final flush may round.
+        let amount = Decimal::new(123_456_789_123, 11);
+        let mut txs = vec![Ok(Transaction {
+            kind: TransactionKind::Deposit { amount },
+            client_id: 30,
+            transaction_id: 300,
+            currency: None,
+        })];
+        for _ in 0..1000 {
+            txs.push(dispute_of(30, 300));
+            txs.push(Ok(Transaction {
+                kind: TransactionKind::Resolve,
+                client_id: 30,
+                transaction_id: 300,
+                currency: None,
+            }));
+        }
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine.process_all(txs).unwrap();
+
+        assert!(engine.dlq.0.lock().unwrap().is_empty());
+        let acct = engine.output_repository.get_account(30).unwrap();
+        assert_eq!(acct.available, amount);
+        assert_eq!(acct.held, Decimal::ZERO);
+        assert_eq!(acct.total, amount);
+        assert_eq!(acct.available.to_string(), "1.23456789123");
+    }
+
+    fn hold_of(client_id: ClientId, amount: u32) -> Result<Transaction, Error> {
+        admin(
+            TransactionKind::Hold {
+                amount: Decimal::from(amount),
+            },
+            client_id,
+        )
+    }
+
+    fn release_of(client_id: ClientId, amount: u32) -> Result<Transaction, Error> {
+        admin(
+            TransactionKind::Release {
+                amount: Decimal::from(amount),
+            },
+            client_id,
+        )
+    }
+
+    #[test]
+    fn hold_and_release_move_funds_without_a_dispute() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![deposit_of(25, 250, 10), hold_of(25, 4)])
+            .unwrap();
+        let acct = engine.output_repository.get_account(25).unwrap();
+        assert_eq!(acct.available, Decimal::from(6u32));
+        assert_eq!(acct.held, Decimal::from(4u32));
+        assert_eq!(acct.total, Decimal::from(10u32));
+        assert_eq!(engine.output_repository.open_disputes(25), 0);
+        assert!(engine.verify_invariants().is_ok());
+
+        engine.process_all(vec![release_of(25, 3)]).unwrap();
+        let acct = engine.output_repository.get_account(25).unwrap();
+        assert_eq!(acct.available, Decimal::from(9u32));
+        assert_eq!(acct.held, Decimal::from(1u32));
+        assert_eq!(acct.on_hold, Decimal::from(1u32));
+    }
+
+    #[test]
+    fn release_beyond_manual_holds_is_rejected() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(26, 260, 10),
+                deposit_of(26, 261, 5),
+                hold_of(26, 2),
+                dispute_of(26, 261),
+                release_of(26, 3), // 7 held, but only 2 of it is a manual hold
+                hold_of(26, 9),    // more than is available
+            ])
+            .unwrap();
+
+        let acct = engine.output_repository.get_account(26).unwrap();
+        assert_eq!(acct.available, Decimal::from(8u32));
+        assert_eq!(acct.held, Decimal::from(7u32));
+        assert_eq!(
+            engine.dlq.0.lock().unwrap().as_slice(),
+            [
+                "Engine failed with: Client 26 cannot release more than it has on hold",
+                "Engine failed with: Insufficient funds for client 26",
+            ]
+        );
+    }
+
+    #[test]
+    fn holds_and_releases_must_be_positive() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        let negative = |kind: fn(Decimal) -> TransactionKind| admin(kind(Decimal::new(-5, 0)), 28);
+        engine
+            .process_all(vec![
+                deposit_of(28, 280, 10),
+                negative(|amount| TransactionKind::Hold { amount }),
+                negative(|amount| TransactionKind::Release { amount }),
+                hold_of(28, 0),
+                Ok(Transaction {
+                    kind: TransactionKind::Withdrawal {
+                        amount: Decimal::from(15u32),
+                    },
+                    client_id: 28,
+                    transaction_id: 281,
+                    currency: None,
+                }), // no more than was deposited
+            ])
+            .unwrap();
+
+        let acct = engine.output_repository.get_account(28).unwrap();
+        assert_eq!((acct.available, acct.held), (10.into(), 0.into()));
+        assert_eq!(acct.total, 10.into());
+        let reports = engine.dlq.0.lock().unwrap();
+        assert_eq!(
+            reports[..3],
+            ["Engine failed with: Hold and release amounts must be positive, got one for client 28";
+                3]
+        );
+        assert_eq!(
+            reports[3],
+            "Engine failed with: Insufficient funds for client 28"
+        );
+        assert!(engine.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn holds_and_releases_are_blocked_on_a_locked_account() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(27, 270, 10),
+                hold_of(27, 4),
+                admin(TransactionKind::Freeze, 27),
+            ])
+            .unwrap();
+
+        for tx in [hold_of(27, 1), release_of(27, 4)] {
+            assert!(matches!(
+                engine.apply_transaction(&tx.unwrap()),
+                Err(Error::Engine(EngineError::AccountLocked(27)))
+            ));
+        }
+        let acct = engine.output_repository.get_account(27).unwrap();
+        assert_eq!(acct.held, Decimal::from(4u32));
+        assert_eq!(acct.available, Decimal::from(6u32));
+    }
+
+    #[test]
+    fn freeze_blocks_transactions_until_unfrozen() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(7, 70, 10),
+                admin(TransactionKind::Freeze, 7),
+                deposit_of(7, 71, 5), // rejected while frozen
+                admin(TransactionKind::Unfreeze, 7),
+                deposit_of(7, 72, 1),
+            ])
+            .unwrap();
+
+        let acct = engine.output_repository.get_or_create_account(&7).unwrap();
+        assert!(!acct.locked);
+        assert_eq!(acct.available, Decimal::from(11u32));
+        assert!(engine.output_repository.get_transaction(7, 71).is_none());
+    }
+
+    #[test]
+    fn unfreeze_cannot_lift_a_chargeback_lock() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(8, 80, 10),
+                Ok(Transaction {
+                    kind: TransactionKind::Dispute { amount: None },
+                    client_id: 8,
+                    transaction_id: 80,
+                    currency: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionKind::Chargeback,
+                    client_id: 8,
+                    transaction_id: 80,
+                    currency: None,
+                }),
+            ])
+            .unwrap();
+
+        let unfreeze = admin(TransactionKind::Unfreeze, 8).unwrap();
+        assert!(matches!(
+            engine.apply_transaction(&unfreeze),
+            Err(Error::Engine(EngineError::ChargebackLocked(8)))
+        ));
+        assert!(
+            engine
+                .output_repository
+                .get_or_create_account(&8)
+                .unwrap()
+                .locked
+        );
+    }
+
+    fn deposit_then_withdrawal(engine: &mut Engine<NoopIngestion, StdOutOutput, NoopDLQ>) {
+        let dep = Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(50u32),
+            },
+            client_id: 4,
+            transaction_id: 40,
+            currency: None,
+        };
+        engine.apply_transaction(&dep).unwrap();
+        let wd = Transaction {
+            kind: TransactionKind::Withdrawal {
+                amount: Decimal::from(20u32),
+            },
+            client_id: 4,
+            transaction_id: 41,
+            currency: None,
+        };
+        engine.apply_transaction(&wd).unwrap();
+    }
+
+    #[test]
+    fn overdraft_lets_withdrawals_go_negative_up_to_the_limit() {
+        let mut engine = mk_engine().with_policy(Policy {
+            overdraft: Decimal::from(10u32),
+            ..Default::default()
+        });
+        deposit_then_withdrawal(&mut engine);
+        let withdraw = |transaction_id, amount: u32| Transaction {
+            kind: TransactionKind::Withdrawal {
+                amount: Decimal::from(amount),
+            },
+            client_id: 4,
+            transaction_id,
+            currency: None,
+        };
+
+        engine.apply_transaction(&withdraw(42, 38)).unwrap();
+        assert!(matches!(
+            engine.apply_transaction(&withdraw(43, 3)),
+            Err(Error::Engine(EngineError::InsufficientFunds(4)))
+        ));
+        engine.apply_transaction(&withdraw(44, 2)).unwrap();
+
+        let acct = engine.output_repository.get_or_create_account(&4).unwrap();
+        assert_eq!(acct.available, Decimal::from(-10));
+        assert_eq!(acct.total, Decimal::from(-10));
+    }
+
+    #[test]
+    fn max_held_rejects_disputes_that_would_exceed_it() {
+        let mut engine = mk_engine().with_policy(Policy {
+            max_held: Some(Decimal::from(10u32)),
+            ..Default::default()
+        });
+        engine
+            .process_all(vec![
+                deposit_of(4, 40, 6),
+                deposit_of(4, 41, 5),
+                dispute_of(4, 40),
+            ])
+            .unwrap();
+        let before = engine.output_repository.get_account(4).unwrap();
+        assert_eq!(before.held, Decimal::from(6u32));
+
+        let dispute = |amount| Transaction {
+            kind: TransactionKind::Dispute { amount },
+            client_id: 4,
+            transaction_id: 41,
+            currency: None,
+        };
+        assert!(matches!(
+            engine.apply_transaction(&dispute(None)),
+            Err(Error::Engine(EngineError::HeldLimitExceeded(4)))
+        ));
+        assert_eq!(engine.output_repository.get_account(4), Some(before));
+        assert!(!engine.output_repository.has_dispute(4, 41));
+
+        // holding only part of it reaches the limit exactly
+        engine
+            .apply_transaction(&dispute(Some(Decimal::from(4u32))))
+            .unwrap();
+        let acct = engine.output_repository.get_account(4).unwrap();
+        assert_eq!(acct.held, Decimal::from(10u32));
+        assert_eq!(acct.available, Decimal::ONE);
+    }
+
+    #[test]
+    fn withdrawal_dispute_rejected_by_default_policy() {
+        let mut engine = mk_engine();
+        deposit_then_withdrawal(&mut engine);
+
+        let dispute = Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id: 4,
+            transaction_id: 41,
+            currency: None,
+        };
+        assert!(matches!(
+            engine.apply_transaction(&dispute),
+            Err(Error::Engine(EngineError::WithdrawalDisputeNotAllowed(41)))
+        ));
+
+        let acct = engine.output_repository.get_or_create_account(&4).unwrap();
+        assert_eq!(acct.available, Decimal::from(30u32));
+        assert_eq!(acct.held, Decimal::from(0u32));
+        assert!(!engine.output_repository.has_dispute(4, 41));
+    }
+
+    #[test]
+    fn withdrawal_dispute_allowed_when_policy_permits() {
+        let mut engine = mk_engine().with_policy(Policy {
+            allow_withdrawal_disputes: true,
+            ..Default::default()
+        });
+        deposit_then_withdrawal(&mut engine);
+
+        let dispute = Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id: 4,
+            transaction_id: 41,
+            currency: None,
+        };
+        engine.apply_transaction(&dispute).expect("dispute ok");
+
+        // The withdrawal's -20 is reversed: the funds return to available and the
+        // negative delta waits in held, leaving the total as it was.
+        let acct = engine.output_repository.get_or_create_account(&4).unwrap();
+        assert_eq!(acct.available, Decimal::from(50u32));
+        assert_eq!(acct.held, Decimal::from(-20));
+        assert_eq!(acct.total, Decimal::from(30u32));
+        assert!(engine.output_repository.has_dispute(4, 41));
+    }
+
+    #[test]
+    fn withdrawal_dispute_window_counts_the_clients_own_transactions() {
+        let mut engine = mk_engine().with_policy(Policy {
+            allow_withdrawal_disputes: true,
+            withdrawal_dispute_window: Some(2),
+            ..Default::default()
+        });
+        deposit_then_withdrawal(&mut engine);
+        let on = |kind, transaction_id| Transaction {
+            kind,
+            client_id: 4,
+            transaction_id,
+            currency: None,
+        };
+        let dispute = on(TransactionKind::Dispute { amount: None }, 41);
+
+        // another client's deposit does not age 41
+        engine
+            .apply_transaction(&deposit_of(5, 50, 1).unwrap())
+            .unwrap();
+        engine.apply_transaction(&dispute).expect("in the window");
+        engine
+            .apply_transaction(&on(TransactionKind::Resolve, 41))
+            .unwrap();
+
+        engine
+            .apply_transaction(&deposit_of(4, 42, 1).unwrap())
+            .unwrap();
+        engine
+            .apply_transaction(&deposit_of(4, 43, 1).unwrap())
+            .unwrap();
+        assert!(matches!(
+            engine.apply_transaction(&dispute),
+            Err(Error::Engine(EngineError::OutsideDisputeWindow(41)))
+        ));
+        assert!(!engine.output_repository.has_dispute(4, 41));
+        // deposits stay disputable past the window
+        engine
+            .apply_transaction(&on(TransactionKind::Dispute { amount: None }, 40))
+            .unwrap();
+    }
+
+    #[test]
+    fn dispute_and_resolve_reverse_deltas_of_either_sign() {
+        let mut engine = mk_engine().with_policy(Policy {
+            allow_withdrawal_disputes: true,
+            ..Default::default()
+        });
+        deposit_then_withdrawal(&mut engine);
+        let balances = |engine: &mut Engine<NoopIngestion, StdOutOutput, NoopDLQ>| {
+            let acct = engine.output_repository.get_or_create_account(&4).unwrap();
+            (acct.available, acct.held, acct.total)
+        };
+        let on = |kind, transaction_id| Transaction {
+            kind,
+            client_id: 4,
+            transaction_id,
+            currency: None,
+        };
+
+        engine
+            .apply_transaction(&on(TransactionKind::Dispute { amount: None }, 40))
+            .unwrap();
+        assert_eq!(balances(&mut engine), ((-20).into(), 50.into(), 30.into()));
+        engine
+            .apply_transaction(&on(TransactionKind::Dispute { amount: None }, 41))
+            .unwrap();
+        assert_eq!(balances(&mut engine), (0.into(), 30.into(), 30.into()));
+        assert!(engine.verify_invariants().is_ok());
+
+        engine
+            .apply_transaction(&on(TransactionKind::Resolve, 40))
+            .unwrap();
+        assert_eq!(balances(&mut engine), (50.into(), (-20).into(), 30.into()));
+        engine
+            .apply_transaction(&on(TransactionKind::Resolve, 41))
+            .unwrap();
+        assert_eq!(balances(&mut engine), (30.into(), 0.into(), 30.into()));
+    }
+
+    fn sample_inputs() -> Vec<Result<Transaction, Error>> {
+        vec![
+            Ok(Transaction {
+                kind: TransactionKind::Deposit {
+                    amount: Decimal::from(10u32),
+                },
+                client_id: 1,
+                transaction_id: 1,
+                currency: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionKind::Deposit {
+                    amount: Decimal::from(5u32),
+                },
+                client_id: 2,
+                transaction_id: 2,
+                currency: None,
+            }),
+            Err(Error::Ingestion("bad row".to_string())),
+            Ok(Transaction {
+                kind: TransactionKind::Withdrawal {
+                    amount: Decimal::from(3u32),
+                },
+                client_id: 1,
+                transaction_id: 3,
+                currency: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionKind::Dispute { amount: None },
+                client_id: 2,
+                transaction_id: 2,
+                currency: None,
+            }),
+        ]
+    }
+
+    struct VecIngestion(Vec<Result<Transaction, Error>>);
+
+    impl TransactionStream for VecIngestion {
+        type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+        fn stream(&mut self) -> Result<Self::TxStream, Error> {
+            Ok(Box::pin(stream::iter(std::mem::take(&mut self.0))))
+        }
+    }
+
+    struct StallingIngestion;
+
+    impl TransactionStream for StallingIngestion {
+        type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+        fn stream(&mut self) -> Result<Self::TxStream, Error> {
+            let first = Ok(Transaction {
+                kind: TransactionKind::Deposit {
+                    amount: Decimal::from(7u32),
+                },
+                client_id: 5,
+                transaction_id: 50,
+                currency: None,
+            });
+            Ok(Box::pin(stream::iter(vec![first]).chain(stream::pending())))
+        }
+    }
+
+    #[test]
+    fn processing_a_consumed_csv_reader_is_an_error() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let ingestion = crate::ingestion::CsvReader::new(data.as_bytes()).unwrap();
+        let mut engine = Engine::builder(ingestion).build();
+
+        futures::executor::block_on(engine.process()).unwrap();
+        assert!(matches!(
+            futures::executor::block_on(engine.process()),
+            Err(Error::AlreadyConsumed)
+        ));
+    }
+
+    #[test]
+    fn reset_lets_one_engine_process_independent_inputs() {
+        let first = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndispute,1,1,\n";
+        let second = "type,client,tx,amount\ndeposit,1,1,2.0\ndispute,1,1,\nresolve,1,1,\n";
+        let reader =
+            |data: &'static str| crate::ingestion::CsvReader::new(data.as_bytes()).unwrap();
+        let mut engine = Engine::builder(reader(first))
+            .dlq(RecordingDLQ::default())
+            .build();
+
+        futures::executor::block_on(engine.process()).unwrap();
+        assert_eq!(engine.output_repository.open_disputes(1), 1);
+
+        engine.reset();
+        engine.replace_ingestion(reader(second));
+        assert_eq!(engine.high_water_mark(), None);
+        futures::executor::block_on(engine.process()).unwrap();
+
+        // tx 1 is new again and client 2 is gone
+        assert!(engine.dlq.0.lock().unwrap().is_empty());
+        let accounts = engine.output_repository.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.available, Decimal::from(2u32));
+        assert_eq!(accounts[0].1.held, Decimal::ZERO);
+        assert_eq!(engine.output_repository.open_disputes(1), 0);
+        assert_eq!(engine.metrics().processed(), 6);
+    }
+
+    #[tokio::test]
+    async fn timeout_keeps_partial_state_for_flush() {
+        let mut engine = Engine::new(StallingIngestion, StdOutOutput::new(), NoopDLQ);
+
+        let res = engine.process_with_timeout(Duration::from_millis(50)).await;
+        assert!(matches!(res, Err(Error::Timeout(_))));
+
+        let acct = engine.output_repository.get_or_create_account(&5).unwrap();
+        assert_eq!(acct.available, Decimal::from(7u32));
+        engine.flush().unwrap();
+    }
+
+    #[derive(Default)]
+    struct RecordingAuditSink(std::sync::Mutex<Vec<BalanceEvent>>);
+
+    impl AuditSink for RecordingAuditSink {
+        fn record(&self, event: &BalanceEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn audit_sink_records_each_applied_transaction() {
+        let mut engine = mk_engine().with_audit_sink(RecordingAuditSink::default());
+        let amount = Decimal::from(10u32);
+        engine
+            .process_all(vec![
+                Ok(Transaction {
+                    kind: TransactionKind::Deposit { amount },
+                    client_id: 6,
+                    transaction_id: 60,
+                    currency: None,
+                }),
+                // rejected: not audited
+                Ok(Transaction {
+                    kind: TransactionKind::Resolve,
+                    client_id: 6,
+                    transaction_id: 60,
+                    currency: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionKind::Dispute { amount: None },
+                    client_id: 6,
+                    transaction_id: 60,
+                    currency: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionKind::Chargeback,
+                    client_id: 6,
+                    transaction_id: 60,
+                    currency: None,
+                }),
+            ])
+            .unwrap();
+
+        let events = engine.audit.0.lock().unwrap();
+        let kinds: Vec<_> = events.iter().map(|e| e.kind.name()).collect();
+        assert_eq!(kinds, vec!["deposit", "dispute", "chargeback"]);
+        assert!(
+            events
+                .iter()
+                .all(|e| e.client_id == 6 && e.transaction_id == 60)
+        );
+
+        assert_eq!(events[0].before.available, Decimal::ZERO);
+        assert_eq!(events[0].after.available, amount);
+        assert_eq!(events[1].before, events[0].after);
+        assert_eq!(events[1].after.available, Decimal::ZERO);
+        assert_eq!(events[1].after.held, amount);
+        assert_eq!(events[2].before, events[1].after);
+        assert_eq!(events[2].after.held, Decimal::ZERO);
+        assert!(events[2].after.locked);
+    }
+
+    #[derive(Default)]
+    struct RecordingDLQ(std::sync::Mutex<Vec<String>>);
+
+    impl DeadLetterQueue for RecordingDLQ {
+        fn report(&self, error: &Error) {
+            self.0.lock().unwrap().push(error.to_string());
+        }
+    }
+
+    /// Records each report after yielding, like a queue awaiting a write. Yields without
+    /// tokio so `process_all` can block on it from within a test runtime.
+    #[derive(Default, Clone)]
+    struct AsyncRecordingDLQ(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl AsyncDeadLetterQueue for AsyncRecordingDLQ {
+        async fn report(&self, error: &Error) {
+            yield_once().await;
+            self.0.lock().unwrap().push(error.to_string());
+        }
+
+        async fn report_rejected(&self, tx: &Transaction, error: &Error) {
+            yield_once().await;
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("tx {}: {}", tx.transaction_id, error));
+        }
+    }
+
+    async fn yield_once() {
+        let mut yielded = false;
+        std::future::poll_fn(|cx| {
+            if yielded {
+                return std::task::Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn async_dlq_receives_every_rejection_in_order() {
+        let reports = AsyncRecordingDLQ::default();
+        let mut engine = Engine::builder(VecIngestion(vec![
+            deposit_of(80, 800, 1),
+            deposit_of(80, 800, 1),
+            Err(Error::Ingestion("bad row".to_string())),
+            dispute_of(80, 801),
+        ]))
+        .dlq(RecordingDLQ::default())
+        .async_dlq(reports.clone())
+        .build();
+        engine.process().await.unwrap();
+        engine.process_all(vec![dispute_of(80, 802)]).unwrap();
+
+        assert_eq!(
+            *reports.0.lock().unwrap(),
+            vec![
+                "tx 800: Engine failed with: Transaction ID 800 already exists",
+                "Ingestion failed with: bad row",
+                "tx 801: Engine failed with: Referenced transaction 801 not found",
+                "tx 802: Engine failed with: Referenced transaction 802 not found",
+            ]
+        );
+        // the synchronous queue is bypassed
+        assert!(engine.dlq.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fail_fast_stops_at_the_first_rejection() {
+        let mut engine = Engine::builder(VecIngestion(vec![
+            deposit_of(81, 810, 5),
+            dispute_of(81, 811),
+            deposit_of(81, 812, 7),
+        ]))
+        .dlq(RecordingDLQ::default())
+        .fail_fast()
+        .build();
+        let err = futures::executor::block_on(engine.process()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Stopped at the first rejection (dispute of client 81 tx 811): \
+             Engine failed with: Referenced transaction 811 not found"
+        );
+        assert_eq!(err.reason(), "unknown_reference");
+        // returned instead of reported, and the deposit after it is never applied
+        assert!(engine.dlq.0.lock().unwrap().is_empty());
+        let acct = engine.output_repository.get_or_create_account(&81).unwrap();
+        assert_eq!(acct.total, Decimal::from(5u32));
+        assert!(engine.process_all(vec![deposit_of(81, 813, 1)]).is_ok());
+    }
+
+    #[test]
+    fn builder_defaults_to_memory_output_and_noop_dlq() {
+        let mut engine = Engine::builder(VecIngestion(sample_inputs())).build();
+        futures::executor::block_on(engine.process()).unwrap();
+
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.available, Decimal::from(7u32));
+        assert!(!engine.policy.allow_withdrawal_disputes);
+        engine.flush().unwrap();
+    }
+
+    #[test]
+    fn builder_overrides_only_selected_components() {
+        let mut engine = Engine::builder(VecIngestion(sample_inputs()))
+            .dlq(RecordingDLQ::default())
+            .policy(Policy {
+                allow_withdrawal_disputes: true,
+                ..Default::default()
+            })
+            .build();
+        futures::executor::block_on(engine.process()).unwrap();
+
+        assert_eq!(engine.dlq.0.lock().unwrap().len(), 1);
+        assert!(engine.policy.allow_withdrawal_disputes);
+        assert!(engine.output_repository.has_dispute(2, 2));
+    }
+
+    const TAMPERED_SNAPSHOT: &str = "client,available,held,total,locked\n\
+        1,10.0,0.0,10.0,false\n\
+        2,5.0,1.0,9.0,false\n";
+
+    #[test]
+    fn restore_rejects_tampered_snapshot() {
+        let mut engine = mk_engine();
+        let accounts = crate::snapshot::read_accounts(TAMPERED_SNAPSHOT.as_bytes()).unwrap();
+
+        let res = engine.restore(accounts, false);
+        assert!(matches!(
+            res,
+            Err(Error::Engine(EngineError::InconsistentTotal { .. }))
+        ));
+        // nothing is seeded, not even the consistent account
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.total, Decimal::ZERO);
+    }
+
+    #[test]
+    fn restore_repairs_tampered_snapshot() {
+        let mut engine = mk_engine();
+        let accounts = crate::snapshot::read_accounts(TAMPERED_SNAPSHOT.as_bytes()).unwrap();
+
+        engine.restore(accounts, true).expect("restore ok");
+        let acct = engine.output_repository.get_or_create_account(&2).unwrap();
+        // the total is recomputed; with no ledger loaded the held funds stay held
+        assert_eq!(acct.available, Decimal::from(5u32));
+        assert_eq!(acct.held, Decimal::ONE);
+        assert_eq!(acct.total, Decimal::from(6u32));
+        let acct = engine.output_repository.get_or_create_account(&1).unwrap();
+        assert_eq!(acct.total, Decimal::from(10u32));
+    }
+
+    #[test]
+    fn repair_backfills_held_from_open_disputes() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(3, 30, 4),
+                dispute_of(3, 30),
+                deposit_of(4, 40, 2),
+            ])
+            .unwrap();
+        // written before disputes were tracked: client 3's dispute is not in `held`
+        // and client 4 holds funds no dispute accounts for
+        let snapshot = "client,available,held,total,locked\n\
+            3,4.0,0.0,4.0,false\n\
+            4,1.5,0.5,2.0,false\n";
+        let accounts = crate::snapshot::read_accounts(snapshot.as_bytes()).unwrap();
+
+        engine.restore(accounts, true).expect("restore ok");
+        let repo = engine.output_repository();
+        let three = repo.get_account(3).unwrap();
+        assert_eq!((three.available, three.held), (0.into(), 4.into()));
+        let four = repo.get_account(4).unwrap();
+        assert_eq!((four.available, four.held), (2.into(), 0.into()));
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn process_all_matches_async_process() {
+        let mut sync_engine = mk_engine();
+        sync_engine.process_all(sample_inputs()).unwrap();
+
+        let mut async_engine =
+            Engine::new(VecIngestion(sample_inputs()), StdOutOutput::new(), NoopDLQ);
+        futures::executor::block_on(async_engine.process()).unwrap();
+
+        for client_id in [1, 2] {
+            let a = sync_engine
+                .output_repository
+                .get_or_create_account(&client_id)
+                .unwrap();
+            let (available, held, total, locked) = (a.available, a.held, a.total, a.locked);
+            let b = async_engine
+                .output_repository
+                .get_or_create_account(&client_id)
+                .unwrap();
+            assert_eq!(available, b.available);
+            assert_eq!(held, b.held);
+            assert_eq!(total, b.total);
+            assert_eq!(locked, b.locked);
+        }
+        assert!(sync_engine.output_repository.has_dispute(2, 2));
+        assert!(async_engine.output_repository.has_dispute(2, 2));
+    }
+
+    #[test]
+    fn rejected_over_threshold_deposit_leaves_balances_unchanged() {
+        let mut engine = Engine::builder(VecIngestion(vec![
+            deposit_of(9, 90, 50),
+            deposit_of(9, 91, 500),
+            deposit_of(9, 92, 20),
+        ]))
+        .dlq(RecordingDLQ::default())
+        .validator(crate::validation::MaxDepositValidator::new(Decimal::from(
+            100u32,
+        )))
+        .build();
+        futures::executor::block_on(engine.process()).unwrap();
+
+        let acct = engine.output_repository.get_account(9).unwrap();
+        assert_eq!(acct.available, Decimal::from(70u32));
+        assert_eq!(acct.total, Decimal::from(70u32));
+        // never reached the ledger, so the id stays free
+        assert!(engine.output_repository.get_transaction(9, 91).is_none());
+        let reports = engine.dlq.0.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].contains("Transaction 91 rejected"));
+    }
+
+    #[test]
+    fn rejection_for_new_client_does_not_create_an_account() {
+        let mut engine =
+            mk_engine().with_validator(crate::validation::MaxDepositValidator::new(Decimal::ONE));
+        engine.process_all(vec![deposit_of(10, 100, 5)]).unwrap();
+        assert!(engine.output_repository.get_account(10).is_none());
+    }
+
+    #[test]
+    fn touched_clients_get_a_row_for_a_rejection_alone() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .output(crate::output_repository::WriterOutput::with_writer(
+                Vec::new(),
+            ))
+            .validator(crate::validation::MaxDepositValidator::new(Decimal::ONE))
+            .emit_touched_clients()
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(10, 100, 5),
+                Err(Error::Ingestion("bad row".to_string())),
+            ])
+            .unwrap();
+        engine.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(engine.output_repository().writer().clone()).unwrap(),
+            "client,available,held,total,locked\n10,0.0000,0.0000,0.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn rejections_open_accounts_only_for_touched_clients() {
+        let rejected = || {
+            vec![
+                Ok(Transaction {
+                    kind: TransactionKind::Withdrawal {
+                        amount: Decimal::ONE,
+                    },
+                    client_id: 11,
+                    transaction_id: 110,
+                    currency: None,
+                }),
+                dispute_of(12, 120),
+                Ok(Transaction {
+                    kind: TransactionKind::Resolve,
+                    client_id: 13,
+                    transaction_id: 130,
+                    currency: None,
+                }),
+            ]
+        };
+
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine.process_all(rejected()).unwrap();
+        assert_eq!(engine.dlq.0.lock().unwrap().len(), 3);
+        assert_eq!(engine.output_repository.account_count(), 0);
+
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .emit_touched_clients()
+            .build();
+        engine.process_all(rejected()).unwrap();
+        let clients: Vec<_> = engine
+            .output_repository
+            .accounts()
+            .into_iter()
+            .map(|(client_id, _)| client_id)
+            .collect();
+        assert_eq!(clients, [11, 12, 13]);
+    }
+
+    /// Only compiles because `get_transaction` takes `&self`.
+    struct NoDisputesOver(Decimal);
+
+    impl Validator for NoDisputesOver {
+        fn validate(
+            &self,
+            tx: &Transaction,
+            repo: &dyn OutputRepository<Amount = Decimal>,
+        ) -> Result<(), Error> {
+            match (
+                tx.kind,
+                repo.get_transaction(tx.client_id, tx.transaction_id)
+                    .map(|t| t.kind),
+            ) {
+                (
+                    TransactionKind::Dispute { amount: None },
+                    Some(TransactionKind::Deposit { amount }),
+                ) if amount > self.0 => Err(EngineError::Rejected {
+                    transaction_id: tx.transaction_id,
+                    reason: "dispute too large".to_string(),
+                }
+                .into()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn validators_can_read_the_ledger() {
+        let dispute = |transaction_id| {
+            Ok(Transaction {
+                kind: TransactionKind::Dispute { amount: None },
+                client_id: 11,
+                transaction_id,
+                currency: None,
+            })
+        };
+        let mut engine = mk_engine().with_validator(NoDisputesOver(Decimal::from(10u32)));
+        engine
+            .process_all(vec![
+                deposit_of(11, 110, 5),
+                deposit_of(11, 111, 50),
+                dispute(110),
+                dispute(111),
+            ])
+            .unwrap();
+
+        assert!(engine.output_repository.has_dispute(11, 110));
+        assert!(!engine.output_repository.has_dispute(11, 111));
+        let acct = engine.output_repository.get_account(11).unwrap();
+        assert_eq!(acct.held, Decimal::from(5u32));
+        assert_eq!(acct.available, Decimal::from(50u32));
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejection_is_logged_with_transaction_context() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut engine = mk_engine();
+            engine
+                .process_all(vec![Ok(Transaction {
+                    kind: TransactionKind::Withdrawal {
+                        amount: Decimal::from(5u32),
+                    },
+                    client_id: 12,
+                    transaction_id: 120,
+                    currency: None,
+                })])
+                .unwrap();
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let rejection = logs
+            .lines()
+            .find(|l| l.contains("transaction rejected"))
+            .expect("rejection event");
+        assert!(rejection.contains("DEBUG"));
+        assert!(rejection.contains("client=12"));
+        assert!(rejection.contains("tx=120"));
+        assert!(rejection.contains("Insufficient funds for client 12"));
+        assert!(logs.contains("processing finished applied=0 rejected=1"));
+    }
+
+    fn dispute_of(client_id: ClientId, transaction_id: u32) -> Result<Transaction, Error> {
+        Ok(Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id,
+            transaction_id,
+            currency: None,
+        })
+    }
+
+    #[test]
+    fn disputes_beyond_the_cap_are_rejected() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .policy(Policy {
+                max_open_disputes: Some(2),
+                ..Default::default()
+            })
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(13, 130, 1),
+                deposit_of(13, 131, 2),
+                deposit_of(13, 132, 4),
+                deposit_of(14, 140, 8),
+                dispute_of(13, 130),
+                dispute_of(13, 131),
+                dispute_of(13, 132), // third open dispute: rejected
+                dispute_of(14, 140), // other clients have their own budget
+            ])
+            .unwrap();
+
+        let acct = engine.output_repository.get_account(13).unwrap();
+        assert_eq!(acct.held, Decimal::from(3u32));
+        assert_eq!(acct.available, Decimal::from(4u32));
+        assert!(!engine.output_repository.has_dispute(13, 132));
+        assert!(engine.output_repository.has_dispute(14, 140));
+        assert_eq!(engine.output_repository.open_disputes(13), 2);
+        assert_eq!(
+            engine.dlq.0.lock().unwrap().as_slice(),
+            ["Engine failed with: Client 13 already has the maximum number of open disputes"]
+        );
+    }
+
+    #[test]
+    fn new_clients_past_the_cap_are_rejected() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .policy(Policy {
+                max_clients: Some(2),
+                ..Default::default()
+            })
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(15, 150, 1),
+                deposit_of(16, 160, 2),
+                deposit_of(17, 170, 4), // a third client: rejected
+                deposit_of(15, 151, 8), // known clients carry on
+                dispute_of(17, 170),
+            ])
+            .unwrap();
+
+        assert_eq!(engine.output_repository.account_count(), 2);
+        assert!(engine.output_repository.get_account(17).is_none());
+        let acct = engine.output_repository.get_account(15).unwrap();
+        assert_eq!(acct.available, Decimal::from(9u32));
+        assert_eq!(
+            engine.dlq.0.lock().unwrap().as_slice(),
+            ["Engine failed with: Client 17 has no account and the maximum number of clients is reached";
+                2]
+        );
+    }
+
+    #[test]
+    fn touched_clients_do_not_get_past_the_client_cap() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .policy(Policy {
+                max_clients: Some(1),
+                ..Default::default()
+            })
+            .build()
+            .with_emit_touched_clients();
+        engine
+            .process_all(vec![
+                deposit_of(18, 180, 1),
+                deposit_of(19, 190, 7), // rejected: the cap is reached
+                deposit_of(19, 191, 7), // still no room
+            ])
+            .unwrap();
+
+        assert_eq!(engine.output_repository.account_count(), 1);
+        assert!(engine.output_repository.get_account(19).is_none());
+        assert_eq!(engine.dlq.0.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn clients_may_reuse_each_others_tx_ids() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(24, 240, 10),
+                deposit_of(25, 240, 3),
+                dispute_of(25, 240),
+                Ok(Transaction {
+                    kind: TransactionKind::Chargeback,
+                    client_id: 25,
+                    transaction_id: 240,
+                    currency: None,
+                }),
+                dispute_of(26, 240), // never seen for client 26
+            ])
+            .unwrap();
+
+        let first = engine.output_repository.get_account(24).unwrap();
+        assert_eq!((first.available, first.held), (10.into(), 0.into()));
+        assert!(!first.locked);
+        let second = engine.output_repository.get_account(25).unwrap();
+        assert_eq!((second.available, second.total), (0.into(), 0.into()));
+        assert!(second.locked);
+        assert!(!engine.output_repository.has_dispute(24, 240));
+        assert_eq!(
+            engine.dlq.0.lock().unwrap().as_slice(),
+            ["Engine failed with: Referenced transaction 240 not found"]
+        );
+    }
+
+    #[test]
+    fn resolve_and_chargeback_free_up_the_dispute_cap() {
+        let mut engine = mk_engine().with_policy(Policy {
+            max_open_disputes: Some(1),
+            ..Default::default()
+        });
+        engine
+            .process_all(vec![
+                deposit_of(15, 150, 1),
+                deposit_of(15, 151, 2),
+                deposit_of(15, 152, 4),
+                dispute_of(15, 150),
+                Ok(Transaction {
+                    kind: TransactionKind::Resolve,
+                    client_id: 15,
+                    transaction_id: 150,
+                    currency: None,
+                }),
+                dispute_of(15, 151),
+            ])
+            .unwrap();
+        assert!(engine.output_repository.has_dispute(15, 151));
+        assert_eq!(engine.output_repository.open_disputes(15), 1);
+
+        engine
+            .process_all(vec![Ok(Transaction {
+                kind: TransactionKind::Chargeback,
+                client_id: 15,
+                transaction_id: 151,
+                currency: None,
+            })])
+            .unwrap();
+        assert!(!engine.output_repository.has_dispute(15, 151));
+        assert_eq!(engine.output_repository.open_disputes(15), 0);
+    }
+
+    #[test]
+    fn summary_counts_the_run_and_its_end_state() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(1, 10, 5),
+                deposit_of(2, 20, 5),
+                Err(Error::Ingestion("Invalid amount: x".to_string())),
+                dispute_of(1, 10),
+                dispute_of(1, 11),
+            ])
+            .unwrap();
+
+        let summary = engine.summary(Duration::from_millis(1500));
+        assert_eq!(summary.processed["deposit"], 2);
+        assert_eq!(summary.processed["dispute"], 1);
+        assert_eq!(summary.rejected["dispute"], 1);
+        assert_eq!(summary.rejected[crate::metrics::UNREADABLE], 1);
+        assert_eq!((summary.processed_total, summary.rejected_total), (3, 2));
+        assert_eq!(summary.locked_accounts, 0);
+        assert_eq!(summary.open_disputes, 1);
+        assert_eq!(summary.high_water_mark, Some(20));
+        assert_eq!(summary.duration_secs, 1.5);
+    }
+
+    #[test]
+    fn open_disputes_lists_only_unsettled_disputes() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(16, 160, 1),
+                deposit_of(16, 161, 2),
+                deposit_of(17, 160, 4),
+                dispute_of(16, 160),
+                Ok(Transaction {
+                    kind: TransactionKind::Resolve,
+                    client_id: 16,
+                    transaction_id: 160,
+                    currency: None,
+                }),
+                partial_dispute_of(17, 160, 3),
+                dispute_of(16, 161),
+            ])
+            .unwrap();
+        assert_eq!(
+            engine.open_disputes(),
+            vec![(16, 161, Decimal::TWO), (17, 160, Decimal::from(3u32))]
+        );
+    }
+
+    #[test]
+    fn projected_available_sums_the_open_disputes() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(18, 180, 10),
+                deposit_of(18, 181, 5),
+                deposit_of(18, 182, 1),
+                Ok(Transaction {
+                    kind: TransactionKind::Withdrawal {
+                        amount: Decimal::from(3u32),
+                    },
+                    client_id: 18,
+                    transaction_id: 183,
+                    currency: None,
+                }),
+                dispute_of(18, 180),
+                partial_dispute_of(18, 181, 2),
+                dispute_of(18, 182),
+                Ok(Transaction {
+                    kind: TransactionKind::Resolve,
+                    client_id: 18,
+                    transaction_id: 182,
+                    currency: None,
+                }),
+            ])
+            .unwrap();
+
+        let repo = &engine.output_repository;
+        assert_eq!(
+            repo.projected_available(18),
+            Some((
+                Decimal::from(1u32),
+                Decimal::from(13u32),
+                Decimal::from(1u32)
+            ))
+        );
+        assert_eq!(repo.projected_available(19), None);
+    }
+
+    #[test]
+    fn verify_invariants_passes_after_normal_processing() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(16, 160, 10),
+                deposit_of(16, 161, 5),
+                dispute_of(16, 160),
+                dispute_of(16, 161),
+                Ok(Transaction {
+                    kind: TransactionKind::Resolve,
+                    client_id: 16,
+                    transaction_id: 161,
+                    currency: None,
+                }),
+            ])
+            .unwrap();
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn verify_invariants_catches_desynced_accounts() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(17, 170, 10),
+                dispute_of(17, 170),
+                deposit_of(18, 180, 3),
+            ])
+            .unwrap();
+
+        // Reach past the handlers to corrupt state.
+        engine
+            .output_repository
+            .get_or_create_account(&17)
+            .unwrap()
+            .held = Decimal::from(4u32);
+        engine
+            .output_repository
+            .get_or_create_account(&18)
+            .unwrap()
+            .total = Decimal::from(9u32);
+
+        let offenders = engine.verify_invariants().unwrap_err();
+        assert_eq!(
+            offenders,
+            vec![
+                Inconsistency::Total {
+                    client_id: 17,
+                    available: Decimal::ZERO,
+                    held: Decimal::from(4u32),
+                    total: Decimal::from(10u32),
+                },
+                Inconsistency::Held {
+                    client_id: 17,
+                    held: Decimal::from(4u32),
+                    disputed: Decimal::from(10u32),
+                    on_hold: Decimal::ZERO,
+                },
+                Inconsistency::Total {
+                    client_id: 18,
+                    available: Decimal::from(3u32),
+                    held: Decimal::ZERO,
+                    total: Decimal::from(9u32),
+                },
+            ]
+        );
+    }
+
+    fn in_currency(tx: Result<Transaction, Error>, currency: &str) -> Result<Transaction, Error> {
+        tx.map(|tx| Transaction {
+            currency: Some(currency.to_string()),
+            ..tx
+        })
+    }
+
+    #[test]
+    fn consistent_currency_is_accepted_and_established() {
+        let mut engine = mk_engine();
+        engine
+            .process_all(vec![
+                deposit_of(19, 190, 5), // untagged transactions do not establish a currency
+                in_currency(deposit_of(19, 191, 10), "USD"),
+                in_currency(deposit_of(19, 192, 1), "USD"),
+                deposit_of(19, 193, 2),
+            ])
+            .unwrap();
+
+        let acct = engine.output_repository.get_or_create_account(&19).unwrap();
+        assert_eq!(acct.currency.as_deref(), Some("USD"));
+        assert_eq!(acct.available, Decimal::from(18u32));
+    }
+
+    #[test]
+    fn mixed_currencies_for_a_client_are_rejected() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![
+                in_currency(deposit_of(20, 200, 10), "EUR"),
+                in_currency(deposit_of(20, 201, 7), "GBP"),
+                in_currency(deposit_of(21, 210, 3), "GBP"),
+            ])
+            .unwrap();
+
+        let acct = engine.output_repository.get_account(20).unwrap();
+        assert_eq!(acct.available, Decimal::from(10u32));
+        assert!(engine.output_repository.get_transaction(20, 201).is_none());
+        assert_eq!(
+            engine.dlq.0.lock().unwrap().as_slice(),
+            ["Engine failed with: Client 20 holds EUR, not GBP"]
+        );
+    }
+
+    #[test]
+    fn dispute_ahead_of_its_deposit_is_retried() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .retry_buffer(RetryBuffer::new(4, 3))
+            .build();
+        engine
+            .process_all(vec![dispute_of(21, 210), deposit_of(21, 210, 5)])
+            .unwrap();
+
+        let acct = engine.output_repository.get_account(21).unwrap();
+        assert_eq!(acct.held, Decimal::from(5u32));
+        assert_eq!(acct.available, Decimal::ZERO);
+        assert!(engine.output_repository.has_dispute(21, 210));
+        assert!(engine.dlq.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unresolved_and_overflowing_retries_go_to_the_dlq() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .retry_buffer(RetryBuffer::new(1, 3))
+            .build();
+        engine
+            .process_all(vec![
+                dispute_of(22, 220),
+                dispute_of(22, 221), // buffer full: rejected right away
+                deposit_of(22, 222, 1),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            engine.dlq.0.lock().unwrap().as_slice(),
+            [
+                "Engine failed with: Referenced transaction 221 not found",
+                "Engine failed with: Referenced transaction 220 not found",
+            ]
+        );
+    }
+
+    #[test]
+    fn without_a_retry_buffer_unknown_references_are_rejected() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .build();
+        engine
+            .process_all(vec![dispute_of(23, 230), deposit_of(23, 230, 5)])
+            .unwrap();
+
+        assert!(!engine.output_repository.has_dispute(23, 230));
+        assert_eq!(engine.dlq.0.lock().unwrap().len(), 1);
+    }
+
+    /// `MemoryOutput` that counts account lookups.
+    #[derive(Default)]
+    struct CountingOutput {
+        inner: MemoryOutput,
+        lookups: usize,
+    }
+
+    impl OutputRepository for CountingOutput {
+        type Amount = Decimal;
+
+        fn get_or_create_account(&mut self, client_id: &ClientId) -> Result<&mut Account, Error> {
+            self.lookups += 1;
+            self.inner.get_or_create_account(client_id)
+        }
+        fn get_account(&self, client_id: ClientId) -> Option<crate::domain::AccountSnapshot> {
+            self.inner.get_account(client_id)
+        }
+        fn accounts(&self) -> Vec<(ClientId, crate::domain::AccountSnapshot)> {
+            self.inner.accounts()
+        }
+        fn restore_account(&mut self, client_id: ClientId, account: Account) {
+            self.inner.restore_account(client_id, account)
+        }
+        fn clear(&mut self) {
+            self.inner.clear()
+        }
+        fn flush(&mut self) -> Result<(), Error> {
+            self.inner.flush()
+        }
+        fn report_transaction(
+            &mut self,
+            transaction_id: &u32,
+            tx: &Transaction,
+        ) -> Result<(), Error> {
+            self.inner.report_transaction(transaction_id, tx)
+        }
+        fn get_transaction(
+            &self,
+            client_id: ClientId,
+            transaction_id: u32,
+        ) -> Option<&Transaction> {
+            self.inner.get_transaction(client_id, transaction_id)
+        }
+        fn client_transactions(&self, client_id: ClientId) -> Vec<&Transaction> {
+            self.inner.client_transactions(client_id)
+        }
+        fn mark_transaction_disputed(
+            &mut self,
+            client_id: ClientId,
+            transaction_id: u32,
+            amount: Decimal,
+        ) {
+            self.inner
+                .mark_transaction_disputed(client_id, transaction_id, amount)
+        }
+        fn mark_transaction_resolved(&mut self, client_id: ClientId, transaction_id: u32) {
+            self.inner
+                .mark_transaction_resolved(client_id, transaction_id)
+        }
+        fn forget_transaction(&mut self, client_id: ClientId, transaction_id: u32) {
+            self.inner.forget_transaction(client_id, transaction_id)
+        }
+        fn disputed_amount(&self, client_id: ClientId, transaction_id: u32) -> Option<Decimal> {
+            self.inner.disputed_amount(client_id, transaction_id)
+        }
+        fn open_disputes(&self, client_id: ClientId) -> usize {
+            self.inner.open_disputes(client_id)
+        }
+        fn disputes(&self) -> Vec<(ClientId, u32, Decimal)> {
+            self.inner.disputes()
+        }
+    }
+
+    #[test]
+    fn each_transaction_looks_up_its_account_once() {
+        let referencing = |kind, transaction_id| {
+            Ok(Transaction {
+                kind,
+                client_id: 24,
+                transaction_id,
+                currency: None,
+            })
+        };
+        let txs = vec![
+            deposit_of(24, 240, 10),
+            deposit_of(24, 241, 5),
+            Ok(Transaction {
+                kind: TransactionKind::Withdrawal {
+                    amount: Decimal::from(100u32),
+                },
+                client_id: 24,
+                transaction_id: 242,
+                currency: None,
+            }),
+            deposit_of(24, 240, 1), // duplicate
+            dispute_of(24, 240),
+            referencing(TransactionKind::Resolve, 240),
+            dispute_of(24, 241),
+            referencing(TransactionKind::Chargeback, 241),
+            deposit_of(24, 243, 1), // locked
+        ];
+        let count = txs.len();
+        let mut engine = Engine::builder(NoopIngestion)
+            .output(CountingOutput::default())
+            .build();
+        engine.process_all(txs).unwrap();
+
+        assert_eq!(engine.output_repository.lookups, count);
+        assert!(engine.output_repository.get_account(24).unwrap().locked);
+    }
+
+    /// Runs `txs` through an engine keeping balances in `A` and returns the balances CSV.
+    fn balances_csv<A: Amount>(txs: &[(TransactionKind, ClientId, u32)]) -> String {
+        let output = crate::output_repository::WriterOutput::<_, A>::with_writer(Vec::new());
+        let mut engine = Engine::new(NoopIngestion, output, NoopDLQ);
+        engine
+            .process_all(txs.iter().map(|&(kind, client_id, transaction_id)| {
+                Ok(Transaction {
+                    kind,
+                    client_id,
+                    transaction_id,
+                    currency: None,
+                })
+            }))
+            .unwrap();
+        engine.verify_invariants().unwrap();
+        engine.flush().unwrap();
+        String::from_utf8(engine.output_repository().writer().clone()).unwrap()
+    }
+
+    #[test]
+    fn decimal_and_money_amounts_print_the_same_balances() {
+        let amount = |raw: &str| raw.parse::<Decimal>().unwrap();
+        let txs = [
+            (
+                TransactionKind::Deposit {
+                    amount: amount("10.1234"),
+                },
+                1,
+                1,
+            ),
+            (
+                TransactionKind::Withdrawal {
+                    amount: amount("2.5"),
+                },
+                1,
+                2,
+            ),
+            (
+                TransactionKind::Withdrawal {
+                    amount: amount("100"),
+                },
+                1,
+                3,
+            ), // bounces
+            (
+                TransactionKind::Dispute {
+                    amount: Some(amount("0.0001")),
+                },
+                1,
+                1,
+            ),
+            (
+                TransactionKind::Hold {
+                    amount: amount("1.25"),
+                },
+                1,
+                4,
+            ),
+            (
+                TransactionKind::Release {
+                    amount: amount("0.75"),
+                },
+                1,
+                5,
+            ),
+            (
+                TransactionKind::Deposit {
+                    amount: amount("3.00005"),
+                },
+                2,
+                6,
+            ), // rounded by Money
+            (
+                TransactionKind::Deposit {
+                    amount: amount("7"),
+                },
+                2,
+                7,
+            ),
+            (TransactionKind::Dispute { amount: None }, 2, 7),
+            (TransactionKind::Chargeback, 2, 7),
+            (
+                TransactionKind::Deposit {
+                    amount: amount("0.4"),
+                },
+                3,
+                8,
+            ),
+            (TransactionKind::Dispute { amount: None }, 3, 8),
+            (TransactionKind::Resolve, 3, 8),
+        ];
+
+        let decimal = balances_csv::<Decimal>(&txs);
+        assert_eq!(decimal, balances_csv::<crate::domain::Money>(&txs));
+        assert_eq!(
+            decimal,
+            "client,available,held,total,locked\n\
+             1,7.1233,0.5001,7.6234,false\n\
+             2,3.0000,0.0000,3.0000,true\n\
+             3,0.4000,0.0000,0.4000,false\n"
+        );
+    }
+
+    #[test]
+    fn amounts_out_of_range_for_money_are_rejected() {
+        let mut engine = Engine::new(
+            NoopIngestion,
+            crate::output_repository::WriterOutput::<_, crate::domain::Money>::with_writer(
+                std::io::sink(),
+            ),
+            RecordingDLQ::default(),
+        );
+        engine
+            .process_all(vec![
+                deposit_of(40, 400, 1),
+                Ok(Transaction {
+                    kind: TransactionKind::Deposit {
+                        amount: Decimal::from(i64::MAX),
+                    },
+                    client_id: 40,
+                    transaction_id: 401,
+                    currency: None,
+                }),
+            ])
+            .unwrap();
+
+        let rejected = engine.dlq.0.lock().unwrap();
+        assert_eq!(
+            *rejected,
+            vec![
+                "Engine failed with: Transaction 401 has an amount out of range for the \
+                 engine: 9223372036854775807"
+            ]
+        );
+        let account = engine.output_repository().get_account(40).unwrap();
+        assert_eq!(account.available.to_decimal(), Decimal::ONE);
+    }
+
+    #[test]
+    fn money_rejects_amounts_just_past_its_range() {
+        let mut engine = Engine::new(
+            NoopIngestion,
+            crate::output_repository::WriterOutput::<_, crate::domain::Money>::with_writer(
+                std::io::sink(),
+            ),
+            RecordingDLQ::default(),
+        );
+        let deposit = |client_id, transaction_id, amount| {
+            Ok(Transaction {
+                kind: TransactionKind::Deposit { amount },
+                client_id,
+                transaction_id,
+                currency: None,
+            })
+        };
+        // i64::MAX minor units, and one more
+        let largest = Decimal::new(i64::MAX, 4);
+        engine
+            .process_all(vec![
+                deposit(1, 1, largest),
+                deposit(2, 2, largest + Decimal::new(1, 4)),
+                deposit(1, 3, Decimal::new(1, 4)),
+            ])
+            .unwrap();
+
+        let rejected = engine.dlq.0.lock().unwrap();
+        assert_eq!(
+            *rejected,
+            vec![
+                "Engine failed with: Transaction 2 has an amount out of range for the \
+                 engine: 922337203685477.5808",
+                "Engine failed with: Client 1 balance would overflow",
+            ]
+        );
+        let account = engine.output_repository().get_account(1).unwrap();
+        assert_eq!(account.total.to_decimal(), largest);
+    }
+
+    #[test]
+    fn decimal_balances_at_the_limit_overflow_without_panicking() {
+        let mut engine = Engine::new(NoopIngestion, MemoryOutput::new(), RecordingDLQ::default());
+        let deposit = |client_id, transaction_id| {
+            Ok(Transaction {
+                kind: TransactionKind::Deposit {
+                    amount: Decimal::MAX,
+                },
+                client_id,
+                transaction_id,
+                currency: None,
+            })
+        };
+        engine
+            .process_all(vec![deposit(1, 1), deposit(2, 2), deposit(1, 3)])
+            .unwrap();
+        engine.flush().unwrap();
+
+        let rejected = engine.dlq.0.lock().unwrap();
+        assert_eq!(
+            *rejected,
+            vec!["Engine failed with: Client 1 balance would overflow"]
+        );
+        assert!(engine.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn change_feed_emits_each_balance_change_in_order() {
+        let output =
+            crate::output_repository::WriterOutput::<_>::with_writer(Vec::new()).with_change_feed();
+        let mut engine = Engine::new(NoopIngestion, output, NoopDLQ);
+        engine
+            .process_all(vec![
+                deposit_of(50, 500, 10),
+                Ok(Transaction {
+                    kind: TransactionKind::Withdrawal {
+                        amount: Decimal::from(4u32),
+                    },
+                    client_id: 50,
+                    transaction_id: 501,
+                    currency: None,
+                }),
+                dispute_of(50, 999), // rejected: no row
+            ])
+            .unwrap();
+        engine.flush().unwrap();
+
+        let feed = String::from_utf8(engine.output_repository().writer().clone()).unwrap();
+        assert_eq!(
+            feed,
+            "client,available,held,total,locked\n\
+             50,10.0000,0.0000,10.0000,false\n\
+             50,6.0000,0.0000,6.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn spilling_accounts_to_disk_does_not_change_the_balances() {
+        let inputs = || {
+            let mut inputs = Vec::new();
+            for round in 0..3u32 {
+                for (client_id, tx) in (70..75).zip(70..) {
+                    inputs.push(deposit_of(client_id, round * 100 + tx, 5));
+                }
+            }
+            inputs.extend([
+                dispute_of(71, 71),
+                dispute_of(72, 172),
+                Ok(Transaction {
+                    kind: TransactionKind::Chargeback,
+                    client_id: 72,
+                    transaction_id: 172,
+                    currency: None,
+                }),
+                in_currency(deposit_of(73, 900, 1), "EUR"),
+                in_currency(deposit_of(73, 901, 1), "GBP"), // rejected once 73 is read back
+                admin(TransactionKind::Freeze, 74),
+                deposit_of(74, 902, 1), // rejected: frozen
+            ]);
+            inputs
+        };
+        let run = |output: crate::output_repository::WriterOutput<Vec<u8>>| {
+            let mut engine = Engine::new(NoopIngestion, output, NoopDLQ);
+            engine.process_all(inputs()).unwrap();
+            assert_eq!(engine.verify_invariants(), Ok(()));
+            engine.flush().unwrap();
+            let accounts = engine.output_repository().accounts();
+            assert_eq!(engine.output_repository().account_count(), accounts.len());
+            let balances = String::from_utf8(engine.output_repository().writer().clone());
+            (accounts, balances.unwrap())
+        };
+
+        let unbounded = run(crate::output_repository::WriterOutput::with_writer(
+            Vec::new(),
+        ));
+        let spill = crate::spill::AccountSpill::new(2).unwrap();
+        let capped =
+            run(crate::output_repository::WriterOutput::with_writer(Vec::new()).with_spill(spill));
+        assert_eq!(capped, unbounded);
+        assert!(unbounded.1.contains("72,10.0000,0.0000,10.0000,true"));
+        assert!(unbounded.1.contains("73,16.0000,0.0000,16.0000,false"));
+    }
+
+    #[test]
+    fn conservation_check_balances_deposits_withdrawals_and_chargebacks() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .policy(Policy {
+                allow_withdrawal_disputes: true,
+                ..Default::default()
+            })
+            .build();
+        let accounts = crate::snapshot::read_accounts(
+            "client,available,held,total,locked\n60,2.5,0,2.5,false\n".as_bytes(),
+        )
+        .unwrap();
+        engine.restore(accounts, false).unwrap();
+        let withdrawal = |client_id, transaction_id, amount: u32| {
+            Ok(Transaction {
+                kind: TransactionKind::Withdrawal {
+                    amount: Decimal::from(amount),
+                },
+                client_id,
+                transaction_id,
+                currency: None,
+            })
+        };
+        let chargeback = |client_id, transaction_id| {
+            Ok(Transaction {
+                kind: TransactionKind::Chargeback,
+                client_id,
+                transaction_id,
+                currency: None,
+            })
+        };
+        engine
+            .process_all(vec![
+                deposit_of(60, 600, 10),
+                withdrawal(60, 601, 3),
+                withdrawal(60, 602, 100), // bounces
+                deposit_of(61, 610, 7),
+                partial_dispute_of(61, 610, 2),
+                chargeback(61, 610),
+                withdrawal(60, 603, 1),
+                dispute_of(60, 603),
+                chargeback(60, 603), // hands the withdrawal back
+            ])
+            .unwrap();
+
+        assert!(engine.conservation_check().is_ok());
+        let sixty = engine.output_repository().get_account(60).unwrap();
+        assert_eq!(sixty.total, Decimal::new(95, 1));
+        assert_eq!(
+            engine.output_repository().get_account(61).unwrap().total,
+            5.into()
+        );
+    }
+
+    #[test]
+    fn conservation_check_catches_money_made_out_of_thin_air() {
+        let mut engine = mk_engine();
+        engine.process_all(vec![deposit_of(62, 620, 10)]).unwrap();
+        assert!(engine.conservation_check().is_ok());
+
+        // stands in for a handler that forgets to take funds out of an account
+        let account = engine.output_repository.get_or_create_account(&62).unwrap();
+        account.available += Decimal::ONE;
+        account.sync_total();
+
+        let err = engine.conservation_check().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Engine failed with: Account totals sum to 11, but the funds that came in net to 10"
+        );
+        assert!(engine.flush().is_err());
+    }
+
+    #[test]
+    fn dispute_window_prunes_the_ledger_but_keeps_recent_and_open_disputes() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .policy(Policy {
+                dispute_window: Some(2),
+                ..Default::default()
+            })
+            .build();
+        let settle = |kind, transaction_id| {
+            Ok(Transaction {
+                kind,
+                client_id: 70,
+                transaction_id,
+                currency: None,
+            })
+        };
+        engine
+            .process_all(vec![
+                deposit_of(70, 700, 1),
+                dispute_of(70, 700),
+                deposit_of(70, 701, 1),
+                deposit_of(70, 702, 1),
+                deposit_of(70, 703, 1),
+                deposit_of(70, 704, 1),
+            ])
+            .unwrap();
+        let ids = |engine: &Engine<_, MemoryOutput, _>| {
+            engine
+                .output_repository()
+                .client_transactions(70)
+                .iter()
+                .map(|tx| tx.transaction_id)
+                .collect::<Vec<_>>()
+        };
+        // 700 is out of the window but still disputed
+        assert_eq!(ids(&engine), vec![700, 703, 704]);
+
+        engine
+            .process_all(vec![
+                dispute_of(70, 701), // pruned
+                dispute_of(70, 703),
+                settle(TransactionKind::Resolve, 700),
+                settle(TransactionKind::Resolve, 703),
+            ])
+            .unwrap();
+        assert_eq!(ids(&engine), vec![703, 704]);
+        assert_eq!(
+            *engine.dlq.0.lock().unwrap(),
+            vec!["Engine failed with: Referenced transaction 701 not found"]
+        );
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn dispute_window_still_rejects_replays_of_pruned_ids() {
+        let mut engine = Engine::builder(NoopIngestion)
+            .dlq(RecordingDLQ::default())
+            .policy(Policy {
+                dispute_window: Some(1),
+                ..Default::default()
+            })
+            .build();
+        engine
+            .process_all(vec![
+                deposit_of(71, 1, 5),
+                deposit_of(71, 2, 1),
+                deposit_of(71, 3, 1),
+                deposit_of(71, 1, 5), // 1 was pruned
+            ])
+            .unwrap();
+
+        assert_eq!(
+            engine.output_repository().get_account(71).unwrap().total,
+            Decimal::from(7)
+        );
+        assert_eq!(
+            *engine.dlq.0.lock().unwrap(),
+            vec!["Engine failed with: Transaction ID 1 already exists"]
+        );
+    }
+
+    mod properties {
+        use super::RecordingDLQ;
+        use crate::domain::{ClientId, OutputRepository, Transaction, TransactionKind};
+        use crate::engine::Engine;
+        use crate::policy::Policy;
+        use proptest::prelude::*;
+        use rust_decimal::Decimal;
+        use std::collections::{BTreeMap, VecDeque};
+
+        const CLIENTS: ClientId = 4;
+
+        /// One generated action. Indices pick among the transactions that the action
+        /// can validly apply to at that point, so every sequence shrinks to another
+        /// valid one.
+        #[derive(Debug, Clone)]
+        enum Step {
+            Deposit {
+                client_id: ClientId,
+                cents: i64,
+            },
+            Withdrawal {
+                client_id: ClientId,
+                cents: i64,
+            },
+            Dispute(usize),
+            /// Disputes a transaction again while it is under dispute, partially or not,
+            /// which the engine must reject.
+            Redispute {
+                pick: usize,
+                partial: bool,
+            },
+            Resolve(usize),
+            Chargeback(usize),
+        }
+
+        fn step() -> impl Strategy<Value = Step> {
+            prop_oneof![
+                3 => (1..=CLIENTS, 1i64..100_000)
+                    .prop_map(|(client_id, cents)| Step::Deposit { client_id, cents }),
+                2 => (1..=CLIENTS, 1i64..100_000)
+                    .prop_map(|(client_id, cents)| Step::Withdrawal { client_id, cents }),
+                2 => any::<usize>().prop_map(Step::Dispute),
+                1 => (any::<usize>(), any::<bool>())
+                    .prop_map(|(pick, partial)| Step::Redispute { pick, partial }),
+                1 => any::<usize>().prop_map(Step::Resolve),
+                1 => any::<usize>().prop_map(Step::Chargeback),
+            ]
+        }
+
+        #[derive(Debug, Default, Clone, Copy, PartialEq)]
+        struct Balances {
+            available: Decimal,
+            held: Decimal,
+            total: Decimal,
+            locked: bool,
+        }
+
+        struct Transfer {
+            client_id: ClientId,
+            transaction_id: u32,
+            // negative for a withdrawal
+            amount: Decimal,
+            disputed: bool,
+        }
+
+        /// A deliberately naive account model, kept apart from the engine's handlers.
+        #[derive(Default)]
+        struct Model {
+            balances: BTreeMap<ClientId, Balances>,
+            transfers: Vec<Transfer>,
+            net: Decimal,
+            charged_back: Decimal,
+            // what the engine must report for the steps planned to be rejected
+            rejections: Vec<String>,
+        }
+
+        impl Model {
+            fn balances(&mut self, client_id: ClientId) -> &mut Balances {
+                self.balances.entry(client_id).or_default()
+            }
+
+            fn is_locked(&self, client_id: ClientId) -> bool {
+                self.balances.get(&client_id).is_some_and(|b| b.locked)
+            }
+
+            /// Turns `step` into a transaction the engine must accept, or for a
+            /// `Redispute` reject as noted in `rejections`, or `None` when nothing fits it. Withdrawals stay within both the available funds
+            /// and the total, and a deposit is only charged back while the total covers
+            /// it, so no account can end up below zero.
+            fn plan(
+                &mut self,
+                step: &Step,
+                allow_withdrawal_disputes: bool,
+            ) -> Option<Transaction> {
+                let transaction_id = u32::try_from(self.transfers.len()).unwrap() + 1;
+                let (client_id, kind) = match *step {
+                    Step::Deposit { client_id, cents } if !self.is_locked(client_id) => {
+                        let amount = Decimal::new(cents, 2);
+                        self.transfer(client_id, transaction_id, amount);
+                        (client_id, TransactionKind::Deposit { amount })
+                    }
+                    Step::Withdrawal { client_id, cents } if !self.is_locked(client_id) => {
+                        let b = self.balances(client_id);
+                        let amount = Decimal::new(cents, 2).min(b.available).min(b.total);
+                        if amount <= Decimal::ZERO {
+                            return None;
+                        }
+                        self.transfer(client_id, transaction_id, -amount);
+                        (client_id, TransactionKind::Withdrawal { amount })
+                    }
+                    Step::Dispute(pick) => {
+                        let i = self.pick(pick, |model, t| {
+                            !t.disputed
+                                && (t.amount.is_sign_positive() || allow_withdrawal_disputes)
+                                && !model.is_locked(t.client_id)
+                        })?;
+                        let t = &mut self.transfers[i];
+                        t.disputed = true;
+                        let (client_id, id, amount) = (t.client_id, t.transaction_id, t.amount);
+                        let b = self.balances(client_id);
+                        b.available -= amount;
+                        b.held += amount;
+                        return Some(reference(
+                            client_id,
+                            id,
+                            TransactionKind::Dispute { amount: None },
+                        ));
+                    }
+                    Step::Redispute { pick, partial } => {
+                        let i = self.pick(pick, |_, t| t.disputed)?;
+                        let t = &self.transfers[i];
+                        let (client_id, id, amount) = (t.client_id, t.transaction_id, t.amount);
+                        // a locked account turns it away before the dispute is looked at
+                        self.rejections.push(if self.is_locked(client_id) {
+                            format!("Engine failed with: {} account is locked", client_id)
+                        } else {
+                            format!("Engine failed with: Transaction {} is already disputed", id)
+                        });
+                        let amount = partial.then(|| amount.abs());
+                        return Some(reference(
+                            client_id,
+                            id,
+                            TransactionKind::Dispute { amount },
+                        ));
+                    }
+                    Step::Resolve(pick) => {
+                        let i = self.pick(pick, |_, t| t.disputed)?;
+                        let t = &mut self.transfers[i];
+                        t.disputed = false;
+                        let (client_id, id, amount) = (t.client_id, t.transaction_id, t.amount);
+                        let b = self.balances(client_id);
+                        b.available += amount;
+                        b.held -= amount;
+                        return Some(reference(client_id, id, TransactionKind::Resolve));
+                    }
+                    Step::Chargeback(pick) => {
+                        let i = self.pick(pick, |model, t| {
+                            t.disputed && model.balances[&t.client_id].total >= t.amount
+                        })?;
+                        let t = &mut self.transfers[i];
+                        t.disputed = false;
+                        let (client_id, id, amount) = (t.client_id, t.transaction_id, t.amount);
+                        self.charged_back += amount;
+                        let b = self.balances(client_id);
+                        b.held -= amount;
+                        b.total -= amount;
+                        b.locked = true;
+                        return Some(reference(client_id, id, TransactionKind::Chargeback));
+                    }
+                    _ => return None,
+                };
+                Some(Transaction {
+                    kind,
+                    client_id,
+                    transaction_id,
+                    currency: None,
+                })
+            }
+
+            fn transfer(&mut self, client_id: ClientId, transaction_id: u32, amount: Decimal) {
+                self.transfers.push(Transfer {
+                    client_id,
+                    transaction_id,
+                    amount,
+                    disputed: false,
+                });
+                self.net += amount;
+                let b = self.balances(client_id);
+                b.available += amount;
+                b.total += amount;
+            }
+
+            /// Index of the `pick`th transfer, modulo the eligible ones.
+            fn pick(
+                &self,
+                pick: usize,
+                eligible: impl Fn(&Self, &Transfer) -> bool,
+            ) -> Option<usize> {
+                let candidates: Vec<_> = (0..self.transfers.len())
+                    .filter(|&i| eligible(self, &self.transfers[i]))
+                    .collect();
+                (!candidates.is_empty()).then(|| candidates[pick % candidates.len()])
+            }
+        }
+
+        /// A dispute, resolve or chargeback of `transaction_id`.
+        fn reference(
+            client_id: ClientId,
+            transaction_id: u32,
+            kind: TransactionKind,
+        ) -> Transaction {
+            Transaction {
+                kind,
+                client_id,
+                transaction_id,
+                currency: None,
+            }
+        }
+
+        /// Rows of any kind for any client, many of which the engine rejects.
+        fn noise() -> impl Strategy<Value = Transaction> {
+            (1..=CLIENTS, 1u32..100, 0..4u8, 1i64..100_000).prop_map(
+                |(client_id, transaction_id, kind, cents)| {
+                    let amount = Decimal::new(cents, 2);
+                    Transaction {
+                        kind: match kind {
+                            0 => TransactionKind::Withdrawal { amount },
+                            1 => TransactionKind::Dispute { amount: None },
+                            2 => TransactionKind::Resolve,
+                            _ => TransactionKind::Chargeback,
+                        },
+                        client_id,
+                        transaction_id,
+                        currency: None,
+                    }
+                },
+            )
+        }
+
+        /// Merges the rows of `txs` client by client in the order `picks` choose. Each
+        /// pick takes the next row of one client, so every client's own rows keep their
+        /// order and only the interleaving across clients changes.
+        fn interleave(txs: &[Transaction], picks: &[usize]) -> Vec<Transaction> {
+            let mut queues: BTreeMap<ClientId, VecDeque<Transaction>> = BTreeMap::new();
+            for tx in txs {
+                queues
+                    .entry(tx.client_id)
+                    .or_default()
+                    .push_back(tx.clone());
+            }
+            let mut picks = picks.iter().copied().chain(std::iter::repeat(0));
+            let mut merged = Vec::with_capacity(txs.len());
+            while !queues.is_empty() {
+                let clients: Vec<ClientId> = queues.keys().copied().collect();
+                let client_id = clients[picks.next().unwrap_or(0) % clients.len()];
+                let queue = queues.get_mut(&client_id).unwrap();
+                merged.extend(queue.pop_front());
+                if queue.is_empty() {
+                    queues.remove(&client_id);
+                }
+            }
+            merged
+        }
+
+        /// The balances CSV and the rejections, sorted as only their set is comparable
+        /// across interleavings.
+        fn outcome(txs: Vec<Transaction>) -> (String, Vec<String>) {
+            let output = crate::output_repository::WriterOutput::<_>::with_writer(Vec::new());
+            let mut engine = Engine::new(super::NoopIngestion, output, RecordingDLQ::default());
+            engine.process_all(txs.into_iter().map(Ok)).unwrap();
+            engine.flush().unwrap();
+            let mut rejected = engine.dlq.0.lock().unwrap().clone();
+            rejected.sort();
+            let balances = String::from_utf8(engine.output_repository().writer().clone());
+            (balances.unwrap(), rejected)
+        }
+
+        /// Reordering one client's own rows is not allowed: here it turns a funded
+        /// withdrawal into a rejected one.
+        #[test]
+        fn reordering_within_a_client_changes_the_outcome() {
+            let row = |kind, transaction_id| Transaction {
+                kind,
+                client_id: 1,
+                transaction_id,
+                currency: None,
+            };
+            let amount = Decimal::TEN;
+            let txs = vec![
+                row(TransactionKind::Deposit { amount }, 1),
+                row(TransactionKind::Withdrawal { amount }, 2),
+            ];
+            let mut swapped = txs.clone();
+            swapped.swap(0, 1);
+
+            let (in_order, rejected) = outcome(txs);
+            assert!(rejected.is_empty());
+            let (reordered, rejected) = outcome(swapped);
+            assert_eq!(
+                rejected,
+                ["Engine failed with: Insufficient funds for client 1"]
+            );
+            assert_ne!(in_order, reordered);
+        }
+
+        proptest! {
+            /// The ordering contract: rows apply in input order per client, and clients
+            /// never affect each other, so any interleaving that keeps each client's rows
+            /// in order gives the same balances and rejections.
+            #[test]
+            fn cross_client_interleavings_give_the_same_outcome(
+                steps in prop::collection::vec(step(), 0..64),
+                noise in prop::collection::vec(noise(), 0..16),
+                picks in prop::collection::vec(any::<usize>(), 80),
+            ) {
+                let mut model = Model::default();
+                let mut txs: Vec<_> = steps
+                    .iter()
+                    .filter_map(|step| model.plan(step, false))
+                    .collect();
+                // spread the noise through the valid rows
+                for (i, tx) in noise.into_iter().enumerate() {
+                    let at = (i * 7) % (txs.len() + 1);
+                    txs.insert(at, tx);
+                }
+
+                let reordered = interleave(&txs, &picks);
+                prop_assert_eq!(outcome(txs), outcome(reordered));
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn funds_are_conserved_over_valid_sequences(
+                steps in prop::collection::vec(step(), 0..64),
+                allow_withdrawal_disputes in any::<bool>(),
+            ) {
+                let mut model = Model::default();
+                let txs: Vec<_> = steps
+                    .iter()
+                    .filter_map(|step| model.plan(step, allow_withdrawal_disputes))
+                    .map(Ok)
+                    .collect();
+
+                let mut engine = Engine::builder(super::NoopIngestion)
+                    .dlq(RecordingDLQ::default())
+                    .policy(Policy {
+                        allow_withdrawal_disputes,
+                        ..Default::default()
+                    })
+                    .build();
+                engine.process_all(txs).unwrap();
+
+                prop_assert_eq!(engine.dlq.0.lock().unwrap().clone(), model.rejections.clone());
+                let mut sum = Decimal::ZERO;
+                for (client_id, account) in engine.output_repository().accounts() {
+                    prop_assert_eq!(account.available + account.held, account.total);
+                    prop_assert!(account.total >= Decimal::ZERO, "client {} overdrawn", client_id);
+                    let found = Balances {
+                        available: account.available,
+                        held: account.held,
+                        total: account.total,
+                        locked: account.locked,
+                    };
+                    prop_assert_eq!(found, model.balances[&client_id], "client {}", client_id);
+                    sum += account.total;
+                }
+                prop_assert_eq!(sum, model.net - model.charged_back);
+                prop_assert!(engine.conservation_check().is_ok());
+                prop_assert_eq!(engine.verify_invariants(), Ok(()));
+            }
+        }
     }
 }
@@ -1,102 +1,669 @@
-use std::io::Read;
+pub mod dir;
+pub mod json;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod tcp;
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, Read};
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-use futures::stream::{self, Stream};
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
 use rust_decimal::Decimal;
 use serde::Deserialize;
 
 use crate::domain::traits::TransactionStream;
-use crate::domain::{Error, Transaction, TransactionKind};
+use crate::domain::{ClientId, Error, Transaction, TransactionKind};
+
+/// Knobs for how input records are interpreted.
+#[derive(Debug, Clone, Default)]
+pub struct IngestionOptions {
+    /// Accept `1e3` and `1,000.50` style amounts in addition to plain decimals.
+    pub lenient_amounts: bool,
+    /// Drop records whose `type` is not a known transaction kind instead of reporting
+    /// them, e.g. comment rows in hand-edited files.
+    pub skip_unknown: bool,
+    /// Field separator of CSV files; `,` when `None`.
+    pub delimiter: Option<u8>,
+    /// Read amounts as `1,50` for one and a half, with `.` as the thousands separator
+    /// under `lenient_amounts`. Needs a `delimiter` other than `,`.
+    pub decimal_comma: bool,
+    /// Skip CSV lines starting with this byte, e.g. `#` for hand-written notes. Only the
+    /// very first byte counts: an indented comment is still read as a record.
+    pub comment: Option<u8>,
+    /// Retry a failed read of CSV input, e.g. on a flaky network mount, instead of
+    /// ending the input at the first I/O error. Each failure is still reported.
+    pub continue_on_io_error: bool,
+}
+
+/// Failed reads in a row after which the input ends even with `continue_on_io_error`:
+/// the reader is then taken to be broken for good rather than flaky.
+pub const MAX_CONSECUTIVE_IO_ERRORS: usize = 3;
+
+impl IngestionOptions {
+    /// Field separator of CSV files.
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter.unwrap_or(b',')
+    }
+}
+
+/// Layout of a file input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// JSON lines, or one JSON array of records.
+    Json,
+    /// Pick `Json` or `Csv` from the first non-blank byte, see `sniff_format`.
+    Auto,
+}
+
+impl FromStr for InputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            "auto" => Ok(InputFormat::Auto),
+            other => Err(Error::Args(format!("Unknown input format: {}", other))),
+        }
+    }
+}
+
+/// Byte order mark that spreadsheet exports often put in front of UTF-8 text.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Guesses the format from the first non-blank byte: `{` or `[` means JSON, anything
+/// else CSV. Only a leading byte order mark and whitespace are consumed, which neither
+/// reader needs.
+pub fn sniff_format<R: BufRead>(reader: &mut R) -> Result<InputFormat, Error> {
+    if reader.fill_buf()?.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(InputFormat::Csv);
+        }
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(blank) => {
+                let json = matches!(buf[blank], b'{' | b'[');
+                reader.consume(blank);
+                return Ok(if json {
+                    InputFormat::Json
+                } else {
+                    InputFormat::Csv
+                });
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Whether `kind` names a transaction type the engine understands, in any case.
+fn is_known_type(kind: &str) -> bool {
+    matches!(
+        kind.trim().to_ascii_lowercase().as_str(),
+        "deposit"
+            | "withdrawal"
+            | "dispute"
+            | "resolve"
+            | "chargeback"
+            | "freeze"
+            | "unfreeze"
+            | "hold"
+            | "release"
+    )
+}
+
+/// True for records that `skip_unknown` asks to drop.
+fn skipped(kind: Option<&str>, options: &IngestionOptions) -> bool {
+    let skip = options.skip_unknown && kind.is_some_and(|kind| !is_known_type(kind));
+    if skip {
+        tracing::debug!(kind, "skipping record with unknown type");
+    }
+    skip
+}
+
+/// Drops every record up to and including the deposit or withdrawal that introduced
+/// `transaction_id`, so a restarted run resumes right after an engine's
+/// `high_water_mark`. Passes everything through when `transaction_id` is `None`.
+pub struct SkipUntil<I> {
+    inner: I,
+    transaction_id: Option<u32>,
+}
+
+impl<I: TransactionStream> SkipUntil<I> {
+    pub fn new(inner: I, transaction_id: Option<u32>) -> Self {
+        Self {
+            inner,
+            transaction_id,
+        }
+    }
+}
+
+impl<I: TransactionStream> TransactionStream for SkipUntil<I> {
+    type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
+        let inner = self.inner.stream()?;
+        let Some(until) = self.transaction_id else {
+            return Ok(Box::pin(inner));
+        };
+
+        let mut found = false;
+        Ok(Box::pin(inner.skip_while(move |row| {
+            // The marker itself is skipped too: `found` only takes effect on the next row.
+            let skip = !found;
+            found = introduces(row, until);
+            future::ready(skip)
+        })))
+    }
+}
+
+/// Ends the input right after the deposit or withdrawal that introduced
+/// `transaction_id`, so the balances show the state at that point. The input is read in
+/// full if it never appears, see `reached`. Passes everything through when
+/// `transaction_id` is `None`.
+pub struct ReplayUntil<I> {
+    inner: I,
+    transaction_id: Option<u32>,
+    reached: Arc<AtomicBool>,
+}
+
+impl<I: TransactionStream> ReplayUntil<I> {
+    pub fn new(inner: I, transaction_id: Option<u32>) -> Self {
+        Self {
+            inner,
+            transaction_id,
+            reached: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the stream has handed out the record that introduced `transaction_id`.
+    pub fn reached(&self) -> bool {
+        self.reached.load(Ordering::Relaxed)
+    }
+}
+
+impl<I: TransactionStream> TransactionStream for ReplayUntil<I> {
+    type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
+        let inner = self.inner.stream()?;
+        let Some(until) = self.transaction_id else {
+            return Ok(Box::pin(inner));
+        };
+
+        let reached = Arc::clone(&self.reached);
+        // Unlike `take_while`, stops without waiting for the record after the marker,
+        // which may never come over `--listen`.
+        Ok(Box::pin(stream::unfold(
+            (inner, false),
+            move |(mut inner, done)| {
+                let reached = Arc::clone(&reached);
+                async move {
+                    if done {
+                        return None;
+                    }
+                    let row = inner.next().await?;
+                    let done = introduces(&row, until);
+                    if done {
+                        reached.store(true, Ordering::Relaxed);
+                    }
+                    Some((row, (inner, done)))
+                }
+            },
+        )))
+    }
+}
 
+/// Whether `row` is the deposit or withdrawal that introduced `transaction_id`, rather
+/// than e.g. a dispute naming it.
+fn introduces(row: &Result<Transaction, Error>, until: u32) -> bool {
+    matches!(
+        row,
+        Ok(Transaction {
+            kind: TransactionKind::Deposit { .. } | TransactionKind::Withdrawal { .. },
+            transaction_id,
+            ..
+        }) if *transaction_id == until
+    )
+}
+
+/// Reads transactions from CSV with a header row. A header with no rows after it is
+/// valid input that yields no transactions; input without a header, including an
+/// empty file, is rejected when the reader is created. A leading UTF-8 byte order mark
+/// is skipped by the csv crate, and lines may end in LF or CRLF. A failed read is
+/// reported and ends the input, unless `continue_on_io_error` has it retried.
+///
+/// An optional `idempotency_key` column is honoured as described at `IdempotencyKeys`.
 pub struct CsvReader<R: Read> {
-    reader: Option<csv::Reader<R>>,
+    reader: Option<csv::Reader<RetryingReader<R>>>,
+    // read failures that were retried, still to be reported
+    failures: Arc<Mutex<Vec<io::Error>>>,
+    columns: Columns,
+    options: IngestionOptions,
+    keys: IdempotencyKeys,
+}
+
+/// Retries failed reads of `inner` when asked to, keeping each failure for the stream to
+/// report. A failure it passes on makes the csv reader end the input for good.
+struct RetryingReader<R> {
+    inner: R,
+    retry: bool,
+    failures: Arc<Mutex<Vec<io::Error>>>,
+}
+
+impl<R: Read> Read for RetryingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut failed = 0;
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if self.retry && failed + 1 < MAX_CONSECUTIVE_IO_ERRORS => {
+                    tracing::debug!(error = %e, "retrying a failed read");
+                    failed += 1;
+                    self.failures
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(e);
+                }
+                read => return read,
+            }
+        }
+    }
 }
 
 impl<R: Read> CsvReader<R> {
     pub fn new(reader: R) -> Result<Self, Error> {
-        let rdr = csv::ReaderBuilder::new()
+        Self::with_options(reader, IngestionOptions::default())
+    }
+
+    pub fn with_options(reader: R, options: IngestionOptions) -> Result<Self, Error> {
+        if options.decimal_comma && options.delimiter() == b',' {
+            return Err(Error::Ingestion(
+                "Decimal commas need a delimiter other than ','".to_string(),
+            ));
+        }
+        let failures = Arc::default();
+        let reader = RetryingReader {
+            inner: reader,
+            retry: options.continue_on_io_error,
+            failures: Arc::clone(&failures),
+        };
+        let mut rdr = csv::ReaderBuilder::new()
             .trim(csv::Trim::All)
             .flexible(true)
+            .delimiter(options.delimiter())
+            .comment(options.comment)
             .from_reader(reader);
 
-        Ok(Self { reader: Some(rdr) })
+        // Header names are matched case-insensitively, so `Type,Client,Tx,Amount` works too.
+        let headers: csv::StringRecord = rdr
+            .headers()
+            .map_err(|e| Error::Ingestion(format!("CSV header error: {}", e)))?
+            .iter()
+            .map(|h| h.trim().to_ascii_lowercase())
+            .collect();
+        tracing::debug!(?headers, "reading CSV input");
+        if headers.is_empty() {
+            return Err(Error::Ingestion(
+                "CSV input is empty, expected a header row".to_string(),
+            ));
+        }
+        let columns = Columns::find(&headers)?;
+        rdr.set_headers(headers);
+
+        Ok(Self {
+            reader: Some(rdr),
+            failures,
+            columns,
+            options,
+            keys: IdempotencyKeys::default(),
+        })
+    }
+
+    /// Has this reader drop replays of keys read by the other readers given `keys`,
+    /// e.g. the other files of one directory input.
+    fn sharing_keys(self, keys: IdempotencyKeys) -> Self {
+        Self { keys, ..self }
     }
 }
 
-/// Internal shape used only for CSV deserialization.
+/// Where each known column sits in the header, so columns may come in any order and
+/// unknown ones are ignored.
+#[derive(Debug, Clone, Copy)]
+struct Columns {
+    kind: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    currency: Option<usize>,
+    idempotency_key: Option<usize>,
+}
+
+impl Columns {
+    fn find(headers: &csv::StringRecord) -> Result<Self, Error> {
+        let position = |name: &str| headers.iter().position(|h| h == name);
+        let (kind, client, tx) = match (position("type"), position("client"), position("tx")) {
+            (Some(kind), Some(client), Some(tx)) => (kind, client, tx),
+            _ => {
+                let missing: Vec<_> = ["type", "client", "tx"]
+                    .into_iter()
+                    .filter(|name| position(name).is_none())
+                    .collect();
+                return Err(Error::Ingestion(format!(
+                    "CSV input is missing required columns: {}",
+                    missing.join(", ")
+                )));
+            }
+        };
+
+        Ok(Self {
+            kind,
+            client,
+            tx,
+            amount: position("amount"),
+            currency: position("currency"),
+            idempotency_key: position("idempotency_key"),
+        })
+    }
+
+    fn row(&self, record: &csv::StringRecord) -> Result<CsvRow, Error> {
+        let optional = |index: Option<usize>| {
+            index
+                .and_then(|i| record.get(i))
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+
+        Ok(CsvRow {
+            kind: record.get(self.kind).unwrap_or_default().to_string(),
+            client: parse_field(record, self.client, "client")?,
+            tx: parse_field(record, self.tx, "tx")?,
+            amount: optional(self.amount),
+            currency: optional(self.currency),
+            idempotency_key: optional(self.idempotency_key),
+        })
+    }
+}
+
+fn parse_field<T: FromStr>(record: &csv::StringRecord, index: usize, name: &str) -> Result<T, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    let raw = record.get(index).unwrap_or_default();
+    raw.parse().map_err(|e| {
+        let line = record.position().map_or(0, |p| p.line());
+        Error::Ingestion(format!(
+            "CSV deserialization error: line {}, field {}: {:?}: {}",
+            line, name, raw, e
+        ))
+    })
+}
+
+/// Internal shape of one input record, whatever the format.
+///
+/// The amount is kept as raw text; going through serde's number inference would round
+/// long amounts through `f64` and silently accept exponents.
 #[derive(Debug, Deserialize)]
 struct CsvRow {
     #[serde(rename = "type")]
     kind: String,
-    client: u16,
+    client: ClientId,
     tx: u32,
-    amount: Option<Decimal>,
+    amount: Option<String>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
 }
 
-impl TryFrom<CsvRow> for Transaction {
-    type Error = Error;
+/// Idempotency keys read so far, in any input format. A row whose key was already read
+/// is the same logical operation as that earlier row and is dropped, whatever its `tx`,
+/// which stays the id that disputes reference. Only keys of rows that parsed count, so
+/// a corrected resend of a bad row still goes through. Every key is kept for the whole
+/// input, and rows without a key are never deduplicated. Clones share their keys.
+#[derive(Debug, Clone, Default)]
+struct IdempotencyKeys(Arc<Mutex<HashSet<String>>>);
 
-    fn try_from(row: CsvRow) -> Result<Self, Self::Error> {
-        let kind = match (row.kind.trim().to_ascii_lowercase().as_str(), row.amount) {
+impl IdempotencyKeys {
+    /// `row` as a transaction under `options`, or `None` when it replays a key.
+    fn transaction(
+        &self,
+        row: CsvRow,
+        options: &IngestionOptions,
+    ) -> Option<Result<Transaction, Error>> {
+        let key = row.idempotency_key.clone().filter(|key| !key.is_empty());
+        let tx = row.into_transaction(options);
+        match key {
+            Some(key) if tx.is_ok() && !self.insert(&key) => {
+                tracing::debug!(key, "dropping a replayed idempotency key");
+                None
+            }
+            _ => Some(tx),
+        }
+    }
+
+    /// False when `key` was already read.
+    fn insert(&self, key: &str) -> bool {
+        let mut keys = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        keys.insert(key.to_string())
+    }
+}
+
+/// Parses an amount column. Plain decimals are always accepted; with `lenient_amounts`
+/// scientific notation and comma thousands separators are too. Anything that could be a
+/// decimal comma (`1.000,50`, `1,50`) is rejected rather than guessed at, unless
+/// `decimal_comma` says that is what the input uses. A well-formed number too large for
+/// a `Decimal` is reported as out of range rather than invalid.
+fn parse_amount(raw: &str, options: &IngestionOptions) -> Result<Decimal, Error> {
+    let invalid = || Error::InvalidAmount(raw.to_string());
+    let out_of_range = || Error::AmountOutOfRange(raw.to_string());
+
+    if options.decimal_comma {
+        // `1.000,50` becomes `1,000.50`, which the rules below know how to read.
+        let swapped: String = raw
+            .chars()
+            .map(|c| match c {
+                '.' => ',',
+                ',' => '.',
+                c => c,
+            })
+            .collect();
+        let options = IngestionOptions {
+            decimal_comma: false,
+            ..options.clone()
+        };
+        return parse_amount(&swapped, &options).map_err(|e| match e {
+            Error::AmountOutOfRange(_) => out_of_range(),
+            _ => invalid(),
+        });
+    }
+    // Only called once `Decimal` has refused `number`.
+    let rejected = |number: &str| {
+        if is_number(number) {
+            out_of_range()
+        } else {
+            invalid()
+        }
+    };
+
+    // `Decimal::from_str` takes exponents too, so they are only allowed when asked for.
+    if raw.contains(['e', 'E']) {
+        return if options.lenient_amounts {
+            Decimal::from_scientific(raw).map_err(|_| rejected(raw))
+        } else {
+            Err(invalid())
+        };
+    }
+    if !options.lenient_amounts {
+        return Decimal::from_str(raw).map_err(|_| rejected(raw));
+    }
+
+    let (integer, fraction) = match raw.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (raw, None),
+    };
+    if fraction.is_some_and(|f| f.contains(',')) {
+        return Err(invalid());
+    }
+    let digits = integer.trim_start_matches(['-', '+']);
+    let mut groups = digits.split(',');
+    let first = groups.next().unwrap_or_default();
+    let well_grouped = (1..=3).contains(&first.len())
+        && groups.all(|g| g.len() == 3 && g.bytes().all(|b| b.is_ascii_digit()));
+    if !well_grouped && digits.contains(',') {
+        return Err(invalid());
+    }
+
+    let ungrouped = raw.replace(',', "");
+    Decimal::from_str(&ungrouped).map_err(|_| rejected(&ungrouped))
+}
+
+/// Whether `s` is `[-+]?digits(.digits)?`, optionally followed by an exponent
+/// `e[-+]?digits`: a number, whether or not it fits in a `Decimal`.
+fn is_number(s: &str) -> bool {
+    fn digits(part: &str) -> bool {
+        !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit())
+    }
+    fn unsigned(part: &str) -> &str {
+        part.strip_prefix(['-', '+']).unwrap_or(part)
+    }
+
+    let (mantissa, exponent) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (unsigned(mantissa), Some(exponent)),
+        None => (unsigned(s), None),
+    };
+    let mantissa_ok = match mantissa.split_once('.') {
+        Some((integer, fraction)) => digits(integer) && digits(fraction),
+        None => digits(mantissa),
+    };
+    mantissa_ok && exponent.is_none_or(|exponent| digits(unsigned(exponent)))
+}
+
+impl CsvRow {
+    fn into_transaction(self, options: &IngestionOptions) -> Result<Transaction, Error> {
+        // Normalize so `1.5` and `1.5000` are represented identically downstream.
+        let amount = self
+            .amount
+            .map(|raw| parse_amount(&raw, options).map(|a| a.normalize()))
+            .transpose()?;
+        let kind = match (self.kind.trim().to_ascii_lowercase().as_str(), amount) {
             ("deposit", Some(amount)) => TransactionKind::Deposit { amount },
             ("withdrawal", Some(amount)) => TransactionKind::Withdrawal { amount },
-            ("dispute", None) => TransactionKind::Dispute,
+            ("dispute", amount) => TransactionKind::Dispute { amount },
             ("resolve", None) => TransactionKind::Resolve,
             ("chargeback", None) => TransactionKind::Chargeback,
-            (other, _) => {
-                return Err(Error::Ingestion(format!(
-                    "Invalid transaction type: {}",
-                    other
-                )));
+            ("freeze", None) => TransactionKind::Freeze,
+            ("unfreeze", None) => TransactionKind::Unfreeze,
+            ("hold", Some(amount)) => TransactionKind::Hold { amount },
+            ("release", Some(amount)) => TransactionKind::Release { amount },
+            (other, _) if !is_known_type(other) => {
+                return Err(Error::UnknownType(other.to_string()));
+            }
+            (other, Some(_)) => {
+                return Err(Error::Ingestion(format!("A {} takes no amount", other)));
+            }
+            (other, None) => {
+                return Err(Error::Ingestion(format!("A {} needs an amount", other)));
             }
         };
 
+        let currency = self
+            .currency
+            .map(|c| c.trim().to_ascii_uppercase())
+            .filter(|c| !c.is_empty());
+
         Ok(Transaction {
             kind,
-            client_id: row.client,
-            transaction_id: row.tx,
+            client_id: self.client,
+            transaction_id: self.tx,
+            currency,
         })
     }
 }
 
+impl TryFrom<CsvRow> for Transaction {
+    type Error = Error;
+
+    fn try_from(row: CsvRow) -> Result<Self, Self::Error> {
+        row.into_transaction(&IngestionOptions::default())
+    }
+}
+
 impl<R: Read + Send + 'static> TransactionStream for CsvReader<R> {
     type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
 
-    fn stream(&mut self) -> Self::TxStream {
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
         // Take ownership of the reader so the iterator we build owns all data and is 'static.
-        let reader = match self.reader.take() {
-            Some(r) => r,
-            None => {
-                // Already consumed; return an empty stream.
-                return Box::pin(stream::iter(Vec::<Result<Transaction, Error>>::new()));
-            }
-        };
+        let reader = self.reader.take().ok_or(Error::AlreadyConsumed)?;
+        let (options, row_options) = (self.options.clone(), self.options.clone());
+
+        let columns = self.columns;
+        let keys = self.keys.clone();
 
-        // into_deserialize consumes the reader and returnes an owning iterator
-        let iter = reader
-            .into_deserialize::<CsvRow>()
-            .map(|row_res| match row_res {
-                Ok(row) => Transaction::try_from(row),
-                Err(e) => Err(Error::Ingestion(format!(
-                    "CSV deserialization error: {}",
-                    e
-                ))),
+        // Reads that failed while a record was read are reported ahead of it. The csv
+        // reader ends the input after a failure it sees itself.
+        let failures = Arc::clone(&self.failures);
+        let mut records = reader.into_records();
+        let records = std::iter::from_fn(move || {
+            let record = records.next().map(|record| {
+                record.map_err(|e| match e {
+                    e if e.is_io_error() => Error::IO(e.into()),
+                    e => Error::Ingestion(format!("CSV deserialization error: {}", e)),
+                })
+            });
+            let failed: Vec<_> =
+                std::mem::take(&mut *failures.lock().unwrap_or_else(|e| e.into_inner()));
+            if record.is_none() && failed.is_empty() {
+                return None;
+            }
+            Some(failed.into_iter().map(|e| Err(Error::IO(e))).chain(record))
+        })
+        .flatten();
+        // Records are checked for skippable types before deserializing, so a comment row
+        // is dropped even when its other columns would not parse.
+        let iter = records
+            .filter(move |record| match record {
+                Ok(record) => !skipped(record.get(columns.kind), &options),
+                Err(_) => true,
+            })
+            .filter_map(
+                move |record| match record.and_then(|record| columns.row(&record)) {
+                    Ok(row) => keys.transaction(row, &row_options),
+                    Err(e) => Some(Err(e)),
+                },
+            )
+            .inspect(|res| {
+                if let Err(error) = res {
+                    tracing::debug!(%error, "failed to parse record");
+                }
             });
 
-        Box::pin(stream::iter(iter))
+        Ok(Box::pin(stream::iter(iter)))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::domain::{Amount, Money};
     use futures::StreamExt;
     use std::io::Cursor;
 
     fn run_stream<R: Read + Send + 'static>(
         rdr: &mut CsvReader<R>,
     ) -> Vec<Result<Transaction, Error>> {
-        let mut s = rdr.stream();
+        let mut s = rdr.stream().expect("unconsumed stream");
         futures::executor::block_on(async move {
             let mut out = Vec::new();
             while let Some(item) = s.next().await {
@@ -126,6 +693,7 @@ chargeback, 1, 1,\n";
                 kind: TransactionKind::Deposit { amount },
                 client_id,
                 transaction_id,
+                currency: None,
             }) => {
                 assert_eq!(*client_id, 1);
                 assert_eq!(*transaction_id, 1);
@@ -138,6 +706,7 @@ chargeback, 1, 1,\n";
                 kind: TransactionKind::Withdrawal { amount },
                 client_id,
                 transaction_id,
+                currency: None,
             }) => {
                 assert_eq!(*client_id, 1);
                 assert_eq!(*transaction_id, 2);
@@ -148,7 +717,7 @@ chargeback, 1, 1,\n";
         assert!(matches!(
             rows[2],
             Ok(Transaction {
-                kind: TransactionKind::Dispute,
+                kind: TransactionKind::Dispute { amount: None },
                 ..
             })
         ));
@@ -168,6 +737,57 @@ chargeback, 1, 1,\n";
         ));
     }
 
+    #[test]
+    fn hold_and_release_require_an_amount() {
+        let data = b"type, client, tx, amount\nhold, 1, 8, 2.5\nrelease, 1, 9, 1\nhold, 1, 10,\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+
+        assert_eq!(rows.len(), 3);
+        match &rows[0] {
+            Ok(Transaction {
+                kind: TransactionKind::Hold { amount },
+                ..
+            }) => assert_eq!(*amount, Decimal::new(25, 1)),
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert!(matches!(
+            rows[1],
+            Ok(Transaction {
+                kind: TransactionKind::Release { .. },
+                ..
+            })
+        ));
+        assert!(matches!(&rows[2], Err(Error::Ingestion(_))));
+    }
+
+    #[test]
+    fn parses_freeze_and_unfreeze_without_amount() {
+        let data = b"type, client, tx, amount\nfreeze, 1, 5,\nunfreeze, 1, 6,\nfreeze, 1, 7, 1.0\n";
+        let cursor = Cursor::new(&data[..]);
+        let mut rdr = CsvReader::new(cursor).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+
+        assert_eq!(rows.len(), 3);
+        assert!(matches!(
+            rows[0],
+            Ok(Transaction {
+                kind: TransactionKind::Freeze,
+                client_id: 1,
+                transaction_id: 5,
+                currency: None,
+            })
+        ));
+        assert!(matches!(
+            rows[1],
+            Ok(Transaction {
+                kind: TransactionKind::Unfreeze,
+                ..
+            })
+        ));
+        assert!(matches!(&rows[2], Err(Error::Ingestion(_))));
+    }
+
     #[test]
     fn invalid_type_yields_ingestion_error() {
         let data = b"type, client, tx, amount\nfoo, 1, 1, 10.0\n";
@@ -175,7 +795,7 @@ chargeback, 1, 1,\n";
         let mut rdr = CsvReader::new(cursor).expect("csv reader");
         let rows = run_stream(&mut rdr);
         assert_eq!(rows.len(), 1);
-        assert!(matches!(&rows[0], Err(Error::Ingestion(_))));
+        assert!(matches!(&rows[0], Err(Error::UnknownType(kind)) if kind == "foo"));
     }
 
     #[test]
@@ -186,11 +806,13 @@ chargeback, 1, 1,\n";
         let rows = run_stream(&mut rdr);
         assert_eq!(rows.len(), 1);
         assert!(matches!(&rows[0], Err(Error::Ingestion(_))));
+        // a known type, so not reported as an unknown one
+        assert_eq!(rows[0].as_ref().unwrap_err().code(), "ING_BAD_ROW");
     }
 
     #[test]
-    fn extra_amount_for_dispute_is_error() {
-        let data = b"type, client, tx, amount\ndispute, 1, 1, 2.0\n";
+    fn extra_amount_for_resolve_is_error() {
+        let data = b"type, client, tx, amount\nresolve, 1, 1, 2.0\n";
         let cursor = Cursor::new(&data[..]);
         let mut rdr = CsvReader::new(cursor).expect("csv reader");
         let rows = run_stream(&mut rdr);
@@ -198,6 +820,25 @@ chargeback, 1, 1,\n";
         assert!(matches!(&rows[0], Err(Error::Ingestion(_))));
     }
 
+    #[test]
+    fn dispute_amount_is_optional() {
+        let data = b"type, client, tx, amount\ndispute, 1, 1, 2.5\ndispute, 1, 2,\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let kinds: Vec<_> = run_stream(&mut rdr)
+            .into_iter()
+            .map(|row| row.unwrap().kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TransactionKind::Dispute {
+                    amount: Some(Decimal::new(25, 1))
+                },
+                TransactionKind::Dispute { amount: None },
+            ]
+        );
+    }
+
     #[test]
     fn csv_deserialize_error_surfaces_as_ingestion_error() {
         // invalid client id (non-numeric)
@@ -212,14 +853,550 @@ chargeback, 1, 1,\n";
     }
 
     #[test]
-    fn second_stream_after_consumption_is_empty() {
+    fn mixed_case_headers_are_accepted() {
+        let data = b"Type, Client, TX , AMOUNT\nDeposit, 1, 1, 2.5\n";
+        let cursor = Cursor::new(&data[..]);
+        let mut rdr = CsvReader::new(cursor).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        assert_eq!(rows.len(), 1);
+        match &rows[0] {
+            Ok(Transaction {
+                kind: TransactionKind::Deposit { amount },
+                client_id: 1,
+                transaction_id: 1,
+                currency: None,
+            }) => assert_eq!(*amount, Decimal::new(25, 1)),
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn columns_are_bound_by_name_not_position() {
+        let reordered = b"amount,note,tx,type,client\n2.5,first,7,deposit,3\n,,7,dispute,3\n";
+        let plain = b"type,client,tx,amount\ndeposit,3,7,2.5\ndispute,3,7,\n";
+        let rows = |data: &'static [u8]| {
+            let mut rdr = CsvReader::new(Cursor::new(data)).expect("csv reader");
+            format!("{:?}", run_stream(&mut rdr))
+        };
+        assert_eq!(rows(reordered), rows(plain));
+        assert!(!rows(reordered).contains("Err"));
+    }
+
+    #[test]
+    fn client_ids_past_u16_need_the_wide_type() {
+        let data = b"type,client,tx,amount\ndeposit,70000,1,1.0\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        #[cfg(not(feature = "wide-client-ids"))]
+        assert!(matches!(rows[0], Err(Error::Ingestion(_))));
+        #[cfg(feature = "wide-client-ids")]
+        assert_eq!(rows[0].as_ref().unwrap().client_id, 70_000);
+    }
+
+    #[test]
+    fn bom_and_crlf_line_endings_are_accepted() {
+        let plain = "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1\n";
+        let exported = "\u{feff}Type,Client,TX,Amount\r\ndeposit,1,1,2.5\r\nwithdrawal,1,2,1\r\n";
+        let rows = |data: &'static str| {
+            let mut rdr = CsvReader::new(Cursor::new(data)).expect("csv reader");
+            format!("{:?}", run_stream(&mut rdr))
+        };
+        assert_eq!(rows(exported), rows(plain));
+        assert!(!rows(exported).contains("Err"));
+
+        // a BOM on a semicolon separated export, with the type column first
+        let options = IngestionOptions {
+            delimiter: Some(b';'),
+            ..Default::default()
+        };
+        let data = "\u{feff}type;client;tx;amount\r\ndeposit;1;1;2.5\r\n";
+        let mut rdr = CsvReader::with_options(Cursor::new(data), options).unwrap();
+        assert!(run_stream(&mut rdr)[0].is_ok());
+    }
+
+    /// Hands out one line per read and fails the reads `fails` picks, counting from 0.
+    struct FlakyReader {
+        lines: std::collections::VecDeque<&'static str>,
+        reads: usize,
+        fails: fn(usize) -> bool,
+    }
+
+    impl FlakyReader {
+        fn new(data: &'static str, fails: fn(usize) -> bool) -> Self {
+            Self {
+                lines: data.split_inclusive('\n').collect(),
+                reads: 0,
+                fails,
+            }
+        }
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            if (self.fails)(self.reads - 1) {
+                return Err(std::io::Error::other("connection reset"));
+            }
+            let Some(line) = self.lines.pop_front() else {
+                return Ok(0);
+            };
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            Ok(line.len())
+        }
+    }
+
+    const FLAKY_INPUT: &str =
+        "type,client,tx,amount\ndeposit,1,1,1\ndeposit,1,2,2\ndeposit,1,3,3\n";
+
+    fn flaky_rows(fails: fn(usize) -> bool, continue_on_io_error: bool) -> Vec<String> {
+        let options = IngestionOptions {
+            continue_on_io_error,
+            ..Default::default()
+        };
+        let reader = FlakyReader::new(FLAKY_INPUT, fails);
+        let mut rdr = CsvReader::with_options(reader, options).unwrap();
+        run_stream(&mut rdr)
+            .into_iter()
+            .map(|row| match row {
+                Ok(tx) => tx.transaction_id.to_string(),
+                Err(e) => e.reason().to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_failed_read_is_reported_and_skipped_when_asked_to_continue() {
+        // read 0 is the header, read 2 the second deposit
+        assert_eq!(flaky_rows(|read| read == 2, true), ["1", "io", "2", "3"]);
+        assert_eq!(flaky_rows(|read| read == 2, false), ["1", "io"]);
+    }
+
+    #[test]
+    fn a_reader_that_keeps_failing_ends_the_input() {
+        assert_eq!(flaky_rows(|read| read >= 2, true), ["1", "io", "io", "io"]);
+    }
+
+    #[test]
+    fn header_only_input_has_no_transactions() {
+        for data in ["type,client,tx,amount", "type, client, tx, amount\r\n\n"] {
+            let mut rdr = CsvReader::new(Cursor::new(data)).unwrap();
+            assert!(run_stream(&mut rdr).is_empty(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn input_without_a_header_is_rejected_up_front() {
+        for data in ["", "\n\n"] {
+            match CsvReader::new(Cursor::new(data)) {
+                Err(Error::Ingestion(msg)) => {
+                    assert_eq!(msg, "CSV input is empty, expected a header row")
+                }
+                other => panic!("unexpected: {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_required_column_is_rejected_up_front() {
+        let data = b"type,tx,amount
+deposit,1,1.0
+";
+        match CsvReader::new(Cursor::new(&data[..])) {
+            Err(Error::Ingestion(msg)) => {
+                assert_eq!(msg, "CSV input is missing required columns: client")
+            }
+            other => panic!("unexpected: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn reordered_columns_bind_by_name() {
+        let data = b"Client,Amount,Tx,Type\n7,3.0,9,withdrawal\n";
+        let cursor = Cursor::new(&data[..]);
+        let mut rdr = CsvReader::new(cursor).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        assert_eq!(rows.len(), 1);
+        match &rows[0] {
+            Ok(Transaction {
+                kind: TransactionKind::Withdrawal { amount },
+                client_id: 7,
+                transaction_id: 9,
+                currency: None,
+            }) => assert_eq!(*amount, Decimal::from(3u32)),
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn amounts_are_normalized_regardless_of_scale() {
+        let data = b"type, client, tx, amount\ndeposit, 1, 1, 1.5\ndeposit, 1, 1, 1.5000\n";
+        let cursor = Cursor::new(&data[..]);
+        let mut rdr = CsvReader::new(cursor).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+
+        let amounts: Vec<_> = rows
+            .iter()
+            .map(|row| match row {
+                Ok(Transaction {
+                    kind: TransactionKind::Deposit { amount },
+                    ..
+                }) => *amount,
+                other => panic!("unexpected: {:?}", other),
+            })
+            .collect();
+        assert_eq!(amounts[0], amounts[1]);
+        assert_eq!(amounts[0].scale(), amounts[1].scale());
+        assert_eq!(amounts[1].to_string(), "1.5");
+    }
+
+    #[test]
+    fn second_stream_after_consumption_is_an_error() {
         let data = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
         let cursor = Cursor::new(&data[..]);
         let mut rdr = CsvReader::new(cursor).expect("csv reader");
         let rows1 = run_stream(&mut rdr);
         assert_eq!(rows1.len(), 1);
         // Now attempt to stream again from the same CsvReader instance
-        let rows2 = run_stream(&mut rdr);
-        assert!(rows2.is_empty());
+        assert!(matches!(rdr.stream(), Err(Error::AlreadyConsumed)));
+    }
+
+    fn lenient_amounts(data: &'static [u8]) -> Vec<Result<Transaction, Error>> {
+        let options = IngestionOptions {
+            lenient_amounts: true,
+            ..Default::default()
+        };
+        let mut rdr = CsvReader::with_options(Cursor::new(data), options).expect("csv reader");
+        run_stream(&mut rdr)
+    }
+
+    fn deposit_amount(row: &Result<Transaction, Error>) -> Decimal {
+        match row {
+            Ok(Transaction {
+                kind: TransactionKind::Deposit { amount },
+                ..
+            }) => *amount,
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn integer_amounts_parse_under_every_option() {
+        let data = b"type;client;tx;amount\ndeposit;1;1;100\ndeposit;1;2;0\ndeposit;1;3;-5\n";
+        for options in [
+            IngestionOptions::default(),
+            IngestionOptions {
+                lenient_amounts: true,
+                ..Default::default()
+            },
+            IngestionOptions {
+                decimal_comma: true,
+                ..Default::default()
+            },
+        ] {
+            let options = IngestionOptions {
+                delimiter: Some(b';'),
+                ..options
+            };
+            let mut rdr =
+                CsvReader::with_options(Cursor::new(&data[..]), options.clone()).expect("csv");
+            let amounts: Vec<_> = run_stream(&mut rdr).iter().map(deposit_amount).collect();
+            assert_eq!(
+                amounts,
+                [Decimal::from(100u32), Decimal::ZERO, Decimal::from(-5)],
+                "{:?}",
+                options
+            );
+            // a Money engine sees the same values
+            let minor: Vec<_> = amounts
+                .into_iter()
+                .map(|amount| Money::<4>::from_decimal(amount).map(|m| m.as_minor()))
+                .collect();
+            assert_eq!(minor, [Some(1_000_000), Some(0), Some(-50_000)]);
+        }
+    }
+
+    #[test]
+    fn lenient_amounts_expand_scientific_notation() {
+        let rows = lenient_amounts(b"type,client,tx,amount\ndeposit,1,1,1e3\ndeposit,1,2,2.5E-2\n");
+        assert_eq!(deposit_amount(&rows[0]), Decimal::from(1000u32));
+        assert_eq!(deposit_amount(&rows[1]), Decimal::new(25, 3));
+    }
+
+    #[test]
+    fn lenient_amounts_strip_thousands_separators() {
+        let rows = lenient_amounts(
+            b"type,client,tx,amount\ndeposit,1,1,\"1,000.50\"\ndeposit,1,2,\"12,345,678\"\n",
+        );
+        assert_eq!(deposit_amount(&rows[0]), Decimal::new(100050, 2));
+        assert_eq!(deposit_amount(&rows[1]), Decimal::from(12_345_678u32));
+    }
+
+    #[test]
+    fn lenient_amounts_reject_ambiguous_decimal_commas() {
+        let rows = lenient_amounts(
+            b"type,client,tx,amount\ndeposit,1,1,\"1.000,50\"\ndeposit,1,2,\"1,50\"\ndeposit,1,3,\"1,0000\"\n",
+        );
+        assert_eq!(rows.len(), 3);
+        for row in &rows {
+            assert!(matches!(row, Err(Error::InvalidAmount(_))));
+        }
+    }
+
+    #[test]
+    fn decimal_commas_with_a_semicolon_delimiter() {
+        let read = |lenient_amounts| {
+            let options = IngestionOptions {
+                delimiter: Some(b';'),
+                decimal_comma: true,
+                lenient_amounts,
+                ..Default::default()
+            };
+            let data = "type;client;tx;amount\n\
+                        deposit;1;1;1,50\n\
+                        deposit;1;2;2\n\
+                        deposit;1;3;1.000,5\n\
+                        deposit;1;4;1.5\n";
+            let mut rdr = CsvReader::with_options(Cursor::new(data), options).unwrap();
+            run_stream(&mut rdr)
+        };
+
+        let rows = read(false);
+        assert_eq!(deposit_amount(&rows[0]), Decimal::new(15, 1));
+        assert_eq!(deposit_amount(&rows[1]), Decimal::TWO);
+        // dots are only thousands separators, and only for lenient amounts
+        assert!(matches!(&rows[2], Err(Error::InvalidAmount(raw)) if raw == "1.000,5"));
+        assert!(rows[3].is_err());
+        let rows = read(true);
+        assert_eq!(deposit_amount(&rows[2]), Decimal::new(10005, 1));
+        assert!(rows[3].is_err());
+    }
+
+    #[test]
+    fn decimal_commas_need_another_delimiter() {
+        let options = IngestionOptions {
+            decimal_comma: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            CsvReader::with_options(Cursor::new("type,client,tx,amount\n"), options),
+            Err(Error::Ingestion(_))
+        ));
+    }
+
+    #[test]
+    fn strict_amounts_reject_exponents_and_separators() {
+        let data = b"type,client,tx,amount\ndeposit,1,1,1e3\ndeposit,1,2,\"1,000.50\"\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        assert_eq!(rows.len(), 2);
+        assert!(
+            rows.iter()
+                .all(|row| matches!(row, Err(Error::InvalidAmount(_))))
+        );
+    }
+
+    #[test]
+    fn long_amounts_keep_full_precision() {
+        let data = b"type,client,tx,amount\ndeposit,1,1,12345678901234.5678\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        assert_eq!(
+            deposit_amount(&rows[0]),
+            Decimal::new(123456789012345678, 4)
+        );
+    }
+
+    #[test]
+    fn amounts_past_the_decimal_limit_are_out_of_range() {
+        let data = b"type,client,tx,amount\n\
+            deposit,1,1,79228162514264337593543950335\n\
+            deposit,1,2,79228162514264337593543950336\n\
+            withdrawal,1,3,-99999999999999999999999999999.5\n\
+            deposit,1,4,7922816251426433759354395033x\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        assert_eq!(deposit_amount(&rows[0]), Decimal::MAX);
+        let messages: Vec<_> = rows[1..]
+            .iter()
+            .map(|row| match row {
+                Err(e) => (e.code(), e.to_string()),
+                Ok(tx) => panic!("{:?} parsed", tx),
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (
+                    "ING_AMOUNT_OUT_OF_RANGE",
+                    "Ingestion failed with: Amount out of range: 79228162514264337593543950336"
+                        .to_string()
+                ),
+                (
+                    "ING_AMOUNT_OUT_OF_RANGE",
+                    "Ingestion failed with: Amount out of range: -99999999999999999999999999999.5"
+                        .to_string()
+                ),
+                (
+                    "ING_BAD_AMOUNT",
+                    "Ingestion failed with: Invalid amount: 7922816251426433759354395033x"
+                        .to_string()
+                ),
+            ]
+        );
+
+        let rows = lenient_amounts(
+            b"type,client,tx,amount\ndeposit,1,1,1e40\ndeposit,1,2,\"100,000,000,000,000,000,000,000,000,000\"\n",
+        );
+        for row in &rows {
+            assert!(matches!(row, Err(e) if e.code() == "ING_AMOUNT_OUT_OF_RANGE"));
+        }
+    }
+
+    const WITH_UNKNOWN_TYPES: &[u8] = b"type,client,tx,amount\n\
+deposit,1,1,2.0\n\
+chah,1,\n\
+# a comment\n\
+bonus,1,2,5.0\n\
+withdrawal,1,3,1.0\n";
+
+    #[test]
+    fn unknown_types_are_reported_by_default() {
+        let mut rdr = CsvReader::new(Cursor::new(WITH_UNKNOWN_TYPES)).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows.iter().filter(|row| row.is_err()).count(), 3);
+    }
+
+    #[test]
+    fn skip_unknown_drops_unknown_types_silently() {
+        let options = IngestionOptions {
+            skip_unknown: true,
+            ..Default::default()
+        };
+        let mut rdr =
+            CsvReader::with_options(Cursor::new(WITH_UNKNOWN_TYPES), options).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        let ids: Vec<_> = rows
+            .iter()
+            .map(|row| row.as_ref().map(|tx| tx.transaction_id).unwrap())
+            .collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn skip_unknown_still_reports_known_types_with_bad_fields() {
+        let options = IngestionOptions {
+            skip_unknown: true,
+            ..Default::default()
+        };
+        let data = b"type,client,tx,amount\ndeposit,1,1,\nfreeze,1,2,1.0\n";
+        let mut rdr = CsvReader::with_options(Cursor::new(&data[..]), options).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        assert_eq!(rows.len(), 2);
+        assert!(
+            rows.iter()
+                .all(|row| matches!(row, Err(Error::Ingestion(_))))
+        );
+    }
+
+    #[test]
+    fn comment_lines_are_skipped() {
+        let options = IngestionOptions {
+            comment: Some(b'#'),
+            ..Default::default()
+        };
+        let data = b"# exported by hand\n\
+                     type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     # the next one was disputed by phone\n\
+                     dispute, 1, 1\n\
+                     #withdrawal, 1, 2, 0.5\n\
+                     deposit, 1, 3, 2.0 #not a comment\n";
+        let mut rdr = CsvReader::with_options(Cursor::new(&data[..]), options).expect("csv reader");
+        let rows = run_stream(&mut rdr);
+        let ids: Vec<_> = rows
+            .iter()
+            .map(|row| row.as_ref().map(|tx| tx.transaction_id).ok())
+            .collect();
+        // a `#` past the start of a line stays part of the field
+        assert_eq!(ids, [Some(1), Some(1), None]);
+    }
+
+    #[test]
+    fn skip_until_drops_rows_up_to_the_marker() {
+        let data = b"type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     bogus,1,9,\n\
+                     dispute,1,2,\n\
+                     withdrawal,1,2,0.5\n\
+                     dispute,1,1,\n\
+                     deposit,1,3,2.0\n";
+        let ids = |until| {
+            let rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+            let mut skipping = SkipUntil::new(rdr, until);
+            let rows = futures::executor::block_on(skipping.stream().unwrap().collect::<Vec<_>>());
+            rows.into_iter()
+                .map(|row| row.map_or(0, |tx| tx.transaction_id))
+                .collect::<Vec<_>>()
+        };
+
+        // the dispute naming tx 2 is not the record that introduced it
+        assert_eq!(ids(Some(2)), vec![1, 3]);
+        assert_eq!(ids(None), vec![1, 0, 2, 2, 1, 3]);
+        assert!(ids(Some(7)).is_empty());
+    }
+
+    #[test]
+    fn replay_until_stops_after_the_record_that_introduced_the_id() {
+        let data = b"type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     dispute,1,2,\n\
+                     deposit,1,2,2.0\n\
+                     withdrawal,1,3,0.5\n";
+        let replay = |until| {
+            let rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+            let mut replaying = ReplayUntil::new(rdr, until);
+            let rows = futures::executor::block_on(replaying.stream().unwrap().collect::<Vec<_>>());
+            let ids: Vec<_> = rows
+                .into_iter()
+                .map(|row| row.map_or(0, |tx| tx.transaction_id))
+                .collect();
+            (ids, replaying.reached())
+        };
+
+        // the dispute naming tx 2 is not the record that introduced it
+        assert_eq!(replay(Some(2)), (vec![1, 2, 2], true));
+        assert_eq!(replay(Some(7)), (vec![1, 2, 2, 3], false));
+        assert_eq!(replay(None), (vec![1, 2, 2, 3], false));
+    }
+
+    #[test]
+    fn currency_column_is_optional_and_upper_cased() {
+        let data = b"type,client,tx,amount,currency\ndeposit,1,1,1.0, eur\ndispute,1,1,,\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let currencies: Vec<_> = run_stream(&mut rdr)
+            .into_iter()
+            .map(|row| row.unwrap().currency)
+            .collect();
+        assert_eq!(currencies, vec![Some("EUR".to_string()), None]);
+    }
+
+    #[test]
+    fn replayed_idempotency_keys_are_dropped() {
+        let data = b"type,client,tx,amount,idempotency_key\n\
+            deposit,1,1,1.0,a\n\
+            deposit,1,2,1.0,a\n\
+            deposit,1,3,oops,b\n\
+            deposit,1,3,3.0,b\n\
+            dispute,1,1,,\n\
+            dispute,1,1,,\n";
+        let mut rdr = CsvReader::new(Cursor::new(&data[..])).expect("csv reader");
+        let rows: Vec<_> = run_stream(&mut rdr)
+            .into_iter()
+            .map(|row| row.map(|tx| tx.transaction_id).ok())
+            .collect();
+        // the second `a` differs in tx but is the same operation; the bad `b` row does
+        // not use up its key, and rows without a key are never deduplicated
+        assert_eq!(rows, vec![Some(1), None, Some(3), Some(1), Some(1)]);
     }
 }
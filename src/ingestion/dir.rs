@@ -0,0 +1,143 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use futures::stream::{self, Stream, StreamExt};
+
+use super::{CsvReader, IdempotencyKeys, IngestionOptions};
+use crate::domain::traits::TransactionStream;
+use crate::domain::{Error, Transaction};
+
+/// Reads every `*.csv` file in a directory as one input, in lexical order of the file
+/// names, so daily drops such as `2024-01-01.csv` and `2024-01-02.csv` apply oldest
+/// first. Other files and subdirectories are ignored. Tx ids must be unique across the
+/// files just as within one, and a dispute may reference a transaction in an earlier
+/// file. Likewise an idempotency key read in one file drops its replays in later ones.
+///
+/// Each file needs its own header row. One that cannot be opened or has no header is
+/// reported when its turn comes, and reading carries on with the next.
+pub struct DirectoryReader {
+    files: Option<Vec<PathBuf>>,
+    options: IngestionOptions,
+}
+
+impl DirectoryReader {
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        Self::with_options(dir, IngestionOptions::default())
+    }
+
+    /// Lists the files up front; files added to the directory afterwards are not read.
+    pub fn with_options(dir: &Path, options: IngestionOptions) -> Result<Self, Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "csv") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(Self {
+            files: Some(files),
+            options,
+        })
+    }
+
+    /// The files that will be read, in order.
+    pub fn files(&self) -> &[PathBuf] {
+        self.files.as_deref().unwrap_or_default()
+    }
+}
+
+impl TransactionStream for DirectoryReader {
+    type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
+        let files = self.files.take().ok_or(Error::AlreadyConsumed)?;
+        let options = self.options.clone();
+        let keys = IdempotencyKeys::default();
+
+        // A file is only opened once the one before it has been read to the end.
+        Ok(Box::pin(stream::iter(files).flat_map(move |path| {
+            let opened = File::open(&path)
+                .map_err(Error::from)
+                .and_then(|file| CsvReader::with_options(BufReader::new(file), options.clone()))
+                .map(|reader| reader.sharing_keys(keys.clone()))
+                .and_then(|mut reader| reader.stream());
+            match opened {
+                Ok(rows) => rows,
+                Err(e) => {
+                    tracing::debug!(path = %path.display(), error = %e, "skipping input file");
+                    let e = Error::Ingestion(format!("{}: {}", path.display(), e));
+                    Box::pin(stream::once(async { Err(e) }))
+                }
+            }
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::TransactionKind;
+
+    #[tokio::test]
+    async fn csv_files_are_read_in_lexical_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = "type,client,tx,amount\n";
+        fs::write(
+            dir.path().join("2024-01-02.csv"),
+            format!("{header}deposit,1,2,2.0\n"),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("2024-01-01.csv"),
+            format!("{header}deposit,1,1,1.0\n"),
+        )
+        .unwrap();
+        fs::write(dir.path().join("notes.txt"), "deposit,1,9,9.0\n").unwrap();
+        fs::write(dir.path().join("2024-01-03.csv"), "").unwrap();
+        fs::create_dir(dir.path().join("archive.csv")).unwrap();
+
+        let mut reader = DirectoryReader::open(dir.path()).unwrap();
+        assert_eq!(reader.files().len(), 3);
+        let rows: Vec<_> = reader.stream().unwrap().collect().await;
+
+        let ids: Vec<_> = rows
+            .iter()
+            .filter_map(|row| row.as_ref().ok())
+            .inspect(|tx| assert!(matches!(tx.kind, TransactionKind::Deposit { .. })))
+            .map(|tx| tx.transaction_id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+        // the empty file has no header
+        assert!(
+            matches!(rows.last(), Some(Err(Error::Ingestion(msg))) if msg.contains("2024-01-03.csv"))
+        );
+        assert!(matches!(reader.stream(), Err(Error::AlreadyConsumed)));
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_carry_over_to_later_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = "type,client,tx,amount,idempotency_key\n";
+        fs::write(
+            dir.path().join("2024-01-01.csv"),
+            format!("{header}deposit,1,1,1.0,a\n"),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("2024-01-02.csv"),
+            format!("{header}deposit,1,2,1.0,a\ndeposit,1,3,1.0,b\n"),
+        )
+        .unwrap();
+
+        let mut reader = DirectoryReader::open(dir.path()).unwrap();
+        let rows: Vec<_> = reader.stream().unwrap().collect().await;
+        let ids: Vec<_> = rows
+            .into_iter()
+            .map(|row| row.unwrap().transaction_id)
+            .collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+}
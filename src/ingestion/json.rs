@@ -0,0 +1,241 @@
+use std::io::BufRead;
+use std::pin::Pin;
+
+use futures::stream::{self, Stream};
+use serde::Deserialize;
+
+use super::{CsvRow, IngestionOptions};
+use crate::domain::traits::TransactionStream;
+use crate::domain::{ClientId, Error, Transaction};
+
+/// JSON records may carry the amount as a string or a number. Numbers keep the digits
+/// they were written with (serde_json's `arbitrary_precision`), never passing through `f64`.
+#[derive(Debug, Deserialize)]
+pub(super) struct JsonRow {
+    #[serde(rename = "type")]
+    kind: String,
+    client: ClientId,
+    tx: u32,
+    amount: Option<serde_json::Value>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
+}
+
+impl TryFrom<JsonRow> for CsvRow {
+    type Error = Error;
+
+    fn try_from(row: JsonRow) -> Result<Self, Self::Error> {
+        let amount = match row.amount {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::String(s)) => Some(s),
+            Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+            Some(other) => return Err(Error::InvalidAmount(other.to_string())),
+        };
+        Ok(CsvRow {
+            kind: row.kind,
+            client: row.client,
+            tx: row.tx,
+            amount,
+            currency: row.currency,
+            idempotency_key: row.idempotency_key,
+        })
+    }
+}
+
+/// Reads transactions given as JSON lines, one object per line, or as a single JSON
+/// array of such objects. Blank lines are ignored. An optional `idempotency_key` field
+/// is honoured as for CSV input.
+pub struct JsonReader<R: BufRead> {
+    reader: Option<R>,
+    options: IngestionOptions,
+}
+
+impl<R: BufRead> JsonReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_options(reader, IngestionOptions::default())
+    }
+
+    pub fn with_options(reader: R, options: IngestionOptions) -> Self {
+        Self {
+            reader: Some(reader),
+            options,
+        }
+    }
+}
+
+fn parse_value(value: serde_json::Value) -> Result<CsvRow, Error> {
+    serde_json::from_value::<JsonRow>(value)
+        .map_err(|e| Error::Ingestion(format!("JSON deserialization error: {}", e)))?
+        .try_into()
+}
+
+type Values = Box<dyn Iterator<Item = Result<serde_json::Value, Error>> + Send>;
+
+/// Splits the input into one JSON value per record, each parsed on its own so a bad
+/// record does not take the rest of the input down with it.
+fn values<R: BufRead + Send + 'static>(mut reader: R) -> Result<Values, Error> {
+    let invalid = |e: serde_json::Error| Error::Ingestion(format!("JSON error: {}", e));
+
+    super::sniff_format(&mut reader)?;
+    if reader.fill_buf()?.first() == Some(&b'[') {
+        let values: Vec<serde_json::Value> = serde_json::from_reader(reader).map_err(invalid)?;
+        return Ok(Box::new(values.into_iter().map(Ok)));
+    }
+
+    Ok(Box::new(
+        reader
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+            .map(move |line| serde_json::from_str(&line?).map_err(invalid)),
+    ))
+}
+
+impl<R: BufRead + Send + 'static> TransactionStream for JsonReader<R> {
+    type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
+        let reader = self.reader.take().ok_or(Error::AlreadyConsumed)?;
+        let (options, row_options) = (self.options.clone(), self.options.clone());
+        let keys = super::IdempotencyKeys::default();
+
+        let iter = values(reader)?
+            .map(|value| value.and_then(parse_value))
+            .filter(move |row| match row {
+                Ok(row) => !super::skipped(Some(&row.kind), &options),
+                Err(_) => true,
+            })
+            .filter_map(move |row| match row {
+                Ok(row) => keys.transaction(row, &row_options),
+                Err(e) => Some(Err(e)),
+            })
+            .inspect(|res| {
+                if let Err(error) = res {
+                    tracing::debug!(%error, "failed to parse record");
+                }
+            });
+
+        Ok(Box::pin(stream::iter(iter)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::ingestion::{CsvReader, InputFormat, sniff_format};
+
+    fn collect<T: TransactionStream>(mut ingestion: T) -> Vec<String> {
+        let stream = ingestion.stream().unwrap();
+        futures::executor::block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .map(|tx| format!("{:?}", tx))
+            .collect()
+    }
+
+    const CSV: &str = "type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,1,2,0.25\ndispute,1,1,\n";
+
+    #[test]
+    fn json_lines_and_arrays_match_csv() {
+        let lines = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.5\"}\n\
+                     \n\
+                     {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":0.25}\n\
+                     {\"type\":\"dispute\",\"client\":1,\"tx\":1}\n";
+        let array = "  [{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.5},\n\
+                      {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"0.25\"},\n\
+                      {\"type\":\"dispute\",\"client\":1,\"tx\":1,\"amount\":null}]";
+
+        let from_csv = collect(CsvReader::new(CSV.as_bytes()).unwrap());
+        assert_eq!(collect(JsonReader::new(lines.as_bytes())), from_csv);
+        assert_eq!(collect(JsonReader::new(array.as_bytes())), from_csv);
+    }
+
+    #[test]
+    fn integer_amounts_match_csv_as_numbers_or_strings() {
+        let lines = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":100}\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"0\"}\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":-5}\n";
+        let csv = "type,client,tx,amount\ndeposit,1,1,100\ndeposit,1,2,0\ndeposit,1,3,-5\n";
+        assert_eq!(
+            collect(JsonReader::new(lines.as_bytes())),
+            collect(CsvReader::new(csv.as_bytes()).unwrap())
+        );
+    }
+
+    #[test]
+    fn numeric_amounts_keep_digits_a_float_would_lose() {
+        let lines =
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":9007199254740993.0001}\n";
+        let csv = "type,client,tx,amount\ndeposit,1,1,9007199254740993.0001\n";
+        let rows = collect(JsonReader::new(lines.as_bytes()));
+        assert_eq!(rows, collect(CsvReader::new(csv.as_bytes()).unwrap()));
+        assert!(rows[0].contains("9007199254740993.0001"), "{}", rows[0]);
+    }
+
+    #[test]
+    fn replayed_idempotency_keys_are_dropped_as_for_csv() {
+        let lines = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1,\"idempotency_key\":\"a\"}\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1,\"idempotency_key\":\"a\"}\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":1}\n";
+        let csv = "type,client,tx,amount,idempotency_key\n\
+                   deposit,1,1,1,a\n\
+                   deposit,1,2,1,a\n\
+                   deposit,1,3,1,\n";
+        let rows = collect(JsonReader::new(lines.as_bytes()));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows, collect(CsvReader::new(csv.as_bytes()).unwrap()));
+    }
+
+    #[test]
+    fn a_bad_line_is_reported_and_the_rest_still_read() {
+        let lines = "{\"type\":\"deposit\",\"client\":1}\n\
+                     not json\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":\"2\"}\n";
+        let rows = collect(JsonReader::new(lines.as_bytes()));
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].contains("JSON deserialization error"));
+        assert!(rows[1].contains("JSON error"));
+        assert!(rows[2].starts_with("Ok("));
+    }
+
+    #[test]
+    fn sniffing_keeps_the_bytes_readers_need() {
+        let mut csv = Cursor::new(format!("\n\n{}", CSV));
+        assert_eq!(sniff_format(&mut csv).unwrap(), InputFormat::Csv);
+        assert_eq!(
+            collect(CsvReader::new(csv).unwrap()),
+            collect(CsvReader::new(CSV.as_bytes()).unwrap())
+        );
+
+        let mut json = " {\"type\":\"dispute\",\"client\":1,\"tx\":1}".as_bytes();
+        assert_eq!(sniff_format(&mut json).unwrap(), InputFormat::Json);
+        assert_eq!(collect(JsonReader::new(json)).len(), 1);
+
+        assert_eq!(sniff_format(&mut "".as_bytes()).unwrap(), InputFormat::Csv);
+    }
+
+    #[test]
+    fn a_byte_order_mark_is_skipped() {
+        let json = "\u{feff}{\"type\":\"dispute\",\"client\":1,\"tx\":1}\r\n";
+        assert_eq!(
+            sniff_format(&mut json.as_bytes()).unwrap(),
+            InputFormat::Json
+        );
+        let rows = collect(JsonReader::new(json.as_bytes()));
+        assert_eq!(
+            rows,
+            collect(CsvReader::new("type,client,tx\ndispute,1,1\n".as_bytes()).unwrap())
+        );
+
+        let mut csv = Cursor::new(format!("\u{feff}{}", CSV));
+        assert_eq!(sniff_format(&mut csv).unwrap(), InputFormat::Csv);
+        assert_eq!(
+            collect(CsvReader::new(csv).unwrap()),
+            collect(CsvReader::new(CSV.as_bytes()).unwrap())
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use std::pin::Pin;
+
+use ::parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use ::parquet::file::reader::ChunkReader;
+use arrow_array::cast::AsArray;
+use arrow_array::types::UInt32Type;
+use arrow_array::{Array, ArrayRef, ArrowPrimitiveType, RecordBatch};
+use arrow_cast::{CastOptions, cast_with_options};
+use arrow_schema::DataType;
+use futures::stream::{self, Stream};
+
+use super::{CsvRow, IngestionOptions};
+use crate::domain::traits::TransactionStream;
+use crate::domain::{Error, Transaction};
+
+/// Arrow type matching `ClientId`.
+#[cfg(not(feature = "wide-client-ids"))]
+type ClientIdType = arrow_array::types::UInt16Type;
+#[cfg(feature = "wide-client-ids")]
+type ClientIdType = UInt32Type;
+
+/// Reads transactions from a Parquet file with `type`, `client`, `tx` and `amount`
+/// columns. Columns are matched by name, case-insensitively, and cast to the expected
+/// types, so e.g. a `Decimal128` or `Int64` column works as well as a string one. An
+/// optional `idempotency_key` column is honoured as for CSV input.
+pub struct ParquetReader {
+    reader: Option<ParquetRecordBatchReader>,
+    options: IngestionOptions,
+}
+
+impl ParquetReader {
+    pub fn new<R: ChunkReader + 'static>(reader: R) -> Result<Self, Error> {
+        Self::with_options(reader, IngestionOptions::default())
+    }
+
+    pub fn with_options<R: ChunkReader + 'static>(
+        reader: R,
+        options: IngestionOptions,
+    ) -> Result<Self, Error> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(reader)
+            .and_then(|builder| builder.build())
+            .map_err(|e| Error::Ingestion(format!("Parquet error: {}", e)))?;
+
+        Ok(Self {
+            reader: Some(reader),
+            options,
+        })
+    }
+}
+
+fn column(batch: &RecordBatch, name: &str, to: &DataType) -> Result<Option<ArrayRef>, Error> {
+    let Some(index) = batch
+        .schema()
+        .fields()
+        .iter()
+        .position(|f| f.name().trim().eq_ignore_ascii_case(name))
+    else {
+        return Ok(None);
+    };
+
+    // Unsafe casts fail on overflow instead of silently producing nulls.
+    let options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    cast_with_options(batch.column(index), to, &options)
+        .map(Some)
+        .map_err(|e| Error::Ingestion(format!("Parquet column {}: {}", name, e)))
+}
+
+fn required(batch: &RecordBatch, name: &str, to: &DataType) -> Result<ArrayRef, Error> {
+    column(batch, name, to)?
+        .ok_or_else(|| Error::Ingestion(format!("Parquet column {} is missing", name)))
+}
+
+fn rows(batch: &RecordBatch) -> Result<Vec<Result<CsvRow, Error>>, Error> {
+    let kinds = required(batch, "type", &DataType::Utf8)?;
+    let clients = required(batch, "client", &ClientIdType::DATA_TYPE)?;
+    let txs = required(batch, "tx", &DataType::UInt32)?;
+    let amounts = column(batch, "amount", &DataType::Utf8)?;
+    let currencies = column(batch, "currency", &DataType::Utf8)?;
+    let keys = column(batch, "idempotency_key", &DataType::Utf8)?;
+
+    let (kinds, clients, txs) = (
+        kinds.as_string::<i32>(),
+        clients.as_primitive::<ClientIdType>(),
+        txs.as_primitive::<UInt32Type>(),
+    );
+    let amounts = amounts.as_ref().map(|a| a.as_string::<i32>());
+    let currencies = currencies.as_ref().map(|c| c.as_string::<i32>());
+    let keys = keys.as_ref().map(|k| k.as_string::<i32>());
+
+    Ok((0..batch.num_rows())
+        .map(|i| {
+            if kinds.is_null(i) || clients.is_null(i) || txs.is_null(i) {
+                return Err(Error::Ingestion(format!(
+                    "Parquet row {} is missing type, client or tx",
+                    i
+                )));
+            }
+            let amount = amounts
+                .filter(|a| a.is_valid(i))
+                .map(|a| a.value(i).trim())
+                .filter(|a| !a.is_empty())
+                .map(str::to_string);
+
+            Ok(CsvRow {
+                kind: kinds.value(i).to_string(),
+                client: clients.value(i),
+                tx: txs.value(i),
+                amount,
+                currency: currencies
+                    .filter(|c| c.is_valid(i))
+                    .map(|c| c.value(i).to_string()),
+                idempotency_key: keys
+                    .filter(|k| k.is_valid(i))
+                    .map(|k| k.value(i).to_string()),
+            })
+        })
+        .collect())
+}
+
+impl TransactionStream for ParquetReader {
+    type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
+        let reader = self.reader.take().ok_or(Error::AlreadyConsumed)?;
+        let (options, skip_options) = (self.options.clone(), self.options.clone());
+        let keys = super::IdempotencyKeys::default();
+
+        let iter = reader
+            .flat_map(|batch| {
+                let rows = batch
+                    .map_err(|e| Error::Ingestion(format!("Parquet error: {}", e)))
+                    .and_then(|batch| rows(&batch));
+                match rows {
+                    Ok(rows) => rows,
+                    Err(e) => vec![Err(e)],
+                }
+            })
+            .filter(move |row| match row {
+                Ok(row) => !super::skipped(Some(&row.kind), &skip_options),
+                Err(_) => true,
+            })
+            .filter_map(move |row| match row {
+                Ok(row) => keys.transaction(row, &options),
+                Err(e) => Some(Err(e)),
+            });
+
+        Ok(Box::pin(stream::iter(iter)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ::parquet::arrow::ArrowWriter;
+    use arrow_array::{Decimal128Array, StringArray, UInt16Array, UInt32Array};
+    use arrow_schema::{Field, Schema};
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::ingestion::CsvReader;
+
+    fn write_parquet(batch: RecordBatch) -> std::fs::File {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = ArrowWriter::try_new(&mut file, batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        file
+    }
+
+    fn collect<T: TransactionStream>(mut ingestion: T) -> Vec<String> {
+        let stream = ingestion.stream().unwrap();
+        futures::executor::block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .map(|tx| format!("{:?}", tx))
+            .collect()
+    }
+
+    #[test]
+    fn parquet_rows_match_equivalent_csv() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("type", DataType::Utf8, false),
+            Field::new("client", DataType::UInt16, false),
+            Field::new("tx", DataType::UInt32, false),
+            Field::new("amount", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec![
+                    "deposit",
+                    "withdrawal",
+                    "dispute",
+                    "deposit",
+                    "bogus",
+                ])),
+                Arc::new(UInt16Array::from(vec![1, 1, 1, 2, 2])),
+                Arc::new(UInt32Array::from(vec![1, 2, 1, 3, 4])),
+                Arc::new(StringArray::from(vec![
+                    Some("1.5"),
+                    Some("0.25"),
+                    None,
+                    None,
+                    Some("1.0"),
+                ])),
+            ],
+        )
+        .unwrap();
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.5\n\
+                   withdrawal,1,2,0.25\n\
+                   dispute,1,1,\n\
+                   deposit,2,3,\n\
+                   bogus,2,4,1.0\n";
+
+        let from_parquet = collect(ParquetReader::new(write_parquet(batch)).unwrap());
+        let from_csv = collect(CsvReader::new(csv.as_bytes()).unwrap());
+        assert_eq!(from_parquet.len(), 5);
+        assert_eq!(from_parquet, from_csv);
+    }
+
+    #[test]
+    fn replayed_idempotency_keys_are_dropped_as_for_csv() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("type", DataType::Utf8, false),
+            Field::new("client", DataType::UInt16, false),
+            Field::new("tx", DataType::UInt32, false),
+            Field::new("amount", DataType::Utf8, true),
+            Field::new("idempotency_key", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec!["deposit", "deposit", "deposit"])),
+                Arc::new(UInt16Array::from(vec![1, 1, 1])),
+                Arc::new(UInt32Array::from(vec![1, 2, 3])),
+                Arc::new(StringArray::from(vec!["1.0", "1.0", "1.0"])),
+                Arc::new(StringArray::from(vec![Some("a"), Some("a"), None])),
+            ],
+        )
+        .unwrap();
+        let csv = "type,client,tx,amount,idempotency_key\n\
+                   deposit,1,1,1.0,a\n\
+                   deposit,1,2,1.0,a\n\
+                   deposit,1,3,1.0,\n";
+
+        let rows = collect(ParquetReader::new(write_parquet(batch)).unwrap());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows, collect(CsvReader::new(csv.as_bytes()).unwrap()));
+    }
+
+    #[test]
+    fn decimal_columns_and_mixed_case_names_are_accepted() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("Type", DataType::Utf8, false),
+            Field::new("Client", DataType::UInt16, false),
+            Field::new("TX", DataType::UInt32, false),
+            Field::new("Amount", DataType::Decimal128(10, 4), true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec!["deposit"])),
+                Arc::new(UInt16Array::from(vec![7])),
+                Arc::new(UInt32Array::from(vec![9])),
+                Arc::new(
+                    Decimal128Array::from(vec![Some(12_345)])
+                        .with_precision_and_scale(10, 4)
+                        .unwrap(),
+                ),
+            ],
+        )
+        .unwrap();
+
+        let from_parquet = collect(ParquetReader::new(write_parquet(batch)).unwrap());
+        let from_csv = collect(
+            CsvReader::new("type,client,tx,amount\ndeposit,7,9,1.2345\n".as_bytes()).unwrap(),
+        );
+        assert_eq!(from_parquet, from_csv);
+    }
+
+    #[test]
+    fn missing_required_column_is_an_ingestion_error() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("type", DataType::Utf8, false),
+            Field::new("tx", DataType::UInt32, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec!["deposit"])),
+                Arc::new(UInt32Array::from(vec![1])),
+            ],
+        )
+        .unwrap();
+
+        let rows = collect(ParquetReader::new(write_parquet(batch)).unwrap());
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].contains("Parquet column client is missing"));
+    }
+}
@@ -0,0 +1,306 @@
+use std::pin::Pin;
+
+use futures::stream::{self, Stream};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use super::json::JsonRow;
+use super::{CsvRow, IdempotencyKeys, IngestionOptions};
+use crate::domain::traits::TransactionStream;
+use crate::domain::{Error, Transaction};
+
+/// Control command that asks the engine to flush the current account state.
+pub const FLUSH_COMMAND: &str = "flush";
+
+/// Reads transactions from a TCP connection, one record per line, as they arrive.
+///
+/// A line is either a JSON object (`{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`)
+/// or a CSV record in `type,client,tx,amount,currency,idempotency_key` order, where the
+/// trailing columns may be left off, read under the same `IngestionOptions` as a file.
+/// A CSV header line is skipped and a `flush` line is forwarded to `flush_requests`
+/// instead of being treated as a record.
+pub struct TcpIngestion {
+    connection: Option<TcpStream>,
+    options: IngestionOptions,
+    flush_tx: mpsc::UnboundedSender<()>,
+    flush_rx: Option<mpsc::UnboundedReceiver<()>>,
+}
+
+impl TcpIngestion {
+    pub fn new(connection: TcpStream) -> Self {
+        Self::with_options(connection, IngestionOptions::default())
+    }
+
+    pub fn with_options(connection: TcpStream, options: IngestionOptions) -> Self {
+        let (flush_tx, flush_rx) = mpsc::unbounded_channel();
+        Self {
+            connection: Some(connection),
+            options,
+            flush_tx,
+            flush_rx: Some(flush_rx),
+        }
+    }
+
+    /// Waits for a single client to connect.
+    pub async fn accept(listener: &TcpListener, options: IngestionOptions) -> Result<Self, Error> {
+        let (connection, peer) = listener.accept().await?;
+        tracing::info!(%peer, "accepted connection");
+        Ok(Self::with_options(connection, options))
+    }
+
+    /// Yields once for every `flush` command received. Can only be taken once.
+    pub fn flush_requests(&mut self) -> impl Stream<Item = ()> + Send + Unpin + 'static {
+        let rx = self.flush_rx.take();
+        Box::pin(stream::unfold(rx, |rx| async move {
+            let mut rx = rx?;
+            rx.recv().await.map(|()| ((), Some(rx)))
+        }))
+    }
+}
+
+/// Reads records one line at a time, as `--listen` and `--repl` take them, keeping the
+/// idempotency keys read so far.
+pub(crate) struct LineReader {
+    options: IngestionOptions,
+    keys: IdempotencyKeys,
+}
+
+impl LineReader {
+    pub(crate) fn new(options: IngestionOptions) -> Self {
+        Self {
+            options,
+            keys: IdempotencyKeys::default(),
+        }
+    }
+
+    /// `None` for a line without a record to apply: a blank, header or comment line, a
+    /// record `skip_unknown` drops or one replaying an idempotency key.
+    pub(crate) fn read(&mut self, line: &str) -> Option<Result<Transaction, Error>> {
+        let line = line.trim();
+        if holds_no_record(line, &self.options) {
+            return None;
+        }
+        match read_row(line, &self.options) {
+            Ok(row) if super::skipped(Some(&row.kind), &self.options) => None,
+            Ok(row) => self.keys.transaction(row, &self.options),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn read_row(line: &str, options: &IngestionOptions) -> Result<CsvRow, Error> {
+    if line.starts_with('{') {
+        return serde_json::from_str::<JsonRow>(line)
+            .map_err(|e| Error::Ingestion(format!("JSON deserialization error: {}", e)))?
+            .try_into();
+    }
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(options.delimiter())
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(line.as_bytes())
+        .deserialize::<CsvRow>()
+        .next()
+        .unwrap_or_else(|| Err(csv::Error::from(std::io::Error::other("empty record"))))
+        .map_err(|e| Error::Ingestion(format!("CSV deserialization error: {}", e)))
+}
+
+/// True for a trimmed line that carries no record: a blank, header or comment line.
+fn holds_no_record(line: &str, options: &IngestionOptions) -> bool {
+    let is_header = line
+        .split(char::from(options.delimiter()))
+        .next()
+        .is_some_and(|first| first.trim().eq_ignore_ascii_case("type"));
+    line.is_empty()
+        || is_header
+        || options
+            .comment
+            .is_some_and(|c| line.as_bytes().first() == Some(&c))
+}
+
+impl TransactionStream for TcpIngestion {
+    type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
+        let connection = self.connection.take().ok_or(Error::AlreadyConsumed)?;
+        let lines = BufReader::new(connection).lines();
+        let flush_tx = self.flush_tx.clone();
+        let reader = LineReader::new(self.options.clone());
+
+        Ok(Box::pin(stream::unfold(
+            (lines, flush_tx, reader),
+            |(mut lines, flush_tx, mut reader)| async move {
+                loop {
+                    let line = match lines.next_line().await {
+                        Ok(Some(line)) => line,
+                        Ok(None) => return None,
+                        Err(e) => return Some((Err(Error::IO(e)), (lines, flush_tx, reader))),
+                    };
+                    if line.trim().eq_ignore_ascii_case(FLUSH_COMMAND) {
+                        tracing::debug!("flush command received");
+                        let _ = flush_tx.send(());
+                        // Give the engine a chance to flush before the next record is read.
+                        tokio::task::yield_now().await;
+                        continue;
+                    }
+                    if let Some(tx) = reader.read(&line) {
+                        return Some((tx, (lines, flush_tx, reader)));
+                    }
+                }
+            },
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::TransactionKind;
+    use crate::engine::Engine;
+    use crate::output_repository::WriterOutput;
+    use rust_decimal::Decimal;
+    use tokio::io::AsyncWriteExt;
+
+    fn parse(line: &str) -> Result<Transaction, Error> {
+        LineReader::new(IngestionOptions::default())
+            .read(line)
+            .expect("not skipped")
+    }
+
+    #[test]
+    fn parses_csv_and_json_lines() {
+        let tx = parse("deposit, 1, 2, 1.5").unwrap();
+        assert!(
+            matches!(tx.kind, TransactionKind::Deposit { amount } if amount == Decimal::new(15, 1))
+        );
+        assert_eq!((tx.client_id, tx.transaction_id), (1, 2));
+
+        let tx = parse(r#"{"type":"withdrawal","client":3,"tx":4,"amount":"0.5"}"#).unwrap();
+        assert!(matches!(tx.kind, TransactionKind::Withdrawal { .. }));
+        assert_eq!((tx.client_id, tx.transaction_id), (3, 4));
+
+        let tx = parse(r#"{"type":"deposit","client":3,"tx":5,"amount":2.25}"#).unwrap();
+        assert!(
+            matches!(tx.kind, TransactionKind::Deposit { amount } if amount == Decimal::new(225, 2))
+        );
+
+        let tx = parse(r#"{"type":"dispute","client":3,"tx":4}"#).unwrap();
+        assert!(matches!(tx.kind, TransactionKind::Dispute { amount: None }));
+
+        assert!(matches!(parse("{not json"), Err(Error::Ingestion(_))));
+    }
+
+    #[test]
+    fn lines_are_read_under_the_ingestion_options() {
+        let options = IngestionOptions {
+            lenient_amounts: true,
+            skip_unknown: true,
+            delimiter: Some(b';'),
+            decimal_comma: true,
+            comment: Some(b'#'),
+            ..Default::default()
+        };
+        let mut reader = LineReader::new(options);
+        assert!(reader.read("type;client;tx;amount").is_none());
+        assert!(reader.read("# a note").is_none());
+        assert!(reader.read("refund;1;1;1,5").is_none());
+        assert!(
+            reader
+                .read(r#"{"type":"refund","client":1,"tx":1}"#)
+                .is_none()
+        );
+
+        let mut amount = |line| match reader.read(line) {
+            Some(Ok(Transaction {
+                kind: TransactionKind::Deposit { amount },
+                ..
+            })) => amount,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(amount("deposit;1;2;1,5"), Decimal::new(15, 1));
+        assert_eq!(amount("deposit;1;3;1.000,5"), Decimal::new(10005, 1));
+        assert!(matches!(
+            parse("deposit,1,2,1e3"),
+            Err(Error::InvalidAmount(_))
+        ));
+    }
+
+    #[test]
+    fn replayed_idempotency_keys_are_dropped_across_formats() {
+        let mut reader = LineReader::new(IngestionOptions::default());
+        let mut read = |line| {
+            reader
+                .read(line)
+                .map(|tx| tx.map(|tx| tx.transaction_id).ok())
+        };
+        assert_eq!(read("deposit,1,1,1.0,,a"), Some(Some(1)));
+        assert_eq!(
+            read(r#"{"type":"deposit","client":1,"tx":2,"amount":"1.0","idempotency_key":"a"}"#),
+            None
+        );
+        assert_eq!(
+            read(r#"{"type":"deposit","client":1,"tx":3,"amount":"1.0","idempotency_key":"b"}"#),
+            Some(Some(3))
+        );
+        assert_eq!(read("deposit,1,4,1.0,,b"), None);
+        assert_eq!(read("deposit,1,5,1.0"), Some(Some(5)));
+    }
+
+    #[tokio::test]
+    async fn second_stream_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move { TcpStream::connect(addr).await.unwrap() });
+
+        let mut ingestion = TcpIngestion::accept(&listener, IngestionOptions::default())
+            .await
+            .unwrap();
+        assert!(ingestion.stream().is_ok());
+        assert!(matches!(ingestion.stream(), Err(Error::AlreadyConsumed)));
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn loopback_feed_is_processed_and_flushed_on_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            conn.write_all(
+                b"type,client,tx,amount\n\
+                  deposit,1,1,2.0\n\
+                  {\"type\":\"deposit\",\"client\":2,\"tx\":2,\"amount\":\"3.5\"}\n\
+                  withdrawal,1,3,0.5\n\
+                  flush\n",
+            )
+            .await
+            .unwrap();
+            conn.shutdown().await.unwrap();
+        });
+
+        let mut ingestion = TcpIngestion::accept(&listener, IngestionOptions::default())
+            .await
+            .unwrap();
+        let flushes = ingestion.flush_requests();
+        let mut engine = Engine::builder(ingestion)
+            .output(WriterOutput::with_writer(Vec::new()))
+            .build();
+        engine.process_with_flushes(flushes).await.unwrap();
+        client.await.unwrap();
+
+        let out = String::from_utf8(engine.output_repository().writer().clone()).unwrap();
+        let mut lines: Vec<_> = out.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "1,1.5000,0.0000,1.5000,false",
+                "2,3.5000,0.0000,3.5000,false",
+                "client,available,held,total,locked"
+            ]
+        );
+    }
+}
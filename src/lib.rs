@@ -0,0 +1,19 @@
+pub mod audit;
+pub mod checkpoint;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod dlq;
+pub mod domain;
+pub mod engine;
+pub mod ingestion;
+pub mod metrics;
+pub mod output_repository;
+pub mod policy;
+pub mod repl;
+pub mod retention;
+pub mod retry;
+pub mod snapshot;
+pub mod spill;
+pub mod summary;
+pub mod validation;
@@ -1,27 +1,309 @@
-mod dlq;
-mod domain;
-mod engine;
-mod ingestion;
-mod output_repository;
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader},
+    time::{Duration, Instant},
+};
 
-use std::{env, fs::File, path::Path};
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::net::TcpListener;
+use tracing_subscriber::EnvFilter;
+
+use payments_engine::{
+    checkpoint::Checkpoints,
+    cli::{self, Args},
+    dlq,
+    domain::{
+        Amount, Error,
+        traits::{OutputRepository, TransactionStream},
+    },
+    engine,
+    ingestion::{self, InputFormat, dir::DirectoryReader, json::JsonReader, tcp::TcpIngestion},
+    output_repository, repl,
+    retry::{self, RetryBuffer},
+    snapshot,
+    spill::AccountSpill,
+    validation,
+};
+
+/// Exit status used when `--timeout` elapses, mirroring coreutils `timeout`.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Exit status used when `--client` names a client that has no account.
+const CLIENT_NOT_FOUND_EXIT_CODE: i32 = 1;
+
+/// Exit status used when `--verify` finds inconsistent accounts.
+const INCONSISTENT_EXIT_CODE: i32 = 3;
+
+/// Exit status used for invalid arguments, as with most command line tools.
+const USAGE_EXIT_CODE: i32 = 2;
+
+/// Exit status used when `--fail-fast` stops at a rejected transaction.
+const FAILED_FAST_EXIT_CODE: i32 = 1;
+
+/// Exit status used after SIGINT, following the shell's 128 + signal convention.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut args = env::args();
+    // Silent unless RUST_LOG is set; logs go to stderr so they never mix with the CSV.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")),
+        )
+        .with_writer(io::stderr)
+        .init();
+
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(USAGE_EXIT_CODE);
+        }
+    };
+    if args.help {
+        print!("{}", cli::USAGE);
+        return Ok(());
+    }
+    if args.version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    if args.repl {
+        return repl(&args);
+    }
+
+    match (&args.listen, &args.input) {
+        (Some(addr), _) => {
+            let listener = TcpListener::bind(addr).await?;
+            let mut ingestion = TcpIngestion::accept(&listener, args.ingestion_options()).await?;
+            let commands = ingestion.flush_requests().boxed();
+            run(ingestion, commands, &args).await
+        }
+        (None, Some(path)) if path.is_dir() => {
+            let ingestion = DirectoryReader::with_options(path, args.ingestion_options())?;
+            run(ingestion, stream::empty().boxed(), &args).await
+        }
+        #[cfg(feature = "parquet")]
+        (None, Some(path)) if path.extension().is_some_and(|ext| ext == "parquet") => {
+            let ingestion = ingestion::parquet::ParquetReader::with_options(
+                File::open(path)?,
+                args.ingestion_options(),
+            )?;
+            run(ingestion, stream::empty().boxed(), &args).await
+        }
+        (None, Some(path)) => {
+            let mut reader = BufReader::new(File::open(path)?);
+            let format = match args.input_format {
+                InputFormat::Auto => ingestion::sniff_format(&mut reader)?,
+                format => format,
+            };
+            if format == InputFormat::Json {
+                let ingestion = JsonReader::with_options(reader, args.ingestion_options());
+                run(ingestion, stream::empty().boxed(), &args).await
+            } else {
+                let ingestion =
+                    ingestion::CsvReader::with_options(reader, args.ingestion_options())?;
+                run(ingestion, stream::empty().boxed(), &args).await
+            }
+        }
+        (None, None) => unreachable!("Args::parse requires an input or --listen"),
+    }
+}
 
-    let file_path = args.nth(1).expect("No command line argument was provided");
-    let file_path = Path::new(&file_path);
-    let file = File::open(file_path)?;
+async fn run<I: TransactionStream>(
+    ingestion: I,
+    flushes: BoxStream<'static, ()>,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut dlq = match args.dlq_rate_limit {
+        Some(limit) => dlq::CompositeDLQ::new()
+            .with(dlq::RateLimitedDLQ::new(dlq::StdErrDLQ::default(), limit)),
+        None => dlq::CompositeDLQ::new().with(dlq::StdErrDLQ::default()),
+    };
+    if let Some(path) = &args.rejects_out {
+        dlq.push(dlq::RejectsDLQ::create(path, dlq::NoopDLQ)?);
+    }
+    let destination =
+        output_repository::Destination::create(args.output.as_deref(), args.tee, args.output_gzip)?;
+    let mut output = output_repository::WriterOutput::with_writer(destination)
+        .with_decimals(args.output_decimals());
+    if args.change_feed {
+        output = output.with_change_feed();
+    }
+    if args.with_counts {
+        output = output.with_counts();
+    }
+    if args.pretty {
+        output = output.with_pretty();
+    }
+    if let Some(capacity) = args.max_resident_accounts {
+        output = output.with_spill(AccountSpill::new(capacity)?);
+    }
 
-    let ingestion = ingestion::CsvReader::new(file)?;
-    let dlq = dlq::StdErrDLQ::default();
-    let output = output_repository::StdOutOutput::new();
+    let ingestion = ingestion::SkipUntil::new(ingestion, args.skip_until);
+    let ingestion = ingestion::ReplayUntil::new(ingestion, args.replay_until);
+    let mut engine = engine::Engine::new(ingestion, output, dlq).with_policy(args.policy());
+    #[cfg(feature = "metrics-http")]
+    if let Some(addr) = &args.metrics_addr {
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(payments_engine::metrics::serve(listener, engine.metrics()));
+    }
+    if args.fail_fast {
+        engine = engine.with_fail_fast();
+    }
+    if args.reject_negative_amounts {
+        engine = engine.with_validator(validation::NonNegativeAmountValidator);
+    }
+    if args.emit_touched_clients {
+        engine = engine.with_emit_touched_clients();
+    }
+    if let (Some(every), Some(path)) = (args.checkpoint_every, &args.output) {
+        let format = engine.output_repository().row_format();
+        engine = engine.with_checkpoints(Checkpoints::new(every, path).with_format(format));
+    }
+    if let Some(capacity) = args.retry_buffer {
+        engine = engine.with_retry_buffer(RetryBuffer::new(capacity, retry::DEFAULT_MAX_ATTEMPTS));
+    }
 
-    let mut engine = engine::Engine::new(ingestion, output, dlq);
+    if let Some(path) = &args.restore {
+        let accounts = snapshot::read_accounts(File::open(path)?)?;
+        engine.restore(accounts, args.repair)?;
+    }
 
-    engine.process().await?;
-    engine.flush();
+    let flushes = match args.flush_every {
+        Some(every) => stream::select(flushes, ticks(every)).boxed(),
+        None => flushes,
+    };
+    let processing = engine.process_with_flushes(flushes);
+    let processing = async {
+        match args.timeout {
+            Some(limit) => tokio::time::timeout(limit, processing)
+                .await
+                .unwrap_or(Err(Error::Timeout(limit))),
+            None => processing.await,
+        }
+    };
+    // On SIGINT the processing future is dropped, which releases the engine for the flush below.
+    let res = tokio::select! {
+        res = processing => res,
+        _ = tokio::signal::ctrl_c() => Err(Error::Interrupted),
+    };
+    if let Some(transaction_id) = args.replay_until
+        && res.is_ok()
+        && !engine.ingestion().reached()
+    {
+        eprintln!(
+            "Transaction {} not found; replayed the whole input",
+            transaction_id
+        );
+    }
+    // Flush whatever was applied, even if processing was cut short.
+    let flushed = match args.client {
+        Some(client_id) => engine.flush_client(client_id),
+        None => engine.flush().map(|()| true),
+    };
+    // Even when the flush failed: an early exit would skip the gzip trailer and the
+    // summary.
+    let finished = engine.output_repository_mut().writer_mut().finish();
+    let summarized = match &args.summary {
+        Some(path) => engine.summary(started.elapsed()).write(path),
+        None => Ok(()),
+    };
+    let found = flushed?;
+    finished?;
+    summarized?;
+    if let (Some(client_id), false) = (args.client, found) {
+        eprintln!("Client {} not found", client_id);
+        std::process::exit(CLIENT_NOT_FOUND_EXIT_CODE);
+    }
+
+    if args.report_dust {
+        report_dust(engine.output_repository(), args.output_decimals());
+    }
+
+    if args.report_open_disputes {
+        for (client_id, transaction_id, amount) in engine.open_disputes() {
+            eprintln!(
+                "Open dispute - client {} tx {}: {}",
+                client_id,
+                transaction_id,
+                output_repository::format_amount_to(amount, args.output_decimals())
+            );
+        }
+    }
+
+    if args.verify
+        && let Err(offenders) = engine.verify_invariants()
+    {
+        for offender in offenders {
+            eprintln!("Inconsistent account - {}", offender);
+        }
+        std::process::exit(INCONSISTENT_EXIT_CODE);
+    }
+
+    match res {
+        Err(e @ Error::Timeout(_)) => {
+            eprintln!("{}", e);
+            std::process::exit(TIMEOUT_EXIT_CODE);
+        }
+        Err(e @ Error::Interrupted) => {
+            eprintln!("{}", e);
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        Err(e @ Error::FailedFast { .. }) => {
+            eprintln!("{}", e);
+            std::process::exit(FAILED_FAST_EXIT_CODE);
+        }
+        res => res?,
+    }
 
     Ok(())
 }
+
+/// Applies what is typed on stdin line by line, printing the client's balances after
+/// each, under the same rules as a run over a file.
+fn repl(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let output = output_repository::MemoryOutput::new().with_decimals(args.output_decimals());
+    let format = output.row_format();
+    let mut engine =
+        engine::Engine::new(repl::NoInput, output, dlq::NoopDLQ).with_policy(args.policy());
+    if args.reject_negative_amounts {
+        engine = engine.with_validator(validation::NonNegativeAmountValidator);
+    }
+    if let Some(path) = &args.restore {
+        let accounts = snapshot::read_accounts(File::open(path)?)?;
+        engine.restore(accounts, args.repair)?;
+    }
+    repl::run(
+        &mut engine,
+        io::stdin().lock(),
+        io::stdout().lock(),
+        io::stderr().lock(),
+        &format,
+        &args.ingestion_options(),
+    )?;
+    Ok(())
+}
+
+/// Reports, per account, the part of the total that printing `decimals` places rounds away.
+fn report_dust<O: OutputRepository>(output: &O, decimals: u32) {
+    let mut accounts = output.accounts();
+    accounts.sort_by_key(|(client_id, _)| *client_id);
+    for (client_id, account) in accounts {
+        let dust = output_repository::dust(account.total.to_decimal(), decimals);
+        if !dust.is_zero() {
+            eprintln!("Dust - client {}: {}", client_id, dust);
+        }
+    }
+}
+
+fn ticks(every: Duration) -> BoxStream<'static, ()> {
+    stream::unfold((), move |()| async move {
+        tokio::time::sleep(every).await;
+        Some(((), ()))
+    })
+    .boxed()
+}
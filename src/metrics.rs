@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Transaction kinds counted apart, as named in the input `type` column.
+pub const KINDS: [&str; 9] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "freeze",
+    "unfreeze",
+    "hold",
+    "release",
+];
+
+/// Rejected rows that could not be read, so have no kind.
+pub const UNREADABLE: &str = "unreadable";
+
+/// Running counters for an engine, cumulative across processing runs. Shared behind an
+/// `Arc` so they can be read while the engine keeps processing.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    processed: AtomicU64,
+    rejected: AtomicU64,
+    locked: AtomicU64,
+    // indexed like `KINDS`; rejections have one more slot, for unreadable rows
+    processed_by_kind: [AtomicU64; KINDS.len()],
+    rejected_by_kind: [AtomicU64; KINDS.len() + 1],
+}
+
+impl Metrics {
+    pub(crate) fn record_processed(&self, kind: &str) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        self.processed_by_kind[kind_index(Some(kind))].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `kind` is `None` for a row that could not be read.
+    pub(crate) fn record_rejected(&self, kind: Option<&str>) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        self.rejected_by_kind[kind_index(kind)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_locked(&self) {
+        self.locked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Transactions applied successfully.
+    pub fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Rows reported to the dead letter queue.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Accounts that went from unlocked to locked, by chargeback or freeze.
+    pub fn locked(&self) -> u64 {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// `processed` split by transaction kind, every kind listed.
+    pub fn processed_by_kind(&self) -> BTreeMap<&'static str, u64> {
+        by_kind(&self.processed_by_kind)
+    }
+
+    /// `rejected` split by transaction kind, every kind listed, with rows that could
+    /// not be read under `UNREADABLE`.
+    pub fn rejected_by_kind(&self) -> BTreeMap<&'static str, u64> {
+        by_kind(&self.rejected_by_kind)
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            (
+                "payments_transactions_processed_total",
+                "Transactions applied successfully.",
+                self.processed(),
+            ),
+            (
+                "payments_transactions_rejected_total",
+                "Rows reported to the dead letter queue.",
+                self.rejected(),
+            ),
+            (
+                "payments_accounts_locked_total",
+                "Accounts locked by a chargeback or freeze.",
+                self.locked(),
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+/// Slot of `kind` in the per-kind counters; the one past `KINDS` for unreadable rows.
+fn kind_index(kind: Option<&str>) -> usize {
+    kind.and_then(|kind| KINDS.iter().position(|&known| known == kind))
+        .unwrap_or(KINDS.len())
+}
+
+fn by_kind(counters: &[AtomicU64]) -> BTreeMap<&'static str, u64> {
+    KINDS
+        .iter()
+        .chain([&UNREADABLE])
+        .zip(counters)
+        .map(|(&kind, count)| (kind, count.load(Ordering::Relaxed)))
+        .collect()
+}
+
+/// Serves `GET /metrics` on `listener` until the task is dropped.
+#[cfg(feature = "metrics-http")]
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    metrics: std::sync::Arc<Metrics>,
+) -> std::io::Result<()> {
+    use axum::{Router, extract::State, http::header, response::IntoResponse, routing::get};
+
+    async fn scrape(State(metrics): State<std::sync::Arc<Metrics>>) -> impl IntoResponse {
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics.render(),
+        )
+    }
+
+    let app = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(metrics);
+    axum::serve(listener, app).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_counters() {
+        let metrics = Metrics::default();
+        metrics.record_processed("deposit");
+        metrics.record_processed("dispute");
+        metrics.record_rejected(None);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE payments_transactions_processed_total counter\n"));
+        assert!(text.contains("payments_transactions_processed_total 2\n"));
+        assert!(text.contains("payments_transactions_rejected_total 1\n"));
+        assert!(text.contains("payments_accounts_locked_total 0\n"));
+    }
+
+    #[test]
+    fn counts_are_split_by_kind() {
+        let metrics = Metrics::default();
+        metrics.record_processed("deposit");
+        metrics.record_processed("deposit");
+        metrics.record_rejected(Some("withdrawal"));
+        metrics.record_rejected(None);
+
+        let processed = metrics.processed_by_kind();
+        assert_eq!(processed.len(), KINDS.len());
+        assert_eq!(processed["deposit"], 2);
+        assert_eq!(processed["withdrawal"], 0);
+        let rejected = metrics.rejected_by_kind();
+        assert_eq!(rejected["withdrawal"], 1);
+        assert_eq!(rejected[UNREADABLE], 1);
+        assert_eq!(rejected.values().sum::<u64>(), metrics.rejected());
+    }
+
+    #[cfg(feature = "metrics-http")]
+    #[tokio::test]
+    async fn endpoint_serves_counters_after_processing() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::domain::{Transaction, TransactionKind};
+        use crate::engine::Engine;
+        use crate::ingestion::CsvReader;
+
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     withdrawal,1,2,9.0\n\
+                     dispute,1,1,\n\
+                     chargeback,1,1,\n";
+        let mut engine = Engine::builder(CsvReader::new(input.as_bytes()).unwrap()).build();
+        engine.process().await.unwrap();
+        // a locked account rejects everything else
+        engine
+            .process_all(vec![Ok(Transaction {
+                kind: TransactionKind::Deposit { amount: 1.into() },
+                client_id: 1,
+                transaction_id: 3,
+                currency: None,
+            })])
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, engine.metrics()));
+
+        let mut conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        server.abort();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("payments_transactions_processed_total 3\n"));
+        assert!(response.contains("payments_transactions_rejected_total 2\n"));
+        assert!(response.contains("payments_accounts_locked_total 1\n"));
+    }
+}
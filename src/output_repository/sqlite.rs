@@ -0,0 +1,465 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use rusqlite::{Connection, params};
+use rust_decimal::Decimal;
+
+use super::WriterOutput;
+use crate::domain::{
+    Account, AccountSnapshot, Amount, ClientId, Error, OutputRepository, Transaction,
+    TransactionKind,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    client    INTEGER PRIMARY KEY,
+    available TEXT NOT NULL,
+    held      TEXT NOT NULL,
+    total     TEXT NOT NULL,
+    locked    INTEGER NOT NULL,
+    frozen    INTEGER NOT NULL,
+    currency  TEXT,
+    on_hold   TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS ledger (
+    client   INTEGER NOT NULL,
+    tx       INTEGER NOT NULL,
+    kind     TEXT NOT NULL,
+    amount   TEXT NOT NULL,
+    currency TEXT,
+    disputed TEXT,
+    PRIMARY KEY (client, tx)
+);
+";
+
+/// Keeps accounts and the ledger in a SQLite file, so a run can be queried with SQL
+/// afterwards and resumed by opening the same file again.
+///
+/// The engine works on an in-memory copy; `flush` writes what changed since the last
+/// flush in one transaction, so the file always holds the state of some flush. Amounts
+/// are stored as exact decimal text, and a ledger row's `disputed` holds the signed
+/// amount of its open dispute, `NULL` when there is none. The `(client, tx)` primary key
+/// backs the per-client uniqueness of tx ids.
+#[derive(Debug)]
+pub struct SqliteOutput<A = Decimal> {
+    connection: Connection,
+    inner: WriterOutput<io::Sink, A>,
+    // changed since the last flush
+    dirty_accounts: HashSet<ClientId>,
+    dirty_ledger: HashSet<(ClientId, u32)>,
+    // everything is deleted before the next flush writes
+    cleared: bool,
+}
+
+impl<A: Amount> SqliteOutput<A> {
+    /// Opens or creates the database at `path` and loads whatever an earlier run
+    /// flushed to it.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path).map_err(sql_error)?)
+    }
+
+    /// A database that lives only as long as the output, e.g. for tests.
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::with_connection(Connection::open_in_memory().map_err(sql_error)?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, Error> {
+        connection.execute_batch(SCHEMA).map_err(sql_error)?;
+        let mut output = Self {
+            connection,
+            inner: WriterOutput::with_writer(io::sink()),
+            dirty_accounts: HashSet::new(),
+            dirty_ledger: HashSet::new(),
+            cleared: false,
+        };
+        output.load()?;
+        Ok(output)
+    }
+
+    /// The underlying connection, e.g. to query the flushed state.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        let mut accounts = self
+            .connection
+            .prepare(
+                "SELECT client, available, held, total, locked, frozen, currency, on_hold \
+                 FROM accounts",
+            )
+            .map_err(sql_error)?;
+        let rows = accounts
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, ClientId>(0)?,
+                    [
+                        row.get::<_, String>(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(7)?,
+                    ],
+                    row.get::<_, bool>(4)?,
+                    row.get::<_, bool>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            })
+            .map_err(sql_error)?;
+        for row in rows {
+            let (client_id, [available, held, total, on_hold], locked, frozen, currency) =
+                row.map_err(sql_error)?;
+            let account = Account {
+                available: parse_amount(&available)?,
+                held: parse_amount(&held)?,
+                total: parse_amount(&total)?,
+                locked,
+                frozen,
+                currency,
+                on_hold: parse_amount(&on_hold)?,
+            };
+            self.inner.restore_account(client_id, account);
+        }
+
+        // rowid order is the order the transactions were first recorded in
+        let mut ledger = self
+            .connection
+            .prepare(
+                "SELECT client, tx, kind, amount, currency, disputed FROM ledger ORDER BY rowid",
+            )
+            .map_err(sql_error)?;
+        let rows = ledger
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, ClientId>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                ))
+            })
+            .map_err(sql_error)?;
+        for row in rows {
+            let (client_id, transaction_id, kind, raw, currency, disputed) =
+                row.map_err(sql_error)?;
+            let amount = parse_amount(&raw)?;
+            let kind = match kind.as_str() {
+                "deposit" => TransactionKind::Deposit { amount },
+                "withdrawal" => TransactionKind::Withdrawal { amount },
+                other => return Err(corrupt(format!("ledger kind {}", other))),
+            };
+            let tx = Transaction {
+                kind,
+                client_id,
+                transaction_id,
+                currency,
+            };
+            self.inner.report_transaction(&transaction_id, &tx)?;
+            if let Some(disputed) = disputed {
+                self.inner.mark_transaction_disputed(
+                    client_id,
+                    transaction_id,
+                    parse_amount(&disputed)?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the changes since the last flush in one transaction.
+    fn commit(&mut self) -> rusqlite::Result<()> {
+        let tx = self.connection.transaction()?;
+        if self.cleared {
+            tx.execute_batch("DELETE FROM accounts; DELETE FROM ledger;")?;
+        }
+
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO accounts \
+                 (client, available, held, total, locked, frozen, currency, on_hold) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) \
+                 ON CONFLICT (client) DO UPDATE SET available = ?2, held = ?3, total = ?4, \
+                 locked = ?5, frozen = ?6, currency = ?7, on_hold = ?8",
+            )?;
+            for client_id in &self.dirty_accounts {
+                let Some(account) = self.inner.accounts.get(client_id) else {
+                    continue;
+                };
+                upsert.execute(params![
+                    client_id,
+                    text(account.available),
+                    text(account.held),
+                    text(account.total),
+                    account.locked,
+                    account.frozen,
+                    account.currency,
+                    text(account.on_hold),
+                ])?;
+            }
+
+            // Updating in place keeps the rowid, and with it the recorded order.
+            let mut upsert = tx.prepare(
+                "INSERT INTO ledger (client, tx, kind, amount, currency, disputed) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
+                 ON CONFLICT (client, tx) DO UPDATE SET disputed = ?6",
+            )?;
+            let mut delete = tx.prepare("DELETE FROM ledger WHERE client = ?1 AND tx = ?2")?;
+            // sorted so rows are inserted in a stable order
+            let mut keys: Vec<_> = self.dirty_ledger.iter().copied().collect();
+            keys.sort_unstable();
+            for (client_id, transaction_id) in keys {
+                let Some(transaction) = self.inner.get_transaction(client_id, transaction_id)
+                else {
+                    delete.execute(params![client_id, transaction_id])?;
+                    continue;
+                };
+                let (TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount }) =
+                    transaction.kind
+                else {
+                    continue;
+                };
+                let disputed = self.inner.disputed_amount(client_id, transaction_id);
+                upsert.execute(params![
+                    client_id,
+                    transaction_id,
+                    transaction.kind.name(),
+                    text(amount),
+                    transaction.currency,
+                    disputed.map(text),
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+}
+
+impl<A: Amount> OutputRepository for SqliteOutput<A> {
+    type Amount = A;
+
+    fn get_or_create_account(&mut self, client_id: &ClientId) -> Result<&mut Account<A>, Error> {
+        // the engine may change whatever it gets a mutable reference to
+        self.dirty_accounts.insert(*client_id);
+        self.inner.get_or_create_account(client_id)
+    }
+
+    fn get_account(&self, client_id: ClientId) -> Option<AccountSnapshot<A>> {
+        self.inner.get_account(client_id)
+    }
+
+    fn accounts(&self) -> Vec<(ClientId, AccountSnapshot<A>)> {
+        self.inner.accounts()
+    }
+
+    fn account_count(&self) -> usize {
+        self.inner.account_count()
+    }
+
+    fn restore_account(&mut self, client_id: ClientId, account: Account<A>) {
+        self.dirty_accounts.insert(client_id);
+        self.inner.restore_account(client_id, account);
+    }
+
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.dirty_accounts.clear();
+        self.dirty_ledger.clear();
+        self.cleared = true;
+    }
+
+    /// Commits the changes since the last flush. On failure nothing of them is kept in
+    /// the file, and the next flush tries them again.
+    fn flush(&mut self) -> Result<(), Error> {
+        self.commit().map_err(sql_error)?;
+        self.dirty_accounts.clear();
+        self.dirty_ledger.clear();
+        self.cleared = false;
+        Ok(())
+    }
+
+    fn report_transaction(
+        &mut self,
+        transaction_id: &u32,
+        transaction: &Transaction<A>,
+    ) -> Result<(), Error> {
+        self.inner.report_transaction(transaction_id, transaction)?;
+        self.dirty_ledger
+            .insert((transaction.client_id, *transaction_id));
+        Ok(())
+    }
+
+    fn get_transaction(&self, client_id: ClientId, transaction_id: u32) -> Option<&Transaction<A>> {
+        self.inner.get_transaction(client_id, transaction_id)
+    }
+
+    fn client_transactions(&self, client_id: ClientId) -> Vec<&Transaction<A>> {
+        self.inner.client_transactions(client_id)
+    }
+
+    fn forget_transaction(&mut self, client_id: ClientId, transaction_id: u32) {
+        self.inner.forget_transaction(client_id, transaction_id);
+        self.dirty_ledger.insert((client_id, transaction_id));
+    }
+
+    fn mark_transaction_disputed(&mut self, client_id: ClientId, transaction_id: u32, amount: A) {
+        self.inner
+            .mark_transaction_disputed(client_id, transaction_id, amount);
+        self.dirty_ledger.insert((client_id, transaction_id));
+    }
+
+    fn mark_transaction_resolved(&mut self, client_id: ClientId, transaction_id: u32) {
+        self.inner
+            .mark_transaction_resolved(client_id, transaction_id);
+        self.dirty_ledger.insert((client_id, transaction_id));
+    }
+
+    fn disputed_amount(&self, client_id: ClientId, transaction_id: u32) -> Option<A> {
+        self.inner.disputed_amount(client_id, transaction_id)
+    }
+
+    fn open_disputes(&self, client_id: ClientId) -> usize {
+        self.inner.open_disputes(client_id)
+    }
+
+    fn disputes(&self) -> Vec<(ClientId, u32, A)> {
+        self.inner.disputes()
+    }
+}
+
+fn text<A: Amount>(amount: A) -> String {
+    amount.to_decimal().to_string()
+}
+
+fn parse_amount<A: Amount>(raw: &str) -> Result<A, Error> {
+    Decimal::from_str(raw)
+        .ok()
+        .and_then(A::from_decimal)
+        .ok_or_else(|| corrupt(format!("amount {}", raw)))
+}
+
+fn corrupt(what: String) -> Error {
+    Error::IO(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("SQLite output has an invalid {}", what),
+    ))
+}
+
+fn sql_error(e: rusqlite::Error) -> Error {
+    Error::IO(io::Error::other(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dlq::NoopDLQ;
+    use crate::engine::Engine;
+    use crate::ingestion::CsvReader;
+    use rusqlite::OptionalExtension;
+
+    /// Runs `input` against the database at `path` and flushes.
+    fn run(path: &Path, input: &'static str) {
+        let output = SqliteOutput::<Decimal>::open(path).unwrap();
+        let mut engine = Engine::new(CsvReader::new(input.as_bytes()).unwrap(), output, NoopDLQ);
+        futures::executor::block_on(engine.process()).unwrap();
+        engine.flush().unwrap();
+    }
+
+    fn account(db: &Connection, client_id: ClientId) -> Option<(String, String, String, bool)> {
+        db.query_row(
+            "SELECT available, held, total, locked FROM accounts WHERE client = ?1",
+            [client_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .unwrap()
+    }
+
+    fn disputed(db: &Connection) -> Vec<(ClientId, u32, String)> {
+        db.prepare("SELECT client, tx, disputed FROM ledger WHERE disputed IS NOT NULL")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn balances(
+        available: &str,
+        held: &str,
+        total: &str,
+    ) -> Option<(String, String, String, bool)> {
+        Some((available.into(), held.into(), total.into(), false))
+    }
+
+    #[test]
+    fn flushed_state_can_be_queried_with_sql() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.db");
+        run(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,1.5\n\
+             deposit,1,2,2.0\n\
+             deposit,2,3,4.0\n\
+             dispute,1,2,\n\
+             withdrawal,2,4,1.0\n",
+        );
+
+        let db = Connection::open(&path).unwrap();
+        assert_eq!(account(&db, 1), balances("1.5", "2", "3.5"));
+        assert_eq!(account(&db, 2), balances("3", "0", "3"));
+        assert_eq!(disputed(&db), vec![(1, 2, "2".to_string())]);
+        let rows: u32 = db
+            .query_row("SELECT COUNT(*) FROM ledger", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 4);
+    }
+
+    #[test]
+    fn reopening_resumes_disputes_and_keeps_ids_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.db");
+        run(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,1.5\n\
+             deposit,1,2,2.0\n\
+             dispute,1,2,\n",
+        );
+
+        let reopened = SqliteOutput::<Decimal>::open(&path).unwrap();
+        assert_eq!(reopened.disputed_amount(1, 2), Some(Decimal::new(20, 1)));
+        assert_eq!(reopened.open_disputes(1), 1);
+        let ids: Vec<_> = reopened
+            .client_transactions(1)
+            .iter()
+            .map(|tx| tx.transaction_id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+        drop(reopened);
+
+        // the dispute settles in the second run, and the replayed tx 1 is refused
+        run(
+            &path,
+            "type,client,tx,amount\nresolve,1,2,\ndeposit,1,1,9.0\n",
+        );
+
+        let db = Connection::open(&path).unwrap();
+        assert_eq!(account(&db, 1), balances("3.5", "0", "3.5"));
+        assert!(disputed(&db).is_empty());
+    }
+
+    #[test]
+    fn clear_empties_the_file_on_the_next_flush() {
+        let mut output = SqliteOutput::<Decimal>::in_memory().unwrap();
+        output.get_or_create_account(&1).unwrap().available = Decimal::ONE;
+        output.flush().unwrap();
+        assert!(account(output.connection(), 1).is_some());
+
+        output.clear();
+        assert!(account(output.connection(), 1).is_some());
+        output.flush().unwrap();
+        assert!(account(output.connection(), 1).is_none());
+    }
+}
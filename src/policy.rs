@@ -0,0 +1,28 @@
+use rust_decimal::Decimal;
+
+/// Business rules that vary between payment schemes.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Whether a dispute may reference a withdrawal. Deposits can always be disputed.
+    /// Disputing a withdrawal returns its amount to available and holds it negatively.
+    pub allow_withdrawal_disputes: bool,
+    /// Reject a new dispute once the client already has this many open.
+    pub max_open_disputes: Option<usize>,
+    /// How far below zero a withdrawal may take available funds.
+    pub overdraft: Decimal,
+    /// Reject a dispute that would take the client's held funds, manual holds
+    /// included, above this. A partial dispute only counts the part it holds, and a
+    /// withdrawal dispute lowers held, so neither is ever limited by more than that.
+    pub max_held: Option<Decimal>,
+    /// Forget a deposit or withdrawal once this many newer ones have been recorded, so
+    /// the ledger stays bounded. A forgotten id can no longer be disputed; only its id
+    /// is kept, so a transaction reusing it is still rejected as a duplicate.
+    pub dispute_window: Option<usize>,
+    /// Only let a withdrawal be disputed while it is among the client's last this many
+    /// deposits and withdrawals, like a card scheme's chargeback window. Needs
+    /// `allow_withdrawal_disputes`; deposits are not limited.
+    pub withdrawal_dispute_window: Option<usize>,
+    /// Reject a transaction that would open an account once this many exist. Clients
+    /// that already have one are not limited.
+    pub max_clients: Option<usize>,
+}
@@ -0,0 +1,106 @@
+use std::io::{BufRead, Write};
+use std::pin::Pin;
+
+use futures::stream::{self, Stream};
+
+use crate::domain::traits::{AuditSink, DeadLetterQueue, OutputRepository, TransactionStream};
+use crate::domain::{Error, Transaction};
+use crate::engine::Engine;
+use crate::ingestion::{IngestionOptions, tcp};
+use crate::output_repository::{self, RowFormat};
+
+/// The ingestion of an engine driven by `run`, which hands it transactions one at a time
+/// instead: its stream is empty.
+#[derive(Debug, Default)]
+pub struct NoInput;
+
+impl TransactionStream for NoInput {
+    type TxStream = Pin<Box<dyn Stream<Item = Result<Transaction, Error>> + Send>>;
+
+    fn stream(&mut self) -> Result<Self::TxStream, Error> {
+        Ok(Box::pin(stream::empty()))
+    }
+}
+
+/// Applies the records in `input` as they are typed, one per line as with `--listen`,
+/// and prints the client's balances after each to `out` as a balances CSV row. Lines are
+/// read under `options`, as a file would be. A line that cannot be read or applied is
+/// printed to `err` and the loop carries on until `input` ends. Blank lines and header
+/// lines are skipped.
+pub fn run<I, O, D, A, R, W, E>(
+    engine: &mut Engine<I, O, D, A>,
+    input: R,
+    mut out: W,
+    mut err: E,
+    format: &RowFormat,
+    options: &IngestionOptions,
+) -> Result<(), Error>
+where
+    I: TransactionStream,
+    O: OutputRepository,
+    D: DeadLetterQueue,
+    A: AuditSink,
+    R: BufRead,
+    W: Write,
+    E: Write,
+{
+    output_repository::write_header(&mut out, format)?;
+    out.flush()?;
+    let mut reader = tcp::LineReader::new(options.clone());
+    for line in input.lines() {
+        let Some(tx) = reader.read(&line?) else {
+            continue;
+        };
+        match tx.and_then(|tx| {
+            let client_id = tx.client_id;
+            engine.apply_and_get(tx).map(|account| (client_id, account))
+        }) {
+            Ok((client_id, account)) => {
+                output_repository::write_row(&mut out, client_id, &account, 0, format)?;
+                out.flush()?;
+            }
+            Err(e) => writeln!(err, "{}", e)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dlq::NoopDLQ;
+    use crate::output_repository::MemoryOutput;
+
+    #[test]
+    fn prints_balances_after_each_line_and_errors_apart() {
+        let mut engine = Engine::new(NoInput, MemoryOutput::new(), NoopDLQ);
+        let input = "type,client,tx,amount\n\
+                     deposit, 1, 1, 2.5\n\
+                     \n\
+                     withdrawal, 1, 2, 9\n\
+                     bogus\n\
+                     {\"type\":\"dispute\",\"client\":1,\"tx\":1}\n";
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        run(
+            &mut engine,
+            input.as_bytes(),
+            &mut out,
+            &mut err,
+            &RowFormat::default(),
+            &IngestionOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             1,2.5000,0.0000,2.5000,false\n\
+             1,0.0000,2.5000,2.5000,false\n"
+        );
+        let err = String::from_utf8(err).unwrap();
+        let lines: Vec<_> = err.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", err);
+        assert!(lines[0].contains("Insufficient funds for client 1"));
+        assert!(lines[1].starts_with("Ingestion failed"), "{}", lines[1]);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::domain::ClientId;
+
+/// A ledger entry: tx ids are only unique per client.
+type Key = (ClientId, u32);
+
+/// Decides when a deposit or withdrawal can leave the ledger: once `len` newer ones have
+/// been recorded it may no longer be disputed. One still under dispute stays until the
+/// dispute is resolved or charged back. The ids of forgotten transactions are kept, so
+/// a replay of one is still told apart from a new transaction.
+#[derive(Debug)]
+pub struct DisputeWindow {
+    len: usize,
+    // ids in the order they were recorded, newest at the back
+    recorded: VecDeque<Key>,
+    // past the window but still disputed
+    lingering: HashSet<Key>,
+    // ids that left the ledger
+    forgotten: HashSet<Key>,
+}
+
+impl DisputeWindow {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            recorded: VecDeque::new(),
+            lingering: HashSet::new(),
+            forgotten: HashSet::new(),
+        }
+    }
+
+    /// Notes a newly recorded transaction and returns the ids that fell out of the
+    /// window and can be forgotten now.
+    pub fn record(&mut self, key: Key, is_disputed: impl Fn(Key) -> bool) -> Vec<Key> {
+        self.recorded.push_back(key);
+        let mut expired = Vec::new();
+        while self.recorded.len() > self.len {
+            let Some(oldest) = self.recorded.pop_front() else {
+                break;
+            };
+            if is_disputed(oldest) {
+                self.lingering.insert(oldest);
+            } else {
+                expired.push(oldest);
+            }
+        }
+        self.forgotten.extend(&expired);
+        expired
+    }
+
+    /// Whether `key`, whose dispute just closed, was only kept for it.
+    pub fn settle(&mut self, key: Key) -> bool {
+        let settled = self.lingering.remove(&key);
+        if settled {
+            self.forgotten.insert(key);
+        }
+        settled
+    }
+
+    /// Whether `key` was recorded and has since been forgotten.
+    pub fn forgot(&self, key: Key) -> bool {
+        self.forgotten.contains(&key)
+    }
+
+    pub fn clear(&mut self) {
+        self.recorded.clear();
+        self.lingering.clear();
+        self.forgotten.clear();
+    }
+}
+
+/// The last `len` deposits and withdrawals of every client, newest at the back, to tell
+/// whether a transaction is still recent enough for a dispute. Tracks at most `len` ids
+/// per client.
+#[derive(Debug)]
+pub struct RecentTransactions {
+    len: usize,
+    by_client: HashMap<ClientId, VecDeque<u32>>,
+}
+
+impl RecentTransactions {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            by_client: HashMap::new(),
+        }
+    }
+
+    /// Notes a newly recorded deposit or withdrawal.
+    pub fn record(&mut self, (client_id, transaction_id): Key) {
+        let recent = self.by_client.entry(client_id).or_default();
+        recent.push_back(transaction_id);
+        while recent.len() > self.len {
+            recent.pop_front();
+        }
+    }
+
+    /// Whether `key` is among its client's last `len` recorded transactions.
+    pub fn contains(&self, (client_id, transaction_id): Key) -> bool {
+        self.by_client
+            .get(&client_id)
+            .is_some_and(|recent| recent.contains(&transaction_id))
+    }
+
+    pub fn clear(&mut self) {
+        self.by_client.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disputed_transactions_outlive_the_window_until_settled() {
+        let mut window = DisputeWindow::new(2);
+        assert!(window.record((1, 1), |_| false).is_empty());
+        assert!(window.record((2, 2), |_| false).is_empty());
+        assert_eq!(window.record((1, 3), |_| false), vec![(1, 1)]);
+        // 2 is under dispute as it leaves the window
+        assert!(window.record((1, 4), |key| key == (2, 2)).is_empty());
+        assert!(!window.settle((1, 3)));
+        assert!(!window.settle((1, 2)));
+        assert!(window.settle((2, 2)));
+        assert!(!window.settle((2, 2)));
+        assert!(window.forgot((1, 1)) && window.forgot((2, 2)));
+        assert!(!window.forgot((1, 3)));
+    }
+
+    #[test]
+    fn recent_transactions_are_tracked_per_client() {
+        let mut recent = RecentTransactions::new(2);
+        for key in [(1, 1), (2, 2), (1, 3), (1, 4)] {
+            recent.record(key);
+        }
+        // client 2's own transactions do not push out client 1's
+        assert!(recent.contains((2, 2)));
+        assert!(!recent.contains((1, 1)));
+        assert!(recent.contains((1, 3)));
+        assert!(recent.contains((1, 4)));
+        assert!(!recent.contains((2, 4)));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use crate::domain::{ClientId, Transaction};
+
+/// Maximum times a parked transaction is replayed before it is given up on.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Parks transactions that reference a tx id the engine has not seen yet, e.g. a
+/// dispute that overtook its deposit, so they can be replayed once that id shows up.
+#[derive(Debug)]
+pub struct RetryBuffer {
+    capacity: usize,
+    max_attempts: u32,
+    // keyed by the referenced client and tx id; the sequence number keeps arrival order
+    // for `drain`
+    parked: HashMap<(ClientId, u32), Vec<Parked>>,
+    len: usize,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+pub struct Parked {
+    pub transaction: Transaction,
+    /// Replays already spent on this transaction.
+    pub attempts: u32,
+    seq: u64,
+}
+
+impl RetryBuffer {
+    /// Holds at most `capacity` transactions, each replayed at most `max_attempts` times.
+    pub fn new(capacity: usize, max_attempts: u32) -> Self {
+        Self {
+            capacity,
+            max_attempts,
+            parked: HashMap::new(),
+            len: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// Parks `transaction` until its referenced id appears. Hands it back when the buffer
+    /// is full or the transaction has used up its attempts.
+    pub fn park(&mut self, transaction: Transaction, attempts: u32) -> Result<(), Transaction> {
+        if self.len >= self.capacity || attempts >= self.max_attempts {
+            return Err(transaction);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.len += 1;
+        self.parked
+            .entry((transaction.client_id, transaction.transaction_id))
+            .or_default()
+            .push(Parked {
+                transaction,
+                attempts,
+                seq,
+            });
+        Ok(())
+    }
+
+    /// Removes the transactions waiting on the client's `transaction_id`, oldest first.
+    pub fn take(&mut self, client_id: ClientId, transaction_id: u32) -> Vec<Parked> {
+        let parked = self
+            .parked
+            .remove(&(client_id, transaction_id))
+            .unwrap_or_default();
+        self.len -= parked.len();
+        parked
+    }
+
+    /// Removes everything still parked, oldest first, e.g. at the end of the stream.
+    pub fn drain(&mut self) -> Vec<Parked> {
+        let mut parked: Vec<_> = self.parked.drain().flat_map(|(_, p)| p).collect();
+        parked.sort_by_key(|p| p.seq);
+        self.len = 0;
+        parked
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::TransactionKind;
+
+    fn dispute(transaction_id: u32) -> Transaction {
+        Transaction {
+            kind: TransactionKind::Dispute { amount: None },
+            client_id: 1,
+            transaction_id,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn capacity_and_attempts_are_bounded() {
+        let mut buffer = RetryBuffer::new(2, 1);
+        assert!(buffer.park(dispute(1), 0).is_ok());
+        assert!(buffer.park(dispute(1), 1).is_err()); // out of attempts
+        assert!(buffer.park(dispute(2), 0).is_ok());
+        assert!(buffer.park(dispute(3), 0).is_err()); // full
+        assert_eq!(buffer.len(), 2);
+
+        assert!(buffer.take(2, 1).is_empty()); // another client's tx 1
+        assert_eq!(buffer.take(1, 1).len(), 1);
+        assert!(buffer.take(1, 1).is_empty());
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn drain_returns_arrival_order() {
+        let mut buffer = RetryBuffer::new(10, DEFAULT_MAX_ATTEMPTS);
+        for id in [5, 3, 5, 9] {
+            buffer.park(dispute(id), 0).unwrap();
+        }
+        let ids: Vec<_> = buffer
+            .drain()
+            .iter()
+            .map(|p| p.transaction.transaction_id)
+            .collect();
+        assert_eq!(ids, vec![5, 3, 5, 9]);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, HashMap, btree_map};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rust_decimal::Decimal;
+
+use crate::domain::{Account, Amount, ClientId};
+
+/// Tells apart the scratch files of several spills in one process.
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Caps how many accounts stay in memory. Once more than `capacity` are resident the
+/// least recently used ones are written to a scratch file and read back the next time
+/// they are needed, so inactive clients cost an index entry rather than an account.
+///
+/// The scratch file only grows: an account spilled again is appended, not rewritten.
+/// It is removed when the spill is dropped.
+#[derive(Debug)]
+pub struct AccountSpill<A = Decimal> {
+    capacity: usize,
+    path: PathBuf,
+    file: Mutex<File>,
+    // offset of each spilled account's latest record
+    spilled: HashMap<ClientId, u64>,
+    // recency of the resident accounts, oldest first in `by_age`
+    last_used: HashMap<ClientId, u64>,
+    by_age: BTreeMap<u64, ClientId>,
+    clock: u64,
+    // first I/O error, held back until the owner next flushes
+    error: Mutex<Option<io::Error>>,
+    _amount: PhantomData<A>,
+}
+
+impl<A: Amount> AccountSpill<A> {
+    /// Keeps at most `capacity` accounts resident, but never fewer than one.
+    pub fn new(capacity: usize) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "payments_engine-{}-{}.spill",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self {
+            capacity: capacity.max(1),
+            path,
+            file: Mutex::new(file),
+            spilled: HashMap::new(),
+            last_used: HashMap::new(),
+            by_age: BTreeMap::new(),
+            clock: 0,
+            error: Mutex::new(None),
+            _amount: PhantomData,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The scratch file spilled accounts are written to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of accounts currently on disk.
+    pub fn spilled(&self) -> usize {
+        self.spilled.len()
+    }
+
+    /// Spilled client ids in ascending order.
+    pub fn spilled_ids(&self) -> Vec<ClientId> {
+        let mut ids: Vec<_> = self.spilled.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Makes `client_id` resident, reading it back if it was spilled and creating it if
+    /// it is new, and marks it most recently used. Then spills the least recently used
+    /// other accounts until `resident` is within capacity.
+    ///
+    /// Fails, leaving everything as it was, if a spilled record cannot be read back.
+    pub fn admit(
+        &mut self,
+        client_id: ClientId,
+        resident: &mut BTreeMap<ClientId, Account<A>>,
+    ) -> io::Result<()> {
+        if let btree_map::Entry::Vacant(entry) = resident.entry(client_id) {
+            entry.insert(self.load(client_id)?.unwrap_or_default());
+            self.spilled.remove(&client_id);
+        }
+        self.touch(client_id);
+
+        while resident.len() > self.capacity {
+            let Some((_, oldest)) = self.by_age.pop_first() else {
+                break;
+            };
+            self.last_used.remove(&oldest);
+            let Some(account) = resident.get(&oldest) else {
+                continue;
+            };
+            match self.write(account) {
+                Ok(offset) => {
+                    resident.remove(&oldest);
+                    self.spilled.insert(oldest, offset);
+                }
+                // keep it in memory rather than lose it
+                Err(e) => {
+                    self.keep_error(e);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a spilled account without making it resident. A record that cannot be
+    /// read is skipped and its error kept for `take_error`.
+    pub fn read(&self, client_id: ClientId) -> Option<Account<A>> {
+        self.load(client_id).map_err(|e| self.keep_error(e)).ok()?
+    }
+
+    /// The spilled account of `client_id`, or `None` if it is not spilled.
+    fn load(&self, client_id: ClientId) -> io::Result<Option<Account<A>>> {
+        let Some(&offset) = self.spilled.get(&client_id) else {
+            return Ok(None);
+        };
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(&mut *file).read_line(&mut line)?;
+        parse_record(line.trim_end_matches('\n')).map(Some)
+    }
+
+    /// Forgets `client_id`, e.g. because the caller is about to replace its account.
+    pub fn forget(&mut self, client_id: ClientId) {
+        self.spilled.remove(&client_id);
+        if let Some(tick) = self.last_used.remove(&client_id) {
+            self.by_age.remove(&tick);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.spilled.clear();
+        self.last_used.clear();
+        self.by_age.clear();
+        let file = self.file.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.set_len(0) {
+            self.keep_error(e);
+        }
+    }
+
+    /// The first I/O error since the last call, if any.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.error.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    fn touch(&mut self, client_id: ClientId) {
+        if let Some(tick) = self.last_used.remove(&client_id) {
+            self.by_age.remove(&tick);
+        }
+        self.clock += 1;
+        self.last_used.insert(client_id, self.clock);
+        self.by_age.insert(self.clock, client_id);
+    }
+
+    /// Appends `account` and returns where its record starts.
+    fn write(&self, account: &Account<A>) -> io::Result<u64> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let offset = file.seek(SeekFrom::End(0))?;
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{}",
+            account.available.to_decimal(),
+            account.held.to_decimal(),
+            account.total.to_decimal(),
+            account.on_hold.to_decimal(),
+            account.locked,
+            account.frozen,
+            account.currency.as_deref().unwrap_or("")
+        )?;
+        Ok(offset)
+    }
+
+    fn keep_error(&self, e: io::Error) {
+        self.error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert(e);
+    }
+}
+
+impl<A> Drop for AccountSpill<A> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Parses a record written by `write`. The currency comes last as it is free text.
+fn parse_record<A: Amount>(line: &str) -> io::Result<Account<A>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupt account spill record");
+    let mut fields = line.splitn(7, ',');
+    let mut amount = || {
+        fields
+            .next()
+            .and_then(|field| Decimal::from_str(field).ok())
+            .and_then(A::from_decimal)
+            .ok_or_else(invalid)
+    };
+    let (available, held, total, on_hold) = (amount()?, amount()?, amount()?, amount()?);
+    let mut flag = || {
+        fields
+            .next()
+            .and_then(|field| bool::from_str(field).ok())
+            .ok_or_else(invalid)
+    };
+    let (locked, frozen) = (flag()?, flag()?);
+    let currency = fields.next().ok_or_else(invalid)?;
+    Ok(Account {
+        available,
+        held,
+        total,
+        locked,
+        frozen,
+        currency: (!currency.is_empty()).then(|| currency.to_string()),
+        on_hold,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(available: u32, currency: Option<&str>) -> Account {
+        Account {
+            available: available.into(),
+            held: Decimal::new(25, 1),
+            total: Decimal::from(available) + Decimal::new(25, 1),
+            locked: true,
+            frozen: true,
+            currency: currency.map(str::to_string),
+            on_hold: Decimal::ONE,
+        }
+    }
+
+    #[test]
+    fn least_recently_used_accounts_are_spilled_and_read_back() {
+        let mut spill = AccountSpill::new(2).unwrap();
+        let mut resident = BTreeMap::new();
+        for (client_id, available) in (1..=3).zip(1..) {
+            resident.insert(client_id, account(available, Some("EUR")));
+            spill.admit(client_id, &mut resident).unwrap();
+        }
+        assert_eq!(resident.keys().copied().collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(spill.spilled_ids(), vec![1]);
+
+        // touching 2 makes 3 the oldest once 1 comes back
+        spill.admit(2, &mut resident).unwrap();
+        spill.admit(1, &mut resident).unwrap();
+        assert_eq!(resident.keys().copied().collect::<Vec<_>>(), vec![1, 2]);
+        let three = spill.read(3).unwrap();
+        assert_eq!(three.snapshot(), account(3, Some("EUR")).snapshot());
+        assert_eq!(three.currency.as_deref(), Some("EUR"));
+        assert!(three.frozen);
+
+        let one = &resident[&1];
+        assert_eq!(one.snapshot(), account(1, None).snapshot());
+        assert!(spill.take_error().is_none());
+    }
+
+    #[test]
+    fn an_unreadable_record_fails_admission_instead_of_starting_afresh() {
+        let mut spill = AccountSpill::new(1).unwrap();
+        let mut resident = BTreeMap::new();
+        for client_id in 1..=2 {
+            resident.insert(client_id, account(5, None));
+            spill.admit(client_id, &mut resident).unwrap();
+        }
+        spill.file.get_mut().unwrap().set_len(0).unwrap();
+
+        assert!(spill.admit(1, &mut resident).is_err());
+        // 1 stays spilled rather than coming back as an empty account
+        assert!(!resident.contains_key(&1));
+        assert_eq!(spill.spilled_ids(), vec![1]);
+    }
+
+    #[test]
+    fn scratch_file_is_removed_on_drop() {
+        let spill = AccountSpill::<Decimal>::new(1).unwrap();
+        let path = spill.path.clone();
+        assert!(path.exists());
+        drop(spill);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,36 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+/// What a run did, as one JSON document for CI dashboards: the metrics counters split
+/// by kind, plus the state the run ended in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    /// Transactions applied, by kind.
+    pub processed: BTreeMap<&'static str, u64>,
+    /// Rows rejected, by kind, with rows that could not be read under `unreadable`.
+    pub rejected: BTreeMap<&'static str, u64>,
+    pub processed_total: u64,
+    pub rejected_total: u64,
+    /// Accounts locked at the end, by a chargeback or a freeze.
+    pub locked_accounts: usize,
+    /// Disputes neither resolved nor charged back by the end.
+    pub open_disputes: usize,
+    /// Highest deposit or withdrawal id applied, `null` if there was none.
+    pub high_water_mark: Option<u32>,
+    /// Wall-clock time of the run.
+    pub duration_secs: f64,
+}
+
+impl Summary {
+    /// Writes the summary to `path` as pretty-printed JSON.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::domain::{
+    Amount, EngineError, Error, OutputRepository, Transaction, TransactionKind, Validator,
+};
+
+/// Runs validators in the order they were added; the first rejection wins.
+pub struct ValidatorChain<A = Decimal>(Vec<Box<dyn Validator<A>>>);
+
+impl<A: Amount> Default for ValidatorChain<A> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<A: Amount> ValidatorChain<A> {
+    pub fn push<V: Validator<A> + 'static>(&mut self, validator: V) {
+        self.0.push(Box::new(validator));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<A> fmt::Debug for ValidatorChain<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValidatorChain")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
+
+impl<A: Amount> Validator<A> for ValidatorChain<A> {
+    fn validate(
+        &self,
+        tx: &Transaction<A>,
+        repo: &dyn OutputRepository<Amount = A>,
+    ) -> Result<(), Error> {
+        self.0.iter().try_for_each(|v| v.validate(tx, repo))
+    }
+}
+
+/// Rejects deposits larger than `max`.
+#[derive(Debug, Clone)]
+pub struct MaxDepositValidator {
+    pub max: Decimal,
+}
+
+impl MaxDepositValidator {
+    pub fn new(max: Decimal) -> Self {
+        Self { max }
+    }
+}
+
+impl<A: Amount> Validator<A> for MaxDepositValidator {
+    fn validate(
+        &self,
+        tx: &Transaction<A>,
+        _repo: &dyn OutputRepository<Amount = A>,
+    ) -> Result<(), Error> {
+        match tx.kind {
+            TransactionKind::Deposit { amount } if amount.to_decimal() > self.max => {
+                Err(EngineError::Rejected {
+                    transaction_id: tx.transaction_id,
+                    reason: format!("deposit of {} exceeds the limit of {}", amount, self.max),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rejects deposits, withdrawals, holds and releases of a negative amount. A zero
+/// amount moves nothing and passes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NonNegativeAmountValidator;
+
+impl<A: Amount> Validator<A> for NonNegativeAmountValidator {
+    fn validate(
+        &self,
+        tx: &Transaction<A>,
+        _repo: &dyn OutputRepository<Amount = A>,
+    ) -> Result<(), Error> {
+        match tx.kind {
+            TransactionKind::Deposit { amount }
+            | TransactionKind::Withdrawal { amount }
+            | TransactionKind::Hold { amount }
+            | TransactionKind::Release { amount }
+                if amount.is_negative() =>
+            {
+                Err(EngineError::Rejected {
+                    transaction_id: tx.transaction_id,
+                    reason: format!("{} of a negative amount: {}", tx.kind.name(), amount),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_repository::MemoryOutput;
+
+    fn deposit(amount: u32) -> Transaction {
+        Transaction {
+            kind: TransactionKind::Deposit {
+                amount: Decimal::from(amount),
+            },
+            client_id: 1,
+            transaction_id: 1,
+            currency: None,
+        }
+    }
+
+    struct RejectAll;
+
+    impl Validator for RejectAll {
+        fn validate(
+            &self,
+            tx: &Transaction,
+            _repo: &dyn OutputRepository<Amount = Decimal>,
+        ) -> Result<(), Error> {
+            Err(EngineError::Rejected {
+                transaction_id: tx.transaction_id,
+                reason: "blocked".to_string(),
+            }
+            .into())
+        }
+    }
+
+    #[test]
+    fn max_deposit_allows_up_to_the_limit() {
+        let repo = MemoryOutput::new();
+        let validator = MaxDepositValidator::new(Decimal::from(100u32));
+        assert!(validator.validate(&deposit(100), &repo).is_ok());
+        assert!(matches!(
+            validator.validate(&deposit(101), &repo),
+            Err(Error::Engine(EngineError::Rejected {
+                transaction_id: 1,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn non_negative_amounts_allow_zero_and_reject_negatives() {
+        let repo = MemoryOutput::new();
+        let with_amount = |amount: i64| Transaction {
+            kind: TransactionKind::Withdrawal {
+                amount: Decimal::from(amount),
+            },
+            ..deposit(0)
+        };
+        assert!(
+            NonNegativeAmountValidator
+                .validate(&deposit(100), &repo)
+                .is_ok()
+        );
+        assert!(
+            NonNegativeAmountValidator
+                .validate(&deposit(0), &repo)
+                .is_ok()
+        );
+        let err = NonNegativeAmountValidator
+            .validate(&with_amount(-5), &repo)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Engine failed with: Transaction 1 rejected: withdrawal of a negative amount: -5"
+        );
+    }
+
+    #[test]
+    fn chain_stops_at_first_rejection() {
+        let repo = MemoryOutput::new();
+        let mut chain = ValidatorChain::default();
+        assert!(chain.validate(&deposit(1_000), &repo).is_ok());
+
+        chain.push(MaxDepositValidator::new(Decimal::from(10u32)));
+        chain.push(RejectAll);
+        assert_eq!(chain.len(), 2);
+
+        let err = chain.validate(&deposit(1_000), &repo).unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"));
+        let err = chain.validate(&deposit(1), &repo).unwrap_err();
+        assert!(err.to_string().contains("blocked"));
+    }
+}
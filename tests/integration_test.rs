@@ -1,14 +1,17 @@
-use std::io::Write;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
 
 use assert_cmd::Command;
 use predicates as pred;
+use predicates::prelude::PredicateBooleanExt;
 use tempfile::NamedTempFile;
 
 #[test]
 fn end_to_end_outputs_expected_balances() {
     // Prepare a temporary CSV file with transactions that yield
-    // client 1: 1.5 available, 0 held, total 1.5, unlocked
-    // client 2: 2.0 available, 0 held, total 2.0, unlocked
+    // client 1: 70.0003 available, 0 held, total 70.0003, unlocked
+    // client 2: everything charged back, locked
     let mut file = NamedTempFile::new().expect("create temp file");
     writeln!(
         file,
@@ -35,5 +38,1384 @@ fn end_to_end_outputs_expected_balances() {
         .success()
         .stdout(pred::str::contains("client,available,held,total,locked"))
         .stdout(pred::str::contains("1,70.0003,0.0000,70.0003,false"))
-        .stdout(pred::str::contains("2,50.0001,0.0000,50.0001,true"));
+        .stdout(pred::str::contains("2,0.0000,0.0000,0.0000,true"));
+}
+
+#[test]
+fn client_flag_prints_only_that_clients_row() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.0\n\
+    deposit, 2, 2, 2.0\n\
+    deposit, 3, 3, 3.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--client", "2"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n2,2.0000,0.0000,2.0000,false\n");
+
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--client", "9"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(pred::str::contains("Client 9 not found"));
+}
+
+#[cfg(unix)]
+#[test]
+fn sigint_flushes_current_state_once() {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::process::{Command as StdCommand, Stdio};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    // Reserve a free port, then hand it to the engine.
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    let mut child = StdCommand::new(exe)
+        .args(["--listen", &addr.to_string()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spawn engine");
+
+    let mut conn = (0..50)
+        .find_map(|_| {
+            sleep(Duration::from_millis(50));
+            TcpStream::connect(addr).ok()
+        })
+        .expect("connect to engine");
+    conn.write_all(b"deposit, 1, 1, 2.5\n").unwrap();
+    // Keep the connection open so processing never finishes on its own.
+    sleep(Duration::from_millis(300));
+
+    let status = StdCommand::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let status = child.wait().unwrap();
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    drop(conn);
+
+    assert_eq!(status.code(), Some(130));
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n"
+    );
+}
+
+#[test]
+fn skip_unknown_silences_unknown_type_rows() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.0\n\
+    chah, 1,\n\
+    deposit, 1, 2, 2.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .assert()
+        .success()
+        .stderr(pred::str::contains("DLQ Report"));
+
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--skip-unknown")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n")
+        .stderr("");
+}
+
+#[test]
+fn max_open_disputes_caps_disputes_per_client() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.0\n\
+    deposit, 1, 2, 2.0\n\
+    dispute, 1, 1,\n\
+    dispute, 1, 2,"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--max-open-disputes", "1"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,2.0000,1.0000,3.0000,false\n")
+        .stderr(pred::str::contains("maximum number of open disputes"));
+}
+
+#[test]
+fn max_clients_rejects_transactions_for_new_clients() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.0\n\
+    deposit, 2, 2, 2.0\n\
+    deposit, 1, 3, 2.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--max-clients", "1"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n")
+        .stderr(pred::str::contains(
+            "Client 2 has no account and the maximum number of clients is reached",
+        ));
+}
+
+#[test]
+fn max_clients_holds_with_emit_touched_clients() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.0\n\
+    deposit, 2, 2, 7.0\n\
+    deposit, 2, 3, 7.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--max-clients", "1", "--emit-touched-clients"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n");
+}
+
+#[test]
+fn reject_negative_amounts_turns_away_negative_withdrawals() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.0\n\
+    withdrawal, 1, 2, -5.0\n\
+    deposit, 1, 3, -0.5"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--reject-negative-amounts")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n")
+        .stderr(pred::str::contains(
+            "Transaction 2 rejected: withdrawal of a negative amount: -5",
+        ))
+        .stderr(pred::str::contains(
+            "Transaction 3 rejected: deposit of a negative amount: -0.5",
+        ));
+}
+
+#[test]
+fn verify_passes_for_consistent_state() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.0\n\
+    deposit, 1, 2, 2.0\n\
+    dispute, 1, 1,"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--verify")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,2.0000,1.0000,3.0000,false\n");
+}
+
+#[test]
+fn verify_fails_on_restored_held_without_disputes() {
+    let mut snapshot = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        snapshot,
+        "client,available,held,total,locked\n1,1.0,2.0,3.0,false"
+    )
+    .unwrap();
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount\ndeposit, 1, 1, 1.0").unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--restore")
+        .arg(snapshot.path())
+        .arg("--verify")
+        .assert()
+        .code(3)
+        .stderr(pred::str::contains(
+            "Client 1: held 2 does not equal open disputes 0",
+        ));
+}
+
+#[test]
+fn repair_keeps_restored_held_funds_without_a_ledger() {
+    let mut snapshot = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        snapshot,
+        "client,available,held,total,locked\n1,1.0,2.0,2.5,false"
+    )
+    .unwrap();
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount\nwithdrawal, 1, 1, 1.5").unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--restore")
+        .arg(snapshot.path())
+        .arg("--repair")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.0000,2.0000,3.0000,false\n")
+        .stderr(pred::str::contains("Insufficient funds for client 1"));
+}
+
+#[test]
+fn report_dust_prints_rounded_away_remainders() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.00005\n\
+    deposit, 1, 2, 2.00002\n\
+    deposit, 2, 3, 1.00005\n\
+    deposit, 3, 4, 1.5"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--report-dust")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,3.0001,0.0000,3.0001,false\n\
+             2,1.0000,0.0000,1.0000,false\n\
+             3,1.5000,0.0000,1.5000,false\n",
+        )
+        .stderr("Dust - client 1: -0.00003\nDust - client 2: 0.00005\n");
+}
+
+#[test]
+fn report_open_disputes_lists_disputes_left_open() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.5\n\
+    deposit, 1, 2, 2.0\n\
+    dispute, 1, 1,\n\
+    resolve, 1, 1,\n\
+    dispute, 1, 2,"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--report-open-disputes")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.5000,2.0000,3.5000,false\n",
+        )
+        .stderr("Open dispute - client 1 tx 2: 2.0000\n");
+}
+
+#[test]
+fn fail_fast_succeeds_on_a_clean_file() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.5\n\
+    withdrawal, 1, 2, 0.5"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--fail-fast")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.0000,0.0000,1.0000,false\n",
+        )
+        .stderr("");
+}
+
+#[test]
+fn fail_fast_stops_at_an_early_bad_row() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.5\n\
+    withdrawal, 1, 2, 5.0\n\
+    deposit, 1, 3, 2.0\n\
+    deposit, 2, 4, 1.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--fail-fast")
+        .assert()
+        .code(1)
+        // the rows after the rejected withdrawal are never applied
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.5000,0.0000,1.5000,false\n",
+        )
+        .stderr(
+            "Stopped at the first rejection (withdrawal of client 1 tx 2): \
+             Engine failed with: Insufficient funds for client 1\n",
+        );
+}
+
+#[test]
+fn directory_input_reads_csv_files_in_lexical_order() {
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let header = "type, client, tx, amount\n";
+    for (name, rows) in [
+        ("2024-01-03.csv", "resolve, 1, 1,\nwithdrawal, 1, 4, 1.0\n"),
+        ("2024-01-01.csv", "deposit, 1, 1, 1.5\ndeposit, 2, 2, 2.0\n"),
+        ("2024-01-02.csv", "dispute, 1, 1,\ndeposit, 1, 3, 0.5\n"),
+    ] {
+        std::fs::write(dir.path().join(name), format!("{}{}", header, rows)).unwrap();
+    }
+    std::fs::write(dir.path().join("README.txt"), "deposit, 9, 9, 9.0\n").unwrap();
+
+    // the dispute in the second file references a deposit in the first and is
+    // resolved in the third
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(dir.path())
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.0000,0.0000,1.0000,false\n\
+             2,2.0000,0.0000,2.0000,false\n",
+        )
+        .stderr("");
+}
+
+#[test]
+fn idempotency_key_drops_replayed_rows() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount, idempotency_key\n\
+    deposit, 1, 1, 1.5, op-1\n\
+    deposit, 1, 2, 1.5, op-1\n\
+    dispute, 1, 1,,"
+    )
+    .unwrap();
+
+    // the replay is dropped rather than rejected, and tx 1 stays disputable
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,0.0000,1.5000,1.5000,false\n",
+        )
+        .stderr("");
+}
+
+#[test]
+fn dlq_rate_limit_counts_the_reports_it_drops() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount").unwrap();
+    for tx in 1..=50 {
+        writeln!(file, "withdrawal, 1, {}, 1.0", tx).unwrap();
+    }
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    let output = Command::new(exe)
+        .arg(file.path())
+        .args(["--dlq-rate-limit", "2"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Usually one second; a run that straddles a second boundary prints more rows.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let printed = stderr
+        .lines()
+        .filter(|line| line.starts_with("DLQ Report - Error"))
+        .count();
+    let suppressed: usize = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("DLQ Report - Suppressed "))
+        .map(|rest| rest.trim_end_matches(" errors").parse::<usize>().unwrap())
+        .sum();
+    assert!(printed < 50, "{}", stderr);
+    assert_eq!(printed + suppressed, 50, "{}", stderr);
+}
+
+#[test]
+fn summary_reports_counts_and_end_state_as_json() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 5.0\n\
+    deposit, 2, 2, 3.0\n\
+    withdrawal, 1, 3, 9.0\n\
+    bonus, 1, 4, 1.0\n\
+    dispute, 1, 1,\n\
+    dispute, 2, 2,\n\
+    chargeback, 2, 2,"
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let path = dir.path().join("summary.json");
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--summary")
+        .arg(&path)
+        .assert()
+        .success();
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(summary["processed"]["deposit"], 2);
+    assert_eq!(summary["processed"]["dispute"], 2);
+    assert_eq!(summary["processed"]["chargeback"], 1);
+    assert_eq!(summary["processed"]["withdrawal"], 0);
+    assert_eq!(summary["rejected"]["withdrawal"], 1);
+    assert_eq!(summary["rejected"]["unreadable"], 1);
+    assert_eq!(summary["processed_total"], 5);
+    assert_eq!(summary["rejected_total"], 2);
+    assert_eq!(summary["locked_accounts"], 1);
+    assert_eq!(summary["open_disputes"], 1);
+    assert_eq!(summary["high_water_mark"], 2);
+    assert!(summary["duration_secs"].as_f64().unwrap() >= 0.0);
+}
+
+#[test]
+fn checkpoint_every_writes_numbered_balance_files() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.5\n\
+    deposit, 2, 2, 2.0\n\
+    dispute, 1, 1,\n\
+    deposit, 1, 3, 1.0\n\
+    resolve, 1, 1,"
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let output = dir.path().join("balances.csv");
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--output")
+        .arg(&output)
+        .arg("--checkpoint-every")
+        .arg("2")
+        .assert()
+        .success()
+        .stdout("");
+
+    let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+    assert_eq!(
+        read("balances.csv.1"),
+        "client,available,held,total,locked\n\
+         1,1.5000,0.0000,1.5000,false\n\
+         2,2.0000,0.0000,2.0000,false\n"
+    );
+    assert_eq!(
+        read("balances.csv.2"),
+        "client,available,held,total,locked\n\
+         1,1.0000,1.5000,2.5000,false\n\
+         2,2.0000,0.0000,2.0000,false\n"
+    );
+    assert!(!dir.path().join("balances.csv.3").exists());
+    // the dispute opened before the second checkpoint is still resolved after it
+    assert_eq!(
+        read("balances.csv"),
+        "client,available,held,total,locked\n\
+         1,2.5000,0.0000,2.5000,false\n\
+         2,2.0000,0.0000,2.0000,false\n"
+    );
+}
+
+#[test]
+fn max_held_rejects_disputes_over_the_cap() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 2.0\n\
+    deposit, 1, 2, 5.0\n\
+    dispute, 1, 1,\n\
+    dispute, 1, 2,"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--max-held")
+        .arg("5")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,5.0000,2.0000,7.0000,false\n",
+        )
+        .stderr(
+            "DLQ Report - Error [ENG_HELD_LIMIT_EXCEEDED]: Engine failed with: \
+             Dispute would take client 1 held funds above the limit\n",
+        );
+}
+
+#[test]
+fn withdrawal_dispute_window_rejects_old_withdrawals() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 10.0\n\
+    withdrawal, 1, 2, 3.0\n\
+    withdrawal, 1, 3, 2.0\n\
+    deposit, 1, 4, 1.0\n\
+    dispute, 1, 3,\n\
+    dispute, 1, 2,"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args([
+            "--allow-withdrawal-disputes",
+            "--withdrawal-dispute-window",
+            "2",
+        ])
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,8.0000,-2.0000,6.0000,false\n",
+        )
+        .stderr(
+            "DLQ Report - Error [ENG_OUTSIDE_DISPUTE_WINDOW]: Engine failed with: \
+             Withdrawal 2 is too old to be disputed\n",
+        );
+}
+
+#[test]
+fn dispute_window_rejects_a_replayed_pruned_deposit() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 5.0\n\
+    deposit, 1, 2, 1.0\n\
+    deposit, 1, 3, 1.0\n\
+    deposit, 1, 1, 5.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--dispute-window", "1"])
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,7.0000,0.0000,7.0000,false\n",
+        )
+        .stderr(
+            "DLQ Report - Rejected (retryable) [ENG_DUPLICATE_TX]: Engine failed with: \
+             Transaction ID 1 already exists\n",
+        );
+}
+
+#[test]
+fn amounts_at_the_decimal_limit_do_not_crash() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 79228162514264337593543950335\n\
+    deposit, 2, 2, 79228162514264337593543950335\n\
+    deposit, 3, 3, 79228162514264337593543950336"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--decimals", "0"])
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,79228162514264337593543950335,0,79228162514264337593543950335,false\n\
+             2,79228162514264337593543950335,0,79228162514264337593543950335,false\n",
+        )
+        .stderr(
+            "DLQ Report - Error [ING_AMOUNT_OUT_OF_RANGE]: Ingestion failed with: \
+             Amount out of range: 79228162514264337593543950336\n",
+        );
+}
+
+#[test]
+fn auto_input_format_matches_explicit_selection() {
+    let mut csv = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        csv,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 3.0\n\
+    withdrawal, 1, 2, 1.25\n\
+    deposit, 2, 3, 4.0\n\
+    dispute, 2, 3,"
+    )
+    .unwrap();
+    let mut json = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        json,
+        "{{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"3.0\"}}\n\
+         {{\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":1.25}}\n\
+         {{\"type\":\"deposit\",\"client\":2,\"tx\":3,\"amount\":\"4.0\"}}\n\
+         {{\"type\":\"dispute\",\"client\":2,\"tx\":3}}"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    let balances = |file: &NamedTempFile, format: &str| {
+        let output = Command::new(exe)
+            .arg(file.path())
+            .args(["--input-format", format])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let expected = "client,available,held,total,locked\n\
+                    1,1.7500,0.0000,1.7500,false\n\
+                    2,0.0000,4.0000,4.0000,false\n";
+    assert_eq!(balances(&csv, "csv"), expected);
+    assert_eq!(balances(&csv, "auto"), expected);
+    assert_eq!(balances(&json, "json"), expected);
+    assert_eq!(balances(&json, "auto"), expected);
+}
+
+#[test]
+fn rejects_out_records_a_coded_row_per_rejection() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 2.0\n\
+    withdrawal, 1, 2, 5.0\n\
+    deposit, 1, 1, 1.0"
+    )
+    .unwrap();
+    let rejects = NamedTempFile::new().expect("create temp file");
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--rejects-out")
+        .arg(rejects.path())
+        .assert()
+        .success()
+        .stderr(pred::str::contains(
+            "DLQ Report - Error [ENG_INSUFFICIENT_FUNDS]: ",
+        ));
+
+    // drop the timestamp column, which is wall-clock time
+    let rows: Vec<_> = std::fs::read_to_string(rejects.path())
+        .unwrap()
+        .lines()
+        .map(|line| line.rsplit_once(',').unwrap().0.to_string())
+        .collect();
+    assert_eq!(
+        rows,
+        [
+            "client,tx,type,reason,code",
+            "1,2,withdrawal,insufficient_funds,ENG_INSUFFICIENT_FUNDS",
+            "1,1,deposit,duplicate_transaction,ENG_DUPLICATE_TX"
+        ]
+    );
+}
+
+#[test]
+fn overdraft_allows_and_prints_negative_balances() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 5.0\n\
+    withdrawal, 1, 2, 12.5\n\
+    withdrawal, 1, 3, 0.0001\n\
+    withdrawal, 2, 4, 1.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--overdraft")
+        .arg("7.5")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,-7.5000,0.0000,-7.5000,false\n\
+             2,-1.0000,0.0000,-1.0000,false\n",
+        );
+}
+
+#[test]
+fn decimals_flag_rounds_printed_balances() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 1.2345\n\
+    deposit, 2, 2, 2.0\n\
+    deposit, 2, 3, 0.0051"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--decimals")
+        .arg("2")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.23,0.00,1.23,false\n\
+             2,2.01,0.00,2.01,false\n",
+        );
+}
+
+#[test]
+fn change_feed_prints_a_row_per_balance_change() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 2.0\n\
+    deposit, 2, 2, 1.0\n\
+    withdrawal, 1, 3, 0.5\n\
+    withdrawal, 2, 4, 9.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--change-feed")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,2.0000,0.0000,2.0000,false\n\
+             2,1.0000,0.0000,1.0000,false\n\
+             1,1.5000,0.0000,1.5000,false\n",
+        );
+}
+
+#[test]
+fn header_only_input_prints_only_the_header() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount").unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n");
+}
+
+#[test]
+fn empty_input_is_an_error() {
+    let file = NamedTempFile::new().expect("create temp file");
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(pred::str::contains(
+            "CSV input is empty, expected a header row",
+        ));
+}
+
+#[test]
+fn help_prints_usage_and_succeeds() {
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg("--help")
+        .assert()
+        .success()
+        .stdout(pred::str::starts_with(
+            "Usage: payments_engine [OPTIONS] <INPUT>",
+        ))
+        .stdout(pred::str::contains("--dispute-window <N>"));
+    Command::new(exe)
+        .arg("--version")
+        .assert()
+        .success()
+        .stdout(format!("payments_engine {}\n", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn bad_arguments_exit_with_usage_instead_of_panicking() {
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .assert()
+        .code(2)
+        .stdout("")
+        .stderr(pred::str::starts_with(
+            "Invalid arguments: No input file was provided\n\nUsage:",
+        ))
+        .stderr(pred::str::contains("panicked").not());
+    Command::new(exe)
+        .args(["tx.csv", "--no-such-flag"])
+        .assert()
+        .code(2)
+        .stderr(pred::str::contains("Unknown flag: --no-such-flag"));
+}
+
+#[test]
+fn decimal_comma_reads_semicolon_separated_files() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type;client;tx;amount\n\
+    deposit;1;1;1,50\n\
+    withdrawal;1;2;0,25"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--decimal-comma", "--delimiter", ";"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.2500,0.0000,1.2500,false\n");
+}
+
+#[test]
+fn comment_char_skips_annotated_lines() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "# balances for the March audit\n\
+    type, client, tx, amount\n\
+    deposit, 1, 1, 3.0\n\
+    # reversed after a call with the client\n\
+    withdrawal, 1, 2, 1.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--comment-char", "#"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n")
+        .stderr("");
+}
+
+#[test]
+fn with_counts_adds_a_transaction_count_column() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 3.0\n\
+    withdrawal, 1, 2, 1.0\n\
+    dispute, 1, 1,\n\
+    resolve, 1, 1,\n\
+    deposit, 2, 3, 1.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--with-counts")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked,tx_count\n\
+             1,2.0000,0.0000,2.0000,false,2\n\
+             2,1.0000,0.0000,1.0000,false,1\n",
+        );
+}
+
+#[test]
+fn pretty_prints_an_aligned_table() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 2, 1, 2.5\n\
+    deposit, 10, 2, 12500.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .args(["--pretty", "--decimals", "1"])
+        .assert()
+        .success()
+        .stdout(
+            "client  available  held    total  locked\n\
+             ------  ---------  ----  -------  ------\n\
+             \x20    2        2.5   0.0      2.5   false\n\
+             \x20   10    12500.0   0.0  12500.0   false\n",
+        );
+}
+
+#[test]
+fn max_resident_accounts_prints_the_same_balances() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 3.0\n\
+    deposit, 2, 2, 2.0\n\
+    deposit, 3, 3, 1.0\n\
+    withdrawal, 1, 4, 1.5\n\
+    dispute, 2, 2,\n\
+    deposit, 3, 5, 1.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--max-resident-accounts")
+        .arg("1")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.5000,0.0000,1.5000,false\n\
+             2,0.0000,2.0000,2.0000,false\n\
+             3,2.0000,0.0000,2.0000,false\n",
+        );
+}
+
+#[test]
+fn spreadsheet_exports_with_a_bom_and_crlf_are_read() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    file.write_all(
+        b"\xEF\xBB\xBFtype,client,tx,amount\r\ndeposit,1,1,2.0\r\nwithdrawal,1,2,0.5\r\n",
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    for format in ["csv", "auto"] {
+        Command::new(exe)
+            .arg(file.path())
+            .arg("--input-format")
+            .arg(format)
+            .assert()
+            .success()
+            .stderr("")
+            .stdout("client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n");
+    }
+}
+
+#[test]
+fn output_writes_the_balances_to_a_file() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount\ndeposit, 1, 1, 2.0").unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let balances = dir.path().join("balances.csv");
+    let expected = "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n";
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--output")
+        .arg(&balances)
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(std::fs::read_to_string(&balances).unwrap(), expected);
+
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--output")
+        .arg(dir.path().join("missing").join("balances.csv"))
+        .assert()
+        .failure()
+        .stdout("");
+}
+
+#[test]
+fn tee_prints_what_goes_to_the_file() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 2, 2, 1.0"
+    )
+    .unwrap();
+    let balances = NamedTempFile::new().expect("create temp file");
+    let expected = "client,available,held,total,locked\n\
+                    1,2.0000,0.0000,2.0000,false\n\
+                    2,1.0000,0.0000,1.0000,false\n";
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--output")
+        .arg(balances.path())
+        .arg("--tee")
+        .assert()
+        .success()
+        .stdout(expected);
+    assert_eq!(std::fs::read_to_string(balances.path()).unwrap(), expected);
+}
+
+#[test]
+fn output_gzip_compresses_the_same_balances() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount").unwrap();
+    for tx in 1..=500 {
+        writeln!(file, "deposit, {}, {}, {}.25", tx % 40, tx, tx).unwrap();
+    }
+    let plain = NamedTempFile::new().expect("create temp file");
+    let gzipped = NamedTempFile::new().expect("create temp file");
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--output")
+        .arg(plain.path())
+        .assert()
+        .success();
+    let expected = std::fs::read_to_string(plain.path()).unwrap();
+    // stdout stays uncompressed next to the gzipped file
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--output")
+        .arg(gzipped.path())
+        .arg("--output-gzip")
+        .arg("--tee")
+        .assert()
+        .success()
+        .stdout(expected.clone());
+
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(std::fs::File::open(gzipped.path()).unwrap())
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, expected);
+    assert!(std::fs::metadata(gzipped.path()).unwrap().len() < expected.len() as u64);
+}
+
+#[test]
+fn failed_flush_still_finishes_the_gzip_output_and_the_summary() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount\ndeposit, 2, 1, 1.0").unwrap();
+    // client 1 twice: both rows count as funds brought in, but only one account holds
+    // them, so the conservation check fails the flush
+    let mut snapshot = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        snapshot,
+        "client,available,held,total,locked\n\
+         1,5.0,0.0,5.0,false\n\
+         1,5.0,0.0,5.0,false"
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let (output, summary) = (
+        dir.path().join("balances.csv.gz"),
+        dir.path().join("summary.json"),
+    );
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--restore")
+        .arg(snapshot.path())
+        .arg("--output")
+        .arg(&output)
+        .arg("--output-gzip")
+        .arg("--summary")
+        .arg(&summary)
+        .assert()
+        .failure()
+        .stderr(pred::str::contains("FundsNotConserved"));
+
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(std::fs::File::open(&output).unwrap())
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert!(
+        decoded.starts_with("client,available,held,total,locked\n"),
+        "{}",
+        decoded
+    );
+    let summary: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&summary).unwrap()).unwrap();
+    assert_eq!(summary["processed"]["deposit"], 1);
+}
+
+#[test]
+fn client_flush_runs_the_conservation_check() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(file, "type, client, tx, amount\ndeposit, 2, 1, 1.0").unwrap();
+    // the same unbalanced snapshot as above: printing one client must not hide it
+    let mut snapshot = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        snapshot,
+        "client,available,held,total,locked\n\
+         1,5.0,0.0,5.0,false\n\
+         1,5.0,0.0,5.0,false"
+    )
+    .unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_payments_engine"))
+        .arg(file.path())
+        .arg("--restore")
+        .arg(snapshot.path())
+        .args(["--client", "2"])
+        .assert()
+        .failure()
+        .stdout("client,available,held,total,locked\n2,1.0000,0.0000,1.0000,false\n")
+        .stderr(pred::str::contains("FundsNotConserved"));
+}
+
+#[test]
+fn replay_until_prints_the_balances_at_that_transaction() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 5.0\n\
+    deposit, 2, 2, 3.0\n\
+    withdrawal, 1, 3, 1.5\n\
+    dispute, 2, 2,\n\
+    deposit, 1, 4, 10.0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--replay-until")
+        .arg("3")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,3.5000,0.0000,3.5000,false\n\
+             2,3.0000,0.0000,3.0000,false\n",
+        )
+        .stderr("");
+
+    Command::new(exe)
+        .arg(file.path())
+        .arg("--replay-until")
+        .arg("99")
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,13.5000,0.0000,13.5000,false\n\
+             2,0.0000,3.0000,3.0000,false\n",
+        )
+        .stderr("Transaction 99 not found; replayed the whole input\n");
+}
+
+#[test]
+fn integer_amounts_print_with_four_decimals() {
+    let mut file = NamedTempFile::new().expect("create temp file");
+    writeln!(
+        file,
+        "type, client, tx, amount\n\
+    deposit, 1, 1, 100\n\
+    withdrawal, 1, 2, 0\n\
+    deposit, 2, 3, 0"
+    )
+    .unwrap();
+
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg(file.path())
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,100.0000,0.0000,100.0000,false\n\
+             2,0.0000,0.0000,0.0000,false\n",
+        );
+}
+
+#[test]
+fn repl_prints_balances_as_lines_are_typed() {
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .arg("--repl")
+        .write_stdin(
+            "deposit, 1, 1, 5.0\n\
+             deposit, 2, 2, 1.0\n\
+             withdrawal, 1, 3, 7\n\
+             not a transaction\n\
+             dispute, 1, 1,\n\
+             resolve, 1, 1,\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,5.0000,0.0000,5.0000,false\n\
+             2,1.0000,0.0000,1.0000,false\n\
+             1,0.0000,5.0000,5.0000,false\n\
+             1,5.0000,0.0000,5.0000,false\n",
+        )
+        .stderr(pred::str::contains("Insufficient funds for client 1"))
+        .stderr(pred::str::contains(
+            "Ingestion failed with: CSV deserialization error",
+        ));
+}
+
+#[test]
+fn repl_reads_lines_under_the_ingestion_flags() {
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    Command::new(exe)
+        .args([
+            "--repl",
+            "--skip-unknown",
+            "--delimiter",
+            ";",
+            "--decimal-comma",
+        ])
+        .write_stdin(
+            "type;client;tx;amount\n\
+             deposit;1;1;2,5\n\
+             refund;1;2;1,0\n\
+             withdrawal;1;3;0,5\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,2.5000,0.0000,2.5000,false\n\
+             1,2.0000,0.0000,2.0000,false\n",
+        )
+        .stderr("");
+}
+
+/// Runs the engine over `tests/golden/<case>.csv` with `args` and checks that it prints
+/// exactly `tests/golden/<case>.expected.csv`, byte for byte. To accept a deliberate
+/// change, regenerate the expected files with
+/// `UPDATE_GOLDEN=1 cargo test --test integration_test golden` and review the diff.
+fn assert_golden(case: &str, args: &[&str]) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let expected_path = dir.join(format!("{}.expected.csv", case));
+    let exe = env!("CARGO_BIN_EXE_payments_engine");
+    let output = Command::new(exe)
+        .arg(dir.join(format!("{}.csv", case)))
+        .args(args)
+        .output()
+        .expect("run the engine");
+    assert!(output.status.success(), "{}: {:?}", case, output);
+    let actual = String::from_utf8(output.stdout).expect("UTF-8 output");
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&expected_path, &actual).expect("write golden file");
+        return;
+    }
+    let expected = fs::read_to_string(&expected_path).unwrap_or_else(|e| {
+        panic!(
+            "{}: {} (UPDATE_GOLDEN=1 creates it)",
+            expected_path.display(),
+            e
+        )
+    });
+    assert_eq!(actual, expected, "{} differs from its golden file", case);
+}
+
+#[test]
+fn golden_transfers_print_sorted_rounded_balances() {
+    assert_golden("transfers", &[]);
+}
+
+#[test]
+fn golden_disputes_resolve_and_charge_back() {
+    assert_golden("disputes", &[]);
 }